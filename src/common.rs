@@ -21,12 +21,22 @@
 // SOFTWARE.
 
 use url::Url;
-use std::fmt::Display;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
 use log;
 use std;
 use std::io::{stderr, Write};
 use std::rc::Rc;
 
+use rustc_serialize::{Decodable, Decoder};
+
+use http::HttpClient;
+use publisher::redis::RedisUrl;
+#[cfg(test)]
+use domain_spec::DomainSpec;
+#[cfg(test)]
+use publisher::Publication;
+
 /// This macro is syntactic sugar for passing additional arguments to an error "conversion
 /// constructor". The idea is that you define `From<(YourError, Additional, Args)>` (a conversion
 /// from a tuple to an error) and then use this macro to supply the additional arguments.
@@ -49,11 +59,54 @@ macro_rules! try_ {
 /// The configuration used by beachhead-companion. Matches [Args] mostly.
 /// Note that the meanings of Option and 0 change to match program logic
 /// more naturally.
+#[derive(Clone)]
 pub struct Config {
-    /// The hostname or ip address of the redis server.
-    pub redis_host: Rc<String>,
-    /// The port of the redis server.
-    pub redis_port: u16,
+    /// The systems to publish to (at least one, no duplicates).
+    pub publishers: PublisherKinds,
+    /// Location of the redis server (including database and password).
+    pub redis_url: RedisUrl,
+    /// File to read the redis password from. Takes precedence over a password in `redis_url`.
+    pub redis_password_file: Option<Rc<String>>,
+    /// Location of the etcd server (or its JSON gateway).
+    pub etcd_url: Url,
+    /// Location of the (local) consul agent.
+    pub consul_url: Url,
+    /// Name under which domain specs are registered as consul services.
+    pub consul_service: Rc<String>,
+    /// Directory to write publication files to.
+    pub file_directory: Rc<String>,
+    /// Template for the nginx configuration (see `template`).
+    pub nginx_template: Option<Rc<String>>,
+    /// Where to write the rendered nginx configuration.
+    pub nginx_output: Rc<String>,
+    /// Shell command that validates the nginx configuration (e.g., `nginx -t`).
+    pub nginx_check_command: Option<Rc<String>>,
+    /// Shell command that makes nginx pick up the changed configuration.
+    pub nginx_reload_command: Option<Rc<String>>,
+    /// Directory for the HAProxy map files and backend configuration.
+    pub haproxy_directory: Rc<String>,
+    /// Path of the HAProxy runtime API socket. Used to apply changes without a reload.
+    pub haproxy_socket: Option<Rc<String>>,
+    /// Shell command that makes HAProxy pick up new backends.
+    pub haproxy_reload_command: Option<Rc<String>>,
+    /// Where to write the Traefik dynamic configuration (file provider).
+    pub traefik_file: Rc<String>,
+    /// Traefik entrypoint for routers of http ports.
+    pub traefik_http_entrypoint: Rc<String>,
+    /// Traefik entrypoint for routers of https ports.
+    pub traefik_https_entrypoint: Rc<String>,
+    /// Location of Caddy's admin API.
+    pub caddy_url: Url,
+    /// Name of the Caddy server whose routes are managed.
+    pub caddy_server: Rc<String>,
+    /// Endpoint that publication and withdrawal events are POSTed to.
+    pub webhook_url: Option<Url>,
+    /// File with the secret used to sign webhook requests.
+    pub webhook_secret_file: Option<Rc<String>>,
+    /// How long to wait for the webhook endpoint to respond.
+    pub webhook_timeout_seconds: u32,
+    /// How often to retry a failed webhook request right away.
+    pub webhook_retries: u32,
     /// The prefix for the keys to insert into redis. Will be followed by the container name.
     pub key_prefix: Rc<String>,
     /// The expiration for registrations in seconds. None means no expiration.
@@ -61,12 +114,49 @@ pub struct Config {
     /// The refresh interval for registrations in seconds. None means no refresh,
     /// only set once.
     pub refresh_seconds: Option<u32>,
+    /// The systems to inspect (at least one, no duplicates).
+    pub inspectors: InspectorKinds,
+    /// TOML file that lists services with static addresses (see `InspectorKind::Static`).
+    pub static_file: Option<Rc<String>>,
+    /// Location of the libpod API (usually the Unix socket of `podman system service`).
+    pub podman_url: Url,
+    /// containerd's socket.
+    pub containerd_address: Rc<String>,
+    /// The containerd namespace whose containers are inspected.
+    pub containerd_namespace: Rc<String>,
+    /// The directory in which CNI caches the results of setting up container networks.
+    pub cni_results_dir: Rc<String>,
+    /// The kubelet's read-only API (usually on port 10255).
+    pub kubelet_url: Url,
+    /// JSON dump of the kubelet's `/pods` to read instead of asking the kubelet.
+    pub kubelet_pods_file: Option<Rc<String>>,
     /// URL to the docker socket.
     pub docker_url: Url,
-    /// Whether to use the container hostname (true) or lookup the bridge network IP.
-    pub docker_network: bool,
+    /// Several Docker daemons to inspect instead of the one at `docker_url`. Container names are
+    /// namespaced by the name of their source (`source/name`). Empty means just `docker_url`.
+    pub docker_sources: DockerSources,
+    /// Directory with the client certificate (`cert.pem`), its key (`key.pem`) and the CA
+    /// certificate (`ca.pem`) for Docker daemons that require TLS. Applies to all sources.
+    pub docker_cert_path: Option<Rc<String>>,
+    /// Verify the certificate of the Docker daemon against `ca.pem` in `docker_cert_path`.
+    pub docker_tls_verify: bool,
+    /// Name of the docker network whose IP address to publish. None means the default bridge
+    /// network (or the only network the container is attached to).
+    pub docker_network: Option<Rc<String>>,
+    /// Publish the container's first alias on the network (or its name) instead of its IP address.
+    pub docker_network_alias: bool,
+    /// Publish this address together with the host ports that container ports are mapped to
+    /// (`docker run -p`) instead of the container's own address and ports.
+    pub publish_host: Option<Rc<String>>,
     /// Name of the environment variable to look up in docker container configuration.
     pub envvar: Rc<String>,
+    /// Name of the container label that lists domain specs (same format as the environment
+    /// variable).
+    pub label: Rc<String>,
+    /// Prefix for structured per-domain labels (e.g., `beachhead.example_org.https=8043`).
+    pub label_prefix: Rc<String>,
+    /// Where to look for domain specs on a container (environment variable, labels or both).
+    pub domain_source: DomainSource,
     /// Indicates whether this is a dry-run where the Redis update is not performed.
     pub dry_run: bool,
     /// How to handle missing environment variables on containers.
@@ -81,6 +171,270 @@ pub struct Config {
     pub systemd: bool,
     /// The number of milliseconds a service manager waits between 'alive' pings from this program.
     pub watchdog_microseconds: Option<u64>,
+    /// Whether to subscribe to change notifications from the inspector (e.g., the docker event
+    /// stream) in addition to the periodic refresh.
+    pub watch_events: bool,
+    /// Settings that differ for individual containers, indexed by container name (without the
+    /// leading '/'). Only available via the configuration file.
+    pub container_overrides: HashMap<String, ContainerOverrides>,
+}
+
+/// Per-container settings. Settings that are None fall back to the global configuration.
+#[derive(Debug,Clone,Default)]
+pub struct ContainerOverrides {
+    pub envvar: Option<Rc<String>>,
+    pub label: Option<Rc<String>>,
+    pub domain_source: Option<DomainSource>,
+    pub docker_network: Option<Rc<String>>,
+    pub missing_envvar: Option<MissingEnvVarHandling>,
+}
+
+impl Config {
+    /// The configuration to use for a particular container (global configuration with the
+    /// container's overrides applied).
+    pub fn for_container(&self, container_name: &str) -> Config {
+        let mut config = self.clone();
+        if let Some(overrides) = self.container_overrides
+            .get(container_name.trim_left_matches('/')) {
+            if let Some(ref envvar) = overrides.envvar {
+                config.envvar = envvar.clone();
+            }
+            if let Some(ref label) = overrides.label {
+                config.label = label.clone();
+            }
+            if let Some(domain_source) = overrides.domain_source {
+                config.domain_source = domain_source;
+            }
+            if let Some(ref docker_network) = overrides.docker_network {
+                config.docker_network = Some(docker_network.clone());
+            }
+            if let Some(missing_envvar) = overrides.missing_envvar {
+                config.missing_envvar = missing_envvar;
+            }
+        }
+        config
+    }
+
+    /// Checks for settings that are required by the selected publishers and inspectors (or
+    /// otherwise depend on each other). Used on startup and when the configuration is reloaded.
+    pub fn check(&self) -> Result<(), String> {
+        if self.publishers.contains(PublisherKind::Nginx) && self.nginx_template.is_none() {
+            return Err("--publisher=nginx requires --nginx-template.".to_owned());
+        }
+        if self.publishers.contains(PublisherKind::Webhook) && self.webhook_url.is_none() {
+            return Err("--publisher=webhook requires --webhook-url.".to_owned());
+        }
+        if self.inspectors.contains(InspectorKind::Static) && self.static_file.is_none() {
+            return Err("--inspector=static requires --static-file.".to_owned());
+        }
+        if self.publishers.contains(PublisherKind::Webhook) && self.webhook_timeout_seconds == 0 {
+            return Err("--webhook-timeout must be at least 1 second.".to_owned());
+        }
+        let mut endpoints = Vec::new();
+        if self.publishers.contains(PublisherKind::Etcd) {
+            endpoints.push(("--etcd-url", &self.etcd_url));
+        }
+        if self.publishers.contains(PublisherKind::Consul) {
+            endpoints.push(("--consul-url", &self.consul_url));
+        }
+        if self.publishers.contains(PublisherKind::Caddy) {
+            endpoints.push(("--caddy-url", &self.caddy_url));
+        }
+        if let Some(ref webhook_url) = self.webhook_url {
+            endpoints.push(("--webhook-url", webhook_url));
+        }
+        if self.inspectors.contains(InspectorKind::Podman) {
+            endpoints.push(("--podman-url", &self.podman_url));
+        }
+        if self.inspectors.contains(InspectorKind::Kubelet) && self.kubelet_pods_file.is_none() {
+            endpoints.push(("--kubelet-url", &self.kubelet_url));
+        }
+        for (option, url) in endpoints {
+            // Rejects unsupported schemes and broken TLS setups.
+            if let Err(e) = HttpClient::new(url) {
+                return Err(format!("Invalid {}. {}", option, e));
+            }
+        }
+        if self.inspectors.contains(InspectorKind::Docker) {
+            let clash = self.docker_sources
+                .0
+                .iter()
+                .find(|source| self.inspectors.0.iter().any(|kind| kind.name() == source.name));
+            if let Some(source) = clash {
+                return Err(format!("Docker source '{}' has the same name as an inspector.",
+                                   source.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The system that configurations get published to.
+#[derive(Debug,Eq,PartialEq,Copy,Clone)]
+pub enum PublisherKind {
+    /// Keys with expiration in Redis.
+    Redis,
+    /// Keys attached to a lease in etcd (v3 API).
+    Etcd,
+    /// Services with TTL checks registered with the local consul agent.
+    Consul,
+    /// One JSON file per publication in a directory.
+    File,
+    /// An nginx configuration file rendered from a template.
+    Nginx,
+    /// HAProxy map files and backends, updated via the runtime API where possible.
+    Haproxy,
+    /// Dynamic configuration for Traefik's file provider.
+    Traefik,
+    /// Routes of a Caddy server, managed through Caddy's admin API.
+    Caddy,
+    /// JSON events POSTed to an HTTP endpoint.
+    Webhook,
+}
+
+impl PublisherKind {
+    pub fn all() -> &'static [PublisherKind] {
+        static ALL: [PublisherKind; 9] = [PublisherKind::Redis,
+                                          PublisherKind::Etcd,
+                                          PublisherKind::Consul,
+                                          PublisherKind::File,
+                                          PublisherKind::Nginx,
+                                          PublisherKind::Haproxy,
+                                          PublisherKind::Traefik,
+                                          PublisherKind::Caddy,
+                                          PublisherKind::Webhook];
+        &ALL
+    }
+
+    /// The name used on the command line and in the configuration file.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PublisherKind::Redis => "redis",
+            PublisherKind::Etcd => "etcd",
+            PublisherKind::Consul => "consul",
+            PublisherKind::File => "file",
+            PublisherKind::Nginx => "nginx",
+            PublisherKind::Haproxy => "haproxy",
+            PublisherKind::Traefik => "traefik",
+            PublisherKind::Caddy => "caddy",
+            PublisherKind::Webhook => "webhook",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<PublisherKind> {
+        let raw = raw.trim().to_lowercase();
+        PublisherKind::all().iter().cloned().find(|kind| kind.name() == raw)
+    }
+}
+
+/// One or more publishers, given as a comma-separated list (e.g., `redis,file`). Publications go
+/// to all of them.
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct PublisherKinds(pub Vec<PublisherKind>);
+
+impl PublisherKinds {
+    pub fn contains(&self, kind: PublisherKind) -> bool {
+        self.0.contains(&kind)
+    }
+}
+
+impl Display for PublisherKinds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|kind| kind.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// The system that containers (or services) and their domain specs are read from.
+#[derive(Debug,Eq,PartialEq,Copy,Clone)]
+pub enum InspectorKind {
+    /// Running containers of one or more Docker daemons.
+    Docker,
+    /// Services with static addresses, listed in a file.
+    Static,
+    /// Running containers of Podman (libpod API).
+    Podman,
+    /// Running containers of containerd in one namespace (via its gRPC API).
+    Containerd,
+    /// Running pods of a single Kubernetes node (kubelet).
+    Kubelet,
+}
+
+impl InspectorKind {
+    pub fn all() -> &'static [InspectorKind] {
+        static ALL: [InspectorKind; 5] = [InspectorKind::Docker,
+                                          InspectorKind::Static,
+                                          InspectorKind::Podman,
+                                          InspectorKind::Containerd,
+                                          InspectorKind::Kubelet];
+        &ALL
+    }
+
+    /// The name used on the command line and in the configuration file. With several
+    /// inspectors, also the source that container names are namespaced by.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            InspectorKind::Docker => "docker",
+            InspectorKind::Static => "static",
+            InspectorKind::Podman => "podman",
+            InspectorKind::Containerd => "containerd",
+            InspectorKind::Kubelet => "kubelet",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<InspectorKind> {
+        let raw = raw.trim().to_lowercase();
+        InspectorKind::all().iter().cloned().find(|kind| kind.name() == raw)
+    }
+}
+
+/// One or more inspectors, given as a comma-separated list (e.g., `docker,static`).
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct InspectorKinds(pub Vec<InspectorKind>);
+
+impl InspectorKinds {
+    pub fn contains(&self, kind: InspectorKind) -> bool {
+        self.0.contains(&kind)
+    }
+}
+
+impl Display for InspectorKinds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|kind| kind.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// A Docker daemon to inspect, with the name that its containers are namespaced by.
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct DockerSource {
+    pub name: String,
+    pub url: Url,
+}
+
+/// Docker daemons given as a comma-separated list of `name=url` pairs (e.g.,
+/// `east=tcp://10.0.0.1:2376,west=tcp://10.0.1.1:2376`).
+#[derive(Debug,Eq,PartialEq,Clone,Default)]
+pub struct DockerSources(pub Vec<DockerSource>);
+
+impl DockerSources {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DockerSource> {
+        self.0.iter().find(|source| source.name == name)
+    }
+}
+
+impl Display for DockerSources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pairs: Vec<String> = self.0
+            .iter()
+            .map(|source| format!("{}={}", source.name, source.url))
+            .collect();
+        write!(f, "{}", pairs.join(","))
+    }
 }
 
 /// Behaviour when confronted with a container that does not have a beachhead environment variable
@@ -95,6 +449,18 @@ pub enum MissingEnvVarHandling {
     Ignore,
 }
 
+/// Where to look for domain specs on a container. When both sources are used, domain specs
+/// from labels take precedence over specs for the same domain from the environment variable.
+#[derive(Debug,Eq,PartialEq,Copy,Clone)]
+pub enum DomainSource {
+    /// Only look at the environment variable.
+    Env,
+    /// Only look at container labels.
+    Labels,
+    /// Look at both the environment variable and labels.
+    Both,
+}
+
 /// Behaviour when confronted with a container that cannot be inspected. See enum constants for
 /// details. The main idea behind this setting is that it's not beachhead companion's job to
 /// monitor your containers. If it's not there, don't publish its configuration (let it expire).
@@ -112,6 +478,109 @@ impl Default for MissingEnvVarHandling {
     }
 }
 
+impl Default for DomainSource {
+    fn default() -> DomainSource {
+        DomainSource::Both
+    }
+}
+
+// Decoded by hand (instead of derived) so that the lowercase spelling works both on the command
+// line and in the configuration file.
+impl Decodable for DomainSource {
+    fn decode<D: Decoder>(d: &mut D) -> Result<DomainSource, D::Error> {
+        let raw = try!(d.read_str());
+        match raw.to_lowercase().as_str() {
+            "env" => Ok(DomainSource::Env),
+            "labels" => Ok(DomainSource::Labels),
+            "both" => Ok(DomainSource::Both),
+            _ => {
+                Err(d.error(&format!("Invalid domain source '{}'. Expected env, labels or both.",
+                                     raw)))
+            }
+        }
+    }
+}
+
+impl Decodable for PublisherKinds {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PublisherKinds, D::Error> {
+        let raw = try!(d.read_str());
+        let mut kinds = Vec::new();
+        for name in raw.split(',') {
+            match PublisherKind::parse(name) {
+                Some(kind) => {
+                    if !kinds.contains(&kind) {
+                        kinds.push(kind);
+                    }
+                }
+                None => {
+                    let names: Vec<&str> =
+                        PublisherKind::all().iter().map(|kind| kind.name()).collect();
+                    return Err(d.error(&format!("Invalid publisher '{}'. Expected a \
+                                                 comma-separated list of {}.",
+                                                name.trim(),
+                                                names.join(", "))));
+                }
+            }
+        }
+        Ok(PublisherKinds(kinds))
+    }
+}
+
+impl Decodable for InspectorKinds {
+    fn decode<D: Decoder>(d: &mut D) -> Result<InspectorKinds, D::Error> {
+        let raw = try!(d.read_str());
+        let mut kinds = Vec::new();
+        for name in raw.split(',') {
+            match InspectorKind::parse(name) {
+                Some(kind) => {
+                    if !kinds.contains(&kind) {
+                        kinds.push(kind);
+                    }
+                }
+                None => {
+                    let names: Vec<&str> =
+                        InspectorKind::all().iter().map(|kind| kind.name()).collect();
+                    return Err(d.error(&format!("Invalid inspector '{}'. Expected a \
+                                                 comma-separated list of {}.",
+                                                name.trim(),
+                                                names.join(", "))));
+                }
+            }
+        }
+        Ok(InspectorKinds(kinds))
+    }
+}
+
+impl Decodable for DockerSources {
+    fn decode<D: Decoder>(d: &mut D) -> Result<DockerSources, D::Error> {
+        let raw = try!(d.read_str());
+        let mut sources: Vec<DockerSource> = Vec::new();
+        for pair in raw.split(',').map(|pair| pair.trim()).filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let url = parts.next().map(|url| Url::parse(url.trim()));
+            let valid_name = !name.is_empty() &&
+                             name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+            match url {
+                Some(Ok(url)) if valid_name => {
+                    if sources.iter().any(|source| source.name == name) {
+                        return Err(d.error(&format!("Docker source '{}' given more than once.",
+                                                    name)));
+                    }
+                    sources.push(DockerSource { name: name.to_owned(), url: url });
+                }
+                _ => {
+                    return Err(d.error(&format!("Invalid Docker source '{}'. Expected \
+                                                 NAME=URL where NAME consists of letters, \
+                                                 digits, '-' and '_'.",
+                                                pair)))
+                }
+            }
+        }
+        Ok(DockerSources(sources))
+    }
+}
+
 impl Default for MissingContainerHandling {
     fn default() -> MissingContainerHandling {
         MissingContainerHandling::Ignore
@@ -125,20 +594,59 @@ impl Default for MissingContainerHandling {
 impl Default for Config {
     fn default() -> Config {
         Config {
-            redis_host: Rc::new("localhost".to_owned()),
-            redis_port: 6379,
+            publishers: PublisherKinds(vec![PublisherKind::Redis]),
+            redis_url: RedisUrl::tcp("localhost".to_owned(), 6379),
+            redis_password_file: None,
+            etcd_url: Url::parse("http://127.0.0.1:2379").unwrap(),
+            consul_url: Url::parse("http://127.0.0.1:8500").unwrap(),
+            consul_service: Rc::new("beachhead".to_owned()),
+            file_directory: Rc::new("/run/beachhead-companion".to_owned()),
+            nginx_template: None,
+            nginx_output: Rc::new("/etc/nginx/conf.d/beachhead.conf".to_owned()),
+            nginx_check_command: None,
+            nginx_reload_command: None,
+            haproxy_directory: Rc::new("/etc/haproxy/beachhead".to_owned()),
+            haproxy_socket: None,
+            haproxy_reload_command: None,
+            traefik_file: Rc::new("/etc/traefik/dynamic/beachhead.toml".to_owned()),
+            traefik_http_entrypoint: Rc::new("web".to_owned()),
+            traefik_https_entrypoint: Rc::new("websecure".to_owned()),
+            caddy_url: Url::parse("http://localhost:2019").unwrap(),
+            caddy_server: Rc::new("beachhead".to_owned()),
+            webhook_url: None,
+            webhook_secret_file: None,
+            webhook_timeout_seconds: 5,
+            webhook_retries: 2,
             key_prefix: Rc::new("".to_owned()),
             expire_seconds: Some(60),
             refresh_seconds: Some(27),
+            inspectors: InspectorKinds(vec![InspectorKind::Docker]),
+            static_file: None,
+            podman_url: Url::parse("unix:///run/podman/podman.sock").unwrap(),
+            containerd_address: Rc::new("/run/containerd/containerd.sock".to_owned()),
+            containerd_namespace: Rc::new("default".to_owned()),
+            cni_results_dir: Rc::new("/var/lib/cni/results".to_owned()),
+            kubelet_url: Url::parse("http://127.0.0.1:10255").unwrap(),
+            kubelet_pods_file: None,
             docker_url: Url::parse("unix://var/run/docker.sock").unwrap(),
-            docker_network: false,
+            docker_sources: DockerSources::default(),
+            docker_cert_path: None,
+            docker_tls_verify: false,
+            docker_network: None,
+            docker_network_alias: false,
+            publish_host: None,
             envvar: Rc::new("BEACHHEAD_DOMAINS".to_owned()),
+            label: Rc::new("beachhead.domains".to_owned()),
+            label_prefix: Rc::new("beachhead.".to_owned()),
+            domain_source: Default::default(),
             dry_run: false,
             missing_envvar: Default::default(),
             missing_container: Default::default(),
             enumerate: false,
             systemd: false,
             watchdog_microseconds: None,
+            watch_events: false,
+            container_overrides: HashMap::new(),
         }
     }
 }
@@ -156,6 +664,39 @@ pub fn init_log() {
     }
 }
 
+/// A publication for tests.
+#[cfg(test)]
+pub fn test_publication(key: &str, host: &str, specs: Vec<DomainSpec>) -> Publication {
+    Publication {
+        key: key.to_owned(),
+        host: host.to_owned(),
+        specs: specs,
+    }
+}
+
+/// A domain spec for tests, without path prefix and options.
+#[cfg(test)]
+pub fn test_spec(domain_name: &str, http_port: Option<u16>, https_port: Option<u16>) -> DomainSpec {
+    DomainSpec {
+        domain_name: domain_name.to_owned(),
+        path_prefix: None,
+        http_port: http_port,
+        https_port: https_port,
+        options: std::collections::BTreeMap::new(),
+    }
+}
+
+/// A fresh, empty directory for a test (`beachhead-<name>` in the temp directory).
+#[cfg(test)]
+pub fn test_directory(name: &str) -> std::path::PathBuf {
+    use std::env;
+    use std::fs;
+    let directory = env::temp_dir().join(format!("beachhead-{}", name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
 /// Turn an optional result into a result of an optional value.
 ///
 /// # Examples
@@ -226,6 +767,8 @@ pub fn stay_very_calm_and<T, E>(result: Result<T, Vec<E>>)
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
+    use url::Url;
 
     #[test]
     fn default_config() {
@@ -240,6 +783,59 @@ mod tests {
         assert!(config.expire_seconds.is_some());
     }
 
+    #[test]
+    fn for_container_overrides() {
+        init_log();
+        // #### GIVEN ####
+        let mut config: Config = Default::default();
+        config.container_overrides.insert("admin".to_owned(),
+                                          ContainerOverrides {
+                                              envvar: Some(Rc::new("ADMIN_DOMAINS".to_owned())),
+                                              domain_source: Some(DomainSource::Env),
+                                              ..Default::default()
+                                          });
+
+        // #### WHEN  ####
+        let admin_config = config.for_container("/admin");
+        let other_config = config.for_container("other");
+
+        // #### THEN  ####
+        assert_eq!(admin_config.envvar.as_str(), "ADMIN_DOMAINS");
+        assert_eq!(admin_config.domain_source, DomainSource::Env);
+        assert_eq!(admin_config.label, config.label);
+        assert_eq!(other_config.envvar, config.envvar);
+        assert_eq!(other_config.domain_source, DomainSource::Both);
+    }
+
+    #[test]
+    fn check_requirements() {
+        init_log();
+        // #### GIVEN ####
+        let config: Config = Default::default();
+        let mut nginx = config.clone();
+        nginx.publishers = PublisherKinds(vec![PublisherKind::Nginx]);
+        let mut webhook = config.clone();
+        webhook.publishers = PublisherKinds(vec![PublisherKind::Webhook]);
+        webhook.webhook_url = Some(Url::parse("ftp://hooks.example.org/beachhead").unwrap());
+        let mut clash = config.clone();
+        clash.inspectors = InspectorKinds(vec![InspectorKind::Docker, InspectorKind::Static]);
+        clash.static_file = Some(Rc::new("/etc/beachhead/services.toml".to_owned()));
+        let source = DockerSource { name: "static".to_owned(), url: config.docker_url.clone() };
+        clash.docker_sources = DockerSources(vec![source]);
+
+        // #### WHEN  ####
+        let default_result = config.check();
+        let nginx_result = nginx.check();
+        let webhook_result = webhook.check();
+        let clash_result = clash.check();
+
+        // #### THEN  ####
+        assert_eq!(default_result, Ok(()));
+        assert_eq!(nginx_result, Err("--publisher=nginx requires --nginx-template.".to_owned()));
+        assert!(webhook_result.unwrap_err().starts_with("Invalid --webhook-url."));
+        assert!(clash_result.unwrap_err().contains("'static'"));
+    }
+
     #[test]
     fn optional_result_full() {
         init_log();
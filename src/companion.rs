@@ -22,8 +22,9 @@
 
 
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::mem;
 use std::sync::Arc;
 use std::cmp::{Ordering, min};
 use std::rc::Rc;
@@ -34,16 +35,47 @@ use chrono::{self, Local, DateTime};
 use chan_signal::Signal;
 use systemd::daemon;
 
-use inspector::{Inspect, Inspection, InspectionError};
+use inspector::{Inspect, Inspection, InspectionError, InspectorEvent};
 use publisher::{Publication, PublishingError, Publish};
 use common::{Config, MissingEnvVarHandling, MissingContainerHandling};
+use config_file::ConfigFileError;
 
 struct Context {
     pub config: Arc<Config>,
     pub inspector: Box<Inspect>,
     pub publisher: Box<Publish>,
     pub termination_signal: chan::Receiver<Signal>,
+    /// Change notifications from the inspector.
+    pub inspector_events: chan::Receiver<InspectorEvent>,
+    /// Handed to the inspector when watching starts. The context keeps a copy so that the channel
+    /// stays open even if the inspector stops watching.
+    inspector_events_send: chan::Sender<InspectorEvent>,
+    /// Whether the inspector is currently reporting changes.
+    watching: bool,
     next_watchdog: Option<chrono::DateTime<chrono::Local>>,
+    /// When the next full refresh is due. Survives wake-ups caused by inspector events, so that
+    /// a steady stream of events doesn't postpone the periodic refresh indefinitely.
+    next_refresh: Option<chrono::DateTime<chrono::Local>>,
+    /// The last successful publication for each container (by container name). Used to withdraw
+    /// publications of containers that have disappeared.
+    published: HashMap<Rc<String>, Publication>,
+    /// Withdrawals since the start of the current publisher batch. Restored to `published` if
+    /// the batch can't be sent.
+    batch_withdrawn: Vec<(Rc<String>, Publication)>,
+}
+
+/// Reason for waking up from `Context::wait`.
+#[derive(Debug, Eq, PartialEq)]
+enum Wakeup {
+    /// Exit the companion loop (either because we are in one-shot mode or because termination
+    /// was requested).
+    Stop,
+    /// Time for the periodic refresh of all containers.
+    Refresh,
+    /// The inspector reported changes to individual containers.
+    Events(Vec<InspectorEvent>),
+    /// Re-read the configuration (SIGHUP).
+    Reload,
 }
 
 impl Context {
@@ -53,23 +85,158 @@ impl Context {
            termination_signal: chan::Receiver<Signal>)
            -> Context {
         let next_watchdog = config.watchdog_microseconds.map(|_| Local::now());
+        let (inspector_events_send, inspector_events) = chan::async();
         Context {
             config: config,
             termination_signal: termination_signal,
             inspector: inspector,
             publisher: publisher,
+            inspector_events: inspector_events,
+            inspector_events_send: inspector_events_send,
+            watching: false,
             next_watchdog: next_watchdog,
+            next_refresh: None,
+            published: HashMap::new(),
+            batch_withdrawn: Vec::new(),
         }
     }
 
+    /// Ask the inspector to report changes as they happen.
+    fn watch(&mut self) -> Result<(), CompanionError> {
+        try!(self.inspector.watch(self.inspector_events_send.clone()));
+        self.watching = true;
+        Ok(())
+    }
+
+    /// Whether the configuration asks for change notifications. They are pointless in one-shot
+    /// mode.
+    fn should_watch(&self) -> bool {
+        self.config.watch_events && self.config.refresh_seconds.is_some()
+    }
+
+    /// Switch to a reloaded configuration. The inspector and publisher decide for themselves
+    /// whether they need to reconnect.
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), CompanionError> {
+        let config = if config.publishers != self.config.publishers {
+            warn!("Switching publishers from {} to {} requires a restart. Keeping {}.",
+                  self.config.publishers,
+                  config.publishers,
+                  self.config.publishers);
+            let mut config = (*config).clone();
+            config.publishers = self.config.publishers.clone();
+            Arc::new(config)
+        } else {
+            config
+        };
+        let config = if config.inspectors != self.config.inspectors {
+            warn!("Switching inspectors from {} to {} requires a restart. Keeping {}.",
+                  self.config.inspectors,
+                  config.inspectors,
+                  self.config.inspectors);
+            let mut config = (*config).clone();
+            config.inspectors = self.config.inspectors.clone();
+            Arc::new(config)
+        } else {
+            config
+        };
+        // Sources can change their URL, but adding or removing them means building another
+        // inspector.
+        let source_names = |config: &Config| -> Vec<String> {
+            config.docker_sources.0.iter().map(|source| source.name.clone()).collect()
+        };
+        let config = if source_names(&config) != source_names(&self.config) {
+            warn!("Switching Docker sources from '{}' to '{}' requires a restart. Keeping '{}'.",
+                  self.config.docker_sources,
+                  config.docker_sources,
+                  self.config.docker_sources);
+            let mut config = (*config).clone();
+            config.docker_sources = self.config.docker_sources.clone();
+            Arc::new(config)
+        } else {
+            config
+        };
+        self.config = config.clone();
+        // The refresh interval might have changed.
+        self.next_refresh = None;
+        if self.config.watchdog_microseconds.is_none() {
+            self.next_watchdog = None;
+        } else if self.next_watchdog.is_none() {
+            self.next_watchdog = Some(Local::now());
+        }
+
+        let inspector_result = self.inspector.reconfigure(config.clone());
+        let publisher_result = self.publisher.reconfigure(config);
+        if self.watching && !self.should_watch() {
+            self.inspector.unwatch();
+            self.watching = false;
+        } else if !self.watching && self.should_watch() {
+            if let Err(e) = self.watch() {
+                warn!("Failed to watch for changes. Relying on periodic refresh only. Error: {}",
+                      e);
+            }
+        }
+        try!(inspector_result);
+        try!(publisher_result);
+        Ok(())
+    }
+
     fn inspect(&mut self,
                container_name: Pending<Rc<String>>)
                -> Result<Pending<Inspection>, CompanionError> {
         container_name.try_map(|name| self.inspector.inspect(&name)).map_err(From::from)
     }
 
-    fn publish(&mut self, publication: Publication) -> Result<(), CompanionError> {
+    fn publish(&mut self,
+               container_name: &Rc<String>,
+               publication: Publication)
+               -> Result<(), CompanionError> {
         try!(self.publisher.publish(&publication));
+        self.published.insert(container_name.clone(), publication);
+        Ok(())
+    }
+
+    /// Withdraw the last publication for the indicated container (if there is one).
+    fn withdraw(&mut self, container_name: &Rc<String>) -> Result<(), CompanionError> {
+        if let Some(publication) = self.published.remove(container_name) {
+            // Hosts (IP addresses) get re-used. Don't withdraw a publication that now belongs to
+            // a different container.
+            if self.published.values().any(|p| p.key == publication.key) {
+                debug!("Not withdrawing configuration for container {}. Key {} was taken over \
+                        by another container.",
+                       container_name,
+                       publication.key);
+                return Ok(());
+            }
+            info!("Withdrawing configuration for container {}. Key: {}",
+                  container_name,
+                  publication.key);
+            if let Err(e) = self.publisher.withdraw(&publication) {
+                // Try again on the next refresh.
+                self.published.insert(container_name.clone(), publication);
+                return Err(From::from(e));
+            }
+            self.batch_withdrawn.push((container_name.clone(), publication));
+        }
+        Ok(())
+    }
+
+    /// Start collecting publisher updates for one refresh iteration (see `flush`).
+    fn begin_batch(&mut self) {
+        self.batch_withdrawn.clear();
+        self.publisher.begin_batch();
+    }
+
+    /// Send the updates collected since `begin_batch`.
+    fn flush(&mut self) -> Result<(), CompanionError> {
+        let withdrawn = mem::replace(&mut self.batch_withdrawn, Vec::new());
+        if let Err(e) = self.publisher.flush() {
+            // The withdrawals didn't go out. Try again on the next refresh (unless the container
+            // has been published again in the meantime).
+            for (container_name, publication) in withdrawn {
+                self.published.entry(container_name).or_insert(publication);
+            }
+            return Err(From::from(e));
+        }
         Ok(())
     }
 
@@ -95,12 +262,13 @@ impl Context {
                 enum_result = Err(e)
             } else {
                 enum_result = Ok(());
-                for name in enumeration.drain(..) {
-                    let boxed_name = Rc::new(name);
-                    let key = boxed_name.clone();
-                    container_index.entry(key)
-                        .or_insert(Pending { explicit: false, todo: boxed_name });
-                }
+            }
+            // Even if enumeration failed, some sources might have been enumerated.
+            for name in enumeration.drain(..) {
+                let boxed_name = Rc::new(name);
+                let key = boxed_name.clone();
+                container_index.entry(key)
+                    .or_insert(Pending { explicit: false, todo: boxed_name });
             }
         } else {
             enum_result = Ok(())
@@ -110,14 +278,20 @@ impl Context {
         (final_names, enum_result.map_err(|e| From::from(e)))
     }
 
-    /// Wait for the next refresh. Returns true when we should continue with another refresh;
-    /// Returns false when we should exit (either because we are on one-shot mode or because
-    /// termination was requested)
-    fn wait(&mut self) -> bool {
+    /// Wait for the next refresh or for change notifications from the inspector.
+    /// Returns `Wakeup::Stop` when we should exit (either because we are in one-shot mode or
+    /// because termination was requested).
+    fn wait(&mut self) -> Wakeup {
         if let Some(refresh_seconds) = self.config.refresh_seconds {
             let start_of_wait = chrono::Local::now();
-            let timeout_duration = chrono::Duration::seconds(refresh_seconds as i64);
-            let next_refresh = start_of_wait + timeout_duration;
+            let next_refresh = match self.next_refresh {
+                Some(next_refresh) => next_refresh,
+                None => {
+                    let timeout_duration = chrono::Duration::seconds(refresh_seconds as i64);
+                    start_of_wait + timeout_duration
+                }
+            };
+            self.next_refresh = Some(next_refresh);
             let (_s1, refresh_timeout) =
                 deadline_to_alarm_clock(start_of_wait, Some(next_refresh), "refresh");
 
@@ -133,16 +307,14 @@ impl Context {
                     // NOTE: default needs to come first (required by macro)
                     chan_select! {
                         default => {
-                            signal_received = false;
+                            signal_received = None;
                         },
                         rsig.recv() -> sig => {
-                            debug!("Received {:?} signal. Shutting down.", sig);
-                            signal_received = true;
+                            signal_received = Some(sig);
                         },
                     }
-                    if signal_received {
-                        // Signal end of companion loop
-                        return false;
+                    if let Some(sig) = signal_received {
+                        return signal_to_wakeup(sig);
                     }
                 }
 
@@ -155,6 +327,9 @@ impl Context {
                 const DO_STOP: i32 = 0;
                 const DO_CONTINUE: i32 = 1;
                 const DO_PING: i32 = 3;
+                const DO_EVENTS: i32 = 4;
+                let mut events = Vec::new();
+                let mut signal_wakeup = Wakeup::Stop;
                 {
                     // Compute effective timeouts for the next wait
                     let now = chrono::Local::now();
@@ -163,9 +338,10 @@ impl Context {
 
                     // Same as above: constrain mutable borrow to the smallest possible regions.
                     let rsig = &mut self.termination_signal;
+                    let revents = &mut self.inspector_events;
                     chan_select! {
                         rsig.recv() -> sig => {
-                            debug!("Received {:?} signal. Shutting down.", sig);
+                            signal_wakeup = signal_to_wakeup(sig);
                             do_next = DO_STOP;
                         },
                         refresh_timeout.recv() => {
@@ -175,32 +351,63 @@ impl Context {
                         watchdog_timeout.recv() => {
                             debug!("Waking up to send 'alive' ping to service manager.");
                             do_next = DO_PING
+                        },
+                        revents.recv() -> event => {
+                            // The context holds on to a sender, so the channel never closes.
+                            events.extend(event);
+                            do_next = DO_EVENTS
                         }
                     };
                 }
 
                 // Act on the outcome of the chan_select!
                 if do_next == DO_STOP {
-                    return false;
+                    return signal_wakeup;
                 } else if do_next == DO_CONTINUE {
-                    return true;
+                    self.next_refresh = None;
+                    return Wakeup::Refresh;
                 } else if do_next == DO_PING {
                     self.notify_status("Waiting");
                     // no return, re-enter the wait loop
+                } else if do_next == DO_EVENTS {
+                    // Events tend to come in bursts (e.g., stop followed by die). Handle all
+                    // of them in one go.
+                    self.drain_events(&mut events);
+                    debug!("Received inspector events: {:?}", events);
+                    return Wakeup::Events(events);
                 } else {
                     error!(concat!("Program error: unexpected state in companion loop: {}. ",
-                                   "Expected one of {}, {} or {}"),
+                                   "Expected one of {}, {}, {} or {}"),
                            do_next,
                            DO_STOP,
                            DO_CONTINUE,
-                           DO_PING);
-                    return false;
+                           DO_PING,
+                           DO_EVENTS);
+                    return Wakeup::Stop;
                 }
             }
         } else {
             // Only refresh once and then exit.
             debug!("Refresh disabled. Shutting down.");
-            false
+            Wakeup::Stop
+        }
+    }
+
+    /// Collect inspector events that are already waiting in the channel without blocking.
+    fn drain_events(&mut self, events: &mut Vec<InspectorEvent>) {
+        loop {
+            let revents = &mut self.inspector_events;
+            let mut received = None;
+            chan_select! {
+                default => {},
+                revents.recv() -> event => {
+                    received = event;
+                },
+            }
+            match received {
+                Some(event) => events.push(event),
+                None => return,
+            }
         }
     }
 
@@ -241,6 +448,18 @@ impl Context {
     }
 }
 
+/// Interpret a signal received by the companion loop. SIGHUP asks for a configuration reload, all
+/// other signals (and the signal channel closing) end the loop.
+fn signal_to_wakeup(signal: Option<Signal>) -> Wakeup {
+    if signal == Some(Signal::HUP) {
+        info!("Received HUP signal. Reloading configuration.");
+        Wakeup::Reload
+    } else {
+        debug!("Received {:?} signal. Shutting down.", signal);
+        Wakeup::Stop
+    }
+}
+
 fn deadline_to_alarm_clock(now: DateTime<Local>,
                            deadline: Option<DateTime<Local>>,
                            desc: &str)
@@ -271,8 +490,18 @@ fn deadline_to_alarm_clock(now: DateTime<Local>,
     }
 }
 
-fn to_publication(inspection: Pending<Inspection>) -> Publication {
-    Publication { host: inspection.todo.host, specs: inspection.todo.specs }
+fn to_publication(config: &Config,
+                  container_name: &str,
+                  inspection: Pending<Inspection>)
+                  -> Publication {
+    // Publications are usually identified by their host. When host ports are published, all
+    // containers share the same host, so the container name needs to be part of the key.
+    let key = if config.publish_host.is_some() {
+        format!("{}_{}", inspection.todo.host, container_name.trim_left_matches('/'))
+    } else {
+        inspection.todo.host.clone()
+    };
+    Publication { key: key, host: inspection.todo.host, specs: inspection.todo.specs }
 }
 
 fn notify(entries: &[(&str, &str)]) -> Result<(), CompanionError> {
@@ -288,13 +517,24 @@ fn notify(entries: &[(&str, &str)]) -> Result<(), CompanionError> {
 }
 pub const STATE_STOPPING: &'static str = "STOPPING";
 
+/// Run the companion loop until a termination signal arrives (or just once if refreshing is
+/// disabled). On SIGHUP, `reload` is asked for the new configuration and list of explicitly
+/// listed containers. If that fails, the companion carries on with the current configuration.
 pub fn run(config: Arc<Config>,
            inspector: Box<Inspect>,
            publisher: Box<Publish>,
            termination_signal: chan::Receiver<Signal>,
-           explicit_container_names: &Vec<Rc<String>>)
+           explicit_container_names: &Vec<Rc<String>>,
+           reload: &mut FnMut() -> Result<(Config, Vec<Rc<String>>), ConfigFileError>)
            -> Result<(), Vec<CompanionError>> {
+    let mut explicit_container_names = explicit_container_names.clone();
     let mut ctx = Context::new(config.clone(), inspector, publisher, termination_signal);
+    if ctx.should_watch() {
+        if let Err(e) = ctx.watch() {
+            warn!("Failed to watch for changes. Relying on periodic refresh only. Error: {}",
+                  e);
+        }
+    }
     info!("Companion initialized.");
     if config.systemd {
         if let Err(e) = notify(&[(daemon::STATE_READY, "1")]) {
@@ -304,38 +544,36 @@ pub fn run(config: Arc<Config>,
         }
     }
 
+    let mut wakeup = Wakeup::Refresh;
     loop {
-        debug!("Start iteration.");
-        ctx.notify_status("Refreshing");
-
         // Errors that occurred in this iteration.
+        // We only return the errors from the last iteration. All errors have been logged.
         let mut errors = Vec::new();
 
-        // Combine explicitly listed names with containers obtained from enumeration.
-        let names = {
-            let (names, enum_result) = ctx.enumerate(explicit_container_names);
-            if let Err(e) = enum_result {
-                errors.push(e)
-            }
-            names
-        };
-        debug!("Enumerated containers: {:#?}", names);
-
-        // Refresh each of the containers.
-        for name in names.into_iter() {
-            refresh_container(name, &mut errors, &mut ctx);
+        // All updates of one iteration go out together. If the publisher is unavailable, that
+        // results in a single error instead of one per container.
+        ctx.begin_batch();
+        if let Wakeup::Events(events) = wakeup {
+            refresh_changed(events, &explicit_container_names, &mut errors, &mut ctx);
+        } else {
+            refresh_all(&explicit_container_names, &mut errors, &mut ctx);
+        }
+        if let Err(e) = ctx.flush() {
+            error!("Failed to publish configuration updates. Error: {}", e);
+            errors.push(e);
         }
 
         ctx.notify_status("Waiting");
-        // Wait for refresh timeout or external abort (kill signal).
+        // Wait for refresh timeout, inspector events or external abort (kill signal).
         // Returns immediately if we are only supposed to run once.
-        if ctx.wait() {
-            // We only return the errors from the last iteration. All errors have been logged.
-            errors.clear();
-        } else {
+        wakeup = ctx.wait();
+        if wakeup == Wakeup::Reload {
+            // The next iteration refreshes all containers with the new configuration.
+            reload_config(reload, &mut explicit_container_names, &mut ctx);
+        } else if wakeup == Wakeup::Stop {
             // We are shutting down. This can have various reasons. Maybe we are in run-once mode
             // or maybe we received a signal.
-            if config.systemd {
+            if ctx.config.systemd {
                 let shutdown = [(daemon::STATE_STATUS, "Stopping"), (STATE_STOPPING, "1")];
                 if let Err(e) = notify(&shutdown) {
                     warn!(concat!("Failed to update service status in systemd service manager \
@@ -347,7 +585,7 @@ pub fn run(config: Arc<Config>,
 
             // Return errors from the last iteration. This is mainly useful for the case where
             // we only run once. Lets the tool set an appropriate status code on program exit.
-            if errors.is_empty() || config.refresh_seconds.is_some() {
+            if errors.is_empty() || ctx.config.refresh_seconds.is_some() {
                 return Ok(());
             } else {
                 return Err(errors);
@@ -356,6 +594,118 @@ pub fn run(config: Arc<Config>,
     }
 }
 
+/// Replace the configuration with the one produced by `reload`. Errors are logged, the companion
+/// keeps going with whatever configuration it ends up with.
+fn reload_config(reload: &mut FnMut() -> Result<(Config, Vec<Rc<String>>), ConfigFileError>,
+                 explicit_container_names: &mut Vec<Rc<String>>,
+                 ctx: &mut Context) {
+    match reload() {
+        Ok((config, container_names)) => {
+            *explicit_container_names = container_names;
+            if let Err(e) = ctx.reconfigure(Arc::new(config)) {
+                error!("Error while switching to the reloaded configuration. Error: {}", e);
+            } else {
+                info!("Configuration reloaded.");
+            }
+        }
+        Err(e) => {
+            error!("Failed to reload configuration. Keeping the current configuration. Error: {}",
+                   e);
+        }
+    }
+}
+
+/// Inspect and publish updates for all containers (explicitly listed and enumerated).
+/// Withdraws publications of containers that have disappeared since the last refresh.
+fn refresh_all(explicit_container_names: &[Rc<String>],
+               errors: &mut Vec<CompanionError>,
+               ctx: &mut Context) {
+    debug!("Start iteration.");
+    ctx.notify_status("Refreshing");
+
+    // Combine explicitly listed names with containers obtained from enumeration.
+    let (names, enum_result) = ctx.enumerate(explicit_container_names);
+    let enumeration_failed = enum_result.is_err();
+    if let Err(e) = enum_result {
+        errors.push(e)
+    }
+    debug!("Enumerated containers: {:#?}", names);
+
+    // Refresh each of the containers.
+    let mut seen = HashSet::new();
+    for name in names.into_iter() {
+        seen.insert(name.todo.clone());
+        refresh_container(name, errors, ctx);
+    }
+
+    // If enumeration failed, we can't tell which containers are gone (except for those whose
+    // source could be enumerated). Their publications will be withdrawn on the next successful
+    // refresh (or simply expire).
+    let vanished: Vec<Rc<String>> = {
+        let inspector = &ctx.inspector;
+        ctx.published
+            .keys()
+            .filter(|name| !seen.contains(*name))
+            .filter(|name| !enumeration_failed || inspector.enumerated_despite_failure(name))
+            .cloned()
+            .collect()
+    };
+    for name in vanished {
+        if let Err(e) = ctx.withdraw(&name) {
+            error!("Failed to withdraw configuration for vanished container '{}'. Error: {}",
+                   name,
+                   e);
+            errors.push(e);
+        }
+    }
+}
+
+/// Inspect and publish updates for containers reported by the inspector's change notifications.
+/// Events identify containers by id, while publications are tracked by the name the container
+/// was enumerated with, so ids are resolved to names first. When a container vanishes, can't be
+/// resolved or when we are not enumerating, we fall back to a full refresh.
+fn refresh_changed(events: Vec<InspectorEvent>,
+                   explicit_container_names: &[Rc<String>],
+                   errors: &mut Vec<CompanionError>,
+                   ctx: &mut Context) {
+    let any_vanished = events.iter().any(|event| match *event {
+        InspectorEvent::Vanished(_) => true,
+        InspectorEvent::Changed(_) => false,
+    });
+    if !ctx.config.enumerate || any_vanished {
+        debug!("Refresh all containers in response to inspector events.");
+        refresh_all(explicit_container_names, errors, ctx);
+        return;
+    }
+
+    let mut names = Vec::new();
+    for event in events {
+        if let InspectorEvent::Changed(id) = event {
+            match ctx.inspector.container_name(&id) {
+                Ok(name) => {
+                    debug!("Container {} ({}) changed.", name, id);
+                    names.push(name);
+                }
+                Err(e) => {
+                    // Most likely gone again already.
+                    debug!("Failed to resolve name of changed container {}. Refresh all \
+                            containers instead. Error: {}",
+                           id,
+                           e);
+                    refresh_all(explicit_container_names, errors, ctx);
+                    return;
+                }
+            }
+        }
+    }
+    let mut refreshed = HashSet::new();
+    for name in names {
+        if refreshed.insert(name.clone()) {
+            refresh_container(Pending { explicit: false, todo: Rc::new(name) }, errors, ctx);
+        }
+    }
+}
+
 /// Inspect and publish updates for the indicated container.
 /// If errors happen along the way it will primarily be reported to the log.
 /// Errors that are considered 'problematic' (according to configuration) will *additionally*
@@ -367,7 +717,7 @@ fn refresh_container(name: Pending<Rc<String>>,
                      ctx: &mut Context) {
     let current_container = name.todo.clone();
     let was_explicit = name.explicit;
-    let config = ctx.config.clone();
+    let config = ctx.config.for_container(&current_container);
 
     // Retrieve requested configuration from the container.
     debug!("Inspect {}", current_container);
@@ -391,6 +741,7 @@ fn refresh_container(name: Pending<Rc<String>>,
             if consider_error {
                 errors.push(e)
             }
+            withdraw_container(&current_container, errors, ctx);
 
             // Need to skip the update for this container (inspection failed)
             return;
@@ -411,14 +762,16 @@ fn refresh_container(name: Pending<Rc<String>>,
             (_, _) => level = LogLevel::Info,
         }
         log!(level,
-             "No environment variable '{}' configured for container {}. Skipping.",
+             "No environment variable '{}' or label '{}' configured for container {}. Skipping.",
              config.envvar,
+             config.label,
              current_container);
+        withdraw_container(&current_container, errors, ctx);
         return;
     }
 
     // Publish updated configuration
-    let publication = to_publication(inspection);
+    let publication = to_publication(&config, &current_container, inspection);
 
     if config.dry_run {
         info!("DRY RUN: would update {} with {:#?}", current_container, publication)
@@ -426,7 +779,17 @@ fn refresh_container(name: Pending<Rc<String>>,
         info!("Updating configuration for container {}. Publishing {:?}",
               current_container,
               publication);
-        if let Err(e) = ctx.publish(publication) {
+        // Publications are identified by their key (usually the host). If the container moved to
+        // a different host (e.g., got a new IP address after a restart), the old publication
+        // needs to go.
+        let moved = ctx.published
+            .get(&current_container)
+            .map(|p| p.key != publication.key)
+            .unwrap_or(false);
+        if moved {
+            withdraw_container(&current_container, errors, ctx);
+        }
+        if let Err(e) = ctx.publish(&current_container, publication) {
             error!("Failed to publish updated configuration for container '{}'. Error: {}",
                    current_container,
                    e);
@@ -436,6 +799,18 @@ fn refresh_container(name: Pending<Rc<String>>,
 }
 
 
+/// Withdraw the last publication of a container. Errors are logged and appended to `errors`.
+fn withdraw_container(container_name: &Rc<String>,
+                      errors: &mut Vec<CompanionError>,
+                      ctx: &mut Context) {
+    if let Err(e) = ctx.withdraw(container_name) {
+        error!("Failed to withdraw configuration for container '{}'. Error: {}",
+               container_name,
+               e);
+        errors.push(e);
+    }
+}
+
 /// Thing that needs to be handled annotated with whether it was requested explicitly or discovered
 /// on a best-effort basis. (Affects behaviour in the case of errors)
 #[derive(Debug)]
@@ -510,6 +885,7 @@ quick_error! {
 #[cfg(test)]
 #[allow(unused_variables, unused_imports)]
 mod tests {
+    use std::collections::BTreeMap;
     use std::sync::Arc;
     use std::rc::Rc;
     use std::cell::RefCell;
@@ -519,12 +895,16 @@ mod tests {
     use chan;
 
     use super::*;
-    use super::{Context, Pending, refresh_container};
+    use super::{Context, Pending, Wakeup, refresh_container, refresh_changed, refresh_all,
+                to_publication};
+    use ::publisher::Publication;
     use common::{self, Config, MissingEnvVarHandling, MissingContainerHandling};
     use ::inspector::mock_inspector::{MockInspector, FakeError};
-    use ::inspector::Inspection;
+    use ::inspector::{Inspection, InspectorEvent};
+    use ::inspector::multi::MultiInspector;
     use ::domain_spec::DomainSpec;
     use ::publisher::mock_publisher::{MockPublisher, MockError};
+    use ::publisher::composite::CompositePublisher;
 
     #[test]
     fn empty() {
@@ -556,10 +936,10 @@ mod tests {
                                    term_recv);
 
         // #### WHEN  ####
-        let do_continue = ctx.wait();
+        let wakeup = ctx.wait();
 
         // #### THEN  ####
-        assert!(!do_continue, "One shot companion context tried to run more than once.");
+        assert!(wakeup == Wakeup::Stop, "One shot companion context tried to run more than once.");
     }
 
     #[test]
@@ -576,10 +956,10 @@ mod tests {
 
         // #### WHEN  ####
         term_send.send(Signal::INT);
-        let do_continue = ctx.wait();
+        let wakeup = ctx.wait();
 
         // #### THEN  ####
-        assert!(!do_continue,
+        assert!(wakeup == Wakeup::Stop,
                 concat!("Companion context tried to run after ", "termination was requested."));
     }
 
@@ -597,10 +977,10 @@ mod tests {
 
         // #### WHEN  ####
         term_send.send(Signal::TERM);
-        let do_continue = ctx.wait();
+        let wakeup = ctx.wait();
 
         // #### THEN  ####
-        assert!(!do_continue,
+        assert!(wakeup == Wakeup::Stop,
                 concat!("Companion context tried to run after ", "termination was requested."));
     }
 
@@ -617,10 +997,86 @@ mod tests {
                                    term_recv);
 
         // #### WHEN  ####
-        let do_continue = ctx.wait();
+        let wakeup = ctx.wait();
+
+        // #### THEN  ####
+        assert!(wakeup == Wakeup::Refresh, "Refresh should be successful.");
+    }
+
+    #[test]
+    fn wait_inspector_events() {
+        common::init_log();
+        // #### GIVEN ####
+        let (term_send, term_recv) = chan::sync(1);
+        let mut cfg = Config::default();
+        cfg.refresh_seconds = Some(60);
+        let mut inspector = MockInspector::default();
+        inspector.watch_events = vec![InspectorEvent::Changed("alpha".to_owned()),
+                                      InspectorEvent::Vanished("beta".to_owned())];
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(MockPublisher::default()),
+                                   term_recv);
+
+        // #### WHEN  ####
+        ctx.watch().expect("Mock inspector should support watching");
+        let wakeup = ctx.wait();
+
+        // #### THEN  ####
+        assert_eq!(wakeup,
+                   Wakeup::Events(vec![InspectorEvent::Changed("alpha".to_owned()),
+                                       InspectorEvent::Vanished("beta".to_owned())]));
+        assert!(ctx.next_refresh.is_some(),
+                "Events must not reset the deadline for the periodic refresh.");
+    }
+
+    #[test]
+    fn wait_reload_hup() {
+        common::init_log();
+        // #### GIVEN ####
+        let (term_send, term_recv) = chan::sync(1);
+        let mut cfg = Config::default();
+        cfg.refresh_seconds = Some(60);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(MockInspector::default()),
+                                   Box::new(MockPublisher::default()),
+                                   term_recv);
+
+        // #### WHEN  ####
+        term_send.send(Signal::HUP);
+        let wakeup = ctx.wait();
 
         // #### THEN  ####
-        assert!(do_continue, "Refresh should be successful.");
+        assert_eq!(wakeup, Wakeup::Reload);
+    }
+
+    #[test]
+    fn reconfigure() {
+        common::init_log();
+        // #### GIVEN ####
+        let (term_send, term_recv) = chan::sync(1);
+        let mut cfg = Config::default();
+        cfg.watch_events = false;
+        let inspector = Arc::new(RefCell::new(MockInspector::default()));
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector.clone()),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.next_refresh = Some(chrono::Local::now());
+        let mut new_cfg = Config::default();
+        new_cfg.watch_events = true;
+        new_cfg.key_prefix = Rc::new("/reloaded/".to_owned());
+
+        // #### WHEN  ####
+        ctx.reconfigure(Arc::new(new_cfg)).expect("Reconfigure mock inspector and publisher");
+
+        // #### THEN  ####
+        assert_eq!(ctx.config.key_prefix.as_str(), "/reloaded/");
+        assert_eq!(inspector.borrow().reconfigurations, 1);
+        assert_eq!(publisher.borrow().reconfigurations, 1);
+        assert!(inspector.borrow().watching, "Watching was enabled by the new configuration.");
+        assert!(ctx.next_refresh.is_none(), "Refresh interval needs to be recomputed.");
     }
 
     #[test]
@@ -769,13 +1225,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -835,13 +1295,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -892,13 +1356,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -949,13 +1417,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -1006,13 +1478,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -1063,13 +1539,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(),
                                          Ok(Inspection {
@@ -1120,13 +1600,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(), Err(Box::new(|| From::from(FakeError))));
         inspector.inspect_results.insert(alpha.clone(),
@@ -1172,13 +1656,17 @@ mod tests {
         let mut inspector = MockInspector::default();
         let spec1 = DomainSpec {
             domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
         inspector.inspect_results.insert(beta.clone(), Err(Box::new(|| From::from(FakeError))));
         inspector.inspect_results.insert(alpha.clone(),
@@ -1206,12 +1694,507 @@ mod tests {
                 publisher.borrow().publications);
     }
 
+    #[test]
+    fn refresh_changed_enumerate() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let beta = Rc::new("beta".to_owned());
+        let mut inspector = MockInspector::default();
+        let spec1 = DomainSpec {
+            domain_name: "one.alpha.domain".to_owned(),
+            path_prefix: None,
+            http_port: Some(80),
+            https_port: Some(443),
+            options: BTreeMap::new(),
+        };
+        let spec2 = DomainSpec {
+            domain_name: "two.beta.domain".to_owned(),
+            path_prefix: None,
+            http_port: Some(8080),
+            https_port: None,
+            options: BTreeMap::new(),
+        };
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "alpha.host".to_owned(),
+                                             specs: vec![spec1.clone()],
+                                         }));
+        inspector.inspect_results.insert(beta.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "beta.host".to_owned(),
+                                             specs: vec![spec2.clone()],
+                                         }));
+        inspector.container_ids.insert("4a1f2b".to_owned(), (*alpha).clone());
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        let mut errors = Vec::new();
+        let events = vec![InspectorEvent::Changed("4a1f2b".to_owned()),
+                          InspectorEvent::Changed("4a1f2b".to_owned())];
+
+        // #### WHEN  ####
+        refresh_changed(events, &Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        let publications = &publisher.borrow().publications;
+        assert!(publications.len() == 1,
+                "Expected exactly one publication for alpha. Got {:#?}",
+                publications);
+        assert_eq!(publications[0].host, "alpha.host");
+    }
+
+    #[test]
+    fn refresh_changed_by_id() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+        cfg.publish_host = Some(Rc::new("192.0.2.10".to_owned()));
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector: alpha is already published and gets restarted
+        let alpha = Rc::new("/alpha".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.enumerate_result = Ok(vec![(*alpha).clone()]);
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "192.0.2.10".to_owned(),
+                                             specs: Vec::new(),
+                                         }));
+        inspector.container_ids.insert("4a1f2b9c0d7e".to_owned(), (*alpha).clone());
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        let mut errors = Vec::new();
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+        let events = vec![InspectorEvent::Changed("4a1f2b9c0d7e".to_owned())];
+
+        // #### WHEN  ####
+        refresh_changed(events, &Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        assert_eq!(ctx.published.keys().collect::<Vec<_>>(), vec![&alpha]);
+        let publications = &publisher.borrow().publications;
+        assert!(publications.len() == 2 && publications.iter().all(|p| p.key == "192.0.2.10_alpha"),
+                "Expected alpha to be published twice under its name. Got {:#?}",
+                publications);
+        assert!(publisher.borrow().withdrawals.is_empty());
+    }
+
+    #[test]
+    fn refresh_changed_unknown_id() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector: the changed container is gone before it could be resolved
+        let beta = Rc::new("beta".to_owned());
+        let inspector = MockInspector::default();
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.published.insert(beta.clone(), publication("beta.host"));
+        let mut errors = Vec::new();
+        let events = vec![InspectorEvent::Changed("4a1f2b".to_owned())];
+
+        // #### WHEN  ####
+        refresh_changed(events, &Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        let withdrawals = &publisher.borrow().withdrawals;
+        assert!(withdrawals.len() == 1 && withdrawals[0].host == "beta.host",
+                "Expected the full refresh to withdraw beta. Got {:#?}",
+                withdrawals);
+    }
+
+    #[test]
+    fn refresh_changed_vanished() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let beta = Rc::new("beta".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.enumerate_result = Ok(vec![(*alpha).clone()]);
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "alpha.host".to_owned(),
+                                             specs: Vec::new(),
+                                         }));
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.published.insert(beta.clone(), publication("beta.host"));
+        let mut errors = Vec::new();
+        let events = vec![InspectorEvent::Vanished("4a1f2b".to_owned())];
+
+        // #### WHEN  ####
+        refresh_changed(events, &Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        let withdrawals = &publisher.borrow().withdrawals;
+        assert!(withdrawals.len() == 1 && withdrawals[0].host == "beta.host",
+                "Expected beta to be withdrawn. Got {:#?}",
+                withdrawals);
+    }
+
+    #[test]
+    fn refresh_all_withdraw_vanished() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let beta = Rc::new("beta".to_owned());
+        let gamma = Rc::new("gamma".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.enumerate_result = Ok(vec![(*alpha).clone(), (*gamma).clone()]);
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "alpha.host".to_owned(),
+                                             specs: Vec::new(),
+                                         }));
+        // gamma cannot be inspected (anymore)
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        for name in vec![&alpha, &beta, &gamma] {
+            ctx.published.insert(name.clone(), publication(&format!("{}.host", name)));
+        }
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        let withdrawals = &publisher.borrow().withdrawals;
+        assert!(withdrawals.len() == 2, "Expected 2 withdrawals. Got {:#?}", withdrawals);
+        assert!(withdrawals.iter().any(|p| p.host == "beta.host"),
+                "Expected beta (vanished) to be withdrawn. Got {:#?}",
+                withdrawals);
+        assert!(withdrawals.iter().any(|p| p.host == "gamma.host"),
+                "Expected gamma (failed inspection) to be withdrawn. Got {:#?}",
+                withdrawals);
+        assert!(ctx.published.contains_key(&alpha), "alpha should still be published.");
+        assert_eq!(ctx.published.len(), 1);
+    }
+
+    #[test]
+    fn flush_fail_restores_withdrawals() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher that fails to send the batch
+        let mut mock_publisher = MockPublisher::default();
+        mock_publisher.flush_error = Some(Box::new(|| From::from(MockError)));
+        let publisher = Arc::new(RefCell::new(mock_publisher));
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let beta = Rc::new("beta".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.enumerate_result = Ok(vec![(*alpha).clone()]);
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "alpha.host".to_owned(),
+                                             specs: Vec::new(),
+                                         }));
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.published.insert(beta.clone(), publication("beta.host"));
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        ctx.begin_batch();
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+        let flush_result = ctx.flush();
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        assert!(flush_result.is_err(), "Expected flush to fail.");
+        assert_eq!(publisher.borrow().flushes, 1);
+        assert!(ctx.published.contains_key(&beta),
+                "beta should be withdrawn again on the next refresh.");
+        assert!(ctx.published.contains_key(&alpha), "alpha should be published.");
+    }
+
+    #[test]
+    fn refresh_all_withdraw_partially_published() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let cfg = Config::default();
+
+        // one of two publishers fails
+        let failing = Arc::new(RefCell::new(MockPublisher::default()));
+        failing.borrow_mut().error_trigger =
+            Some(("fail".to_owned(), Box::new(|| From::from(MockError))));
+        let healthy = Arc::new(RefCell::new(MockPublisher::default()));
+        let publisher = CompositePublisher::new(vec![("failing".to_owned(),
+                                                      Box::new(failing.clone())),
+                                                     ("healthy".to_owned(),
+                                                      Box::new(healthy.clone()))]);
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "alpha.host".to_owned(),
+                                             specs: vec![common::test_spec("fail.alpha.domain",
+                                                                           Some(80),
+                                                                           None)],
+                                         }));
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher),
+                                   term_recv);
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        refresh_all(&vec![alpha.clone()], &mut errors, &mut ctx);
+        let published = ctx.published.contains_key(&alpha);
+        // alpha disappears
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        assert!(published, "alpha should be published by the healthy publisher.");
+        assert!(ctx.published.is_empty(), "alpha should be withdrawn.");
+        let withdrawals = &healthy.borrow().withdrawals;
+        assert!(withdrawals.len() == 1 && withdrawals[0].host == "alpha.host",
+                "Expected alpha to be withdrawn from the healthy publisher. Got {:#?}",
+                withdrawals);
+        assert!(failing.borrow().withdrawals.is_empty(),
+                "The failing publisher never accepted alpha.");
+    }
+
+    #[test]
+    fn refresh_all_enumerate_fail_keeps_publications() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector
+        let beta = Rc::new("beta".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.enumerate_result = Err(Box::new(|| From::from(FakeError)));
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.published.insert(beta.clone(), publication("beta.host"));
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 1, "Expected enumeration error, got {:#?}", errors);
+        assert!(publisher.borrow().withdrawals.len() == 0,
+                "Unexpected withdrawals: {:#?}",
+                publisher.borrow().withdrawals);
+    }
+
+    #[test]
+    fn refresh_all_enumerate_partial_fail() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let mut cfg = Config::default();
+        cfg.enumerate = true;
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // two sources, one of which cannot be reached
+        let mut east = MockInspector::default();
+        east.enumerate_result = Err(Box::new(|| From::from(FakeError)));
+        let west = MockInspector::default();
+        let inspector = MultiInspector::new(vec![("east".to_owned(), Box::new(east)),
+                                                 ("west".to_owned(), Box::new(west))]);
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        let east_web = Rc::new("east/web".to_owned());
+        let west_web = Rc::new("west/web".to_owned());
+        ctx.published.insert(east_web.clone(), publication("east.host"));
+        ctx.published.insert(west_web.clone(), publication("west.host"));
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        refresh_all(&Vec::new(), &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 1, "Expected enumeration error, got {:#?}", errors);
+        let withdrawals = &publisher.borrow().withdrawals;
+        assert!(withdrawals.len() == 1, "Expected 1 withdrawal. Got {:#?}", withdrawals);
+        assert_eq!(withdrawals[0].host, "west.host");
+        assert!(ctx.published.contains_key(&east_web),
+                "east/web should be kept while its source is unreachable.");
+    }
+
+    #[test]
+    fn refresh_moved_host() {
+        common::init_log();
+        // #### GIVEN ####
+        // test configuration
+        let cfg = Config::default();
+
+        // mock publisher
+        let publisher = Arc::new(RefCell::new(MockPublisher::default()));
+
+        // mock inspector
+        let alpha = Rc::new("alpha".to_owned());
+        let mut inspector = MockInspector::default();
+        inspector.inspect_results.insert(alpha.clone(),
+                                         Ok(Inspection {
+                                             envvar_present: true,
+                                             host: "new.alpha.host".to_owned(),
+                                             specs: Vec::new(),
+                                         }));
+
+        // companion context
+        let (term_send, term_recv) = chan::sync(1);
+        let mut ctx = Context::new(Arc::new(cfg),
+                                   Box::new(inspector),
+                                   Box::new(publisher.clone()),
+                                   term_recv);
+        ctx.published.insert(alpha.clone(), publication("old.alpha.host"));
+        let mut errors = Vec::new();
+
+        // #### WHEN  ####
+        refresh_container(Pending { todo: alpha.clone(), explicit: true }, &mut errors, &mut ctx);
+
+        // #### THEN  ####
+        assert!(errors.len() == 0, "Expected no errors, got {:#?}", errors);
+        let mock = publisher.borrow();
+        assert!(mock.withdrawals.len() == 1 && mock.withdrawals[0].host == "old.alpha.host",
+                "Expected old host to be withdrawn. Got {:#?}",
+                mock.withdrawals);
+        assert!(mock.publications.len() == 1 && mock.publications[0].host == "new.alpha.host",
+                "Expected new host to be published. Got {:#?}",
+                mock.publications);
+    }
+
+    #[test]
+    fn publication_key_publish_host() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut cfg = Config::default();
+        cfg.publish_host = Some(Rc::new("10.0.0.5".to_owned()));
+        let inspection = Inspection {
+            envvar_present: true,
+            host: "10.0.0.5".to_owned(),
+            specs: Vec::new(),
+        };
+
+        // #### WHEN  ####
+        let publication =
+            to_publication(&cfg, "/alpha", Pending { todo: inspection, explicit: false });
+
+        // #### THEN  ####
+        assert_eq!(publication.host, "10.0.0.5");
+        assert_eq!(publication.key, "10.0.0.5_alpha");
+    }
+
+    /// A publication without domain specs for the indicated host.
+    fn publication(host: &str) -> Publication {
+        common::test_publication(host, host, Vec::new())
+    }
+
     /// Normally, DomainSpec isn't directly comparable because instances might not be in canonical
     /// form, but for testing, this is good enough.
     impl PartialEq for DomainSpec {
         fn eq(&self, other: &DomainSpec) -> bool {
-            self.domain_name == other.domain_name && self.http_port == other.http_port &&
-            self.https_port == other.https_port
+            self.domain_name == other.domain_name && self.path_prefix == other.path_prefix &&
+            self.http_port == other.http_port && self.https_port == other.https_port &&
+            self.options == other.options
         }
     }
     impl Eq for DomainSpec {}
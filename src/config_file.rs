@@ -0,0 +1,416 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Support for reading settings from a TOML configuration file. Keys are named like the long
+//! command line options (dashes or underscores). Per-container overrides go into
+//! `[container.<name>]` sections.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use rustc_serialize::Decodable;
+use toml;
+use url::Url;
+
+use common::{ContainerOverrides, DockerSources, DomainSource, InspectorKinds,
+             MissingEnvVarHandling, PublisherKinds};
+use publisher::redis::RedisUrl;
+
+/// Contents of the configuration file. All settings are optional. Settings that are missing fall
+/// back to the command line (or its defaults).
+#[derive(RustcDecodable, Debug, Default, Clone)]
+pub struct ConfigFile {
+    pub verbose: Option<bool>,
+    pub quiet: Option<bool>,
+    pub no_timestamp: Option<bool>,
+    pub publisher: Option<PublisherKinds>,
+    pub redis_host: Option<String>,
+    pub redis_port: Option<u16>,
+    pub redis_url: Option<RedisUrl>,
+    pub redis_password_file: Option<String>,
+    pub etcd_url: Option<Url>,
+    pub consul_url: Option<Url>,
+    pub consul_service: Option<String>,
+    pub file_directory: Option<String>,
+    pub nginx_template: Option<String>,
+    pub nginx_output: Option<String>,
+    pub nginx_check: Option<String>,
+    pub nginx_reload: Option<String>,
+    pub haproxy_directory: Option<String>,
+    pub haproxy_socket: Option<String>,
+    pub haproxy_reload: Option<String>,
+    pub traefik_file: Option<String>,
+    pub traefik_http_entrypoint: Option<String>,
+    pub traefik_https_entrypoint: Option<String>,
+    pub caddy_url: Option<Url>,
+    pub caddy_server: Option<String>,
+    pub webhook_url: Option<Url>,
+    pub webhook_secret_file: Option<String>,
+    pub webhook_timeout: Option<u32>,
+    pub webhook_retries: Option<u32>,
+    pub expire: Option<u32>,
+    pub refresh: Option<u32>,
+    pub key_prefix: Option<String>,
+    pub inspector: Option<InspectorKinds>,
+    pub static_file: Option<String>,
+    pub podman_url: Option<Url>,
+    pub containerd_address: Option<String>,
+    pub containerd_namespace: Option<String>,
+    pub cni_results: Option<String>,
+    pub kubelet_url: Option<Url>,
+    pub kubelet_pods_file: Option<String>,
+    pub docker_url: Option<Url>,
+    pub docker_sources: Option<DockerSources>,
+    pub docker_cert_path: Option<String>,
+    pub docker_tls_verify: Option<bool>,
+    pub docker_network: Option<String>,
+    pub docker_network_alias: Option<bool>,
+    pub publish_host: Option<String>,
+    pub envvar: Option<String>,
+    pub label: Option<String>,
+    pub label_prefix: Option<String>,
+    pub domain_source: Option<DomainSource>,
+    pub enumerate: Option<bool>,
+    pub systemd: Option<bool>,
+    pub no_events: Option<bool>,
+    pub error_missing_envvar: Option<bool>,
+    pub ignore_missing_envvar: Option<bool>,
+    pub error_missing_container: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// Explicitly listed containers.
+    pub containers: Option<Vec<String>>,
+    /// Per-container overrides, indexed by container name.
+    pub container: Option<HashMap<String, ContainerSection>>,
+}
+
+/// A `[container.<name>]` section.
+#[derive(RustcDecodable, Debug, Default, Clone)]
+pub struct ContainerSection {
+    pub envvar: Option<String>,
+    pub label: Option<String>,
+    pub domain_source: Option<DomainSource>,
+    pub docker_network: Option<String>,
+    pub error_missing_envvar: Option<bool>,
+    pub ignore_missing_envvar: Option<bool>,
+}
+
+impl ConfigFile {
+    /// Read and parse the configuration file at `path`.
+    pub fn load(path: &str) -> Result<ConfigFile, ConfigFileError> {
+        load_toml(path)
+    }
+
+    /// Parse configuration file contents. `path` is only used for error messages.
+    pub fn parse(text: &str, path: &str) -> Result<ConfigFile, ConfigFileError> {
+        parse_toml(text, path)
+    }
+
+    /// Per-container overrides in the form used by `Config`.
+    pub fn container_overrides(&self) -> HashMap<String, ContainerOverrides> {
+        let mut overrides = HashMap::new();
+        if let Some(ref sections) = self.container {
+            for (name, section) in sections.iter() {
+                let missing_envvar = match (section.error_missing_envvar,
+                                            section.ignore_missing_envvar) {
+                    (Some(true), _) => Some(MissingEnvVarHandling::Report),
+                    (_, Some(true)) => Some(MissingEnvVarHandling::Ignore),
+                    _ => None,
+                };
+                overrides.insert(name.trim_left_matches('/').to_owned(),
+                                 ContainerOverrides {
+                                     envvar: section.envvar.clone().map(Rc::new),
+                                     label: section.label.clone().map(Rc::new),
+                                     domain_source: section.domain_source,
+                                     docker_network: section.docker_network.clone().map(Rc::new),
+                                     missing_envvar: missing_envvar,
+                                 });
+            }
+        }
+        overrides
+    }
+}
+
+/// Read and parse the TOML file at `path` (e.g., the configuration file).
+pub fn load_toml<T: Decodable>(path: &str) -> Result<T, ConfigFileError> {
+    let mut text = String::new();
+    try_!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)),
+          path.to_owned());
+    parse_toml(&text, path)
+}
+
+/// Parse TOML text. Keys that `T` doesn't know are reported as an error. `path` is only used for
+/// error messages.
+pub fn parse_toml<T: Decodable>(text: &str, path: &str) -> Result<T, ConfigFileError> {
+    let mut parser = toml::Parser::new(text);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let errors = parser.errors
+                .iter()
+                .map(|e| {
+                    let (line, column) = parser.to_linecol(e.lo);
+                    format!("line {}, column {}: {}", line + 1, column + 1, e.desc)
+                })
+                .collect();
+            return Err(ConfigFileError::Syntax(path.to_owned(), errors));
+        }
+    };
+
+    let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+    let decoded = try_!(T::decode(&mut decoder), path.to_owned());
+
+    // The decoder leaves behind everything it didn't consume.
+    let mut unknown_keys = Vec::new();
+    if let Some(ref leftover) = decoder.toml {
+        collect_keys("", leftover, &mut unknown_keys);
+    }
+    if unknown_keys.is_empty() {
+        Ok(decoded)
+    } else {
+        Err(ConfigFileError::UnknownKeys(path.to_owned(), unknown_keys))
+    }
+}
+
+/// Collect the (dotted) paths of all non-table values.
+fn collect_keys(prefix: &str, value: &toml::Value, keys: &mut Vec<String>) {
+    if let toml::Value::Table(ref table) = *value {
+        for (key, nested) in table.iter() {
+            let path = if prefix.is_empty() {
+                key.to_owned()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            collect_keys(&path, nested, keys);
+        }
+    } else {
+        keys.push(prefix.to_owned());
+    }
+}
+
+// ############### CONFIG FILE ERROR ######################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConfigFileError {
+        Io(path: String, err: io::Error) {
+            description("Failed to read configuration file.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+        Syntax(path: String, errors: Vec<String>) {
+            description("Configuration file is not valid TOML.")
+            display(me) -> ("{} File: {}, errors: {}", me.description(), path, errors.join("; "))
+        }
+        Decode(path: String, err: toml::DecodeError) {
+            description("Invalid setting in configuration file.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path, err)
+            from(e: (toml::DecodeError, String)) -> (e.1, e.0)
+        }
+        UnknownKeys(path: String, keys: Vec<String>) {
+            description("Unknown settings in configuration file.")
+            display(me) -> ("{} File: {}, unknown keys: {}", me.description(), path,
+                keys.join(", "))
+        }
+        Invalid(reason: String) {
+            description("Invalid configuration.")
+            display(me) -> ("{} {}", me.description(), reason)
+        }
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{self, DockerSource, DockerSources, DomainSource, MissingEnvVarHandling,
+                 PublisherKind, PublisherKinds};
+    use url::Url;
+
+    #[test]
+    fn empty() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = "";
+
+        // #### WHEN  ####
+        let config_file = ConfigFile::parse(text, "empty.toml").expect("Parse empty file");
+
+        // #### THEN  ####
+        assert!(config_file.redis_host.is_none());
+        assert!(config_file.containers.is_none());
+        assert!(config_file.container_overrides().is_empty());
+    }
+
+    #[test]
+    fn settings() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = r#"
+            redis-host = "redis.example.org"
+            redis_port = 6380
+            expire = 120
+            docker_url = "tcp://docker.example.org:2375"
+            domain_source = "labels"
+            enumerate = true
+            containers = ["web", "admin"]
+
+            [container.admin]
+            envvar = "ADMIN_DOMAINS"
+            error_missing_envvar = true
+        "#;
+
+        // #### WHEN  ####
+        let config_file = ConfigFile::parse(text, "settings.toml").expect("Parse settings");
+
+        // #### THEN  ####
+        assert_eq!(config_file.redis_host, Some("redis.example.org".to_owned()));
+        assert_eq!(config_file.redis_port, Some(6380));
+        assert_eq!(config_file.expire, Some(120));
+        assert_eq!(config_file.docker_url.as_ref().map(|u| u.serialize()),
+                   Some("tcp://docker.example.org:2375".to_owned()));
+        assert_eq!(config_file.domain_source, Some(DomainSource::Labels));
+        assert_eq!(config_file.enumerate, Some(true));
+        assert_eq!(config_file.containers,
+                   Some(vec!["web".to_owned(), "admin".to_owned()]));
+        let overrides = config_file.container_overrides();
+        let admin = overrides.get("admin").expect("Overrides for admin container");
+        assert_eq!(admin.envvar.as_ref().map(|e| e.as_str()), Some("ADMIN_DOMAINS"));
+        assert_eq!(admin.missing_envvar, Some(MissingEnvVarHandling::Report));
+        assert!(admin.docker_network.is_none());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = r#"
+            redis_host = "localhost"
+            redis_hots = "typo"
+
+            [container.web]
+            envar = "TYPO"
+        "#;
+
+        // #### WHEN  ####
+        let result = ConfigFile::parse(text, "typo.toml");
+
+        // #### THEN  ####
+        match result {
+            Err(ConfigFileError::UnknownKeys(_, keys)) => {
+                assert!(keys.contains(&"redis_hots".to_owned()), "Keys: {:?}", keys);
+                assert!(keys.contains(&"container.web.envar".to_owned()), "Keys: {:?}", keys);
+                assert_eq!(keys.len(), 2);
+            }
+            other => assert!(false, "Expected unknown keys error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn syntax_error() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = "redis_host = \n";
+
+        // #### WHEN  ####
+        let result = ConfigFile::parse(text, "broken.toml");
+
+        // #### THEN  ####
+        match result {
+            Err(ConfigFileError::Syntax(_, errors)) => {
+                assert!(errors.len() > 0);
+                assert!(errors[0].starts_with("line 1"), "Errors: {:?}", errors);
+            }
+            other => assert!(false, "Expected syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publishers() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = "publisher = \"redis, File,redis\"";
+
+        // #### WHEN  ####
+        let config_file = ConfigFile::parse(text, "publishers.toml").expect("Parse publishers");
+
+        // #### THEN  ####
+        assert_eq!(config_file.publisher,
+                   Some(PublisherKinds(vec![PublisherKind::Redis, PublisherKind::File])));
+        let result = ConfigFile::parse("publisher = \"redis,nowhere\"", "invalid.toml");
+        match result {
+            Err(ConfigFileError::Decode(..)) => (),
+            other => assert!(false, "Expected decode error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn docker_sources() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = "docker_sources = \"east=tcp://10.0.0.1:2375, west=tcp://10.0.1.1:2375\"";
+
+        // #### WHEN  ####
+        let config_file = ConfigFile::parse(text, "sources.toml").expect("Parse docker sources");
+
+        // #### THEN  ####
+        let expected = vec![DockerSource {
+                                name: "east".to_owned(),
+                                url: Url::parse("tcp://10.0.0.1:2375").unwrap(),
+                            },
+                            DockerSource {
+                                name: "west".to_owned(),
+                                url: Url::parse("tcp://10.0.1.1:2375").unwrap(),
+                            }];
+        assert_eq!(config_file.docker_sources, Some(DockerSources(expected)));
+        for invalid in &["east=tcp://10.0.0.1:2375,east=tcp://10.0.1.1:2375",
+                         "tcp://10.0.0.1:2375",
+                         "ea/st=tcp://10.0.0.1:2375"] {
+            let text = format!("docker_sources = \"{}\"", invalid);
+            match ConfigFile::parse(&text, "invalid.toml") {
+                Err(ConfigFileError::Decode(..)) => (),
+                other => assert!(false, "Expected decode error for {}, got {:?}", invalid, other),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_value() {
+        common::init_log();
+        // #### GIVEN ####
+        let text = "domain_source = \"nowhere\"";
+
+        // #### WHEN  ####
+        let result = ConfigFile::parse(text, "invalid.toml");
+
+        // #### THEN  ####
+        match result {
+            Err(ConfigFileError::Decode(..)) => (),
+            other => assert!(false, "Expected decode error, got {:?}", other),
+        }
+    }
+}
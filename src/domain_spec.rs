@@ -20,6 +20,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::error::Error;
 use std::num::ParseIntError;
@@ -28,42 +29,112 @@ use regex::Regex;
 
 use common::optional_result;
 
-/// Specification for a single domain.
+/// Specification for a single domain (or a path prefix on a domain).
 /// Contains either an http, an https or both ports.
 #[derive(Debug, Clone)]
 pub struct DomainSpec {
     pub domain_name: String,
+    /// Only requests for paths below this prefix (e.g., `/api`) are routed to the container.
+    /// None means the whole domain.
+    pub path_prefix: Option<String>,
     pub http_port: Option<u16>,
     pub https_port: Option<u16>,
+    /// Routing options, indexed by their name (see `DomainOption::name`).
+    pub options: BTreeMap<&'static str, DomainOption>,
+}
+
+/// Routing option that can be added to a domain spec (e.g., `example.org:http:websocket`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DomainOption {
+    /// `redirect-https`: Redirect plain http requests to https.
+    RedirectHttps,
+    /// `websocket`: Pass on requests to upgrade to a WebSocket connection.
+    Websocket,
+    /// `max-body=10m`: Largest request body to accept, in bytes.
+    MaxBody(u64),
+}
+
+impl DomainOption {
+    /// The name of the option in domain specs.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DomainOption::RedirectHttps => "redirect-https",
+            DomainOption::Websocket => "websocket",
+            DomainOption::MaxBody(_) => "max-body",
+        }
+    }
 }
 
 lazy_static! {
     static ref DS_PAT: Regex =
-        Regex::new(r"([a-zA-Z0-9][a-zA-Z0-9.-]+[a-zA-Z0-9]\.?)(:(\S+))?").unwrap();
+        Regex::new(r"([a-zA-Z0-9][a-zA-Z0-9.-]+[a-zA-Z0-9]\.?)(/[^\s:]*)?(:(\S+))?").unwrap();
     static ref ID_PAT: Regex = Regex::new(r"[^A-Za-z0-9_]").unwrap();
 }
 
 impl DomainSpec {
     pub fn spec_id(&self) -> String {
-        ID_PAT.replace_all(&self.domain_name, "_")
+        match self.path_prefix {
+            Some(ref path_prefix) => {
+                ID_PAT.replace_all(&format!("{}{}", self.domain_name, path_prefix), "_")
+            }
+            None => ID_PAT.replace_all(&self.domain_name, "_"),
+        }
+    }
+
+    /// Whether `name` is a parameter of domain specs (a protocol or an option).
+    pub fn is_param(name: &str) -> bool {
+        match name {
+            "http" | "https" | "redirect-https" | "websocket" | "max-body" => true,
+            _ => false,
+        }
     }
 
     pub fn parse_all(raw: &str, specs: &mut Vec<DomainSpec>) -> Result<(), DomainSpecError> {
+        fn error(key: &str, spec: &DomainSpec, cause: OptionError) -> DomainSpecError {
+            DomainSpecError {
+                domain_name: spec.domain_name.clone(),
+                cause: cause,
+                key: Some(key.to_owned()),
+            }
+        }
         fn parse_port(key: &str,
                       value: Option<&str>,
                       spec: &DomainSpec)
                       -> Result<Option<u16>, DomainSpecError> {
             match optional_result(value.map(|v| u16::from_str_radix(v, 10))) {
-                Err(e) => {
-                    Err(DomainSpecError {
-                        domain_name: spec.domain_name.clone(),
-                        cause: e,
-                        key: Some(key.to_owned()),
-                    })
-                }
+                Err(e) => Err(error(key, spec, OptionError::Port(e))),
                 Ok(port) => Ok(port),
             }
         }
+        /// Options without a value are enabled, otherwise the value has to be true or false.
+        fn parse_flag(key: &str,
+                      value: Option<&str>,
+                      spec: &DomainSpec)
+                      -> Result<bool, DomainSpecError> {
+            match value.map(|v| v.to_lowercase()) {
+                None => Ok(true),
+                Some(ref v) if v == "true" => Ok(true),
+                Some(ref v) if v == "false" => Ok(false),
+                Some(v) => Err(error(key, spec, OptionError::Flag(v))),
+            }
+        }
+        /// A number of bytes, optionally followed by k, m or g (powers of 1024, like nginx).
+        fn parse_size(key: &str,
+                      value: Option<&str>,
+                      spec: &DomainSpec)
+                      -> Result<u64, DomainSpecError> {
+            let raw = value.unwrap_or("").to_lowercase();
+            let (digits, factor) = match raw.chars().last() {
+                Some('k') => (&raw[..raw.len() - 1], 1 << 10),
+                Some('m') => (&raw[..raw.len() - 1], 1 << 20),
+                Some('g') => (&raw[..raw.len() - 1], 1 << 30),
+                _ => (&raw[..], 1),
+            };
+            u64::from_str_radix(digits, 10)
+                .ok()
+                .and_then(|size| size.checked_mul(factor))
+                .ok_or_else(|| error(key, spec, OptionError::Size(raw.clone())))
+        }
         for captures in DS_PAT.captures_iter(raw) {
             // The first capture group is guaranteed to be there.
             let mut domain_name = captures.at(1).unwrap();
@@ -74,14 +145,25 @@ impl DomainSpec {
                 domain_name = &domain_name[0..(domain_name.len() - 1)]
             }
 
-            let raw_params = captures.at(3)
+            // "/api/" and "/api" are the same prefix, "/" is the whole domain.
+            let path_prefix = captures.at(2)
+                .map(|path| path.trim_right_matches('/'))
+                .and_then(|path| if path.is_empty() {
+                    None
+                } else {
+                    Some(path.to_owned())
+                });
+
+            let raw_params = captures.at(4)
                 .map(|params| params.trim().split(':').collect())
                 .unwrap_or_else(|| Vec::new());
 
             let mut spec = DomainSpec {
                 domain_name: domain_name.to_owned(),
+                path_prefix: path_prefix,
                 http_port: None,
                 https_port: None,
+                options: BTreeMap::new(),
             };
             for raw_param in raw_params {
                 let param_parts: Vec<&str> = raw_param.splitn(2, '=').collect();
@@ -94,21 +176,44 @@ impl DomainSpec {
                 };
 
                 // Merely having an 'http' or 'https' key present, enables the mapping
-                match key.as_str() {
+                let option = match key.as_str() {
                     "http" => {
                         let parsed_port_opt = try!(parse_port(&key, value, &spec));
                         spec.http_port = Some(parsed_port_opt.unwrap_or(80));
+                        None
                     }
                     "https" => {
                         let parsed_port_opt = try!(parse_port(&key, value, &spec));
                         spec.https_port = Some(parsed_port_opt.unwrap_or(443));
+                        None
+                    }
+                    "redirect-https" => {
+                        if try!(parse_flag(&key, value, &spec)) {
+                            Some(DomainOption::RedirectHttps)
+                        } else {
+                            None
+                        }
+                    }
+                    "websocket" => {
+                        if try!(parse_flag(&key, value, &spec)) {
+                            Some(DomainOption::Websocket)
+                        } else {
+                            None
+                        }
+                    }
+                    "max-body" => {
+                        Some(DomainOption::MaxBody(try!(parse_size(&key, value, &spec))))
                     }
                     _ => {
                         // Forwards compatibility: don't treat unknown parameters as errors.
                         warn!("Unknown domain spec parameter. Parameter name: '{}' value: {:?}",
                               key,
                               value);
+                        None
                     }
+                };
+                if let Some(option) = option {
+                    spec.options.insert(option.name(), option);
                 }
             }
 
@@ -128,9 +233,7 @@ impl DomainSpec {
 #[derive(Debug)]
 pub struct DomainSpecError {
     pub domain_name: String,
-
-    // move into enum when more options come
-    pub cause: ParseIntError,
+    pub cause: OptionError,
     pub key: Option<String>,
 }
 
@@ -153,6 +256,26 @@ impl Error for DomainSpecError {
     }
 }
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum OptionError {
+        Port(err: ParseIntError) {
+            description("Invalid port.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+        }
+        Flag(value: String) {
+            description("Expected true, false or no value at all.")
+            display(me) -> ("{} Value: \"{}\"", me.description(), value)
+        }
+        Size(value: String) {
+            description("Invalid size. Expected a number of bytes, optionally followed by k, m \
+                         or g.")
+            display(me) -> ("{} Value: \"{}\"", me.description(), value)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -329,4 +452,91 @@ mod tests {
         assert_eq!(spec.https_port, Some(8043));
         assert_valid_id(spec);
     }
+
+    #[test]
+    fn path_prefix() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut specs = Vec::new();
+
+        // #### WHEN  ####
+        DomainSpec::parse_all("example.org/api:http=8080 example.org/ www.example.org/static/",
+                              &mut specs)
+            .expect("Parse \"example.org/api:http=8080 example.org/ www.example.org/static/\" \
+                     successfully");
+
+        // #### THEN  ####
+        assert_eq!(specs.len(), 3);
+        let api = &specs[0];
+        assert_eq!(api.domain_name, "example.org");
+        assert_eq!(api.path_prefix, Some("/api".to_owned()));
+        assert_eq!(api.http_port, Some(8080));
+        assert_eq!(api.https_port, None);
+        assert_eq!(api.spec_id(), "example_org_api");
+        assert_valid_id(api);
+
+        assert_eq!(specs[1].domain_name, "example.org");
+        assert_eq!(specs[1].path_prefix, None, "\"/\" should mean the whole domain.");
+        assert_eq!(specs[1].spec_id(), "example_org");
+        assert_eq!(specs[2].path_prefix,
+                   Some("/static".to_owned()),
+                   "A trailing slash should be ignored.");
+    }
+
+    #[test]
+    fn options() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut specs = Vec::new();
+
+        // #### WHEN  ####
+        DomainSpec::parse_all("example.org:https=8043:redirect-https:websocket:max-body=10m \
+                               www.example.org:websocket=false:max-body=512",
+                              &mut specs)
+            .expect("Parse domain specs with options successfully");
+
+        // #### THEN  ####
+        assert_eq!(specs.len(), 2);
+        let main = &specs[0];
+        assert_eq!(main.https_port, Some(8043));
+        assert_eq!(main.options.len(), 3);
+        assert_eq!(main.options.get("redirect-https"), Some(&DomainOption::RedirectHttps));
+        assert_eq!(main.options.get("websocket"), Some(&DomainOption::Websocket));
+        assert_eq!(main.options.get("max-body"), Some(&DomainOption::MaxBody(10 * 1024 * 1024)));
+
+        let www = &specs[1];
+        assert_eq!(www.http_port, Some(80));
+        assert_eq!(www.https_port, Some(443));
+        assert_eq!(www.options.len(), 1);
+        assert_eq!(www.options.get("max-body"), Some(&DomainOption::MaxBody(512)));
+    }
+
+    #[test]
+    fn invalid_options() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut specs = Vec::new();
+
+        // #### WHEN  ####
+        let size = DomainSpec::parse_all("example.org:max-body=lots", &mut specs);
+        let flag = DomainSpec::parse_all("example.org:websocket=sometimes", &mut specs);
+        let missing_size = DomainSpec::parse_all("example.org:max-body", &mut specs);
+
+        // #### THEN  ####
+        match size {
+            Err(DomainSpecError { cause: OptionError::Size(ref value), ref key, .. }) => {
+                assert_eq!(value, "lots");
+                assert_eq!(key, &Some("max-body".to_owned()));
+            }
+            other => panic!("Expected invalid size, got {:?}", other),
+        }
+        match flag {
+            Err(DomainSpecError { cause: OptionError::Flag(ref value), .. }) => {
+                assert_eq!(value, "sometimes")
+            }
+            other => panic!("Expected invalid flag, got {:?}", other),
+        }
+        assert!(missing_size.is_err(), "max-body requires a value.");
+        assert_eq!(specs.len(), 0);
+    }
 }
@@ -0,0 +1,680 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Minimal gRPC client for containerd's API: unary calls over HTTP/2 (without TLS, with prior
+//! knowledge) on a Unix socket. Like the HTTP client, it opens one connection per call, which
+//! carries a single stream. Messages are (de)serialized with the handful of protobuf wire format
+//! helpers below instead of generated code.
+
+use std::error::Error;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use hpack::{Decoder, Encoder};
+use hpack::decoder::DecoderError;
+
+/// Sent by the client before any frame (RFC 7540, section 3.5).
+const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub const FRAME_DATA: u8 = 0x0;
+pub const FRAME_HEADERS: u8 = 0x1;
+pub const FRAME_RST_STREAM: u8 = 0x3;
+pub const FRAME_SETTINGS: u8 = 0x4;
+pub const FRAME_PING: u8 = 0x6;
+pub const FRAME_GOAWAY: u8 = 0x7;
+pub const FRAME_WINDOW_UPDATE: u8 = 0x8;
+pub const FRAME_CONTINUATION: u8 = 0x9;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+/// Settings parameter that turns off server push.
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+
+/// The largest frame payload that both sides accept without announcing more.
+pub const MAX_FRAME_SIZE: usize = 16384;
+
+/// The client's only stream on a connection.
+const STREAM_ID: u32 = 1;
+
+/// The status of a successful call.
+const STATUS_OK: u32 = 0;
+
+/// One HTTP/2 frame.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Frame {
+        Frame { kind: kind, flags: flags, stream_id: stream_id, payload: payload }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Frame, GrpcError> {
+        let mut head = [0; 9];
+        try!(reader.read_exact(&mut head));
+        let length = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+        // Neither side announces a larger maximum.
+        if length > MAX_FRAME_SIZE {
+            return Err(GrpcError::Protocol(format!("Frame of {} bytes exceeds the maximum.",
+                                                   length)));
+        }
+        let mut payload = vec![0; length];
+        try!(reader.read_exact(&mut payload));
+        Ok(Frame::new(head[3], head[4], read_u32(&head[5..9]) & 0x7fff_ffff, payload))
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let length = self.payload.len();
+        out.extend_from_slice(&[(length >> 16) as u8, (length >> 8) as u8, length as u8]);
+        out.push(self.kind);
+        out.push(self.flags);
+        out.extend_from_slice(&u32_bytes(self.stream_id));
+        out.extend_from_slice(&self.payload);
+    }
+
+    /// The payload without padding and priority information (DATA and HEADERS frames).
+    pub fn content(&self) -> Result<&[u8], GrpcError> {
+        let mut content = &self.payload[..];
+        if self.has_flag(FLAG_PADDED) {
+            let padding = *try!(content.first().ok_or_else(invalid_padding)) as usize;
+            if padding >= content.len() {
+                return Err(invalid_padding());
+            }
+            content = &content[1..content.len() - padding];
+        }
+        if self.kind == FRAME_HEADERS && self.has_flag(FLAG_PRIORITY) {
+            if content.len() < 5 {
+                let reason = "HEADERS frame too short for its priority.";
+                return Err(GrpcError::Protocol(reason.to_owned()));
+            }
+            content = &content[5..];
+        }
+        Ok(content)
+    }
+}
+
+fn invalid_padding() -> GrpcError {
+    GrpcError::Protocol("Padding exceeds the frame.".to_owned())
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+fn u32_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+/// Appends the frames that carry a header block: a HEADERS frame followed by as many
+/// CONTINUATION frames as needed. All header blocks sent on a connection share `encoder`.
+pub fn write_headers(out: &mut Vec<u8>,
+                     encoder: &mut Encoder,
+                     stream_id: u32,
+                     headers: &[(&str, &str)],
+                     end_stream: bool) {
+    let headers = headers.iter()
+        .map(|&(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect();
+    let block = encoder.encode(&headers);
+    let mut chunks: Vec<&[u8]> = block.chunks(MAX_FRAME_SIZE).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let kind = if i == 0 { FRAME_HEADERS } else { FRAME_CONTINUATION };
+        let mut flags = if i == last { FLAG_END_HEADERS } else { 0 };
+        if i == 0 && end_stream {
+            flags |= FLAG_END_STREAM;
+        }
+        Frame::new(kind, flags, stream_id, chunk.to_vec()).write_to(out);
+    }
+}
+
+/// Appends `message` with the gRPC length prefix, split into DATA frames.
+pub fn write_message(out: &mut Vec<u8>, stream_id: u32, message: &[u8], end_stream: bool) {
+    // Not compressed
+    let mut data = vec![0];
+    data.extend_from_slice(&u32_bytes(message.len() as u32));
+    data.extend_from_slice(message);
+    let chunks: Vec<&[u8]> = data.chunks(MAX_FRAME_SIZE).collect();
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let flags = if i == last && end_stream { FLAG_END_STREAM } else { 0 };
+        Frame::new(FRAME_DATA, flags, stream_id, chunk.to_vec()).write_to(out);
+    }
+}
+
+/// The message in gRPC's length-prefixed `data`.
+pub fn read_message(data: &[u8]) -> Result<&[u8], GrpcError> {
+    if data.len() < 5 {
+        return Err(GrpcError::Protocol("Response without a message.".to_owned()));
+    }
+    if data[0] != 0 {
+        // No compression was offered (grpc-accept-encoding).
+        return Err(GrpcError::Protocol("Compressed message.".to_owned()));
+    }
+    let length = read_u32(&data[1..5]) as usize;
+    if data.len() - 5 < length {
+        return Err(GrpcError::Protocol("Message ends early.".to_owned()));
+    }
+    Ok(&data[5..5 + length])
+}
+
+/// Decodes a complete header block. All header blocks received on a connection share `decoder`.
+pub fn decode_headers(decoder: &mut Decoder, block: &[u8])
+                      -> Result<Vec<(String, String)>, GrpcError> {
+    let headers = try!(decoder.decode(block));
+    Ok(headers.into_iter()
+        .map(|(name, value)| {
+            (String::from_utf8_lossy(&name).into_owned(),
+             String::from_utf8_lossy(&value).into_owned())
+        })
+        .collect())
+}
+
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|h| h.0 == name).map(|h| &h.1[..])
+}
+
+/// Client for the gRPC server on a Unix socket.
+#[derive(Debug, Clone)]
+pub struct GrpcClient {
+    socket_path: String,
+    timeout: Option<Duration>,
+}
+
+impl GrpcClient {
+    pub fn new(socket_path: &str) -> GrpcClient {
+        GrpcClient { socket_path: socket_path.to_owned(), timeout: None }
+    }
+
+    /// How long to wait for the server to accept or answer a call. None waits indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Calls `method` (`/package.Service/Method`) with the serialized `request` and returns the
+    /// serialized response. `metadata` is sent along as headers with lowercase names.
+    pub fn call(&self,
+                method: &str,
+                metadata: &[(&str, &str)],
+                request: &[u8])
+                -> Result<Vec<u8>, GrpcError> {
+        let stream = try!(UnixStream::connect(&self.socket_path));
+        try!(stream.set_read_timeout(self.timeout));
+        try!(stream.set_write_timeout(self.timeout));
+
+        let mut headers = vec![(":method", "POST"),
+                               (":scheme", "http"),
+                               (":path", method),
+                               (":authority", "localhost"),
+                               ("content-type", "application/grpc"),
+                               ("te", "trailers")];
+        headers.extend_from_slice(metadata);
+        let mut out = PREFACE.to_vec();
+        // No server push
+        let mut settings = vec![(SETTINGS_ENABLE_PUSH >> 8) as u8, SETTINGS_ENABLE_PUSH as u8];
+        settings.extend_from_slice(&u32_bytes(0));
+        Frame::new(FRAME_SETTINGS, 0, 0, settings).write_to(&mut out);
+        write_headers(&mut out, &mut Encoder::new(), STREAM_ID, &headers, false);
+        // Requests are small enough for the initial flow control window (64 KiB).
+        write_message(&mut out, STREAM_ID, request, true);
+        let mut writer = &stream;
+        try!(writer.write_all(&out));
+
+        let (headers, data) = try!(read_response(&mut BufReader::new(&stream), &mut writer));
+        if header(&headers, ":status") != Some("200") {
+            return Err(GrpcError::Protocol(format!("HTTP status {}.",
+                                                   header(&headers, ":status").unwrap_or("-"))));
+        }
+        let status = try!(header(&headers, "grpc-status")
+            .ok_or_else(|| GrpcError::Protocol("Response without grpc-status.".to_owned())));
+        let status = try!(status.parse::<u32>()
+            .map_err(|_| GrpcError::Protocol(format!("Invalid grpc-status {}.", status))));
+        if status != STATUS_OK {
+            let message = header(&headers, "grpc-message").unwrap_or("").to_owned();
+            return Err(GrpcError::Status(status, message));
+        }
+        Ok(try!(read_message(&data)).to_vec())
+    }
+}
+
+/// The headers (including trailers) and the data of a response.
+type Response = (Vec<(String, String)>, Vec<u8>);
+
+/// Reads frames until the server ends the stream. Answers pings and settings and hands out flow
+/// control credit for the data received.
+fn read_response<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Response, GrpcError> {
+    let mut decoder = Decoder::new();
+    let mut headers = Vec::new();
+    let mut data = Vec::new();
+    // Header block split across HEADERS and CONTINUATION frames
+    let mut block = Vec::new();
+    let mut block_ends_stream = false;
+    loop {
+        let frame = try!(Frame::read_from(reader));
+        let mut reply = Vec::new();
+        match frame.kind {
+            FRAME_SETTINGS if !frame.has_flag(FLAG_ACK) => {
+                Frame::new(FRAME_SETTINGS, FLAG_ACK, 0, Vec::new()).write_to(&mut reply);
+            }
+            FRAME_PING if !frame.has_flag(FLAG_ACK) => {
+                Frame::new(FRAME_PING, FLAG_ACK, 0, frame.payload.clone()).write_to(&mut reply);
+            }
+            // Otherwise, the server still finishes the call.
+            FRAME_GOAWAY if frame.payload.len() >= 8 &&
+                            read_u32(&frame.payload[0..4]) & 0x7fff_ffff < STREAM_ID => {
+                return Err(GrpcError::Protocol(format!("Server refused the call (GOAWAY error \
+                                                        code {}).",
+                                                       read_u32(&frame.payload[4..8]))));
+            }
+            FRAME_RST_STREAM if frame.stream_id == STREAM_ID && frame.payload.len() >= 4 => {
+                return Err(GrpcError::Protocol(format!("Server reset the call (error code {}).",
+                                                       read_u32(&frame.payload[0..4]))));
+            }
+            FRAME_HEADERS | FRAME_CONTINUATION if frame.stream_id == STREAM_ID => {
+                if frame.kind == FRAME_HEADERS {
+                    block_ends_stream = frame.has_flag(FLAG_END_STREAM);
+                }
+                block.extend_from_slice(try!(frame.content()));
+                if frame.has_flag(FLAG_END_HEADERS) {
+                    headers.extend(try!(decode_headers(&mut decoder, &block)));
+                    block.clear();
+                    if block_ends_stream {
+                        return Ok((headers, data));
+                    }
+                }
+            }
+            FRAME_DATA if frame.stream_id == STREAM_ID => {
+                data.extend_from_slice(try!(frame.content()));
+                if frame.has_flag(FLAG_END_STREAM) {
+                    return Ok((headers, data));
+                }
+                // Padding counts against the window, too.
+                if !frame.payload.is_empty() {
+                    let credit = u32_bytes(frame.payload.len() as u32);
+                    Frame::new(FRAME_WINDOW_UPDATE, 0, 0, credit.to_vec()).write_to(&mut reply);
+                    Frame::new(FRAME_WINDOW_UPDATE, 0, STREAM_ID, credit.to_vec())
+                        .write_to(&mut reply);
+                }
+            }
+            // Acknowledgements, window updates and priorities
+            _ => {}
+        }
+        if !reply.is_empty() {
+            try!(writer.write_all(&reply));
+        }
+    }
+}
+
+// ############### PROTOBUF ###############################
+
+/// Value of a field in a protobuf message. Only the wire types are distinguished.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    /// Strings, bytes, embedded messages and packed repeated fields
+    LengthDelimited(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Varint(value) | Value::Fixed64(value) => Some(value),
+            Value::Fixed32(value) => Some(value as u64),
+            Value::LengthDelimited(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::LengthDelimited(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        self.as_bytes().map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// The fields of a protobuf message as (field number, value) in the order they were serialized.
+/// Repeated fields occur once per element.
+pub fn decode_fields<'a>(message: &'a [u8]) -> Result<Vec<(u32, Value<'a>)>, GrpcError> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while position < message.len() {
+        let key = try!(decode_varint(message, &mut position));
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => Value::Varint(try!(decode_varint(message, &mut position))),
+            1 => Value::Fixed64(try!(fixed(message, &mut position, 8))),
+            2 => {
+                let length = try!(decode_varint(message, &mut position)) as usize;
+                if message.len() - position < length {
+                    return Err(invalid_message("Field ends early."));
+                }
+                position += length;
+                Value::LengthDelimited(&message[position - length..position])
+            }
+            5 => Value::Fixed32(try!(fixed(message, &mut position, 4)) as u32),
+            _ => return Err(invalid_message("Unsupported wire type.")),
+        };
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+/// Appends a string (or bytes, or embedded message) field to `message`.
+pub fn encode_bytes_field(message: &mut Vec<u8>, field: u32, value: &[u8]) {
+    encode_varint(message, (field as u64) << 3 | 2);
+    encode_varint(message, value.len() as u64);
+    message.extend_from_slice(value);
+}
+
+/// Appends a varint field (integers, booleans and enums) to `message`.
+pub fn encode_varint_field(message: &mut Vec<u8>, field: u32, value: u64) {
+    encode_varint(message, (field as u64) << 3);
+    encode_varint(message, value);
+}
+
+fn encode_varint(message: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        message.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+fn decode_varint(message: &[u8], position: &mut usize) -> Result<u64, GrpcError> {
+    let mut value = 0;
+    for shift in 0..10 {
+        let byte = try!(message.get(*position)
+            .ok_or_else(|| invalid_message("Varint ends early.")));
+        *position += 1;
+        value |= (*byte as u64 & 0x7f) << (7 * shift);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_message("Varint too long."))
+}
+
+/// Reads a little-endian integer of `size` bytes.
+fn fixed(message: &[u8], position: &mut usize, size: usize) -> Result<u64, GrpcError> {
+    if message.len() - *position < size {
+        return Err(invalid_message("Field ends early."));
+    }
+    let value = message[*position..*position + size]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u64);
+    *position += size;
+    Ok(value)
+}
+
+fn invalid_message(reason: &str) -> GrpcError {
+    GrpcError::Protocol(format!("Invalid protobuf message. {}", reason))
+}
+
+// ############### GRPC ERROR #############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum GrpcError {
+        Io(err: io::Error) {
+            description("gRPC call failed.")
+            cause(err)
+            from()
+            display(me) -> ("{} Error: {}", me.description(), err)
+        }
+        Hpack(err: DecoderError) {
+            description("Invalid headers in gRPC response.")
+            from()
+            display(me) -> ("{} Error: {:?}", me.description(), err)
+        }
+        Protocol(reason: String) {
+            description("Invalid gRPC response.")
+            display(me) -> ("{} {}", me.description(), reason)
+        }
+        Status(status: u32, message: String) {
+            description("gRPC call was not successful.")
+            display(me) -> ("{} Status: {}, message: {}", me.description(), status, message)
+        }
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read, Write};
+    use std::thread;
+
+    use hpack::{Decoder, Encoder};
+
+    use super::*;
+    use common;
+    use mock_grpc_server::{MockGrpcServer, RecordedCall};
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn owned(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn rfc_7541_header_blocks() {
+        common::init_log();
+        // #### GIVEN ####
+        // RFC 7541, appendix C.4: requests with Huffman coding
+        let requests = ["828684418cf1e3c2e5f23a6ba0ab90f4ff",
+                        "828684be5886a8eb10649cbf",
+                        "828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"];
+        // Appendix C.6: responses with Huffman coding that evict entries from a 256 byte table
+        let responses = ["488264025885aec3771a4b6196d07abe941054d444a8200595040b8166e082a62d1bff\
+                          6e919d29ad171863c78f0b97c8e9ae82ae43d3",
+                         "4883640effc1c0bf",
+                         "88c16196d07abe941054d444a8200595040b8166e084a62d1bffc05a839bd9ab77ad94\
+                          e7821dd7f2e6c7b335dfdfcd5b3960d5af27087f3672c1ab270fb5291f9587316065c0\
+                          03ed4ee5b1063d5007"];
+        let mut request_decoder = Decoder::new();
+        let mut response_decoder = Decoder::new();
+        response_decoder.set_max_table_size(256);
+
+        // #### WHEN  ####
+        let requests: Vec<_> = requests.iter()
+            .map(|block| decode_headers(&mut request_decoder, &from_hex(block)).unwrap())
+            .collect();
+        let responses: Vec<_> = responses.iter()
+            .map(|block| decode_headers(&mut response_decoder, &from_hex(block)).unwrap())
+            .collect();
+
+        // #### THEN  ####
+        assert_eq!(requests[0],
+                   owned(&[(":method", "GET"),
+                           (":scheme", "http"),
+                           (":path", "/"),
+                           (":authority", "www.example.com")]));
+        assert_eq!(requests[1][4], ("cache-control".to_owned(), "no-cache".to_owned()));
+        assert_eq!(requests[2],
+                   owned(&[(":method", "GET"),
+                           (":scheme", "https"),
+                           (":path", "/index.html"),
+                           (":authority", "www.example.com"),
+                           ("custom-key", "custom-value")]));
+        assert_eq!(responses[1],
+                   owned(&[(":status", "307"),
+                           ("cache-control", "private"),
+                           ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                           ("location", "https://www.example.com")]));
+        assert_eq!(responses[2],
+                   owned(&[(":status", "200"),
+                           ("cache-control", "private"),
+                           ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                           ("location", "https://www.example.com"),
+                           ("content-encoding", "gzip"),
+                           ("set-cookie",
+                            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1")]));
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut message = Vec::new();
+        encode_bytes_field(&mut message, 1, b"web");
+        encode_varint_field(&mut message, 3, 300);
+        encode_bytes_field(&mut message, 1, b"api");
+        // Fixed32 field 4 (1) and fixed64 field 5 (2)
+        message.extend_from_slice(&[0x25, 1, 0, 0, 0, 0x29, 2, 0, 0, 0, 0, 0, 0, 0]);
+
+        // #### WHEN  ####
+        let fields = decode_fields(&message).expect("Decode message");
+
+        // #### THEN  ####
+        assert_eq!(fields,
+                   vec![(1, Value::LengthDelimited(&b"web"[..])),
+                        (3, Value::Varint(300)),
+                        (1, Value::LengthDelimited(&b"api"[..])),
+                        (4, Value::Fixed32(1)),
+                        (5, Value::Fixed64(2))]);
+        assert!(decode_fields(&message[..message.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn unary_call() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockGrpcServer::with_handler(Box::new(|call: &RecordedCall| {
+            let mut response = Vec::new();
+            encode_bytes_field(&mut response, 1, &call.request);
+            Ok(response)
+        }));
+        let client = GrpcClient::new(&server.socket_path());
+        let mut request = Vec::new();
+        encode_bytes_field(&mut request, 1, b"hello");
+
+        // #### WHEN  ####
+        let response = client.call("/test.Echo/Call", &[("x-tenant", "a")], &request)
+            .expect("Call echo");
+
+        // #### THEN  ####
+        let fields = decode_fields(&response).expect("Decode response");
+        assert_eq!(fields, vec![(1, Value::LengthDelimited(&request[..]))]);
+        let calls = server.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].method, "/test.Echo/Call");
+        assert_eq!(calls[0].metadata("x-tenant"), Some("a"));
+    }
+
+    #[test]
+    fn status_error() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockGrpcServer::with_handler(Box::new(|_: &RecordedCall| {
+            Err((5, "container \"web\": not found".to_owned()))
+        }));
+        let client = GrpcClient::new(&server.socket_path());
+
+        // #### WHEN  ####
+        let result = client.call("/test.Containers/Get", &[], &[]);
+
+        // #### THEN  ####
+        match result {
+            Err(GrpcError::Status(5, ref message)) => assert!(message.contains("not found")),
+            other => panic!("Expected status 5, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn padded_and_continued_frames() {
+        common::init_log();
+        // #### GIVEN ####
+        // A server that pings, pads its data and splits the trailers into two frames.
+        let (mut server, client_end) = ::std::os::unix::net::UnixStream::pair()
+            .expect("Create socket pair");
+        let server_thread = thread::spawn(move || {
+            let mut reader = BufReader::new(server.try_clone().unwrap());
+            let mut preface = [0; 24];
+            reader.read_exact(&mut preface).unwrap();
+            let mut out = Vec::new();
+            let mut encoder = Encoder::new();
+            Frame::new(FRAME_PING, 0, 0, vec![7; 8]).write_to(&mut out);
+            write_headers(&mut out, &mut encoder, 1, &[(":status", "200")], false);
+            let mut data = vec![3];
+            data.extend_from_slice(&[0, 0, 0, 0, 2, 0x08, 0x2a]);
+            data.extend_from_slice(&[0, 0, 0]);
+            Frame::new(FRAME_DATA, FLAG_PADDED, 1, data).write_to(&mut out);
+            let trailers = encoder.encode(&vec![(b"grpc-status".to_vec(), b"0".to_vec())]);
+            let (first, rest) = trailers.split_at(4);
+            Frame::new(FRAME_HEADERS, FLAG_END_STREAM, 1, first.to_vec()).write_to(&mut out);
+            Frame::new(FRAME_CONTINUATION, FLAG_END_HEADERS, 1, rest.to_vec()).write_to(&mut out);
+            server.write_all(&out).unwrap();
+            // What the client sends back, up to the flow control credit for the stream
+            let mut replies = Vec::new();
+            loop {
+                let frame = Frame::read_from(&mut reader).unwrap();
+                let done = frame.kind == FRAME_WINDOW_UPDATE && frame.stream_id == 1;
+                replies.push(frame);
+                if done {
+                    return replies;
+                }
+            }
+        });
+        let mut writer = &client_end;
+        writer.write_all(PREFACE).unwrap();
+
+        // #### WHEN  ####
+        let (headers, data) = read_response(&mut BufReader::new(&client_end), &mut writer)
+            .expect("Read response");
+
+        // #### THEN  ####
+        assert_eq!(header(&headers, "grpc-status"), Some("0"));
+        assert_eq!(read_message(&data).expect("Read message"), &[0x08, 0x2a]);
+        let replies = server_thread.join().unwrap();
+        assert_eq!(replies,
+                   vec![Frame::new(FRAME_PING, FLAG_ACK, 0, vec![7; 8]),
+                        Frame::new(FRAME_WINDOW_UPDATE, 0, 0, vec![0, 0, 0, 11]),
+                        Frame::new(FRAME_WINDOW_UPDATE, 0, 1, vec![0, 0, 0, 11])]);
+    }
+}
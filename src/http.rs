@@ -0,0 +1,695 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Minimal HTTP/1.1 client for the JSON APIs of publisher backends (etcd, consul, ...) and
+//! inspectors (docker, podman). Opens one connection per request (`Connection: close`), which is
+//! plenty for the handful of requests per refresh. https:// URLs are supported via OpenSSL.
+
+use std::cmp::min;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_OP_NO_COMPRESSION, SSL_OP_NO_SSLV2,
+                   SSL_OP_NO_SSLV3, SSL_VERIFY_PEER};
+use openssl::ssl::error::SslError;
+use openssl::x509::X509FileType;
+use openssl_verify::verify_callback;
+use rustc_serialize::json::{self, Json};
+use url::Url;
+
+/// Client for a single HTTP endpoint. Request paths are relative to the path of the endpoint URL.
+/// The query of the endpoint URL is added to every request.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    address: Address,
+    base_path: String,
+    query: Option<String>,
+    timeout: Option<Duration>,
+    /// Set for https:// URLs.
+    tls_opt: Option<Tls>,
+}
+
+/// TLS settings for https:// URLs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TlsSettings {
+    /// PEM file with the CA certificates to verify the server with. None means the system's
+    /// default CA certificates.
+    pub ca_file: Option<String>,
+    /// PEM files with the client certificate and its private key.
+    pub client_cert: Option<(String, String)>,
+    /// Whether to verify the server's certificate (including the host name).
+    pub verify: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> TlsSettings {
+        TlsSettings { ca_file: None, client_cert: None, verify: true }
+    }
+}
+
+/// The OpenSSL context shared by the connections of a client.
+#[derive(Clone)]
+struct Tls {
+    context: Arc<SslContext>,
+    verify: bool,
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tls {{ verify: {} }}", self.verify)
+    }
+}
+
+impl Tls {
+    fn new(settings: &TlsSettings) -> Result<Tls, SslError> {
+        let mut context = try!(SslContext::new(SslMethod::Sslv23));
+        context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_COMPRESSION);
+        try!(context.set_cipher_list("DEFAULT"));
+        match settings.ca_file {
+            Some(ref ca_file) => try!(context.set_CA_file(ca_file)),
+            None => try!(context.set_default_verify_paths()),
+        }
+        if let Some((ref cert_file, ref key_file)) = settings.client_cert {
+            try!(context.set_certificate_file(cert_file, X509FileType::PEM));
+            try!(context.set_private_key_file(key_file, X509FileType::PEM));
+            try!(context.check_private_key());
+        }
+        Ok(Tls { context: Arc::new(context), verify: settings.verify })
+    }
+
+    fn connect(&self, host: &str, stream: TcpStream) -> Result<SslStream<TcpStream>, SslError> {
+        let mut ssl = try!(Ssl::new(&self.context));
+        // Server name indication
+        try!(ssl.set_hostname(host));
+        if self.verify {
+            let host = host.to_owned();
+            ssl.set_verify_callback(SSL_VERIFY_PEER,
+                                    move |ok, x509_ctx| verify_callback(&host, ok, x509_ctx));
+        }
+        SslStream::connect(ssl, stream)
+    }
+}
+
+/// A connection to the endpoint, whatever the transport.
+trait Connection: Read + Write {}
+
+impl<S: Read + Write> Connection for S {}
+
+#[derive(Debug, Clone)]
+enum Address {
+    Tcp(String, u16),
+    /// Path of a Unix domain socket (`unix:///path/to/socket` URLs).
+    Unix(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: String,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|h| h.0 == name).map(|h| &h.1[..])
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding")
+            .map(|te| te.to_lowercase().contains("chunked"))
+            .unwrap_or(false)
+    }
+}
+
+impl HttpClient {
+    /// Client for `url`. https:// servers are verified against the system's CA certificates.
+    pub fn new(url: &Url) -> Result<HttpClient, HttpError> {
+        HttpClient::with_tls(url, &TlsSettings::default())
+    }
+
+    /// Client for `url` that uses `tls` for https:// (ignored for other URLs).
+    pub fn with_tls(url: &Url, tls: &TlsSettings) -> Result<HttpClient, HttpError> {
+        let invalid = |reason: &'static str| HttpError::InvalidUrl(url.serialize(), reason);
+        let query = url.query.clone();
+        if url.scheme == "unix" {
+            // Both unix:///var/run/x.sock and unix://var/run/x.sock are common.
+            let serialized = url.serialize();
+            let path = serialized["unix:".len()..].trim_left_matches('/');
+            if path.is_empty() {
+                return Err(invalid("Missing socket path."));
+            }
+            return Ok(HttpClient {
+                address: Address::Unix(format!("/{}", path)),
+                base_path: String::new(),
+                query: query,
+                timeout: None,
+                tls_opt: None,
+            });
+        }
+        let tls_opt = match &url.scheme[..] {
+            "http" => None,
+            "https" => Some(try!(Tls::new(tls))),
+            _ => return Err(invalid("Only http://, https:// and unix:// URLs are supported.")),
+        };
+        let host = try!(url.serialize_host().ok_or_else(|| invalid("Missing host.")));
+        let port = try!(url.port_or_default().ok_or_else(|| invalid("Missing port.")));
+        let base_path = url.serialize_path().unwrap_or_else(String::new);
+        Ok(HttpClient {
+            // IPv6 addresses come in brackets
+            address: Address::Tcp(host.trim_left_matches('[').trim_right_matches(']').to_owned(),
+                                  port),
+            base_path: base_path.trim_right_matches('/').to_owned(),
+            query: query,
+            timeout: None,
+            tls_opt: tls_opt,
+        })
+    }
+
+    /// Limits the time spent waiting for the server (per connection attempt and per read/write,
+    /// not for the whole request). Must not be zero.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Request target for `path`: base path, `path` and the query of the endpoint URL.
+    fn target(&self, path: &str) -> String {
+        let path = if self.base_path.is_empty() && path.is_empty() {
+            "/"
+        } else {
+            path
+        };
+        let mut target = format!("{}{}", self.base_path, path);
+        if let Some(ref query) = self.query {
+            target.push(if path.contains('?') { '&' } else { '?' });
+            target.push_str(query);
+        }
+        target
+    }
+
+    /// Connect to the first address of `host` that accepts the connection (within the timeout).
+    fn connect_tcp(&self, host: &str, port: u16) -> Result<TcpStream, HttpError> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(try!(TcpStream::connect((host, port)))),
+        };
+        let mut last_error = None;
+        for address in try!((host, port).to_socket_addrs()) {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(HttpError::Io(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No address found for {}.", host))
+        })))
+    }
+
+    /// Open a connection to the endpoint (TLS handshake included for https://).
+    fn connect(&self) -> Result<Box<Connection>, HttpError> {
+        match self.address {
+            Address::Tcp(ref host, port) => {
+                let stream = try!(self.connect_tcp(host, port));
+                try!(stream.set_read_timeout(self.timeout));
+                try!(stream.set_write_timeout(self.timeout));
+                match self.tls_opt {
+                    Some(ref tls) => Ok(Box::new(try!(tls.connect(host, stream)))),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            Address::Unix(ref path) => {
+                let stream = try!(UnixStream::connect(path));
+                try!(stream.set_read_timeout(self.timeout));
+                try!(stream.set_write_timeout(self.timeout));
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    /// Open a connection and send the request over it.
+    fn send(&self,
+            method: &str,
+            path: &str,
+            headers: &[(&str, &str)],
+            body: &[u8])
+            -> Result<BufReader<Box<Connection>>, HttpError> {
+        let mut request = Vec::new();
+        let host_header = match self.address {
+            Address::Tcp(ref host, port) if host.contains(':') => format!("[{}]:{}", host, port),
+            Address::Tcp(ref host, port) => format!("{}:{}", host, port),
+            // Servers on Unix sockets don't care, but HTTP/1.1 requires the header.
+            Address::Unix(_) => "localhost".to_owned(),
+        };
+        try!(write!(&mut request,
+                    "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+                    method,
+                    self.target(path),
+                    host_header,
+                    body.len()));
+        for &(name, value) in headers {
+            try!(write!(&mut request, "{}: {}\r\n", name, value));
+        }
+        try!(write!(&mut request, "\r\n"));
+        request.extend_from_slice(body);
+
+        let mut connection = try!(self.connect());
+        try!(connection.write_all(&request));
+        try!(connection.flush());
+        Ok(BufReader::new(connection))
+    }
+
+    pub fn request(&self,
+                   method: &str,
+                   path: &str,
+                   headers: &[(&str, &str)],
+                   body: &[u8])
+                   -> Result<HttpResponse, HttpError> {
+        read_response(&mut try!(self.send(method, path, headers, body)))
+    }
+
+    /// Send a request whose response body is consumed as it arrives (e.g., an event stream).
+    /// Returns the response without body and a reader for the (decoded) body.
+    pub fn stream(&self,
+                  method: &str,
+                  path: &str)
+                  -> Result<(HttpResponse, Box<BufRead>), HttpError> {
+        let mut reader = try!(self.send(method, path, &[], &[]));
+        let response = try!(read_head(&mut reader));
+        let body: Box<BufRead> = if response.is_chunked() {
+            Box::new(BufReader::new(ChunkedReader::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+        Ok((response, body))
+    }
+
+    /// Send `body` as JSON and parse the JSON response. Fails unless the status is 2xx.
+    pub fn json(&self, method: &str, path: &str, body: &Json) -> Result<Json, HttpError> {
+        let raw_body = body.to_string();
+        let response = try!(self.request(method,
+                                         path,
+                                         &[("Content-Type", "application/json")],
+                                         raw_body.as_bytes()));
+        if !response.is_success() {
+            let body = response.body_text();
+            return Err(HttpError::Status(response.status, response.reason, body));
+        }
+        if response.body.iter().all(|b| (*b as char).is_whitespace()) {
+            return Ok(Json::Null);
+        }
+        Ok(try!(Json::from_str(&response.body_text())))
+    }
+}
+
+/// Parse an HTTP/1.1 response (status line, headers and body). Supports bodies delimited by
+/// Content-Length, chunked transfer encoding or the end of the connection.
+pub fn read_response<R: BufRead>(reader: &mut R) -> Result<HttpResponse, HttpError> {
+    let mut response = try!(read_head(reader));
+    let content_length = response.header("content-length").and_then(|l| l.parse::<u64>().ok());
+    let mut body = Vec::new();
+    if response.is_chunked() {
+        try!(ChunkedReader::new(reader.by_ref()).read_to_end(&mut body));
+    } else if let Some(length) = content_length {
+        try!(reader.by_ref().take(length).read_to_end(&mut body));
+    } else if response.status != 204 && response.status != 304 {
+        try!(reader.read_to_end(&mut body));
+    }
+    response.body = body;
+    Ok(response)
+}
+
+/// Parse status line and headers of an HTTP/1.1 response. The body is left in `reader`.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<HttpResponse, HttpError> {
+    let status_line = try!(read_line(reader));
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/") {
+        return Err(HttpError::InvalidResponse(format!("Unexpected status line: {}", status_line)));
+    }
+    let status = try!(parts.next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| HttpError::InvalidResponse(format!("Invalid status: {}", status_line))));
+    let reason = parts.next().unwrap_or("").to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = try!(read_line(reader));
+        if line.is_empty() {
+            break;
+        }
+        match line.find(':') {
+            Some(idx) => {
+                headers.push((line[..idx].trim().to_lowercase(), line[idx + 1..].trim().to_owned()))
+            }
+            None => return Err(HttpError::InvalidResponse(format!("Invalid header: {}", line))),
+        }
+    }
+    Ok(HttpResponse {
+        status: status,
+        reason: reason,
+        headers: headers,
+        body: Vec::new(),
+    })
+}
+
+/// Decodes a body with chunked transfer encoding while it is read.
+struct ChunkedReader<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader { inner: inner, remaining: 0, done: false }
+    }
+
+    /// Read the size line of the next chunk (and the trailers after the last one).
+    fn next_chunk(&mut self) -> Result<(), HttpError> {
+        let size_line = try!(read_line(&mut self.inner));
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        self.remaining = try!(usize::from_str_radix(size_hex, 16).map_err(|_| {
+            HttpError::InvalidResponse(format!("Invalid chunk size: {}", size_line))
+        }));
+        if self.remaining == 0 {
+            // Skip trailers
+            while !try!(read_line(&mut self.inner)).is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            try!(self.next_chunk().map_err(|e| match e {
+                HttpError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            }));
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = min(buf.len(), self.remaining);
+        let read = try!(self.inner.read(&mut buf[..max]));
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "Connection closed in the middle of a chunk."));
+        }
+        self.remaining -= read;
+        if self.remaining == 0 {
+            // CRLF after the chunk data
+            try!(self.inner.read_line(&mut String::new()));
+        }
+        Ok(read)
+    }
+}
+
+/// Read a CRLF (or LF) terminated line without the line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, HttpError> {
+    let mut line = String::new();
+    if try!(reader.read_line(&mut line)) == 0 {
+        return Err(HttpError::InvalidResponse("Connection closed unexpectedly.".to_owned()));
+    }
+    Ok(line.trim_right_matches(|c| c == '\r' || c == '\n').to_owned())
+}
+
+// ############### HTTP ERROR #############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum HttpError {
+        Io(err: io::Error) {
+            description("HTTP request failed.")
+            cause(err)
+            from()
+            display(me) -> ("{} Error: {}", me.description(), err)
+        }
+        Tls(err: SslError) {
+            description("TLS failure.")
+            cause(err)
+            from()
+            display(me) -> ("{} Error: {}", me.description(), err)
+        }
+        InvalidUrl(url: String, reason: &'static str) {
+            description("Invalid HTTP URL.")
+            display(me) -> ("{} {} URL: {}", me.description(), reason, url)
+        }
+        InvalidResponse(reason: String) {
+            description("Invalid HTTP response.")
+            display(me) -> ("{} {}", me.description(), reason)
+        }
+        Status(status: u16, reason: String, body: String) {
+            description("HTTP request was not successful.")
+            display(me) -> ("{} Status: {} {}, response: {}", me.description(), status, reason,
+                body)
+        }
+        Json(err: json::ParserError) {
+            description("Invalid JSON in HTTP response.")
+            cause(err)
+            from()
+            display(me) -> ("{} Error: {}", me.description(), err)
+        }
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use rustc_serialize::json::Json;
+    use url::Url;
+
+    use super::*;
+    use common;
+
+    #[test]
+    fn content_length() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Test: a:b\r\n\r\nhelloignored";
+
+        // #### WHEN  ####
+        let response = read_response(&mut BufReader::new(raw.as_bytes()))
+            .expect("Parse response");
+
+        // #### THEN  ####
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.header("X-Test"), Some("a:b"));
+        assert_eq!(response.body_text(), "hello");
+    }
+
+    #[test]
+    fn chunked() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   4\r\nnot \r\n5;ext=1\r\nfound\r\n0\r\n\r\n";
+
+        // #### WHEN  ####
+        let response = read_response(&mut BufReader::new(raw.as_bytes()))
+            .expect("Parse response");
+
+        // #### THEN  ####
+        assert_eq!(response.status, 404);
+        assert!(!response.is_success());
+        assert_eq!(response.body_text(), "not found");
+    }
+
+    #[test]
+    fn chunked_stream() {
+        common::init_log();
+        // #### GIVEN ####
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            let mut writer = &stream;
+            writer.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                               6\r\nfirst\n\r\n")
+                .unwrap();
+            // The second line only arrives later and is split across chunks.
+            thread::sleep(Duration::from_millis(50));
+            writer.write_all(b"3\r\nsec\r\n4\r\nond\n\r\n0\r\n\r\n").unwrap();
+        });
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        let client = HttpClient::new(&url).expect("Create HTTP client");
+
+        // #### WHEN  ####
+        let (response, body) = client.stream("GET", "/events").expect("Stream request");
+        let lines: Vec<String> = body.lines().map(|line| line.expect("Read line")).collect();
+
+        // #### THEN  ####
+        server.join().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(lines, vec!["first".to_owned(), "second".to_owned()]);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        common::init_log();
+        // #### GIVEN ####
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = Vec::new();
+            let mut buf = [0; 1];
+            // Read up to the end of the body ('}')
+            while reader.read(&mut buf).unwrap() == 1 {
+                request.push(buf[0]);
+                if buf[0] == b'}' {
+                    break;
+                }
+            }
+            let mut writer = &stream;
+            writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+                               {\"answer\":42}")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let url = Url::parse(&format!("http://127.0.0.1:{}/api", port)).unwrap();
+        let client = HttpClient::new(&url).expect("Create HTTP client");
+
+        // #### WHEN  ####
+        let response = client.json("POST", "/v1/test", &Json::from_str("{\"q\":1}").unwrap())
+            .expect("JSON request");
+
+        // #### THEN  ####
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v1/test HTTP/1.1\r\n"),
+                "Request: {}",
+                request);
+        assert!(request.ends_with("\r\n\r\n{\"q\":1}"), "Request: {}", request);
+        assert_eq!(response.find("answer").and_then(|a| a.as_u64()), Some(42));
+    }
+
+    #[test]
+    fn query_string() {
+        common::init_log();
+        // #### GIVEN ####
+        let url = Url::parse("http://127.0.0.1:8080/hooks?token=s3cret").unwrap();
+        let client = HttpClient::new(&url).expect("Create HTTP client");
+
+        // #### WHEN  ####
+        let plain = client.target("/beachhead");
+        let with_query = client.target("/beachhead?dry=1");
+
+        // #### THEN  ####
+        assert_eq!(plain, "/hooks/beachhead?token=s3cret");
+        assert_eq!(with_query, "/hooks/beachhead?dry=1&token=s3cret");
+    }
+
+    #[test]
+    fn unsupported_scheme() {
+        common::init_log();
+        // #### GIVEN ####
+        let url = Url::parse("ftp://hooks.example.org/beachhead").unwrap();
+
+        // #### WHEN  ####
+        let result = HttpClient::new(&url);
+
+        // #### THEN  ####
+        match result {
+            Err(HttpError::InvalidUrl(..)) => (),
+            other => panic!("Expected invalid URL, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_timeout() {
+        common::init_log();
+        // #### GIVEN ####
+        // Nobody listens on this port (it belongs to a listener that has been closed again).
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        let mut client = HttpClient::new(&url).expect("Create HTTP client");
+        client.set_timeout(Some(Duration::from_secs(1)));
+
+        // #### WHEN  ####
+        let result = client.request("GET", "/", &[], &[]);
+
+        // #### THEN  ####
+        match result {
+            Err(HttpError::Io(_)) => (),
+            other => panic!("Expected connection failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unix_socket() {
+        common::init_log();
+        // #### GIVEN ####
+        let path = env::temp_dir().join("beachhead-http-unix-socket.sock");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            let mut writer = &stream;
+            writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]").unwrap();
+            request_line
+        });
+        let url = Url::parse(&format!("unix://{}", path.to_string_lossy())).unwrap();
+        let client = HttpClient::new(&url).expect("Create HTTP client");
+
+        // #### WHEN  ####
+        let response = client.request("GET", "/v4.0.0/libpod/containers/json", &[], &[])
+            .expect("Request via Unix socket");
+
+        // #### THEN  ####
+        assert_eq!(server.join().unwrap(),
+                   "GET /v4.0.0/libpod/containers/json HTTP/1.1\r\n");
+        assert_eq!(response.body_text(), "[]");
+    }
+}
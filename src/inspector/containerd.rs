@@ -0,0 +1,567 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Inspects the containers of a plain containerd (without dockerd) through containerd's gRPC API
+//! on its Unix socket. Containers live in a containerd namespace (`default` for nerdctl, `k8s.io`
+//! for Kubernetes) and are known by the name nerdctl gave them (label `nerdctl/name`) or their
+//! id. The environment comes from the OCI spec's `process.env`, the labels from containerd and the
+//! address from the result that CNI cached when it set up the container's network.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json::Json;
+
+use common::Config;
+use grpc::{self, GrpcClient, GrpcError};
+use super::*;
+use super::docker_compat::{inspection, string_at};
+
+/// How long to wait for containerd to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+const LIST_CONTAINERS: &'static str = "/containerd.services.containers.v1.Containers/List";
+const LIST_TASKS: &'static str = "/containerd.services.tasks.v1.Tasks/List";
+/// Metadata that selects the namespace of a call.
+const NAMESPACE_HEADER: &'static str = "containerd-namespace";
+/// `containerd.v1.types.Status` of a running task.
+const TASK_RUNNING: u64 = 2;
+/// The name nerdctl gave a container.
+const NAME_LABEL: &'static str = "nerdctl/name";
+/// The ports nerdctl published for a container, as JSON.
+const PORTS_LABEL: &'static str = "nerdctl/ports";
+/// Tells the pod sandboxes of Kubernetes (`sandbox`) from the actual containers.
+const KIND_LABEL: &'static str = "io.cri-containerd.kind";
+/// The pod sandbox of a Kubernetes container, whose network the container shares.
+const SANDBOX_ANNOTATION: &'static str = "io.kubernetes.cri.sandbox-id";
+/// The network interface that CNI sets up first.
+const PRIMARY_INTERFACE: &'static str = "eth0";
+
+pub struct ContainerdInspector {
+    config: Arc<Config>,
+}
+
+/// The parts of a `containerd.services.containers.v1.Container` that are inspected.
+#[derive(Debug)]
+struct Container {
+    id: String,
+    labels: HashMap<String, String>,
+    /// The OCI runtime spec.
+    spec: Json,
+}
+
+/// The address that CNI assigned to a container on one of its networks.
+#[derive(Debug)]
+struct CniAddress {
+    network: String,
+    interface: String,
+    address: String,
+}
+
+impl ContainerdInspector {
+    pub fn new(config: Arc<Config>) -> ContainerdInspector {
+        ContainerdInspector { config: config }
+    }
+
+    fn call(&self, method: &str, request: &[u8]) -> Result<Vec<u8>, ContainerdError> {
+        let address = &self.config.containerd_address;
+        let mut client = GrpcClient::new(address);
+        client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+        debug!("Calling {} in containerd namespace {}", method, self.config.containerd_namespace);
+        let metadata = [(NAMESPACE_HEADER, &self.config.containerd_namespace[..])];
+        Ok(try_!(client.call(method, &metadata, request), (**address).clone()))
+    }
+
+    /// Containers that match any of the `filters` (containerd's filter syntax), all of them if
+    /// there are no filters.
+    fn containers(&self, filters: &[String]) -> Result<Vec<Container>, ContainerdError> {
+        let mut request = Vec::new();
+        for filter in filters {
+            grpc::encode_bytes_field(&mut request, 1, filter.as_bytes());
+        }
+        let response = try!(self.call(LIST_CONTAINERS, &request));
+        let mut containers = Vec::new();
+        for (field, value) in try_!(grpc::decode_fields(&response), self.address()) {
+            if let (1, Some(container)) = (field, value.as_bytes()) {
+                containers.push(try!(self.parse_container(container)));
+            }
+        }
+        Ok(containers)
+    }
+
+    /// Ids of the containers whose task is running.
+    fn running(&self) -> Result<HashSet<String>, ContainerdError> {
+        let response = try!(self.call(LIST_TASKS, &[]));
+        let mut running = HashSet::new();
+        for (field, value) in try_!(grpc::decode_fields(&response), self.address()) {
+            if let (1, Some(process)) = (field, value.as_bytes()) {
+                let (mut container_id, mut status) = (None, None);
+                for (field, value) in try_!(grpc::decode_fields(process), self.address()) {
+                    match field {
+                        1 => container_id = value.as_string(),
+                        4 => status = value.as_u64(),
+                        _ => {}
+                    }
+                }
+                if let (Some(container_id), Some(TASK_RUNNING)) = (container_id, status) {
+                    running.insert(container_id);
+                }
+            }
+        }
+        Ok(running)
+    }
+
+    fn parse_container(&self, message: &[u8]) -> Result<Container, ContainerdError> {
+        let mut container = Container {
+            id: String::new(),
+            labels: HashMap::new(),
+            spec: Json::Null,
+        };
+        let mut spec = None;
+        for (field, value) in try_!(grpc::decode_fields(message), self.address()) {
+            match (field, value.as_bytes()) {
+                (1, Some(id)) => container.id = String::from_utf8_lossy(id).into_owned(),
+                (2, Some(entry)) => {
+                    // Map entries are messages with the key in field 1 and the value in field 2.
+                    let (mut key, mut value) = (String::new(), String::new());
+                    for (field, part) in try_!(grpc::decode_fields(entry), self.address()) {
+                        match field {
+                            1 => key = part.as_string().unwrap_or_else(String::new),
+                            2 => value = part.as_string().unwrap_or_else(String::new),
+                            _ => {}
+                        }
+                    }
+                    container.labels.insert(key, value);
+                }
+                (5, Some(any)) => {
+                    // google.protobuf.Any with the JSON of the spec in field 2
+                    for (field, part) in try_!(grpc::decode_fields(any), self.address()) {
+                        if let (2, Some(json)) = (field, part.as_bytes()) {
+                            spec = Some(String::from_utf8_lossy(json).into_owned());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(spec) = spec {
+            container.spec = try!(Json::from_str(&spec).map_err(|e| {
+                ContainerdError::InvalidSpec(container.id.clone(), e.to_string())
+            }));
+        }
+        Ok(container)
+    }
+
+    /// The addresses CNI assigned to the container, or to the pod sandbox whose network it shares.
+    fn cni_addresses(&self, container: &Container) -> Result<Vec<CniAddress>, ContainerdError> {
+        let mut ids = vec![&container.id[..]];
+        let sandbox_id = string_at(&container.spec, &["annotations", SANDBOX_ANNOTATION]);
+        if !sandbox_id.is_empty() {
+            ids.push(sandbox_id);
+        }
+        let directory = &self.config.cni_results_dir;
+        let entries = match fs::read_dir(&**directory) {
+            Ok(entries) => entries,
+            // CNI hasn't set up any networks yet.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ContainerdError::CniResults((**directory).clone(), e)),
+        };
+        let mut addresses = Vec::new();
+        for entry in entries {
+            let path = try_!(entry, (**directory).clone()).path();
+            // Cache files are named NETWORK-CONTAINERID-INTERFACE.
+            let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+            if !file_name.map(|n| ids.iter().any(|id| n.contains(id))).unwrap_or(false) {
+                continue;
+            }
+            let mut content = String::new();
+            try_!(File::open(&path).and_then(|mut file| file.read_to_string(&mut content)),
+                  path.to_string_lossy().into_owned());
+            let result = match Json::from_str(&content) {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Skipping invalid CNI result {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if !ids.contains(&string_at(&result, &["containerId"])) {
+                continue;
+            }
+            let address = result.find_path(&["result", "ips"])
+                .and_then(|ips| ips.as_array())
+                .and_then(|ips| ips.first())
+                .map(|ip| string_at(ip, &["address"]))
+                .unwrap_or("");
+            // The address comes with the prefix length of the subnet (10.4.0.5/24).
+            addresses.push(CniAddress {
+                network: string_at(&result, &["networkName"]).to_owned(),
+                interface: string_at(&result, &["ifName"]).to_owned(),
+                address: address.split('/').next().unwrap_or("").to_owned(),
+            });
+        }
+        addresses.sort_by(|a, b| a.network.cmp(&b.network));
+        Ok(addresses)
+    }
+
+    fn address(&self) -> String {
+        (*self.config.containerd_address).clone()
+    }
+}
+
+impl Container {
+    fn name(&self) -> &str {
+        self.labels.get(NAME_LABEL).map(|name| &name[..]).unwrap_or(&self.id)
+    }
+
+    fn is_sandbox(&self) -> bool {
+        self.labels.get(KIND_LABEL).map(|kind| kind == "sandbox").unwrap_or(false)
+    }
+
+    /// The container in the format of `docker inspect`.
+    fn docker_format(&self) -> Json {
+        let env = self.spec.find_path(&["process", "env"]).cloned();
+        let labels = self.labels
+            .iter()
+            .map(|(name, value)| (name.clone(), Json::String(value.clone())))
+            .collect();
+        let mut config = BTreeMap::new();
+        config.insert("Env".to_owned(), env.unwrap_or(Json::Array(Vec::new())));
+        config.insert("Labels".to_owned(), Json::Object(labels));
+        let mut container = BTreeMap::new();
+        container.insert("Id".to_owned(), Json::String(self.id.clone()));
+        container.insert("Name".to_owned(), Json::String(self.name().to_owned()));
+        container.insert("Config".to_owned(), Json::Object(config));
+        Json::Object(container)
+    }
+
+    /// The container's network in the format of `docker inspect`'s `NetworkSettings`.
+    fn network_settings(&self, addresses: &[CniAddress]) -> Json {
+        let mut networks = BTreeMap::new();
+        for address in addresses {
+            let mut endpoint = BTreeMap::new();
+            endpoint.insert("IPAddress".to_owned(), Json::String(address.address.clone()));
+            networks.insert(address.network.clone(), Json::Object(endpoint));
+        }
+        let primary = addresses.iter()
+            .find(|address| address.interface == PRIMARY_INTERFACE)
+            .map(|address| address.address.clone())
+            .unwrap_or_else(String::new);
+        let mut settings = BTreeMap::new();
+        settings.insert("IPAddress".to_owned(), Json::String(primary));
+        settings.insert("Networks".to_owned(), Json::Object(networks));
+        settings.insert("Ports".to_owned(), Json::Object(self.published_ports()));
+        Json::Object(settings)
+    }
+
+    /// nerdctl's published ports ([{HostPort, ContainerPort, Protocol}]) in Docker's format.
+    fn published_ports(&self) -> BTreeMap<String, Json> {
+        let mut ports = BTreeMap::new();
+        let label = self.labels.get(PORTS_LABEL).map(|label| &label[..]).unwrap_or("[]");
+        let mappings = Json::from_str(label).ok();
+        let mappings = mappings.as_ref().and_then(|m| m.as_array()).map(|m| &m[..]).unwrap_or(&[]);
+        for mapping in mappings {
+            let number = |key: &str| mapping.find(key).and_then(|port| port.as_u64());
+            if let (Some(container_port), Some(host_port)) = (number("ContainerPort"),
+                                                              number("HostPort")) {
+                let protocol = string_at(mapping, &["Protocol"]);
+                let protocol = if protocol.is_empty() { "tcp" } else { protocol };
+                let mut binding = BTreeMap::new();
+                binding.insert("HostPort".to_owned(), Json::String(host_port.to_string()));
+                ports.insert(format!("{}/{}", container_port, protocol),
+                             Json::Array(vec![Json::Object(binding)]));
+            }
+        }
+        ports
+    }
+}
+
+// ############### CONTAINERD ERROR #######################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ContainerdError {
+        Grpc(address: String, err: GrpcError) {
+            description("Failed to communicate with the containerd API.")
+            cause(err)
+            display(me) -> ("{} Address: {}, error: {}", me.description(), address, err)
+            from(e: (GrpcError, String)) -> (e.1, e.0)
+        }
+        InvalidSpec(container_id: String, reason: String) {
+            description("Container has an invalid OCI spec.")
+            display(me) -> ("{} Container: {}, {}", me.description(), container_id, reason)
+        }
+        NoSuchContainer(namespace: String, container_name: String) {
+            description("No such container in the containerd namespace.")
+            display(me) -> ("{} Namespace: {}, container: {}", me.description(), namespace,
+                container_name)
+        }
+        CniResults(path: String, err: io::Error) {
+            description("Failed to read the cached CNI results.")
+            cause(err)
+            display(me) -> ("{} Path: {}, error: {}", me.description(), path, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+    }
+}
+
+impl InspectionInnerError for ContainerdError {}
+
+/// A string for containerd's filter syntax.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Inspect for ContainerdInspector {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        let running = try!(self.running());
+        for container in try!(self.containers(&[])) {
+            // Sandboxes only hold the network namespace of their pod.
+            if running.contains(&container.id) && !container.is_sandbox() {
+                container_names.push(container.name().to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        // Filters are combined with OR.
+        let filters = [format!("id=={}", quote(container_name)),
+                       format!("labels.{}=={}", quote(NAME_LABEL), quote(container_name))];
+        let containers = try!(self.containers(&filters));
+        // An id takes precedence over a name.
+        let container = try!(containers.iter()
+            .find(|container| container.id == container_name)
+            .or_else(|| containers.first())
+            .ok_or_else(|| {
+                ContainerdError::NoSuchContainer((*self.config.containerd_namespace).clone(),
+                                                 container_name.to_owned())
+            }));
+        let addresses = try!(self.cni_addresses(container));
+        inspection(&self.config,
+                   container_name,
+                   &container.docker_format(),
+                   &container.network_settings(&addresses))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        // Every call connects with the current settings, there is no connection to renew.
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use grpc::{decode_fields, encode_bytes_field, encode_varint_field};
+    use inspector::Inspect;
+    use mock_grpc_server::{MockGrpcServer, RecordedCall};
+
+    /// A serialized `containerd.services.containers.v1.Container`.
+    fn container(id: &str, labels: &[(&str, &str)], spec: &str) -> Vec<u8> {
+        let mut container = Vec::new();
+        encode_bytes_field(&mut container, 1, id.as_bytes());
+        for &(key, value) in labels {
+            let mut entry = Vec::new();
+            encode_bytes_field(&mut entry, 1, key.as_bytes());
+            encode_bytes_field(&mut entry, 2, value.as_bytes());
+            encode_bytes_field(&mut container, 2, &entry);
+        }
+        let mut any = Vec::new();
+        encode_bytes_field(&mut any,
+                           1,
+                           b"types.containerd.io/opencontainers/runtime-spec/1/Spec");
+        encode_bytes_field(&mut any, 2, spec.as_bytes());
+        encode_bytes_field(&mut container, 5, &any);
+        container
+    }
+
+    /// A serialized `containerd.v1.types.Process` (the main task of a container).
+    fn task(container_id: &str, status: u64) -> Vec<u8> {
+        let mut task = Vec::new();
+        encode_bytes_field(&mut task, 1, container_id.as_bytes());
+        encode_bytes_field(&mut task, 2, container_id.as_bytes());
+        encode_varint_field(&mut task, 3, 4242);
+        encode_varint_field(&mut task, 4, status);
+        task
+    }
+
+    /// Stand-in for containerd with the nerdctl container `web`, the Kubernetes container `9a2e`
+    /// in the pod sandbox `s1` and the stopped container `old`.
+    fn containerd_server() -> MockGrpcServer {
+        MockGrpcServer::with_handler(Box::new(|call: &RecordedCall| {
+            let containers = [("4f1c",
+                               "web",
+                               container("4f1c",
+                                         &[("nerdctl/name", "web"),
+                                           ("nerdctl/ports",
+                                            r#"[{"HostIP": "0.0.0.0", "HostPort": 8080,
+                                                 "ContainerPort": 80, "Protocol": "tcp"}]"#)],
+                                         r#"{"process": {"env": ["PATH=/bin",
+                                             "BEACHHEAD_DOMAINS=example.org:http"]}}"#)),
+                              ("9a2e",
+                               "",
+                               container("9a2e",
+                                         &[("io.cri-containerd.kind", "container"),
+                                           ("beachhead.api_example_org.http", "3000")],
+                                         r#"{"process": {"env": []}, "annotations":
+                                             {"io.kubernetes.cri.sandbox-id": "s1"}}"#)),
+                              ("s1",
+                               "",
+                               container("s1", &[("io.cri-containerd.kind", "sandbox")], "{}")),
+                              ("7c3d",
+                               "old",
+                               container("7c3d", &[("nerdctl/name", "old")], "{}"))];
+            let mut response = Vec::new();
+            match &call.method[..] {
+                "/containerd.services.tasks.v1.Tasks/List" => {
+                    for &(id, status) in &[("4f1c", 2), ("9a2e", 2), ("s1", 2), ("7c3d", 3)] {
+                        encode_bytes_field(&mut response, 1, &task(id, status));
+                    }
+                }
+                "/containerd.services.containers.v1.Containers/List" => {
+                    let filters: Vec<String> = decode_fields(&call.request)
+                        .unwrap()
+                        .into_iter()
+                        .filter_map(|(_, filter)| filter.as_string())
+                        .collect();
+                    for &(id, name, ref container) in &containers {
+                        if filters.is_empty() ||
+                           filters.contains(&format!("id==\"{}\"", id)) ||
+                           filters.contains(&format!("labels.\"nerdctl/name\"==\"{}\"", name)) {
+                            encode_bytes_field(&mut response, 1, container);
+                        }
+                    }
+                }
+                _ => return Err((12, "unknown method".to_owned())),
+            }
+            Ok(response)
+        }))
+    }
+
+    /// Writes the CNI results of `web` and of the pod sandbox `s1`.
+    fn cni_results(test_name: &str) -> PathBuf {
+        let directory = env::temp_dir()
+            .join(format!("beachhead-containerd-inspector-{}", test_name));
+        fs::create_dir_all(&directory).expect("Create directory for CNI results");
+        let results = [("bridge-4f1c-eth0", "4f1c", "bridge", "10.4.0.5/24"),
+                       ("k8s-pod-network-s1-eth0", "s1", "k8s-pod-network", "10.42.0.6/24")];
+        for &(file_name, container_id, network, address) in &results {
+            let result = format!(r#"{{"kind": "cniCacheV1", "containerId": "{}",
+                                      "networkName": "{}", "ifName": "eth0",
+                                      "result": {{"cniVersion": "1.0.0",
+                                                  "ips": [{{"address": "{}"}}]}}}}"#,
+                                 container_id,
+                                 network,
+                                 address);
+            File::create(directory.join(file_name))
+                .and_then(|mut file| file.write_all(result.as_bytes()))
+                .expect("Write CNI result");
+        }
+        directory
+    }
+
+    fn containerd_config(server: &MockGrpcServer, cni_results: &Path) -> Config {
+        let mut config = Config::default();
+        config.containerd_address = Rc::new(server.socket_path());
+        config.containerd_namespace = Rc::new("apps".to_owned());
+        config.cni_results_dir = Rc::new(cni_results.to_string_lossy().into_owned());
+        config
+    }
+
+    #[test]
+    fn enumerate_running() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = containerd_server();
+        let config = containerd_config(&server, &cni_results("enumerate_running"));
+        let mut inspector = ContainerdInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate containers");
+
+        // #### THEN  ####
+        assert_eq!(names, vec!["web".to_owned(), "9a2e".to_owned()]);
+        for call in server.calls() {
+            assert_eq!(call.metadata("containerd-namespace"), Some("apps"));
+        }
+    }
+
+    #[test]
+    fn inspect_env_and_labels() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = containerd_server();
+        let config = containerd_config(&server, &cni_results("inspect_env_and_labels"));
+        let mut inspector = ContainerdInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let web = inspector.inspect("web").expect("Inspect web");
+        let api = inspector.inspect("9a2e").expect("Inspect 9a2e");
+
+        // #### THEN  ####
+        assert_eq!(web.host, "10.4.0.5", "The address should come from the CNI result.");
+        assert!(web.envvar_present);
+        assert_eq!(web.specs.len(), 1);
+        assert_eq!(web.specs[0].domain_name, "example.org");
+        assert_eq!(api.host, "10.42.0.6", "Pod containers use the address of their sandbox.");
+        assert_eq!(api.specs.len(), 1);
+        assert_eq!(api.specs[0].domain_name, "api.example.org");
+        assert_eq!(api.specs[0].http_port, Some(3000));
+    }
+
+    #[test]
+    fn inspect_publish_host() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = containerd_server();
+        let mut config = containerd_config(&server, &cni_results("inspect_publish_host"));
+        config.publish_host = Some(Rc::new("192.0.2.10".to_owned()));
+        let mut inspector = ContainerdInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let inspection = inspector.inspect("web").expect("Inspect web");
+        let missing = inspector.inspect("gone");
+
+        // #### THEN  ####
+        assert_eq!(inspection.host, "192.0.2.10");
+        assert_eq!(inspection.specs[0].http_port, Some(8080));
+        match missing {
+            Err(ref e) => assert!(e.to_string().contains("container: gone"), "{}", e),
+            Ok(_) => panic!("Unknown containers should fail to inspect."),
+        }
+    }
+}
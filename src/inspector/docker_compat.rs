@@ -0,0 +1,143 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Reads container details in the format of `docker inspect` (the Docker Engine API), which other
+//! container engines (Podman) reproduce, as JSON. containerd's containers are translated into it.
+
+use std::collections::HashMap;
+
+use rustc_serialize::json::Json;
+
+use common::{Config, DomainSource};
+use super::*;
+use super::docker::{map_published_ports, parse_container_env_vars, parse_container_labels,
+                    select_host, NetworkEndpoint};
+
+/// Inspection of `container` (as reported by `docker inspect`) whose network is described by
+/// `network_settings` (usually its own `NetworkSettings`).
+pub fn inspection(config: &Config,
+                  container_name: &str,
+                  container: &Json,
+                  network_settings: &Json)
+                  -> Result<Inspection, InspectionError> {
+    // `container_name` might be an id, overrides are keyed by the actual name.
+    let name = string_at(container, &["Name"]).trim_left_matches('/').to_owned();
+    let config = config.for_container(&name);
+    let container_host = if let Some(ref address) = config.publish_host {
+        (**address).clone()
+    } else {
+        try!(select_host(&config,
+                         &name,
+                         string_at(network_settings, &["IPAddress"]),
+                         &networks(network_settings, string_at(container, &["Id"]))))
+    };
+
+    let mut envvar_present = false;
+    let mut specs = Vec::new();
+    // Labels are parsed last, so that they can override specs from the environment variable.
+    if config.domain_source != DomainSource::Labels {
+        try!(parse_container_env_vars(&container_env(container),
+                                      &config,
+                                      &mut envvar_present,
+                                      &mut specs));
+    }
+    if config.domain_source != DomainSource::Env {
+        try!(parse_container_labels(&container_labels(container),
+                                    &config,
+                                    &mut envvar_present,
+                                    &mut specs));
+    }
+    if config.publish_host.is_some() {
+        try!(map_published_ports(container_name,
+                                 &published_tcp_ports(network_settings),
+                                 &mut specs));
+    }
+    Ok(Inspection { envvar_present: envvar_present, specs: specs, host: container_host })
+}
+
+/// The string at `path` in `json` or "" if there is none.
+pub fn string_at<'a>(json: &'a Json, path: &[&str]) -> &'a str {
+    json.find_path(path).and_then(|value| value.as_string()).unwrap_or("")
+}
+
+/// Environment variables (`NAME=value`) from the container configuration.
+pub fn container_env(container: &Json) -> Option<Vec<String>> {
+    container.find_path(&["Config", "Env"]).and_then(|env| env.as_array()).map(|env| {
+        env.iter().filter_map(|line| line.as_string()).map(|line| line.to_owned()).collect()
+    })
+}
+
+pub fn container_labels(container: &Json) -> Option<HashMap<String, String>> {
+    let labels_opt = container.find_path(&["Config", "Labels"]).and_then(|l| l.as_object());
+    labels_opt.map(|labels| {
+        labels.iter()
+            .filter_map(|(name, value)| value.as_string().map(|v| (name.clone(), v.to_owned())))
+            .collect()
+    })
+}
+
+/// The endpoint on each network the container with `container_id` is attached to.
+pub fn networks(network_settings: &Json, container_id: &str) -> HashMap<String, NetworkEndpoint> {
+    let mut networks = HashMap::new();
+    if let Some(attached) = network_settings.find("Networks").and_then(|n| n.as_object()) {
+        for (network, endpoint) in attached {
+            let aliases = endpoint.find("Aliases")
+                .and_then(|aliases| aliases.as_array())
+                .map(|aliases| &aliases[..])
+                .unwrap_or(&[])
+                .iter()
+                .filter_map(|alias| alias.as_string())
+                // Docker (before API 1.45) adds the short container id, which is no better
+                // than the container name.
+                .filter(|alias| !(alias.len() == 12 && container_id.starts_with(alias)))
+                .map(|alias| alias.to_owned())
+                .collect();
+            networks.insert(network.clone(),
+                            NetworkEndpoint {
+                                ip: string_at(endpoint, &["IPAddress"]).to_owned(),
+                                aliases: aliases,
+                            });
+        }
+    }
+    networks
+}
+
+/// Host ports that TCP container ports are published on. Keys are container ports, values are host
+/// ports. Docker's format: "8080/tcp" => [{HostIp, HostPort}].
+pub fn published_tcp_ports(network_settings: &Json) -> HashMap<u16, u16> {
+    let mut published = HashMap::new();
+    if let Some(ports) = network_settings.find("Ports").and_then(|p| p.as_object()) {
+        for (container_port, mappings) in ports {
+            let mut parts = container_port.splitn(2, '/');
+            let port = parts.next().and_then(|p| u16::from_str_radix(p, 10).ok());
+            let protocol = parts.next().unwrap_or("tcp");
+            let host_port = mappings.as_array()
+                .and_then(|mappings| mappings.first())
+                .map(|mapping| string_at(mapping, &["HostPort"]))
+                .and_then(|host_port| u16::from_str_radix(host_port, 10).ok());
+            if let (Some(port), "tcp", Some(host_port)) = (port, protocol, host_port) {
+                published.insert(port, host_port);
+            }
+        }
+    }
+    published
+}
@@ -0,0 +1,424 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Inspects the pods of a single Kubernetes node (e.g., k3s) through the kubelet's read-only
+//! `/pods` endpoint or a JSON dump of it. Pods are named `namespace/name`. Domain specs are read
+//! from the environment variable of the pod's containers and from pod annotations, which take the
+//! place of container labels. The pod IP is published.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json::Json;
+
+use common::{Config, DomainSource};
+use http::{HttpClient, HttpError};
+use super::*;
+use super::docker::{map_published_ports, parse_container_env_vars, parse_container_labels};
+use super::docker_compat::string_at;
+
+/// How long to wait for the kubelet to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+
+pub struct KubeletInspector {
+    config: Arc<Config>,
+    client_opt: Option<HttpClient>,
+    /// The running pods as of the last `enumerate`. The refresh that follows inspects them
+    /// without fetching the pod list again for each pod.
+    pods_opt: Option<HashMap<String, Json>>,
+}
+
+impl KubeletInspector {
+    pub fn new(config: Arc<Config>) -> KubeletInspector {
+        KubeletInspector { config: config, client_opt: None, pods_opt: None }
+    }
+
+    fn endpoint(&self) -> String {
+        self.config.kubelet_url.serialize()
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, KubeletError> {
+        if self.client_opt.is_none() {
+            let mut client = try_!(HttpClient::new(&self.config.kubelet_url), self.endpoint());
+            client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    /// The pod list, from the dump if one is configured and from the kubelet otherwise.
+    fn pod_list(&mut self) -> Result<Json, KubeletError> {
+        let (source, text) = if let Some(ref path) = self.config.kubelet_pods_file {
+            let mut text = String::new();
+            try_!(File::open(&**path).and_then(|mut file| file.read_to_string(&mut text)),
+                  (**path).clone());
+            ((**path).clone(), text)
+        } else {
+            let endpoint = self.endpoint();
+            let client = try!(self.client());
+            let response = try_!(client.request("GET", "/pods", &[], &[]), endpoint.clone());
+            if !response.is_success() {
+                let body = response.body_text();
+                return Err(KubeletError::Http(endpoint,
+                                              HttpError::Status(response.status,
+                                                                response.reason,
+                                                                body)));
+            }
+            (endpoint, response.body_text())
+        };
+        Json::from_str(&text).map_err(|e| KubeletError::InvalidPodList(source, e.to_string()))
+    }
+
+    /// Running pods that have an address, by name.
+    fn running_pods(&mut self) -> Result<Vec<(String, Json)>, KubeletError> {
+        let pod_list = try!(self.pod_list());
+        let items = try!(pod_list.find("items").and_then(|items| items.as_array()).ok_or_else(|| {
+            KubeletError::InvalidPodList(self.endpoint(), "missing items".to_owned())
+        }));
+        Ok(items.iter()
+            .filter(|pod| string_at(pod, &["status", "phase"]) == "Running")
+            .filter(|pod| !string_at(pod, &["status", "podIP"]).is_empty())
+            .map(|pod| (pod_name(pod), pod.clone()))
+            .collect())
+    }
+}
+
+/// `namespace/name` of a pod.
+fn pod_name(pod: &Json) -> String {
+    format!("{}/{}",
+            string_at(pod, &["metadata", "namespace"]),
+            string_at(pod, &["metadata", "name"]))
+}
+
+/// Literal environment variables (`NAME=value`) of all containers of the pod. Variables that
+/// refer to config maps, secrets etc. are left out.
+fn pod_env(pod: &Json) -> Vec<String> {
+    let mut env = Vec::new();
+    for container in containers(pod) {
+        if let Some(vars) = container.find("env").and_then(|env| env.as_array()) {
+            for var in vars {
+                if let Some(value) = var.find("value").and_then(|value| value.as_string()) {
+                    env.push(format!("{}={}", string_at(var, &["name"]), value));
+                }
+            }
+        }
+    }
+    env
+}
+
+fn pod_annotations(pod: &Json) -> HashMap<String, String> {
+    pod.find_path(&["metadata", "annotations"])
+        .and_then(|annotations| annotations.as_object())
+        .map(|annotations| {
+            annotations.iter()
+                .filter_map(|(name, value)| value.as_string().map(|v| (name.clone(), v.to_owned())))
+                .collect()
+        })
+        .unwrap_or_else(HashMap::new)
+}
+
+/// Host ports that TCP container ports are exposed on (`hostPort`).
+fn host_ports(pod: &Json) -> HashMap<u16, u16> {
+    let mut host_ports = HashMap::new();
+    for container in containers(pod) {
+        if let Some(ports) = container.find("ports").and_then(|ports| ports.as_array()) {
+            for port in ports {
+                let protocol = port.find("protocol").and_then(|p| p.as_string()).unwrap_or("TCP");
+                // A hostPort of 0 (or none at all) means the port isn't exposed on the host.
+                let number = |field: &str| {
+                    port.find(field)
+                        .and_then(|n| n.as_u64())
+                        .and_then(|n| if n > 0 && n <= 65535 { Some(n as u16) } else { None })
+                };
+                if let (Some(port), "TCP", Some(host_port)) =
+                       (number("containerPort"), protocol, number("hostPort")) {
+                    host_ports.insert(port, host_port);
+                }
+            }
+        }
+    }
+    host_ports
+}
+
+fn containers(pod: &Json) -> &[Json] {
+    pod.find_path(&["spec", "containers"])
+        .and_then(|containers| containers.as_array())
+        .map(|containers| &containers[..])
+        .unwrap_or(&[])
+}
+
+// ############### KUBELET ERROR ##########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum KubeletError {
+        Http(endpoint: String, err: HttpError) {
+            description("Failed to get pods from the kubelet.")
+            cause(err)
+            display(me) -> ("{} Endpoint: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+        File(path: String, err: io::Error) {
+            description("Failed to read pod list.")
+            cause(err)
+            display(me) -> ("{} Path: {}, error: {}", me.description(), path, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+        InvalidPodList(source: String, reason: String) {
+            description("Invalid pod list.")
+            display(me) -> ("{} Source: {}, {}", me.description(), source, reason)
+        }
+        UnknownPod(name: String) {
+            description("Pod is not running on this node.")
+            display(me) -> ("{} Pod: {}", me.description(), name)
+        }
+    }
+}
+
+impl InspectionInnerError for KubeletError {}
+
+impl Inspect for KubeletInspector {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        self.pods_opt = None;
+        let pods = try!(self.running_pods());
+        container_names.extend(pods.iter().map(|&(ref name, _)| name.clone()));
+        self.pods_opt = Some(pods.into_iter().collect());
+        Ok(())
+    }
+
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        let cached = self.pods_opt.as_ref().and_then(|pods| pods.get(container_name)).cloned();
+        let pod = match cached {
+            Some(pod) => pod,
+            // Not enumerated (e.g., a container name given on the command line)
+            None => {
+                let pods = try!(self.running_pods());
+                try!(pods.into_iter()
+                    .find(|&(ref name, _)| name == container_name)
+                    .map(|(_, pod)| pod)
+                    .ok_or_else(|| KubeletError::UnknownPod(container_name.to_owned())))
+            }
+        };
+        let config = self.config.for_container(container_name);
+        let pod_host = match config.publish_host {
+            Some(ref address) => (**address).clone(),
+            None => string_at(&pod, &["status", "podIP"]).to_owned(),
+        };
+
+        let mut envvar_present = false;
+        let mut specs = Vec::new();
+        // Annotations are parsed last, so that they can override specs from the environment
+        // variable.
+        if config.domain_source != DomainSource::Labels {
+            try!(parse_container_env_vars(&Some(pod_env(&pod)),
+                                          &config,
+                                          &mut envvar_present,
+                                          &mut specs));
+        }
+        if config.domain_source != DomainSource::Env {
+            try!(parse_container_labels(&Some(pod_annotations(&pod)),
+                                        &config,
+                                        &mut envvar_present,
+                                        &mut specs));
+        }
+        if config.publish_host.is_some() {
+            try!(map_published_ports(container_name, &host_ports(&pod), &mut specs));
+        }
+        Ok(Inspection { envvar_present: envvar_present, specs: specs, host: pod_host })
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        if config.kubelet_url != self.config.kubelet_url {
+            info!("Kubelet URL changed to {}.", config.kubelet_url);
+            self.client_opt = None;
+        }
+        // The pods might come from somewhere else now.
+        self.pods_opt = None;
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use inspector::Inspect;
+    use mock_http_server::{MockHttpServer, RecordedRequest};
+
+    /// Trimmed-down response of a k3s kubelet's `/pods`: `default/web` with its domain spec in an
+    /// environment variable, `shop/api` with annotations, a pod that is still pending and a
+    /// completed job.
+    const PODS: &'static str = r#"{
+      "kind": "PodList", "apiVersion": "v1", "metadata": {},
+      "items": [
+        {"metadata": {"name": "web", "namespace": "default", "uid": "5d2e7a0c",
+                      "annotations": {"kubernetes.io/config.seen": "2016-06-01T10:00:00Z"}},
+         "spec": {"containers": [
+           {"name": "nginx", "image": "nginx:1.11",
+            "env": [{"name": "BEACHHEAD_DOMAINS", "value": "example.org:http"},
+                    {"name": "SECRET",
+                     "valueFrom": {"secretKeyRef": {"name": "web", "key": "secret"}}}],
+            "ports": [{"containerPort": 80, "hostPort": 8080, "protocol": "TCP"}]}]},
+         "status": {"phase": "Running", "hostIP": "192.168.1.20", "podIP": "10.42.0.12"}},
+        {"metadata": {"name": "api", "namespace": "shop",
+                      "annotations": {"beachhead.api_example_org.http": "3000"}},
+         "spec": {"containers": [{"name": "api", "image": "shop/api:2"}]},
+         "status": {"phase": "Running", "podIP": "10.42.0.13"}},
+        {"metadata": {"name": "starting", "namespace": "default"},
+         "spec": {"containers": [{"name": "app", "image": "app:1"}]},
+         "status": {"phase": "Pending"}},
+        {"metadata": {"name": "migrate-x7k2p", "namespace": "shop"},
+         "spec": {"containers": [{"name": "migrate", "image": "shop/api:2"}]},
+         "status": {"phase": "Succeeded", "podIP": "10.42.0.9"}}
+      ]
+    }"#;
+
+    fn kubelet_server() -> MockHttpServer {
+        MockHttpServer::with_handler(Box::new(|request: &RecordedRequest| {
+            if request.path == "/pods" {
+                (200, PODS.to_owned())
+            } else {
+                (404, "404 page not found".to_owned())
+            }
+        }))
+    }
+
+    fn kubelet_config(server: &MockHttpServer) -> Config {
+        let mut config = Config::default();
+        config.kubelet_url = server.url();
+        config
+    }
+
+    #[test]
+    fn enumerate_running() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = kubelet_server();
+        let mut inspector = KubeletInspector::new(Arc::new(kubelet_config(&server)));
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate pods");
+
+        // #### THEN  ####
+        assert_eq!(names, vec!["default/web".to_owned(), "shop/api".to_owned()]);
+    }
+
+    #[test]
+    fn inspect_env_and_annotations() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = kubelet_server();
+        let mut inspector = KubeletInspector::new(Arc::new(kubelet_config(&server)));
+
+        // #### WHEN  ####
+        let web = inspector.inspect("default/web").expect("Inspect web");
+        let api = inspector.inspect("shop/api").expect("Inspect api");
+        let pending = inspector.inspect("default/starting");
+
+        // #### THEN  ####
+        assert_eq!(web.host, "10.42.0.12", "The pod IP should be published.");
+        assert!(web.envvar_present);
+        assert_eq!(web.specs.len(), 1);
+        assert_eq!(web.specs[0].domain_name, "example.org");
+        assert_eq!(web.specs[0].http_port, Some(80));
+        assert_eq!(api.host, "10.42.0.13");
+        assert_eq!(api.specs.len(), 1);
+        assert_eq!(api.specs[0].domain_name, "api.example.org");
+        assert_eq!(api.specs[0].http_port, Some(3000));
+        assert!(pending.is_err(), "Pods that aren't running should fail to inspect.");
+    }
+
+    #[test]
+    fn inspect_enumerated_pods() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = kubelet_server();
+        let mut inspector = KubeletInspector::new(Arc::new(kubelet_config(&server)));
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate pods");
+        for name in &names {
+            inspector.inspect(name).expect("Inspect pod");
+        }
+        let pending = inspector.inspect("default/starting");
+
+        // #### THEN  ####
+        assert_eq!(server.requests().len(),
+                   2,
+                   "Only pods that weren't enumerated should need the pod list again.");
+        assert!(pending.is_err());
+    }
+
+    #[test]
+    fn inspect_publish_host() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = kubelet_server();
+        let mut config = kubelet_config(&server);
+        config.publish_host = Some(Rc::new("192.168.1.20".to_owned()));
+        let mut inspector = KubeletInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let inspection = inspector.inspect("default/web").expect("Inspect web");
+
+        // #### THEN  ####
+        assert_eq!(inspection.host, "192.168.1.20");
+        assert_eq!(inspection.specs[0].http_port, Some(8080), "hostPort should be used.");
+    }
+
+    #[test]
+    fn pods_file() {
+        common::init_log();
+        // #### GIVEN ####
+        let path = env::temp_dir().join("beachhead-kubelet-inspector-pods.json");
+        File::create(&path)
+            .and_then(|mut file| file.write_all(PODS.as_bytes()))
+            .expect("Write pod list");
+        let mut config = Config::default();
+        config.kubelet_pods_file = Some(Rc::new(path.to_string_lossy().into_owned()));
+        let mut inspector = KubeletInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate pods");
+        let api = inspector.inspect("shop/api").expect("Inspect api");
+
+        // #### THEN  ####
+        assert_eq!(names, vec!["default/web".to_owned(), "shop/api".to_owned()]);
+        assert_eq!(api.host, "10.42.0.13");
+    }
+}
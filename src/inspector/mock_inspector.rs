@@ -24,13 +24,26 @@ use std::fmt::{self, Display, Debug};
 use std::error::Error;
 use std::convert::From;
 use std::collections::HashMap;
+use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 
+use chan;
+
+use common::Config;
 use super::*;
 
 pub struct MockInspector {
     pub enumerate_result: Result<Vec<String>, Box<Fn() -> InspectionError>>,
     pub inspect_results: HashMap<Rc<String>, Result<Inspection, Box<Fn() -> InspectionError>>>,
+    /// Container names by id (see `Inspect::container_name`). Unknown ids fail to resolve.
+    pub container_ids: HashMap<String, String>,
+    /// Events to report as soon as the companion starts watching.
+    pub watch_events: Vec<InspectorEvent>,
+    /// Whether the companion is currently watching.
+    pub watching: bool,
+    /// How often the inspector has been reconfigured.
+    pub reconfigurations: usize,
 }
 
 impl Debug for MockInspector {
@@ -61,13 +74,27 @@ impl Debug for MockInspector {
                 }
             }
         }
-        write!(f, "] }}")
+        try!(write!(f, "], container_ids: "));
+        try!(Debug::fmt(&self.container_ids, f));
+        try!(write!(f, ", watch_events: "));
+        try!(Debug::fmt(&self.watch_events, f));
+        write!(f,
+               ", watching: {}, reconfigurations: {} }}",
+               self.watching,
+               self.reconfigurations)
     }
 }
 
 impl MockInspector {
     pub fn new() -> MockInspector {
-        MockInspector { enumerate_result: Ok(Vec::new()), inspect_results: HashMap::new() }
+        MockInspector {
+            enumerate_result: Ok(Vec::new()),
+            inspect_results: HashMap::new(),
+            container_ids: HashMap::new(),
+            watch_events: Vec::new(),
+            watching: false,
+            reconfigurations: 0,
+        }
     }
 }
 
@@ -94,6 +121,51 @@ impl Inspect for MockInspector {
             Some(&Err(ref f)) => Err(f()),
         }
     }
+    fn container_name(&mut self, container_id: &str) -> Result<String, InspectionError> {
+        match self.container_ids.get(container_id) {
+            Some(name) => Ok(name.clone()),
+            None => {
+                Err(From::from(InspectionNotMocked { container_name: container_id.to_string() }))
+            }
+        }
+    }
+    fn watch(&mut self, events: chan::Sender<InspectorEvent>) -> Result<(), InspectionError> {
+        for event in self.watch_events.drain(..) {
+            events.send(event);
+        }
+        self.watching = true;
+        Ok(())
+    }
+    fn unwatch(&mut self) {
+        self.watching = false;
+    }
+    fn reconfigure(&mut self, _config: Arc<Config>) -> Result<(), InspectionError> {
+        self.reconfigurations += 1;
+        Ok(())
+    }
+}
+
+/// Runtime checked reference to allow a mock inspector to be inspected even after it has been
+/// handed over. Panics if it cannot perform a mutable borrow.
+impl Inspect for Arc<RefCell<MockInspector>> {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        (*self).borrow_mut().enumerate(container_names)
+    }
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        (*self).borrow_mut().inspect(container_name)
+    }
+    fn container_name(&mut self, container_id: &str) -> Result<String, InspectionError> {
+        (*self).borrow_mut().container_name(container_id)
+    }
+    fn watch(&mut self, events: chan::Sender<InspectorEvent>) -> Result<(), InspectionError> {
+        (*self).borrow_mut().watch(events)
+    }
+    fn unwatch(&mut self) {
+        (*self).borrow_mut().unwatch()
+    }
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        (*self).borrow_mut().reconfigure(config)
+    }
 }
 
 /// Error that gets thrown when there is no mock data for a particular container name.
@@ -22,21 +22,75 @@
 
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use chan;
+
+use common::Config;
 use domain_spec::DomainSpec;
 
 pub trait Inspect {
+    /// Add the names of all running containers to `container_names`. Inspectors that combine
+    /// several sources add the containers of the sources that could be enumerated even if an error
+    /// is returned.
     fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError>;
     fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError>;
+
+    /// After `enumerate` has failed: whether the container's absence from the (partial)
+    /// enumeration still means that it is gone, because its source could be enumerated.
+    /// Inspectors that enumerate all or nothing keep the default.
+    fn enumerated_despite_failure(&self, _container_name: &str) -> bool {
+        false
+    }
+
+    /// Resolve the container id reported by a change notification to the name the container is
+    /// enumerated (and therefore published) under. Inspectors whose events already carry that
+    /// name keep the default.
+    fn container_name(&mut self, container_id: &str) -> Result<String, InspectionError> {
+        Ok(container_id.to_owned())
+    }
+
+    /// Start watching the inspected system for changes. Changes are reported via the `events`
+    /// channel as they happen. Inspectors that don't support change notifications rely on the
+    /// periodic refresh alone.
+    fn watch(&mut self, _events: chan::Sender<InspectorEvent>) -> Result<(), InspectionError> {
+        Ok(())
+    }
+
+    /// Stop reporting changes (see `watch`).
+    fn unwatch(&mut self) {}
+
+    /// Switch to a new configuration (e.g., after it has been reloaded). Connections are only
+    /// re-established if their settings changed. The new configuration is in effect even if an
+    /// error is returned.
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError>;
 }
 
 #[derive(Debug, Clone)]
 pub struct Inspection {
     pub host: String,
     pub specs: Vec<DomainSpec>,
+    /// Whether the container carries domain specs at all (environment variable or labels).
     pub envvar_present: bool,
 }
 
+/// Change notification from an inspector. Identifies the affected container by name or id.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InspectorEvent {
+    /// The container was started or its configuration/status changed. Inspect it again.
+    Changed(String),
+    /// The container stopped, died or was renamed (it is no longer known by the name it was
+    /// published under).
+    Vanished(String),
+}
+
+pub mod containerd;
 pub mod docker;
+mod docker_compat;
+pub mod kubelet;
+pub mod multi;
+pub mod podman;
+pub mod static_file;
 #[cfg(test)]
 pub mod mock_inspector;
 
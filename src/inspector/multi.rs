@@ -0,0 +1,320 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Combines several inspectors (e.g., one per Docker daemon) into one. Container names are
+//! namespaced by source (`source/name`) so that containers with the same name on different hosts
+//! don't collide. A source that cannot be reached doesn't keep the others from being enumerated;
+//! the publications of its containers are left alone until it is back (or they expire).
+
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+
+use chan;
+
+use common::Config;
+use super::*;
+
+struct Source {
+    name: String,
+    inspector: Box<Inspect>,
+    /// Whether the last enumeration of this source succeeded.
+    enumerated: bool,
+}
+
+pub struct MultiInspector {
+    sources: Vec<Source>,
+}
+
+impl MultiInspector {
+    pub fn new(inspectors: Vec<(String, Box<Inspect>)>) -> MultiInspector {
+        let sources = inspectors.into_iter()
+            .map(|(name, inspector)| {
+                Source { name: name, inspector: inspector, enumerated: false }
+            })
+            .collect();
+        MultiInspector { sources: sources }
+    }
+
+    /// Splits a namespaced container name into the index of its source and the name the source
+    /// knows the container by.
+    fn locate<'a>(&self,
+                  container_name: &'a str)
+                  -> Result<(usize, &'a str), MultiInspectorError> {
+        let trimmed = container_name.trim_left_matches('/');
+        if let Some(separator) = trimmed.find('/') {
+            let (source_name, name) = (&trimmed[..separator], &trimmed[separator + 1..]);
+            if let Some(index) = self.sources.iter().position(|s| s.name == source_name) {
+                return Ok((index, name));
+            }
+        }
+        Err(MultiInspectorError::UnknownSource(container_name.to_owned()))
+    }
+}
+
+/// Prefix a container name (or id) with the name of its source.
+fn namespaced(source_name: &str, container_name: &str) -> String {
+    format!("{}/{}", source_name, container_name.trim_left_matches('/'))
+}
+
+// ############### MULTI INSPECTOR ERROR ##################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum MultiInspectorError {
+        UnknownSource(container_name: String) {
+            description("Container name does not refer to a known source.")
+            display(me) -> ("{} Expected 'source/name', got '{}'.", me.description(),
+                container_name)
+        }
+        Failed(total: usize, failures: Vec<(String, InspectionError)>) {
+            description("Inspection failed.")
+            display(me) -> ("{} {} of {} sources failed. {}", me.description(),
+                failures.len(), total, failures.iter()
+                    .map(|&(ref name, ref err)| format!("{}: {}", name, err))
+                    .collect::<Vec<_>>()
+                    .join("; "))
+        }
+    }
+}
+
+impl InspectionInnerError for MultiInspectorError {}
+
+impl Inspect for MultiInspector {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        let mut failures = Vec::new();
+        for source in &mut self.sources {
+            let mut names = Vec::new();
+            match source.inspector.enumerate(&mut names) {
+                Ok(()) => source.enumerated = true,
+                Err(e) => {
+                    warn!("Failed to enumerate containers of source {}. Error: {}",
+                          source.name,
+                          e);
+                    source.enumerated = false;
+                    failures.push((source.name.clone(), e));
+                }
+            }
+            container_names.extend(names.iter().map(|name| namespaced(&source.name, name)));
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(From::from(MultiInspectorError::Failed(self.sources.len(), failures)))
+        }
+    }
+
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        let (index, name) = try!(self.locate(container_name));
+        self.sources[index].inspector.inspect(name)
+    }
+
+    fn container_name(&mut self, container_id: &str) -> Result<String, InspectionError> {
+        let (index, id) = try!(self.locate(container_id));
+        let name = try!(self.sources[index].inspector.container_name(id));
+        Ok(namespaced(&self.sources[index].name, &name))
+    }
+
+    fn enumerated_despite_failure(&self, container_name: &str) -> bool {
+        match self.locate(container_name) {
+            Ok((index, _)) => self.sources[index].enumerated,
+            // Containers of sources that no longer exist are gone for good.
+            Err(_) => true,
+        }
+    }
+
+    fn watch(&mut self, events: chan::Sender<InspectorEvent>) -> Result<(), InspectionError> {
+        let mut failures = Vec::new();
+        for source in &mut self.sources {
+            // Each source reports to its own channel. A thread forwards the events with the
+            // container ids namespaced. It ends once the source has dropped its sender.
+            let (source_events, forwarded) = chan::async();
+            let source_name = source.name.clone();
+            let thread_events = events.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("events-{}", source.name))
+                .spawn(move || {
+                    for event in forwarded.iter() {
+                        thread_events.send(match event {
+                            InspectorEvent::Changed(id) => {
+                                InspectorEvent::Changed(namespaced(&source_name, &id))
+                            }
+                            InspectorEvent::Vanished(id) => {
+                                InspectorEvent::Vanished(namespaced(&source_name, &id))
+                            }
+                        });
+                    }
+                });
+            let result = match spawned {
+                Ok(_) => source.inspector.watch(source_events),
+                Err(e) => Err(From::from(e)),
+            };
+            if let Err(e) = result {
+                failures.push((source.name.clone(), e));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(From::from(MultiInspectorError::Failed(self.sources.len(), failures)))
+        }
+    }
+
+    fn unwatch(&mut self) {
+        for source in &mut self.sources {
+            source.inspector.unwatch();
+        }
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        let mut failures = Vec::new();
+        for source in &mut self.sources {
+            if let Err(e) = source.inspector.reconfigure(config.clone()) {
+                failures.push((source.name.clone(), e));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(From::from(MultiInspectorError::Failed(self.sources.len(), failures)))
+        }
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use chan;
+
+    use super::*;
+    use common;
+    use inspector::{Inspect, Inspection, InspectorEvent};
+    use inspector::mock_inspector::{MockInspector, FakeError};
+
+    fn mock(names: &[&str]) -> Arc<RefCell<MockInspector>> {
+        let mock = Arc::new(RefCell::new(MockInspector::default()));
+        mock.borrow_mut().enumerate_result = Ok(names.iter().map(|n| (*n).to_owned()).collect());
+        mock
+    }
+
+    fn unreachable() -> Arc<RefCell<MockInspector>> {
+        let mock = Arc::new(RefCell::new(MockInspector::default()));
+        mock.borrow_mut().enumerate_result = Err(Box::new(|| From::from(FakeError)));
+        mock
+    }
+
+    fn inspection(host: &str) -> Inspection {
+        Inspection { host: host.to_owned(), specs: Vec::new(), envvar_present: true }
+    }
+
+    #[test]
+    fn enumerate_namespaced() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut inspector = MultiInspector::new(vec![
+            ("east".to_owned(), Box::new(mock(&["/web", "/db"]))),
+            ("west".to_owned(), Box::new(mock(&["/web"]))),
+        ]);
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        let result = inspector.enumerate(&mut names);
+
+        // #### THEN  ####
+        assert!(result.is_ok(), "Enumeration should succeed. Got {:?}", result);
+        assert_eq!(names, vec!["east/web", "east/db", "west/web"]);
+    }
+
+    #[test]
+    fn enumerate_partial_failure() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut inspector = MultiInspector::new(vec![
+            ("east".to_owned(), Box::new(unreachable())),
+            ("west".to_owned(), Box::new(mock(&["/web"]))),
+        ]);
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        let result = inspector.enumerate(&mut names);
+
+        // #### THEN  ####
+        match result {
+            Ok(()) => panic!("Enumeration should fail for the unreachable source."),
+            Err(e) => {
+                let message = format!("{}", e);
+                assert!(message.contains("1 of 2 sources failed. east: "),
+                        "Unexpected error message: {}",
+                        message);
+            }
+        }
+        assert_eq!(names, vec!["west/web"]);
+        assert!(!inspector.enumerated_despite_failure("east/web"),
+                "Containers of the unreachable source must be kept.");
+        assert!(inspector.enumerated_despite_failure("west/db"),
+                "Containers missing from a reachable source are gone.");
+    }
+
+    #[test]
+    fn inspect_routed_to_source() {
+        common::init_log();
+        // #### GIVEN ####
+        let east = mock(&[]);
+        let west = mock(&[]);
+        east.borrow_mut()
+            .inspect_results
+            .insert(Rc::new("web".to_owned()), Ok(inspection("10.0.0.2")));
+        west.borrow_mut()
+            .inspect_results
+            .insert(Rc::new("web".to_owned()), Ok(inspection("10.0.1.2")));
+        let mut inspector = MultiInspector::new(vec![("east".to_owned(), Box::new(east)),
+                                                     ("west".to_owned(), Box::new(west))]);
+
+        // #### WHEN  ####
+        let inspected = inspector.inspect("/west/web");
+        let unknown = inspector.inspect("north/web");
+
+        // #### THEN  ####
+        assert_eq!(inspected.expect("Inspection should succeed").host, "10.0.1.2");
+        assert!(unknown.is_err(), "Unknown source should be reported.");
+    }
+
+    #[test]
+    fn watch_namespaced() {
+        common::init_log();
+        // #### GIVEN ####
+        let west = mock(&[]);
+        west.borrow_mut().watch_events = vec![InspectorEvent::Changed("abc".to_owned())];
+        let mut inspector = MultiInspector::new(vec![("west".to_owned(), Box::new(west))]);
+        let (send, recv) = chan::async();
+
+        // #### WHEN  ####
+        inspector.watch(send).expect("Mock inspector should support watching");
+
+        // #### THEN  ####
+        assert_eq!(recv.recv(), Some(InspectorEvent::Changed("west/abc".to_owned())));
+    }
+}
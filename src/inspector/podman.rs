@@ -0,0 +1,284 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Inspects Podman containers through the libpod REST API of `podman system service`, usually on
+//! the user's Unix socket for rootless Podman. Containers in a pod share the network of the pod's
+//! infra container, so their address and published ports are taken from there. Domain specs are
+//! read from the environment variable and labels just like for Docker containers.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json::Json;
+
+use common::Config;
+use http::{HttpClient, HttpError};
+use super::*;
+use super::docker_compat::{inspection, string_at};
+
+/// How long to wait for Podman to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// The libpod API accepts any version in the path. Only fields that have been around since
+/// Podman 2 are used.
+const API_PREFIX: &'static str = "/v4.0.0/libpod";
+
+pub struct PodmanInspector {
+    config: Arc<Config>,
+    client_opt: Option<HttpClient>,
+}
+
+impl PodmanInspector {
+    pub fn new(config: Arc<Config>) -> PodmanInspector {
+        PodmanInspector { config: config, client_opt: None }
+    }
+
+    fn endpoint(&self) -> String {
+        self.config.podman_url.serialize()
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, PodmanError> {
+        if self.client_opt.is_none() {
+            let mut client = try_!(HttpClient::new(&self.config.podman_url), self.endpoint());
+            client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    fn get(&mut self, path: &str) -> Result<Json, PodmanError> {
+        let endpoint = self.endpoint();
+        let client = try!(self.client());
+        let response = try_!(client.request("GET", &format!("{}{}", API_PREFIX, path), &[], &[]),
+                             endpoint.clone());
+        if !response.is_success() {
+            let body = response.body_text();
+            return Err(PodmanError::Http(endpoint,
+                                         HttpError::Status(response.status,
+                                                           response.reason,
+                                                           body)));
+        }
+        Ok(try_!(Json::from_str(&response.body_text()).map_err(HttpError::from), endpoint))
+    }
+
+    /// The network settings that apply to a container: its own or those of its pod's infra
+    /// container.
+    fn network_settings(&mut self, container: &Json) -> Result<Json, PodmanError> {
+        let pod = string_at(container, &["Pod"]);
+        let is_infra = container.find("IsInfra").and_then(|i| i.as_boolean()).unwrap_or(false);
+        if pod.is_empty() || is_infra {
+            return Ok(container.find("NetworkSettings").cloned().unwrap_or(Json::Null));
+        }
+        let pod_info = try!(self.get(&format!("/pods/{}/json", pod)));
+        let infra_id = string_at(&pod_info, &["InfraContainerID"]);
+        if infra_id.is_empty() {
+            return Err(PodmanError::NoInfraContainer(self.endpoint(), pod.to_owned()));
+        }
+        let infra = try!(self.get(&format!("/containers/{}/json", infra_id)));
+        Ok(infra.find("NetworkSettings").cloned().unwrap_or(Json::Null))
+    }
+}
+
+// ############### PODMAN ERROR ###########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum PodmanError {
+        Http(endpoint: String, err: HttpError) {
+            description("Failed to communicate with the Podman API.")
+            cause(err)
+            display(me) -> ("{} Endpoint: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+        InvalidResponse(endpoint: String, reason: &'static str) {
+            description("Unexpected response from the Podman API.")
+            display(me) -> ("{} Endpoint: {}, {}", me.description(), endpoint, reason)
+        }
+        NoInfraContainer(endpoint: String, pod: String) {
+            description("Pod has no infra container to take the network address from.")
+            display(me) -> ("{} Endpoint: {}, pod: {}", me.description(), endpoint, pod)
+        }
+    }
+}
+
+impl InspectionInnerError for PodmanError {}
+
+impl Inspect for PodmanInspector {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        // Only running containers are listed by default.
+        let containers = try!(self.get("/containers/json"));
+        let containers = try!(containers.as_array().ok_or_else(|| {
+            PodmanError::InvalidResponse(self.endpoint(), "Container list is not an array.")
+        }));
+        for container in containers {
+            // Infra containers only hold the network namespace of their pod.
+            if container.find("IsInfra").and_then(|i| i.as_boolean()).unwrap_or(false) {
+                continue;
+            }
+            let name = container.find("Names")
+                .and_then(|names| names.as_array())
+                .and_then(|names| names.first())
+                .and_then(|name| name.as_string());
+            if let Some(name) = name {
+                container_names.push(name.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        let container = try!(self.get(&format!("/containers/{}/json", container_name)));
+        let network_settings = try!(self.network_settings(&container));
+        inspection(&self.config, container_name, &container, &network_settings)
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        if config.podman_url != self.config.podman_url {
+            info!("Podman URL changed to {}.", config.podman_url);
+            self.client_opt = None;
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use inspector::Inspect;
+    use mock_http_server::{MockHttpServer, RecordedRequest};
+
+    fn podman_config(server: &MockHttpServer) -> Arc<Config> {
+        let mut config = Config::default();
+        config.podman_url = server.url();
+        Arc::new(config)
+    }
+
+    /// Stand-in for the libpod API with a standalone container `web` and a container `api` in
+    /// pod `p1`, whose network belongs to the infra container `infra1`.
+    fn podman_server() -> MockHttpServer {
+        MockHttpServer::with_handler(Box::new(|request: &RecordedRequest| {
+            let body = match &request.path[..] {
+                "/v4.0.0/libpod/containers/json" => {
+                    r#"[{"Names": ["web"], "IsInfra": false, "Pod": ""},
+                        {"Names": ["api"], "IsInfra": false, "Pod": "p1"},
+                        {"Names": ["p1-infra"], "IsInfra": true, "Pod": "p1"}]"#
+                }
+                "/v4.0.0/libpod/containers/web/json" => {
+                    r#"{"Name": "web", "Pod": "",
+                        "Config": {"Env": ["PATH=/bin", "BEACHHEAD_DOMAINS=example.org:http"],
+                                   "Labels": null},
+                        "NetworkSettings": {"IPAddress": "",
+                                            "Networks": {"podman": {"IPAddress": "10.88.0.5"}},
+                                            "Ports": {"80/tcp": [{"HostIp": "",
+                                                                  "HostPort": "8080"}]}}}"#
+                }
+                "/v4.0.0/libpod/containers/api/json" => {
+                    r#"{"Name": "api", "Pod": "p1", "IsInfra": false,
+                        "Config": {"Env": [],
+                                   "Labels": {"beachhead.api_example_org.http": "3000"}},
+                        "NetworkSettings": {"IPAddress": "", "Networks": {}}}"#
+                }
+                "/v4.0.0/libpod/pods/p1/json" => r#"{"Id": "p1", "InfraContainerID": "infra1"}"#,
+                "/v4.0.0/libpod/containers/infra1/json" => {
+                    r#"{"Name": "p1-infra", "Pod": "p1", "IsInfra": true,
+                        "NetworkSettings": {"IPAddress": "10.88.0.9", "Networks": {}}}"#
+                }
+                _ => return (404, r#"{"message": "no such container"}"#.to_owned()),
+            };
+            (200, body.to_owned())
+        }))
+    }
+
+    #[test]
+    fn enumerate_skips_infra() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = podman_server();
+        let mut inspector = PodmanInspector::new(podman_config(&server));
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate containers");
+
+        // #### THEN  ####
+        assert_eq!(names, vec!["web".to_owned(), "api".to_owned()]);
+    }
+
+    #[test]
+    fn inspect_env() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = podman_server();
+        let mut inspector = PodmanInspector::new(podman_config(&server));
+
+        // #### WHEN  ####
+        let inspection = inspector.inspect("web").expect("Inspect web");
+
+        // #### THEN  ####
+        assert_eq!(inspection.host, "10.88.0.5");
+        assert!(inspection.envvar_present);
+        assert_eq!(inspection.specs.len(), 1);
+        assert_eq!(inspection.specs[0].domain_name, "example.org");
+    }
+
+    #[test]
+    fn inspect_pod_member() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = podman_server();
+        let mut inspector = PodmanInspector::new(podman_config(&server));
+
+        // #### WHEN  ####
+        let inspection = inspector.inspect("api").expect("Inspect api");
+
+        // #### THEN  ####
+        assert_eq!(inspection.host, "10.88.0.9", "Pod members use the infra address.");
+        assert_eq!(inspection.specs.len(), 1);
+        assert_eq!(inspection.specs[0].domain_name, "api.example.org");
+        assert_eq!(inspection.specs[0].http_port, Some(3000));
+    }
+
+    #[test]
+    fn inspect_publish_host() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = podman_server();
+        let mut config = (*podman_config(&server)).clone();
+        config.publish_host = Some(Rc::new("192.0.2.10".to_owned()));
+        let mut inspector = PodmanInspector::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let inspection = inspector.inspect("web").expect("Inspect web");
+        let missing = inspector.inspect("gone");
+
+        // #### THEN  ####
+        assert_eq!(inspection.host, "192.0.2.10");
+        assert_eq!(inspection.specs[0].http_port, Some(8080));
+        assert!(missing.is_err(), "Unknown containers should fail to inspect.");
+    }
+}
@@ -0,0 +1,225 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! Services that don't run in containers (e.g., directly on VMs), listed with their address and
+//! domain specs in a TOML file:
+//!
+//! ```toml
+//! [service.billing]
+//! host = "10.0.5.12"
+//! domains = "billing.example.org:http=8080 admin.billing.example.org:https=8443"
+//! ```
+//!
+//! The file is read again for every enumeration and inspection, so changes are picked up on the
+//! next refresh.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use common::Config;
+use config_file::{self, ConfigFileError};
+use domain_spec::DomainSpec;
+use super::*;
+
+pub struct StaticInspector {
+    config: Arc<Config>,
+}
+
+/// Contents of the static file.
+#[derive(RustcDecodable, Debug, Clone)]
+struct StaticFile {
+    /// Services, indexed by name.
+    service: Option<BTreeMap<String, ServiceSection>>,
+}
+
+/// A `[service.<name>]` section.
+#[derive(RustcDecodable, Debug, Clone)]
+struct ServiceSection {
+    /// Address to publish.
+    host: String,
+    /// Domain specs in the same format as the environment variable of a container.
+    domains: Option<String>,
+}
+
+impl StaticInspector {
+    pub fn new(config: Arc<Config>) -> StaticInspector {
+        StaticInspector { config: config }
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, ServiceSection>, InspectionError> {
+        let path = match self.config.static_file {
+            Some(ref path) => path,
+            None => return Err(From::from(StaticFileError::NotConfigured)),
+        };
+        let static_file: StaticFile = try!(config_file::load_toml(path));
+        Ok(static_file.service.unwrap_or_else(BTreeMap::new))
+    }
+}
+
+impl Inspect for StaticInspector {
+    fn enumerate(&mut self, container_names: &mut Vec<String>) -> Result<(), InspectionError> {
+        let services = try!(self.read());
+        container_names.extend(services.into_iter().map(|(name, _)| name));
+        Ok(())
+    }
+
+    fn inspect(&mut self, container_name: &str) -> Result<Inspection, InspectionError> {
+        let services = try!(self.read());
+        let service = match services.get(container_name) {
+            Some(service) => service,
+            None => {
+                return Err(From::from(StaticFileError::UnknownService(container_name.to_owned())))
+            }
+        };
+        let mut specs = Vec::new();
+        if let Some(ref domains) = service.domains {
+            try!(DomainSpec::parse_all(domains, &mut specs));
+        }
+        Ok(Inspection {
+            host: service.host.clone(),
+            specs: specs,
+            envvar_present: service.domains.is_some(),
+        })
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), InspectionError> {
+        // The file is read on demand anyway.
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### STATIC FILE ERROR ######################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum StaticFileError {
+        NotConfigured {
+            description("No static file configured (see --static-file).")
+        }
+        UnknownService(name: String) {
+            description("Service not listed in static file.")
+            display(me) -> ("{} Service: {}", me.description(), name)
+        }
+    }
+}
+
+impl InspectionInnerError for StaticFileError {}
+
+impl InspectionInnerError for ConfigFileError {}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use inspector::Inspect;
+
+    fn inspector(test_name: &str, contents: &str) -> StaticInspector {
+        let path = env::temp_dir().join(format!("beachhead-static-inspector-{}.toml", test_name));
+        write(&path.to_string_lossy(), contents);
+        let mut cfg = Config::default();
+        cfg.static_file = Some(Rc::new(path.to_string_lossy().into_owned()));
+        StaticInspector::new(Arc::new(cfg))
+    }
+
+    fn write(path: &str, contents: &str) {
+        let _ = fs::remove_file(path);
+        let mut file = File::create(path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    const SERVICES: &'static str = "[service.billing]\n\
+                                    host = \"10.0.5.12\"\n\
+                                    domains = \"billing.example.org:http=8080\"\n\
+                                    [service.archive]\n\
+                                    host = \"10.0.5.13\"\n";
+
+    #[test]
+    fn enumerate_and_inspect() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut inspector = inspector("enumerate_and_inspect", SERVICES);
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        inspector.enumerate(&mut names).expect("Enumerate services");
+        let billing = inspector.inspect("billing").expect("Inspect billing");
+        let archive = inspector.inspect("archive").expect("Inspect archive");
+
+        // #### THEN  ####
+        assert_eq!(names, vec!["archive".to_owned(), "billing".to_owned()]);
+        assert_eq!(billing.host, "10.0.5.12");
+        assert_eq!(billing.specs.len(), 1);
+        assert_eq!(billing.specs[0].domain_name, "billing.example.org");
+        assert_eq!(billing.specs[0].http_port, Some(8080));
+        assert!(billing.envvar_present);
+        assert!(!archive.envvar_present, "archive has no domain specs.");
+    }
+
+    #[test]
+    fn reread_on_refresh() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut inspector = inspector("reread_on_refresh", SERVICES);
+        let path = (**inspector.config.static_file.as_ref().unwrap()).clone();
+        inspector.inspect("billing").expect("Inspect billing");
+
+        // #### WHEN  ####
+        write(&path, "[service.billing]\nhost = \"10.0.6.12\"\n");
+        let billing = inspector.inspect("billing").expect("Inspect billing again");
+        let archive = inspector.inspect("archive");
+
+        // #### THEN  ####
+        assert_eq!(billing.host, "10.0.6.12");
+        assert!(archive.is_err(), "archive has been removed from the file.");
+    }
+
+    #[test]
+    fn unknown_key() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut inspector = inspector("unknown_key",
+                                      "[service.billing]\nhost = \"10.0.5.12\"\nport = 80\n");
+
+        // #### WHEN  ####
+        let mut names = Vec::new();
+        let result = inspector.enumerate(&mut names);
+
+        // #### THEN  ####
+        match result {
+            Ok(()) => panic!("Typos in the static file must be reported."),
+            Err(e) => {
+                assert!(format!("{}", e).contains("service.billing.port"),
+                        "Unexpected error: {}",
+                        e)
+            }
+        }
+    }
+}
@@ -22,13 +22,16 @@
 
 extern crate regex;
 extern crate redis;
-extern crate shiplift;
 extern crate env_logger;
 extern crate chrono;
 extern crate rustc_serialize;
 extern crate url;
 extern crate chan_signal;
 extern crate systemd;
+extern crate toml;
+extern crate openssl;
+extern crate openssl_verify;
+extern crate hpack;
 
 #[macro_use]
 extern crate log;
@@ -45,5 +48,13 @@ pub mod domain_spec;
 pub mod inspector;
 pub mod publisher;
 pub mod companion;
+pub mod config_file;
+pub mod http;
+pub mod grpc;
+pub mod template;
+#[cfg(test)]
+pub mod mock_http_server;
+#[cfg(test)]
+pub mod mock_grpc_server;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -20,18 +20,25 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use std::collections::HashSet;
 use std::env;
+use std::io::Write;
 use std::sync::Arc;
 use std::rc::Rc;
 
 extern crate docopt;
 extern crate libbeachheadcompanion;
 
-use libbeachheadcompanion::common::{stay_calm_and, stay_very_calm_and, Config,
-                                    MissingContainerHandling, MissingEnvVarHandling};
-use libbeachheadcompanion::inspector;
-use libbeachheadcompanion::publisher;
+use libbeachheadcompanion::common::{stay_calm_and, stay_very_calm_and, Config, DockerSources,
+                                    DomainSource, InspectorKind, InspectorKinds,
+                                    MissingContainerHandling, MissingEnvVarHandling,
+                                    PublisherKind, PublisherKinds};
+use libbeachheadcompanion::inspector::{self, Inspect};
+use libbeachheadcompanion::inspector::docker::DockerInspector;
+use libbeachheadcompanion::publisher::{self, Publish};
+use libbeachheadcompanion::publisher::redis::RedisUrl;
 use libbeachheadcompanion::companion;
+use libbeachheadcompanion::config_file::{ConfigFile, ConfigFileError};
 
 extern crate rustc_serialize;
 extern crate url;
@@ -56,6 +63,7 @@ use systemd::daemon;
 const USAGE: &'static str = "
 Usage: beachhead-companion [options] [--ignore-missing-envvar] [--error-missing-container] [--] <containers>...
        beachhead-companion [options] [--error-missing-envvar] --enumerate
+       beachhead-companion [options] [--ignore-missing-envvar] [--error-missing-envvar] [--error-missing-container] --config=FILE [<containers>...]
        beachhead-companion --help
        beachhead-companion --version
 
@@ -66,24 +74,148 @@ Options:
     --quiet             Only show warnings and errors.
     --no-timestamp      Don't include timestamp in log messages. Used in case timestamps get added
                         externally.
+    --config=FILE       Read settings from the TOML file FILE (e.g.,
+                        /etc/beachhead-companion.toml). Options given on the command line take
+                        precedence over the file. Send SIGHUP to re-read the file.
+    --publisher=BACKENDS
+                        Where to publish to: redis, etcd, consul, file, nginx, haproxy, traefik,
+                        caddy or webhook. Separate several backends with commas (e.g.,
+                        'redis,file'). Changing publishers requires a restart. [default: redis]
     --redis-host=HOST   Hostname or IP of the Redis server [default: localhost]
     --redis-port=PORT   Port of the Redis server [default: 6379]
+    --redis-url=URL     Location of the Redis server, replaces --redis-host and --redis-port.
+                        Either redis://[:PASSWORD@]HOST[:PORT][/DB] or
+                        redis+unix://[:PASSWORD@]/PATH/TO/SOCKET[?db=DB].
+    --redis-password-file=FILE
+                        Read the Redis password from FILE. Keeps the password out of the
+                        process list.
+    --etcd-url=URL      Location of the etcd server (v3 API via its JSON gateway). Publications
+                        are attached to a lease that expires unless refreshed.
+                        [default: http://127.0.0.1:2379]
+    --consul-url=URL    Location of the local consul agent. Each domain is registered as a
+                        service with a TTL check. [default: http://127.0.0.1:8500]
+    --consul-service=NAME
+                        Name of the consul services. Tags carry the domain and the ports
+                        (domain=..., http=..., https=...). [default: beachhead]
+    --file-directory=DIR
+                        Directory to write one JSON file per container to. Files are replaced
+                        atomically and removed once expired. [default: /run/beachhead-companion]
+    --nginx-template=FILE
+                        Mustache template to render the nginx configuration from (required by
+                        the nginx publisher). See README for the available data.
+    --nginx-output=FILE
+                        Where to write the rendered nginx configuration.
+                        [default: /etc/nginx/conf.d/beachhead.conf]
+    --nginx-check=COMMAND
+                        Shell command that validates the configuration (e.g., 'nginx -t'). If it
+                        fails, the previous configuration is restored.
+    --nginx-reload=COMMAND
+                        Shell command that is run when the configuration has changed (e.g.,
+                        'nginx -s reload').
+    --haproxy-directory=DIR
+                        Directory for the HAProxy map files (http.map, https.map) and backends
+                        (backends.cfg). [default: /etc/haproxy/beachhead]
+    --haproxy-socket=PATH
+                        HAProxy runtime API socket. Changes that fit the loaded backends are
+                        applied through it instead of reloading HAProxy.
+    --haproxy-reload=COMMAND
+                        Shell command that makes HAProxy load new backends (e.g.,
+                        'systemctl reload haproxy').
+    --traefik-file=FILE
+                        Where to write the dynamic configuration for Traefik's file provider.
+                        [default: /etc/traefik/dynamic/beachhead.toml]
+    --traefik-http-entrypoint=NAME
+                        Traefik entrypoint for http ports. [default: web]
+    --traefik-https-entrypoint=NAME
+                        Traefik entrypoint for https ports. [default: websecure]
+    --caddy-url=URL     Location of Caddy's admin API. [default: http://localhost:2019]
+    --caddy-server=NAME
+                        Caddy server (in apps/http/servers) whose routes are managed. It has to
+                        exist in Caddy's configuration. [default: beachhead]
+    --webhook-url=URL   Endpoint to POST publication and withdrawal events to (JSON). Required
+                        for --publisher=webhook.
+    --webhook-secret-file=FILE
+                        Sign webhook requests with the secret in FILE (HMAC-SHA256 of the body in
+                        the X-Beachhead-Signature header).
+    --webhook-timeout=SECONDS
+                        How long to wait for the webhook endpoint to respond. [default: 5]
+    --webhook-retries=N
+                        How often to retry a failed webhook request before backing off.
+                        [default: 2]
     --expire=SECONDS    Number of seconds after which to expire registration.
                         0 means no expiration. [default: 60]
     --refresh=SECONDS   Number of seconds after which to refresh registrations.
                         Defaults to 45% of the expiration time. At least 10 seconds.
                         0 means set once and then exit.
-    --key-prefix=KEY    Key prefix to use in redis. Will be followed by container name.
+    --key-prefix=KEY    Key prefix to use in redis/etcd. Will be followed by container name.
                         [default: /beachhead/]
-    --docker-url=URL    URL to the docker socket. [default: unix://var/run/docker.sock]
-    --docker-network    Whether to use the container hostname (set) or use the bridge
-                        network IP (unset/default).
+    --inspector=SOURCES
+                        Where to read containers and their domain specs from: docker, podman,
+                        containerd, kubelet or static. Separate several with commas (e.g.,
+                        'docker,static'); container names then become SOURCE/CONTAINER. Changing
+                        inspectors requires a restart. [default: docker]
+    --static-file=FILE  TOML file that lists services with static addresses (e.g., on VMs) for the
+                        static inspector. Read again on every refresh.
+    --podman-url=URL    Location of the Podman API service for --inspector=podman. Defaults to
+                        $CONTAINER_HOST, the socket of rootless Podman in $XDG_RUNTIME_DIR or
+                        unix:///run/podman/podman.sock.
+    --containerd-address=PATH
+                        containerd's socket for --inspector=containerd.
+                        [default: /run/containerd/containerd.sock]
+    --containerd-namespace=NAME
+                        The containerd namespace to inspect (e.g., k8s.io for the containers of
+                        Kubernetes). [default: default]
+    --cni-results=DIR   Where CNI caches the network setup of containers, which holds the
+                        addresses of containerd's containers. [default: /var/lib/cni/results]
+    --kubelet-url=URL   The kubelet's read-only API for --inspector=kubelet.
+                        [default: http://127.0.0.1:10255]
+    --kubelet-pods-file=FILE
+                        Read pods from a JSON dump of the kubelet's /pods instead of asking the
+                        kubelet. Read again on every refresh.
+    --docker-url=URL    URL to the docker socket. Defaults to $DOCKER_HOST if set.
+                        [default: unix://var/run/docker.sock]
+    --docker-sources=SOURCES
+                        Inspect several Docker daemons instead of the one at --docker-url. A
+                        comma-separated list of NAME=URL pairs (e.g.,
+                        'east=tcp://10.0.0.1:2375,west=tcp://10.0.1.1:2375'). Container names
+                        become NAME/CONTAINER. Changing sources requires a restart.
+    --docker-cert-path=DIR
+                        Authenticate to Docker daemons that require TLS (tcp:// or https://)
+                        with the client certificate DIR/cert.pem and its key DIR/key.pem.
+                        Defaults to $DOCKER_CERT_PATH if set.
+    --docker-tls-verify
+                        Verify the certificate of the Docker daemon against DIR/ca.pem, with DIR
+                        from --docker-cert-path. Enabled if $DOCKER_TLS_VERIFY is set.
+    --docker-network=NAME
+                        Publish the IP address the container has on the docker network NAME.
+                        By default, the IP on the default bridge network is used (or the IP
+                        on the only network the container is attached to).
+    --docker-network-alias
+                        Publish the container's first network alias (docker run --network-alias,
+                        the service name with compose) instead of its IP address, or its name if
+                        it has no alias. Docker's embedded DNS resolves both for other containers
+                        on the same user-defined network (see --docker-network).
+    --publish-host=ADDRESS
+                        Publish ADDRESS and the host ports that the container ports from the
+                        domain specs are mapped to (docker run -p) instead of the container's
+                        address. For reverse proxies that run on a different machine.
     --envvar=VAR        Name of the environment variable to look for in the container.
                         [default: BEACHHEAD_DOMAINS]
+    --label=LABEL       Name of the container label to look for. Has the same format as the
+                        environment variable. [default: beachhead.domains]
+    --label-prefix=PREFIX
+                        Prefix of structured per-domain labels of the form
+                        PREFIX<domain>.<http|https|domain>=<value>. [default: beachhead.]
+    --domain-source=SOURCE
+                        Where to look for domain specs: env, labels or both. With both, labels
+                        take precedence over the environment variable for the same domain.
+                        [default: both]
     --enumerate         Ask docker daemon for list of all running containers instead of
                         passing individual container names/ids. Enumeration will be repeated
                         on each refresh (containers can come and go)
     --systemd           Enable systemd service manager notifications (READY, WATCHDOG).
+    --no-events         Don't subscribe to the docker event stream. Changes to containers will
+                        only be picked up on the next refresh.
     --error-missing-envvar
                         Consider `envvar` missing on a container an error. Automatically enabled
                         for containers that are listed explicitly unless --ignore-missing-envvar
@@ -114,10 +246,22 @@ Example:
   admin.example.org with https=443
   app.example.org with http=8080 and https=8043
 
+Instead of the environment variable, the same domain-specs can be put into a container label
+(beachhead.domains by default). Alternatively, each domain can be described by structured labels:
+  beachhead.app_example_org.http=8080 beachhead.app_example_org.https=8043
+Underscores in the domain part are replaced by dots. Use PREFIX<key>.domain=<domain name> to
+specify the domain name explicitly.
+
 One way to use beachhead-companion is to supply an explicit list of container names/ids to check
 for domain specifications. Alternatively, you can have beachhead-companion check all containers
 via the `--enumerate` flag.
 
+The configuration file uses the names of the long options as keys (e.g.,
+redis-host = \"redis\", enumerate = true). It can list containers
+(containers = [\"web\", \"admin\"]) and override envvar, label, domain-source, docker-network,
+error-missing-envvar and ignore-missing-envvar for individual containers in [container.NAME]
+sections.
+
 Supports more fine-grained logging control via the RUST_LOG environment variable.
 See http://rust-lang-nursery.github.io/log/env_logger for details.
 ";
@@ -125,6 +269,22 @@ See http://rust-lang-nursery.github.io/log/env_logger for details.
 lazy_static! {
     static ref DOCOPT: Docopt = Docopt::new(USAGE).expect("docopt failed to parse USAGE")
         .help(true).version(Some(String::from(libbeachheadcompanion::VERSION)));
+    /// Options that are not given stay empty with this one, even if they have a default value.
+    static ref DOCOPT_WITHOUT_DEFAULTS: Docopt = Docopt::new(without_defaults(USAGE))
+        .expect("docopt failed to parse USAGE").help(false);
+}
+
+/// `usage` without the `[default: ...]` annotations.
+fn without_defaults(usage: &str) -> String {
+    let mut stripped = String::with_capacity(usage.len());
+    let mut rest = usage;
+    while let Some(start) = rest.find("[default:") {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        rest = &rest[rest.find(']').map(|end| end + 1).unwrap_or(rest.len())..];
+    }
+    stripped.push_str(rest);
+    stripped
 }
 
 /// Holds arguments parsed by [docopt]. Will be transferred into [common/Config].
@@ -132,15 +292,54 @@ lazy_static! {
 struct Args {
     flag_verbose: bool,
     flag_quiet: bool,
+    flag_publisher: PublisherKinds,
     flag_redis_host: String,
     flag_redis_port: u16,
+    flag_redis_url: Option<RedisUrl>,
+    flag_redis_password_file: Option<String>,
+    flag_etcd_url: Url,
+    flag_consul_url: Url,
+    flag_consul_service: String,
+    flag_file_directory: String,
+    flag_nginx_template: Option<String>,
+    flag_nginx_output: String,
+    flag_nginx_check: Option<String>,
+    flag_nginx_reload: Option<String>,
+    flag_haproxy_directory: String,
+    flag_haproxy_socket: Option<String>,
+    flag_haproxy_reload: Option<String>,
+    flag_traefik_file: String,
+    flag_traefik_http_entrypoint: String,
+    flag_traefik_https_entrypoint: String,
+    flag_caddy_url: Url,
+    flag_caddy_server: String,
+    flag_webhook_url: Option<Url>,
+    flag_webhook_secret_file: Option<String>,
+    flag_webhook_timeout: u32,
+    flag_webhook_retries: u32,
     flag_expire: u32,
     flag_refresh: Option<u32>,
+    flag_inspector: InspectorKinds,
+    flag_static_file: Option<String>,
+    flag_podman_url: Option<Url>,
+    flag_containerd_address: String,
+    flag_containerd_namespace: String,
+    flag_cni_results: String,
+    flag_kubelet_url: Url,
+    flag_kubelet_pods_file: Option<String>,
     flag_docker_url: Url,
+    flag_docker_sources: DockerSources,
+    flag_docker_cert_path: Option<String>,
+    flag_docker_tls_verify: bool,
     flag_envvar: String,
+    flag_label: String,
+    flag_label_prefix: String,
+    flag_domain_source: DomainSource,
     flag_key_prefix: String,
     arg_containers: Vec<String>,
-    flag_docker_network: bool,
+    flag_docker_network: Option<String>,
+    flag_docker_network_alias: bool,
+    flag_publish_host: Option<String>,
     flag_dry_run: bool,
     flag_error_missing_envvar: bool,
     flag_error_missing_container: bool,
@@ -148,6 +347,8 @@ struct Args {
     flag_enumerate: bool,
     flag_systemd: bool,
     flag_no_timestamp: bool,
+    flag_no_events: bool,
+    flag_config: Option<String>,
 }
 
 // Implement Default by parsing an (almost) empty command line.
@@ -165,12 +366,50 @@ impl Default for Args {
 impl Args {
     fn deconstruct(self) -> (Config, Vec<String>) {
         let config = Config {
-            redis_host: Rc::new(self.flag_redis_host),
-            redis_port: self.flag_redis_port,
+            publishers: self.flag_publisher,
+            redis_url: match self.flag_redis_url {
+                Some(url) => url,
+                None => RedisUrl::tcp(self.flag_redis_host, self.flag_redis_port),
+            },
+            redis_password_file: self.flag_redis_password_file.map(Rc::new),
+            etcd_url: self.flag_etcd_url,
+            consul_url: self.flag_consul_url,
+            consul_service: Rc::new(self.flag_consul_service),
+            file_directory: Rc::new(self.flag_file_directory),
+            nginx_template: self.flag_nginx_template.map(Rc::new),
+            nginx_output: Rc::new(self.flag_nginx_output),
+            nginx_check_command: self.flag_nginx_check.map(Rc::new),
+            nginx_reload_command: self.flag_nginx_reload.map(Rc::new),
+            haproxy_directory: Rc::new(self.flag_haproxy_directory),
+            haproxy_socket: self.flag_haproxy_socket.map(Rc::new),
+            haproxy_reload_command: self.flag_haproxy_reload.map(Rc::new),
+            traefik_file: Rc::new(self.flag_traefik_file),
+            traefik_http_entrypoint: Rc::new(self.flag_traefik_http_entrypoint),
+            traefik_https_entrypoint: Rc::new(self.flag_traefik_https_entrypoint),
+            caddy_url: self.flag_caddy_url,
+            caddy_server: Rc::new(self.flag_caddy_server),
+            webhook_url: self.flag_webhook_url,
+            webhook_secret_file: self.flag_webhook_secret_file.map(Rc::new),
+            webhook_timeout_seconds: self.flag_webhook_timeout,
+            webhook_retries: self.flag_webhook_retries,
             key_prefix: Rc::new(self.flag_key_prefix),
+            inspectors: self.flag_inspector,
+            static_file: self.flag_static_file.map(Rc::new),
+            podman_url: self.flag_podman_url.unwrap_or_else(default_podman_url),
+            containerd_address: Rc::new(self.flag_containerd_address),
+            containerd_namespace: Rc::new(self.flag_containerd_namespace),
+            cni_results_dir: Rc::new(self.flag_cni_results),
+            kubelet_url: self.flag_kubelet_url,
+            kubelet_pods_file: self.flag_kubelet_pods_file.map(Rc::new),
             docker_url: self.flag_docker_url,
+            docker_sources: self.flag_docker_sources,
+            docker_cert_path: self.flag_docker_cert_path.map(Rc::new),
+            docker_tls_verify: self.flag_docker_tls_verify,
             enumerate: self.flag_enumerate,
             envvar: Rc::new(self.flag_envvar),
+            label: Rc::new(self.flag_label),
+            label_prefix: Rc::new(self.flag_label_prefix),
+            domain_source: self.flag_domain_source,
             dry_run: self.flag_dry_run,
             expire_seconds: if self.flag_expire == 0 {
                 None
@@ -186,7 +425,9 @@ impl Args {
                     }
                 })
                 .unwrap(),
-            docker_network: self.flag_docker_network,
+            docker_network: self.flag_docker_network.map(Rc::new),
+            docker_network_alias: self.flag_docker_network_alias,
+            publish_host: self.flag_publish_host.map(Rc::new),
             missing_envvar: match (self.flag_error_missing_envvar,
                                    self.flag_ignore_missing_envvar) {
                 (true, true) => MissingEnvVarHandling::Automatic,
@@ -201,11 +442,213 @@ impl Args {
             },
             systemd: self.flag_systemd,
             watchdog_microseconds: None,
+            watch_events: !self.flag_no_events,
+            container_overrides: Default::default(),
         };
         (config, self.arg_containers)
     }
 }
 
+/// Where Podman's API service listens unless `--podman-url` is given: `CONTAINER_HOST` (like the
+/// podman client), the socket of rootless Podman or the system-wide socket.
+fn default_podman_url() -> Url {
+    let url = if let Some(container_host) = non_empty_env_var("CONTAINER_HOST") {
+        container_host
+    } else if let Some(runtime_dir) = non_empty_env_var("XDG_RUNTIME_DIR") {
+        format!("unix://{}/podman/podman.sock", runtime_dir)
+    } else {
+        "unix:///run/podman/podman.sock".to_owned()
+    };
+    Url::parse(&url).unwrap_or_else(|_| Url::parse("unix:///run/podman/podman.sock").unwrap())
+}
+
+/// The options (e.g., `--redis-host`) given on the command line `argv`, the way docopt reads them.
+fn given_flags(argv: &[String]) -> HashSet<String> {
+    match DOCOPT_WITHOUT_DEFAULTS.clone().argv(argv).parse() {
+        Ok(parsed) => {
+            parsed.map
+                .iter()
+                .filter(|&(key, value)| key.starts_with("--") && value.as_bool())
+                .map(|(key, _)| key.clone())
+                .collect()
+        }
+        // Not a valid command line (which load_args has already ruled out)
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// Fall back on the environment variables of the docker command line client (`DOCKER_HOST`,
+/// `DOCKER_CERT_PATH` and `DOCKER_TLS_VERIFY`) for the Docker connection settings that are given
+/// neither on the command line nor in the configuration file.
+fn apply_docker_environment(args: &mut Args,
+                            config_file: Option<&ConfigFile>,
+                            given: &HashSet<String>,
+                            env_var: &Fn(&str) -> Option<String>) {
+    let url_in_file = config_file.map(|f| f.docker_url.is_some()).unwrap_or(false);
+    let cert_path_in_file = config_file.map(|f| f.docker_cert_path.is_some()).unwrap_or(false);
+    let tls_verify_in_file = config_file.map(|f| f.docker_tls_verify.is_some()).unwrap_or(false);
+
+    if !given.contains("--docker-url") && !url_in_file {
+        if let Some(docker_host) = env_var("DOCKER_HOST") {
+            match Url::parse(&docker_host) {
+                Ok(url) => args.flag_docker_url = url,
+                Err(e) => {
+                    let _ = writeln!(&mut std::io::stderr(),
+                                     "Ignoring invalid DOCKER_HOST '{}'. Error: {}",
+                                     docker_host,
+                                     e);
+                }
+            }
+        }
+    }
+    if !given.contains("--docker-cert-path") && !cert_path_in_file {
+        if let Some(cert_path) = env_var("DOCKER_CERT_PATH") {
+            args.flag_docker_cert_path = Some(cert_path);
+        }
+    }
+    // Like the docker client, treat any non-empty value as enabled.
+    if !given.contains("--docker-tls-verify") && !tls_verify_in_file &&
+       env_var("DOCKER_TLS_VERIFY").is_some() {
+        args.flag_docker_tls_verify = true;
+    }
+}
+
+/// Value of the environment variable `name`, unless it is unset or empty.
+fn non_empty_env_var(name: &str) -> Option<String> {
+    env::var(name).ok().and_then(|value| if value.is_empty() { None } else { Some(value) })
+}
+
+/// Overwrite `target` with the value from the configuration file (if any) unless the option has
+/// been given on the command line.
+fn take<T: Clone>(target: &mut T, value: &Option<T>, given: bool) {
+    if let (false, &Some(ref value)) = (given, value) {
+        *target = value.clone();
+    }
+}
+
+/// Take settings from the configuration file unless they have been given on the command line.
+fn apply_config_file(args: &mut Args, config_file: &ConfigFile, given: &HashSet<String>) {
+    let given = |flag: &str| given.contains(flag);
+
+    // Switches can only be turned on (not off) on the command line, so either source enables them.
+    args.flag_verbose |= config_file.verbose.unwrap_or(false);
+    args.flag_quiet |= config_file.quiet.unwrap_or(false);
+    args.flag_no_timestamp |= config_file.no_timestamp.unwrap_or(false);
+    args.flag_docker_network_alias |= config_file.docker_network_alias.unwrap_or(false);
+    args.flag_docker_tls_verify |= config_file.docker_tls_verify.unwrap_or(false);
+    args.flag_enumerate |= config_file.enumerate.unwrap_or(false);
+    args.flag_systemd |= config_file.systemd.unwrap_or(false);
+    args.flag_no_events |= config_file.no_events.unwrap_or(false);
+    args.flag_error_missing_envvar |= config_file.error_missing_envvar.unwrap_or(false);
+    args.flag_ignore_missing_envvar |= config_file.ignore_missing_envvar.unwrap_or(false);
+    args.flag_error_missing_container |= config_file.error_missing_container.unwrap_or(false);
+    args.flag_dry_run |= config_file.dry_run.unwrap_or(false);
+
+    take(&mut args.flag_publisher, &config_file.publisher, given("--publisher"));
+    take(&mut args.flag_redis_host, &config_file.redis_host, given("--redis-host"));
+    take(&mut args.flag_redis_port, &config_file.redis_port, given("--redis-port"));
+    // A URL from the file must not override a server given on the command line.
+    take(&mut args.flag_redis_url,
+         &config_file.redis_url.clone().map(Some),
+         given("--redis-url") || given("--redis-host") || given("--redis-port"));
+    take(&mut args.flag_redis_password_file,
+         &config_file.redis_password_file.clone().map(Some),
+         given("--redis-password-file"));
+    take(&mut args.flag_etcd_url, &config_file.etcd_url, given("--etcd-url"));
+    take(&mut args.flag_consul_url, &config_file.consul_url, given("--consul-url"));
+    take(&mut args.flag_consul_service,
+         &config_file.consul_service,
+         given("--consul-service"));
+    take(&mut args.flag_file_directory,
+         &config_file.file_directory,
+         given("--file-directory"));
+    take(&mut args.flag_nginx_template,
+         &config_file.nginx_template.clone().map(Some),
+         given("--nginx-template"));
+    take(&mut args.flag_nginx_output, &config_file.nginx_output, given("--nginx-output"));
+    take(&mut args.flag_nginx_check,
+         &config_file.nginx_check.clone().map(Some),
+         given("--nginx-check"));
+    take(&mut args.flag_nginx_reload,
+         &config_file.nginx_reload.clone().map(Some),
+         given("--nginx-reload"));
+    take(&mut args.flag_haproxy_directory,
+         &config_file.haproxy_directory,
+         given("--haproxy-directory"));
+    take(&mut args.flag_haproxy_socket,
+         &config_file.haproxy_socket.clone().map(Some),
+         given("--haproxy-socket"));
+    take(&mut args.flag_haproxy_reload,
+         &config_file.haproxy_reload.clone().map(Some),
+         given("--haproxy-reload"));
+    take(&mut args.flag_traefik_file, &config_file.traefik_file, given("--traefik-file"));
+    take(&mut args.flag_traefik_http_entrypoint,
+         &config_file.traefik_http_entrypoint,
+         given("--traefik-http-entrypoint"));
+    take(&mut args.flag_traefik_https_entrypoint,
+         &config_file.traefik_https_entrypoint,
+         given("--traefik-https-entrypoint"));
+    take(&mut args.flag_caddy_url, &config_file.caddy_url, given("--caddy-url"));
+    take(&mut args.flag_caddy_server, &config_file.caddy_server, given("--caddy-server"));
+    take(&mut args.flag_webhook_url,
+         &config_file.webhook_url.clone().map(Some),
+         given("--webhook-url"));
+    take(&mut args.flag_webhook_secret_file,
+         &config_file.webhook_secret_file.clone().map(Some),
+         given("--webhook-secret-file"));
+    take(&mut args.flag_webhook_timeout,
+         &config_file.webhook_timeout,
+         given("--webhook-timeout"));
+    take(&mut args.flag_webhook_retries,
+         &config_file.webhook_retries,
+         given("--webhook-retries"));
+    take(&mut args.flag_expire, &config_file.expire, given("--expire"));
+    take(&mut args.flag_refresh, &config_file.refresh.map(Some), given("--refresh"));
+    take(&mut args.flag_key_prefix, &config_file.key_prefix, given("--key-prefix"));
+    take(&mut args.flag_inspector, &config_file.inspector, given("--inspector"));
+    take(&mut args.flag_static_file,
+         &config_file.static_file.clone().map(Some),
+         given("--static-file"));
+    take(&mut args.flag_podman_url,
+         &config_file.podman_url.clone().map(Some),
+         given("--podman-url"));
+    take(&mut args.flag_containerd_address,
+         &config_file.containerd_address,
+         given("--containerd-address"));
+    take(&mut args.flag_containerd_namespace,
+         &config_file.containerd_namespace,
+         given("--containerd-namespace"));
+    take(&mut args.flag_cni_results, &config_file.cni_results, given("--cni-results"));
+    take(&mut args.flag_kubelet_url, &config_file.kubelet_url, given("--kubelet-url"));
+    take(&mut args.flag_kubelet_pods_file,
+         &config_file.kubelet_pods_file.clone().map(Some),
+         given("--kubelet-pods-file"));
+    take(&mut args.flag_docker_url, &config_file.docker_url, given("--docker-url"));
+    take(&mut args.flag_docker_sources,
+         &config_file.docker_sources,
+         given("--docker-sources"));
+    take(&mut args.flag_docker_cert_path,
+         &config_file.docker_cert_path.clone().map(Some),
+         given("--docker-cert-path"));
+    take(&mut args.flag_docker_network,
+         &config_file.docker_network.clone().map(Some),
+         given("--docker-network"));
+    take(&mut args.flag_publish_host,
+         &config_file.publish_host.clone().map(Some),
+         given("--publish-host"));
+    take(&mut args.flag_envvar, &config_file.envvar, given("--envvar"));
+    take(&mut args.flag_label, &config_file.label, given("--label"));
+    take(&mut args.flag_label_prefix, &config_file.label_prefix, given("--label-prefix"));
+    take(&mut args.flag_domain_source, &config_file.domain_source, given("--domain-source"));
+
+    // Containers listed on the command line replace the list from the file.
+    if args.arg_containers.is_empty() {
+        if let Some(ref containers) = config_file.containers {
+            args.arg_containers = containers.clone();
+        }
+    }
+}
+
 fn args_transform(args: &mut Args) {
     // Apply some args transformation rules
 
@@ -246,34 +689,156 @@ fn read_systemd_config(config: &mut Config) -> Result<(), std::io::Error> {
     }
 }
 
-fn main() {
+/// Parse the command line and read the configuration file (if any). Used on startup and again
+/// when the configuration is reloaded (SIGHUP).
+fn load_args() -> Result<(Args, Option<ConfigFile>), ConfigFileError> {
     // Parse arguments (handles --help and --version)
     let mut args: Args = DOCOPT.decode().unwrap_or_else(|e| e.exit());
 
+    let config_file = match args.flag_config {
+        Some(ref path) => Some(try!(ConfigFile::load(path))),
+        None => None,
+    };
+    let given = given_flags(&env::args().collect::<Vec<_>>());
+    if let Some(ref config_file) = config_file {
+        apply_config_file(&mut args, config_file, &given);
+    }
+    apply_docker_environment(&mut args,
+                             config_file.as_ref(),
+                             &given,
+                             &non_empty_env_var);
+
     args_transform(&mut args);
+    Ok((args, config_file))
+}
 
-    stay_calm_and(init_log(&args));
+/// Turn parsed arguments and configuration file into the companion configuration and the list of
+/// explicitly listed containers.
+fn build_config(args: Args, config_file: Option<ConfigFile>) -> (Config, Vec<Rc<String>>) {
     let (mut config, arg_containers) = args.deconstruct();
+    if let Some(ref config_file) = config_file {
+        config.container_overrides = config_file.container_overrides();
+    }
+    let mut containers = Vec::with_capacity(arg_containers.len());
+    containers.extend(arg_containers.into_iter().map(|x| Rc::new(x)));
+    (config, containers)
+}
+
+/// Re-read the configuration in response to SIGHUP. Logging options are not affected (the logger
+/// can only be set up once). Configurations that fail the startup checks are rejected.
+fn reload_config() -> Result<(Config, Vec<Rc<String>>), ConfigFileError> {
+    let (args, config_file) = try!(load_args());
+    let (mut config, containers) = build_config(args, config_file);
+    try!(config.check().map_err(ConfigFileError::Invalid));
+    if let Err(e) = read_systemd_config(&mut config) {
+        warn!("systemd support is enabled, but sd_watchdog_enabled call failed. {}", e);
+    }
+    Ok((config, containers))
+}
+
+fn main() {
+    // The configuration file can change logging options, so it is read before logging is set up.
+    let (args, config_file) = match load_args() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            let _ = writeln!(&mut std::io::stderr(), "Fatal error: {}", e);
+            ::std::process::exit(2);
+        }
+    };
+
+    stay_calm_and(init_log(&args));
+    let (mut config, containers) = build_config(args, config_file);
     if let Err(e) = read_systemd_config(&mut config) {
         error!("systemd support is enabled, but sd_watchdog_enabled call failed. {}", e);
         ::std::process::exit(2);
     }
+    if let Err(e) = config.check() {
+        error!("{}", e);
+        ::std::process::exit(2);
+    }
     let config = Arc::new(config);
-    let mut containers = Vec::with_capacity(arg_containers.len());
-    containers.extend(arg_containers.into_iter().map(|x| Rc::new(x)));
     // Signals
     //   Interrupt is to support Ctrl+C
     //   Term is to support graceful shutdown via kill
     //   Abort is to support graceful shutdown when missing a systemd watchdog timeout
-    let abort_signal = chan_signal::notify(&[Signal::INT, Signal::TERM, Signal::ABRT]);
-    let docker_inspector = Box::new(inspector::docker::DockerInspector::new(config.clone()));
-    let redis_publisher = Box::new(publisher::redis::RedisPublisher::new(config.clone()));
+    //   Hangup is to support reloading the configuration
+    let abort_signal =
+        chan_signal::notify(&[Signal::INT, Signal::TERM, Signal::ABRT, Signal::HUP]);
+    let inspector = create_inspector(config.clone());
+    let publisher = create_publisher(config.clone());
 
     stay_very_calm_and(companion::run(config,
-                                      docker_inspector,
-                                      redis_publisher,
+                                      inspector,
+                                      publisher,
                                       abort_signal,
-                                      &containers));
+                                      &containers,
+                                      &mut reload_config));
+}
+
+/// A single inspector or, if several inspectors (or Docker sources) are configured, one that
+/// combines them and namespaces container names by source.
+fn create_inspector(config: Arc<Config>) -> Box<Inspect> {
+    let docker_sources = config.inspectors.contains(InspectorKind::Docker) &&
+                         !config.docker_sources.is_empty();
+    let mut inspectors: Vec<(String, Box<Inspect>)> = Vec::new();
+    for kind in &config.inspectors.0 {
+        if *kind == InspectorKind::Docker && docker_sources {
+            for source in &config.docker_sources.0 {
+                let inspector: Box<Inspect> =
+                    Box::new(DockerInspector::for_source(config.clone(), &source.name));
+                inspectors.push((source.name.clone(), inspector));
+            }
+        } else {
+            inspectors.push((kind.name().to_owned(),
+                             create_single_inspector(*kind, config.clone())));
+        }
+    }
+    if inspectors.len() == 1 && !docker_sources {
+        inspectors.pop().unwrap().1
+    } else {
+        Box::new(inspector::multi::MultiInspector::new(inspectors))
+    }
+}
+
+fn create_single_inspector(kind: InspectorKind, config: Arc<Config>) -> Box<Inspect> {
+    match kind {
+        InspectorKind::Docker => Box::new(DockerInspector::new(config)),
+        InspectorKind::Static => Box::new(inspector::static_file::StaticInspector::new(config)),
+        InspectorKind::Podman => Box::new(inspector::podman::PodmanInspector::new(config)),
+        InspectorKind::Containerd => {
+            Box::new(inspector::containerd::ContainerdInspector::new(config))
+        }
+        InspectorKind::Kubelet => Box::new(inspector::kubelet::KubeletInspector::new(config)),
+    }
+}
+
+/// A single publisher or, if several are configured, a composite publisher that forwards to all
+/// of them.
+fn create_publisher(config: Arc<Config>) -> Box<Publish> {
+    let mut publishers: Vec<(String, Box<Publish>)> = config.publishers
+        .0
+        .iter()
+        .map(|kind| (kind.name().to_owned(), create_single_publisher(*kind, config.clone())))
+        .collect();
+    if publishers.len() == 1 {
+        publishers.pop().unwrap().1
+    } else {
+        Box::new(publisher::composite::CompositePublisher::new(publishers))
+    }
+}
+
+fn create_single_publisher(kind: PublisherKind, config: Arc<Config>) -> Box<Publish> {
+    match kind {
+        PublisherKind::Redis => Box::new(publisher::redis::RedisPublisher::new(config)),
+        PublisherKind::Etcd => Box::new(publisher::etcd::EtcdPublisher::new(config)),
+        PublisherKind::Consul => Box::new(publisher::consul::ConsulPublisher::new(config)),
+        PublisherKind::File => Box::new(publisher::file::FilePublisher::new(config)),
+        PublisherKind::Nginx => Box::new(publisher::nginx::NginxPublisher::new(config)),
+        PublisherKind::Haproxy => Box::new(publisher::haproxy::HaproxyPublisher::new(config)),
+        PublisherKind::Traefik => Box::new(publisher::traefik::TraefikPublisher::new(config)),
+        PublisherKind::Caddy => Box::new(publisher::caddy::CaddyPublisher::new(config)),
+        PublisherKind::Webhook => Box::new(publisher::webhook::WebhookPublisher::new(config)),
+    }
 }
 
 /// Handles the verbosity options by initializing the logger accordingly.
@@ -316,9 +881,13 @@ fn init_log(args: &Args) -> Result<(), log::SetLoggerError> {
 
 #[cfg(test)]
 mod test {
-    use super::{USAGE, args_transform, Args};
+    use super::{USAGE, args_transform, apply_config_file, apply_docker_environment, given_flags,
+                Args};
+    use std::collections::HashSet;
+
     use docopt;
     use libbeachheadcompanion::common;
+    use libbeachheadcompanion::config_file::ConfigFile;
 
     #[test]
     fn docopt_spec() {
@@ -428,4 +997,146 @@ mod test {
         assert_eq!(config.expire_seconds, Some(args_expire));
     }
 
+    fn given(args: &[&str]) -> HashSet<String> {
+        given_flags(&args.iter().map(|arg| (*arg).to_owned()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn given_flags_parsed() {
+        common::init_log();
+        // #### GIVEN ####
+        let argv = ["beachhead-companion", "--docker-url", "tcp://cli.example.org:2376",
+                    "--label", "--docker-cert-path", "--expire", "60", "web"];
+
+        // #### WHEN  ####
+        let given = given(&argv);
+
+        // #### THEN  ####
+        assert!(given.contains("--docker-url"));
+        assert!(given.contains("--expire"), "Given with its default value");
+        assert!(!given.contains("--docker-cert-path"), "The value of --label");
+        assert!(!given.contains("--redis-host"));
+    }
+
+    #[test]
+    fn config_file_applied() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut args: Args = Default::default();
+        let config_file = ConfigFile::parse("redis-host = \"redis\"\nexpire = 120\n\
+                                             enumerate = true\ncontainers = [\"web\"]",
+                                            "test.toml")
+            .expect("Parse config file");
+
+        // #### WHEN  ####
+        apply_config_file(&mut args,
+                          &config_file,
+                          &given(&["beachhead-companion", "--config=test.toml"]));
+
+        // #### THEN  ####
+        assert_eq!(args.flag_redis_host, "redis");
+        assert_eq!(args.flag_expire, 120);
+        assert!(args.flag_enumerate);
+        assert_eq!(args.arg_containers, vec!["web".to_owned()]);
+    }
+
+    #[test]
+    fn config_file_command_line_wins() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut args: Args = Default::default();
+        args.flag_redis_host = "cli-redis".to_owned();
+        args.flag_expire = 30;
+        args.arg_containers = vec!["admin".to_owned()];
+        let config_file = ConfigFile::parse("redis_host = \"redis\"\nexpire = 120\n\
+                                             containers = [\"web\"]",
+                                            "test.toml")
+            .expect("Parse config file");
+
+        // #### WHEN  ####
+        apply_config_file(&mut args,
+                          &config_file,
+                          &given(&["beachhead-companion",
+                                   "--redis-host",
+                                   "cli-redis",
+                                   "--expire=30",
+                                   "--config=test.toml",
+                                   "admin"]));
+
+        // #### THEN  ####
+        assert_eq!(args.flag_redis_host, "cli-redis");
+        assert_eq!(args.flag_expire, 30);
+        assert_eq!(args.arg_containers, vec!["admin".to_owned()]);
+    }
+
+    #[test]
+    fn docker_environment() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut args: Args = Default::default();
+        args.flag_docker_cert_path = Some("/etc/cli-certs".to_owned());
+        let config_file = ConfigFile::parse("docker_url = \"tcp://file.example.org:2376\"",
+                                            "test.toml")
+            .expect("Parse config file");
+        let given = given(&["beachhead-companion", "--docker-cert-path=/etc/cli-certs", "web"]);
+        apply_config_file(&mut args, &config_file, &given);
+        let env_var = |name: &str| match name {
+            "DOCKER_HOST" => Some("tcp://env.example.org:2376".to_owned()),
+            "DOCKER_CERT_PATH" => Some("/etc/env-certs".to_owned()),
+            "DOCKER_TLS_VERIFY" => Some("1".to_owned()),
+            _ => None,
+        };
+
+        // #### WHEN  ####
+        apply_docker_environment(&mut args, Some(&config_file), &given, &env_var);
+
+        // #### THEN  ####
+        assert_eq!(args.flag_docker_url.serialize(), "tcp://file.example.org:2376");
+        assert_eq!(args.flag_docker_cert_path, Some("/etc/cli-certs".to_owned()));
+        assert!(args.flag_docker_tls_verify);
+    }
+
+    #[test]
+    fn docker_environment_fallback() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut args: Args = Default::default();
+        let env_var = |name: &str| match name {
+            "DOCKER_HOST" => Some("tcp://env.example.org:2376".to_owned()),
+            "DOCKER_CERT_PATH" => Some("/etc/env-certs".to_owned()),
+            _ => None,
+        };
+
+        // #### WHEN  ####
+        apply_docker_environment(&mut args,
+                                 None,
+                                 &given(&["beachhead-companion", "web"]),
+                                 &env_var);
+
+        // #### THEN  ####
+        assert_eq!(args.flag_docker_url.serialize(), "tcp://env.example.org:2376");
+        assert_eq!(args.flag_docker_cert_path, Some("/etc/env-certs".to_owned()));
+        assert!(!args.flag_docker_tls_verify);
+    }
+
+    #[test]
+    fn docker_environment_file_disables_tls_verify() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut args: Args = Default::default();
+        let config_file = ConfigFile::parse("docker_tls_verify = false", "test.toml")
+            .expect("Parse config file");
+        let given = given(&["beachhead-companion", "--config=test.toml"]);
+        apply_config_file(&mut args, &config_file, &given);
+        let env_var = |name: &str| match name {
+            "DOCKER_TLS_VERIFY" => Some("1".to_owned()),
+            _ => None,
+        };
+
+        // #### WHEN  ####
+        apply_docker_environment(&mut args, Some(&config_file), &given, &env_var);
+
+        // #### THEN  ####
+        assert!(!args.flag_docker_tls_verify);
+    }
 }
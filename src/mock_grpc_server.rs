@@ -0,0 +1,196 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Stand-in gRPC server for testing clients of gRPC APIs (containerd). Listens on a Unix socket,
+//! records all calls and answers them via a handler function.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use hpack::{Decoder, Encoder};
+
+use grpc::{self, Frame, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_CONTINUATION, FRAME_DATA,
+           FRAME_HEADERS, FRAME_SETTINGS};
+
+#[derive(Debug, Clone)]
+pub struct RecordedCall {
+    /// `/package.Service/Method`
+    pub method: String,
+    /// All headers except for the pseudo-headers.
+    pub metadata: Vec<(String, String)>,
+    pub request: Vec<u8>,
+}
+
+impl RecordedCall {
+    pub fn metadata(&self, name: &str) -> Option<&str> {
+        grpc::header(&self.metadata, name)
+    }
+}
+
+/// The serialized response, or the status and message of a failed call.
+pub type Handler = Fn(&RecordedCall) -> Result<Vec<u8>, (u32, String)> + Send + Sync;
+
+/// Tells the sockets of the servers in this process apart.
+static NEXT_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct MockGrpcServer {
+    socket_path: PathBuf,
+    calls: Arc<Mutex<Vec<RecordedCall>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockGrpcServer {
+    pub fn with_handler(handler: Box<Handler>) -> MockGrpcServer {
+        let socket_path = env::temp_dir().join(format!("beachhead-mock-grpc-{}-{}.sock",
+                                                       process::id(),
+                                                       NEXT_SERVER.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_calls = calls.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let mut reader = BufReader::new(&stream);
+                    if let Some(call) = read_call(&mut reader) {
+                        thread_calls.lock().unwrap().push(call.clone());
+                        let mut writer = &stream;
+                        let _ = writer.write_all(&response_frames(handler(&call)));
+                        // Like a real server, leave closing the connection to the client.
+                        let _ = io::copy(&mut reader, &mut io::sink());
+                    }
+                }
+            }
+        });
+        MockGrpcServer {
+            socket_path: socket_path,
+            calls: calls,
+            stop: stop,
+        }
+    }
+
+    pub fn socket_path(&self) -> String {
+        self.socket_path.to_string_lossy().into_owned()
+    }
+
+    /// All calls received so far.
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+impl Drop for MockGrpcServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the server thread
+        let _ = UnixStream::connect(&self.socket_path);
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+fn read_call<R: Read>(reader: &mut R) -> Option<RecordedCall> {
+    let mut preface = [0; 24];
+    if reader.read_exact(&mut preface).is_err() {
+        return None;
+    }
+    let mut decoder = Decoder::new();
+    let mut block = Vec::new();
+    let mut headers = Vec::new();
+    let mut data = Vec::new();
+    loop {
+        let frame = match Frame::read_from(reader) {
+            Ok(frame) => frame,
+            Err(_) => return None,
+        };
+        let content = match frame.content() {
+            Ok(content) => content.to_vec(),
+            Err(_) => return None,
+        };
+        match frame.kind {
+            FRAME_HEADERS | FRAME_CONTINUATION => {
+                block.extend_from_slice(&content);
+                if frame.has_flag(FLAG_END_HEADERS) {
+                    match grpc::decode_headers(&mut decoder, &block) {
+                        Ok(decoded) => headers.extend(decoded),
+                        Err(_) => return None,
+                    }
+                    block.clear();
+                }
+            }
+            FRAME_DATA => {
+                data.extend_from_slice(&content);
+                if frame.has_flag(FLAG_END_STREAM) {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let request = match grpc::read_message(&data) {
+        Ok(request) => request.to_vec(),
+        Err(_) => return None,
+    };
+    Some(RecordedCall {
+        method: grpc::header(&headers, ":path").unwrap_or("").to_owned(),
+        metadata: headers.into_iter().filter(|h| !h.0.starts_with(':')).collect(),
+        request: request,
+    })
+}
+
+fn response_frames(response: Result<Vec<u8>, (u32, String)>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new();
+    Frame::new(FRAME_SETTINGS, 0, 0, Vec::new()).write_to(&mut out);
+    let headers = [(":status", "200"), ("content-type", "application/grpc")];
+    match response {
+        Ok(message) => {
+            grpc::write_headers(&mut out, &mut encoder, 1, &headers, false);
+            grpc::write_message(&mut out, 1, &message, false);
+            grpc::write_headers(&mut out, &mut encoder, 1, &[("grpc-status", "0")], true);
+        }
+        Err((status, message)) => {
+            // Trailers-only response
+            let status = status.to_string();
+            let mut trailers = headers.to_vec();
+            trailers.push(("grpc-status", &status));
+            trailers.push(("grpc-message", &message));
+            grpc::write_headers(&mut out, &mut encoder, 1, &trailers, true);
+        }
+    }
+    out
+}
@@ -0,0 +1,157 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Stand-in HTTP server for testing publishers that talk to HTTP APIs (consul, caddy, ...).
+//! Records all requests and answers them via a handler function.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0 == name).map(|h| &h.1[..])
+    }
+}
+
+/// Status code and body of a response.
+pub type Handler = Fn(&RecordedRequest) -> (u16, String) + Send + Sync;
+
+pub struct MockHttpServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockHttpServer {
+    /// A server that answers every request with 200 and an empty body.
+    pub fn new() -> MockHttpServer {
+        MockHttpServer::with_handler(Box::new(|_: &RecordedRequest| (200, String::new())))
+    }
+
+    pub fn with_handler(handler: Box<Handler>) -> MockHttpServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_requests = requests.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    if let Some(request) = read_request(&stream) {
+                        thread_requests.lock().unwrap().push(request.clone());
+                        let (status, body) = handler(&request);
+                        let mut writer = &stream;
+                        let _ = write!(writer,
+                                       "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n\r\n{}",
+                                       status,
+                                       body.len(),
+                                       body);
+                    }
+                }
+            }
+        });
+        MockHttpServer {
+            port: port,
+            requests: requests,
+            stop: stop,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://127.0.0.1:{}", self.port)).unwrap()
+    }
+
+    /// All requests received so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+impl Drop for MockHttpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the server thread
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<RecordedRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok() == Some(0) {
+        return None;
+    }
+    let mut parts = request_line.trim().splitn(3, ' ');
+    let method = parts.next().unwrap_or("").to_owned();
+    let path = parts.next().unwrap_or("").to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok().unwrap_or(0) == 0 {
+            return None;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.push((line[..idx].trim().to_lowercase(), line[idx + 1..].trim().to_owned()));
+        }
+    }
+    let length = headers.iter()
+        .find(|h| h.0 == "content-length")
+        .and_then(|h| h.1.parse::<u64>().ok())
+        .unwrap_or(0);
+    let mut body = String::new();
+    if reader.take(length).read_to_string(&mut body).is_err() {
+        return None;
+    }
+    Some(RecordedRequest {
+        method: method,
+        path: path,
+        headers: headers,
+        body: body,
+    })
+}
@@ -0,0 +1,133 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Building blocks for publishers that render the complete configuration of a reverse proxy (as
+//! opposed to storing one entry per publication): the set of current publications and running
+//! the commands that make the proxy pick up changes.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Values;
+use std::error::Error;
+use std::io;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use super::Publication;
+
+/// The current publications (by key). During batches, rendering is deferred until the batch ends.
+#[derive(Debug, Default)]
+pub struct Aggregate {
+    /// Publications and when they were last published.
+    publications: BTreeMap<String, (Publication, Instant)>,
+    in_batch: bool,
+}
+
+impl Aggregate {
+    pub fn new() -> Aggregate {
+        Default::default()
+    }
+
+    /// Returns whether the configuration should be rendered right away (outside of batches).
+    pub fn publish(&mut self, publication: &Publication) -> bool {
+        self.publications.insert(publication.key.clone(), (publication.clone(), Instant::now()));
+        !self.in_batch
+    }
+
+    /// Returns whether the configuration should be rendered right away (outside of batches).
+    pub fn withdraw(&mut self, publication: &Publication) -> bool {
+        self.publications.remove(&publication.key);
+        !self.in_batch
+    }
+
+    pub fn begin_batch(&mut self) {
+        self.in_batch = true;
+    }
+
+    pub fn end_batch(&mut self) {
+        self.in_batch = false;
+    }
+
+    /// Forget publications that haven't been refreshed in time.
+    pub fn expire(&mut self, expire_seconds: Option<u32>) {
+        if let Some(expire_seconds) = expire_seconds {
+            let max_age = Duration::from_secs(expire_seconds as u64);
+            let expired: Vec<String> = self.publications
+                .iter()
+                .filter(|&(_, &(_, last_seen))| last_seen.elapsed() > max_age)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                info!("Publication {} has expired.", key);
+                self.publications.remove(&key);
+            }
+        }
+    }
+
+    /// Current publications, ordered by key.
+    pub fn publications(&self) -> Publications {
+        Publications { inner: self.publications.values() }
+    }
+}
+
+pub struct Publications<'a> {
+    inner: Values<'a, String, (Publication, Instant)>,
+}
+
+impl<'a> Iterator for Publications<'a> {
+    type Item = &'a Publication;
+
+    fn next(&mut self) -> Option<&'a Publication> {
+        self.inner.next().map(|entry| &entry.0)
+    }
+}
+
+/// Run a shell command (e.g., to reload the reverse proxy). Fails unless it exits successfully.
+pub fn run(command: &str) -> Result<(), CommandError> {
+    debug!("Running {}", command);
+    let output = try_!(Command::new("sh").arg("-c").arg(command).output(),
+                       command.to_owned());
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(CommandError::Failed(command.to_owned(), output))
+    }
+}
+
+// ############### COMMAND ERROR ##########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CommandError {
+        Spawn(command: String, err: io::Error) {
+            description("Failed to run command.")
+            cause(err)
+            display(me) -> ("{} Command: {}, error: {}", me.description(), command, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+        Failed(command: String, output: Output) {
+            description("Command failed.")
+            display(me) -> ("{} Command: {}, {}, output: {}{}", me.description(), command,
+                output.status, String::from_utf8_lossy(&output.stdout).trim(),
+                String::from_utf8_lossy(&output.stderr).trim())
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Converges the routes of a Caddy server through Caddy's admin API: one `reverse_proxy` route
+//! per domain (and path prefix), identified by an `@id` starting with `beachhead_`. Routes are only
+//! added, replaced or deleted when they differ from what Caddy has, so refreshes without changes
+//! don't touch the configuration. Other routes of the server are left alone. Domain spec options
+//! are not applied.
+//!
+//! The server (with its listen addresses) has to exist in Caddy's configuration already.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json::{self, Json, ToJson};
+
+use common::Config;
+use http::{HttpClient, HttpError};
+use super::*;
+use super::aggregate::Aggregate;
+
+/// How long to wait for Caddy to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// Prefix of the `@id` of all routes managed by beachhead-companion.
+pub const ROUTE_ID_PREFIX: &'static str = "beachhead_";
+
+pub struct CaddyPublisher {
+    config: Arc<Config>,
+    client_opt: Option<HttpClient>,
+    aggregate: Aggregate,
+}
+
+impl CaddyPublisher {
+    pub fn new(config: Arc<Config>) -> CaddyPublisher {
+        CaddyPublisher {
+            config: config,
+            client_opt: None,
+            aggregate: Aggregate::new(),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        self.config.caddy_url.serialize()
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, CaddyError> {
+        if self.client_opt.is_none() {
+            let mut client = try_!(HttpClient::new(&self.config.caddy_url), self.endpoint());
+            client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    fn request(&mut self, method: &str, path: &str, body: &Json) -> Result<Json, CaddyError> {
+        let endpoint = self.endpoint();
+        let client = try!(self.client());
+        debug!("Caddy admin API: {} {}", method, path);
+        Ok(try_!(client.json(method, path, body), endpoint))
+    }
+
+    /// The routes we want Caddy to have, indexed by `@id`.
+    pub fn desired_routes(&self) -> BTreeMap<String, Json> {
+        // (domain name, path prefix) -> (http upstreams, https upstreams)
+        let mut domains: BTreeMap<(String, Option<String>), (Vec<Json>, Vec<Json>)> =
+            BTreeMap::new();
+        for publication in self.aggregate.publications() {
+            for spec in &publication.specs {
+                let key = (spec.domain_name.to_lowercase(), spec.path_prefix.clone());
+                let entry = domains.entry(key).or_insert_with(|| (Vec::new(), Vec::new()));
+                if let Some(http_port) = spec.http_port {
+                    entry.0.push(upstream(&publication.host, http_port));
+                }
+                if let Some(https_port) = spec.https_port {
+                    entry.1.push(upstream(&publication.host, https_port));
+                }
+            }
+        }
+
+        let mut routes = BTreeMap::new();
+        for (&(ref domain_name, ref path_opt), &(ref http_upstreams, ref https_upstreams)) in
+            &domains {
+            // Caddy terminates TLS itself, so plain http upstreams are preferred.
+            let mut handler = json::Object::new();
+            handler.insert("handler".to_owned(), "reverse_proxy".to_json());
+            if http_upstreams.is_empty() {
+                let mut transport = json::Object::new();
+                transport.insert("protocol".to_owned(), "http".to_json());
+                transport.insert("tls".to_owned(), Json::Object(json::Object::new()));
+                handler.insert("transport".to_owned(), Json::Object(transport));
+                handler.insert("upstreams".to_owned(), Json::Array(https_upstreams.clone()));
+            } else {
+                handler.insert("upstreams".to_owned(), Json::Array(http_upstreams.clone()));
+            }
+
+            let path_prefix = path_opt.as_ref().map(|path| &path[..]).unwrap_or("");
+            let id = route_id(&format!("{}{}", domain_name, path_prefix));
+            let mut request_match = json::Object::new();
+            request_match.insert("host".to_owned(), vec![domain_name.clone()].to_json());
+            if !path_prefix.is_empty() {
+                let paths = vec![format!("{}*", path_prefix)];
+                request_match.insert("path".to_owned(), paths.to_json());
+            }
+            // Requests for longer path prefixes of the same domain belong to the routes of those,
+            // whatever the order of the routes.
+            let nested: Vec<String> = domains.keys()
+                .filter_map(|&(ref other_domain, ref other_path)| match *other_path {
+                    Some(ref other_path) if other_domain == domain_name &&
+                                            other_path.len() > path_prefix.len() &&
+                                            other_path.starts_with(path_prefix) => {
+                        Some(format!("{}*", other_path))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !nested.is_empty() {
+                let mut path_match = json::Object::new();
+                path_match.insert("path".to_owned(), nested.to_json());
+                request_match.insert("not".to_owned(), Json::Array(vec![Json::Object(path_match)]));
+            }
+            let mut route = json::Object::new();
+            route.insert("@id".to_owned(), id.to_json());
+            route.insert("match".to_owned(), Json::Array(vec![Json::Object(request_match)]));
+            route.insert("handle".to_owned(), Json::Array(vec![Json::Object(handler)]));
+            route.insert("terminal".to_owned(), Json::Boolean(true));
+            routes.insert(id, Json::Object(route));
+        }
+        routes
+    }
+
+    /// Bring Caddy's routes in line with the current publications.
+    fn converge(&mut self) -> Result<(), CaddyError> {
+        self.aggregate.expire(self.config.expire_seconds);
+        let desired = self.desired_routes();
+        let routes_path = format!("/config/apps/http/servers/{}/routes", self.config.caddy_server);
+
+        let current = match try!(self.request("GET", &routes_path, &Json::Null)) {
+            Json::Null => {
+                // The server doesn't have any routes yet.
+                if !desired.is_empty() {
+                    let routes: json::Array = desired.into_iter().map(|(_, r)| r).collect();
+                    try!(self.request("PUT", &routes_path, &Json::Array(routes)));
+                }
+                return Ok(());
+            }
+            Json::Array(routes) => routes,
+            _ => {
+                return Err(CaddyError::InvalidResponse(self.endpoint(),
+                                                       "Routes are not an array."))
+            }
+        };
+        let mut managed = BTreeMap::new();
+        for route in current {
+            let id_opt = route.find("@id").and_then(|id| id.as_string()).map(|id| id.to_owned());
+            if let Some(id) = id_opt {
+                if id.starts_with(ROUTE_ID_PREFIX) {
+                    managed.insert(id, route);
+                }
+            }
+        }
+
+        for (id, route) in &desired {
+            match managed.get(id) {
+                Some(current) if current == route => (),
+                // Replaces the route in place
+                Some(_) => {
+                    try!(self.request("PATCH", &format!("/id/{}", id), route));
+                }
+                // Ahead of all other routes, which might include a catch-all route.
+                None => {
+                    try!(self.request("PUT", &format!("{}/0", routes_path), route));
+                }
+            }
+        }
+        for id in managed.keys() {
+            if !desired.contains_key(id) {
+                try!(self.request("DELETE", &format!("/id/{}", id), &Json::Null));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn route_id(domain_name: &str) -> String {
+    let id: String = domain_name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("{}{}", ROUTE_ID_PREFIX, id)
+}
+
+fn upstream(host: &str, port: u16) -> Json {
+    let dial = if host.contains(':') {
+        // IPv6 address
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut upstream = json::Object::new();
+    upstream.insert("dial".to_owned(), dial.to_json());
+    Json::Object(upstream)
+}
+
+// ############### CADDY ERROR ############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CaddyError {
+        Http(endpoint: String, err: HttpError) {
+            description("Failed to communicate with the Caddy admin API.")
+            cause(err)
+            display(me) -> ("{} Endpoint: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+        InvalidResponse(endpoint: String, reason: &'static str) {
+            description("Unexpected response from the Caddy admin API.")
+            display(me) -> ("{} Endpoint: {}, {}", me.description(), endpoint, reason)
+        }
+    }
+}
+
+impl PublishingInnerError for CaddyError {}
+
+impl Publish for CaddyPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.publish(publication) {
+            try!(self.converge());
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.withdraw(publication) {
+            try!(self.converge());
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) {
+        self.aggregate.begin_batch();
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.aggregate.end_batch();
+        Ok(try!(self.converge()))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.caddy_url != self.config.caddy_url {
+            info!("Switching to Caddy admin API {}.", config.caddy_url);
+            self.client_opt = None;
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use common::{self, Config};
+    use mock_http_server::{MockHttpServer, RecordedRequest};
+    use publisher::Publish;
+
+    fn caddy_config(server: &MockHttpServer) -> Arc<Config> {
+        let mut config = Config::default();
+        config.caddy_url = server.url();
+        config.caddy_server = Rc::new("srv0".to_owned());
+        Arc::new(config)
+    }
+
+    /// Stand-in for Caddy that answers GET requests for the routes with `routes` and stores
+    /// the routes of PUT requests to the (empty) routes.
+    fn caddy_server(routes: &str) -> MockHttpServer {
+        let routes = Arc::new(Mutex::new(routes.to_owned()));
+        MockHttpServer::with_handler(Box::new(move |request: &RecordedRequest| {
+            let mut routes = routes.lock().unwrap();
+            match (&request.method[..], &request.path[..]) {
+                ("GET", "/config/apps/http/servers/srv0/routes") => (200, routes.clone()),
+                ("PUT", "/config/apps/http/servers/srv0/routes") => {
+                    *routes = request.body.clone();
+                    (200, String::new())
+                }
+                _ => (200, String::new()),
+            }
+        }))
+    }
+
+    fn paths(requests: &[RecordedRequest]) -> Vec<String> {
+        requests.iter().map(|r| format!("{} {}", r.method, r.path)).collect()
+    }
+
+    #[test]
+    fn initial_routes() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = caddy_server("null");
+        let mut publisher = CaddyPublisher::new(caddy_config(&server));
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        let specs = vec![common::test_spec("app.example.org", Some(8080), Some(8043)),
+                         common::test_spec("secure.example.org", None, Some(8043))];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+        let specs = vec![common::test_spec("App.example.org", Some(8080), None)];
+        publisher.publish(&common::test_publication("web2", "172.17.0.3", specs)).unwrap();
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(paths(&requests),
+                   vec!["GET /config/apps/http/servers/srv0/routes",
+                        "PUT /config/apps/http/servers/srv0/routes"]);
+        let routes = Json::from_str(&requests[1].body).unwrap();
+        let routes = routes.as_array().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].find("@id").and_then(|id| id.as_string()),
+                   Some("beachhead_app_example_org"));
+        assert_eq!(routes[0].to_string(),
+                   "{\"@id\":\"beachhead_app_example_org\",\"handle\":[{\"handler\":\
+                    \"reverse_proxy\",\"upstreams\":[{\"dial\":\"172.17.0.2:8080\"},\
+                    {\"dial\":\"172.17.0.3:8080\"}]}],\"match\":[{\"host\":\
+                    [\"app.example.org\"]}],\"terminal\":true}");
+        let secure_handler = routes[1].find_path(&["handle"]).and_then(|h| h.as_array()).unwrap();
+        assert_eq!(secure_handler[0].find_path(&["upstreams"]).map(|u| u.to_string()),
+                   Some("[{\"dial\":\"172.17.0.2:8043\"}]".to_owned()));
+        assert!(secure_handler[0].find_path(&["transport", "tls"]).is_some());
+    }
+
+    #[test]
+    fn path_routes() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = caddy_server("null");
+        let mut publisher = CaddyPublisher::new(caddy_config(&server));
+        let mut api = common::test_spec("app.example.org", Some(3000), None);
+        api.path_prefix = Some("/api".to_owned());
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        let specs = vec![common::test_spec("app.example.org", Some(8080), None)];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+        publisher.publish(&common::test_publication("api1", "172.17.0.3", vec![api])).unwrap();
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        let routes = Json::from_str(&requests[1].body).unwrap();
+        let routes = routes.as_array().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].find("match").map(|m| m.to_string()),
+                   Some("[{\"host\":[\"app.example.org\"],\"not\":[{\"path\":[\"/api*\"]}]}]"
+                       .to_owned()));
+        assert_eq!(routes[1].find("@id").and_then(|id| id.as_string()),
+                   Some("beachhead_app_example_org_api"));
+        assert_eq!(routes[1].find("match").map(|m| m.to_string()),
+                   Some("[{\"host\":[\"app.example.org\"],\"path\":[\"/api*\"]}]".to_owned()));
+    }
+
+    #[test]
+    fn unchanged() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = caddy_server("null");
+        let mut publisher = CaddyPublisher::new(caddy_config(&server));
+        let specs = vec![common::test_spec("app.example.org", Some(8080), None)];
+        let publication = common::test_publication("web1", "172.17.0.2", specs);
+        publisher.publish(&publication).unwrap();
+        server.clear();
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication).unwrap();
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        assert_eq!(paths(&server.requests()),
+                   vec!["GET /config/apps/http/servers/srv0/routes"]);
+    }
+
+    #[test]
+    fn converge() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = caddy_server("[{\"handle\":[{\"handler\":\"static_response\"}]},
+                                   {\"@id\":\"beachhead_old_example_org\",\"terminal\":true},
+                                   {\"@id\":\"beachhead_app_example_org\",\"terminal\":true}]");
+        let mut publisher = CaddyPublisher::new(caddy_config(&server));
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        let specs = vec![common::test_spec("app.example.org", Some(8080), None),
+                         common::test_spec("new.example.org", Some(8080), None)];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(paths(&requests),
+                   vec!["GET /config/apps/http/servers/srv0/routes",
+                        "PATCH /id/beachhead_app_example_org",
+                        "PUT /config/apps/http/servers/srv0/routes/0",
+                        "DELETE /id/beachhead_old_example_org"]);
+        let new_route = Json::from_str(&requests[2].body).unwrap();
+        assert_eq!(new_route.find("@id").and_then(|id| id.as_string()),
+                   Some("beachhead_new_example_org"));
+    }
+
+    #[test]
+    fn error_status() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::with_handler(Box::new(|_: &RecordedRequest| {
+            (400, "{\"error\":\"invalid traversal path\"}".to_owned())
+        }));
+        let mut publisher = CaddyPublisher::new(caddy_config(&server));
+
+        // #### WHEN  ####
+        let specs = vec![common::test_spec("app.example.org", Some(80), None)];
+        let result = publisher.publish(&common::test_publication("web1", "172.17.0.2", specs));
+
+        // #### THEN  ####
+        assert!(result.is_err());
+    }
+}
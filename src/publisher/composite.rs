@@ -0,0 +1,357 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Forwards everything to several publishers (e.g., Redis and files while migrating from one to
+//! the other). A failing publisher doesn't keep the others from receiving their updates. A
+//! publication only fails if no publisher accepted it; publishers that failed get it again on
+//! the next flush. Withdrawals only go to the publishers that accepted the publication.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+
+use common::Config;
+use super::*;
+
+pub struct CompositePublisher {
+    backends: Vec<Backend>,
+}
+
+struct Backend {
+    /// Name of the publisher (for error reports).
+    name: String,
+    publisher: Box<Publish>,
+    /// Keys of the publications this publisher accepted.
+    accepted: HashSet<String>,
+    /// Publications this publisher failed to accept, by key. Retried on the next flush.
+    pending: HashMap<String, Publication>,
+}
+
+impl CompositePublisher {
+    pub fn new(publishers: Vec<(String, Box<Publish>)>) -> CompositePublisher {
+        CompositePublisher {
+            backends: publishers.into_iter()
+                .map(|(name, publisher)| {
+                    Backend {
+                        name: name,
+                        publisher: publisher,
+                        accepted: HashSet::new(),
+                        pending: HashMap::new(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Call `f` for every publisher, even if some of them fail.
+    fn forward<F>(&mut self, mut f: F) -> Result<(), PublishingError>
+        where F: FnMut(&mut Backend) -> Result<(), PublishingError>
+    {
+        let mut failures = Vec::new();
+        for backend in &mut self.backends {
+            if let Err(e) = f(backend) {
+                failures.push((backend.name.clone(), e));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(From::from(CompositeError::Failed(self.backends.len(), failures)))
+        }
+    }
+
+    /// Publish what the publishers failed to accept before.
+    fn retry_pending(&mut self) {
+        for backend in &mut self.backends {
+            let pending = backend.pending.drain().collect::<Vec<_>>();
+            for (key, publication) in pending {
+                match backend.publisher.publish(&publication) {
+                    Ok(()) => {
+                        backend.accepted.insert(key);
+                    }
+                    Err(e) => {
+                        warn!("Publisher {} still fails to publish {}: {}",
+                              backend.name,
+                              key,
+                              e);
+                        backend.pending.insert(key, publication);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ############### COMPOSITE ERROR ########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CompositeError {
+        Failed(total: usize, failures: Vec<(String, PublishingError)>) {
+            description("Publishing failed.")
+            display(me) -> ("{} {} of {} publishers failed. {}", me.description(),
+                failures.len(), total, failures.iter()
+                    .map(|&(ref name, ref err)| format!("{}: {}", name, err))
+                    .collect::<Vec<_>>()
+                    .join("; "))
+        }
+    }
+}
+
+impl PublishingInnerError for CompositeError {}
+
+impl Publish for CompositePublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let key = &publication.key;
+        let mut failures = Vec::new();
+        for backend in &mut self.backends {
+            match backend.publisher.publish(publication) {
+                Ok(()) => {
+                    backend.pending.remove(key);
+                    backend.accepted.insert(key.clone());
+                }
+                Err(e) => {
+                    backend.pending.insert(key.clone(), publication.clone());
+                    failures.push((backend.name.clone(), e));
+                }
+            }
+        }
+        if !failures.is_empty() && failures.len() == self.backends.len() {
+            // Nobody accepted it, so the caller doesn't consider it published and tries again.
+            for backend in &mut self.backends {
+                backend.pending.remove(key);
+            }
+            return Err(From::from(CompositeError::Failed(self.backends.len(), failures)));
+        }
+        for (name, e) in failures {
+            warn!("Publisher {} failed to publish {}, trying again on the next flush. {}",
+                  name,
+                  key,
+                  e);
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let key = &publication.key;
+        self.forward(|backend| {
+            backend.pending.remove(key);
+            if !backend.accepted.contains(key) {
+                return Ok(());
+            }
+            try!(backend.publisher.withdraw(publication));
+            backend.accepted.remove(key);
+            Ok(())
+        })
+    }
+
+    fn begin_batch(&mut self) {
+        for backend in &mut self.backends {
+            backend.publisher.begin_batch();
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.retry_pending();
+        self.forward(|backend| backend.publisher.flush())
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        self.forward(|backend| backend.publisher.reconfigure(config.clone()))
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use publisher::{Publication, Publish, PublishingError};
+    use publisher::mock_publisher::{MockPublisher, MockError};
+
+    fn publication(domain_name: &str) -> Publication {
+        common::test_publication("172.17.0.2",
+                                 "172.17.0.2",
+                                 vec![common::test_spec(domain_name, Some(80), None)])
+    }
+
+    fn failing_mock() -> Arc<RefCell<MockPublisher>> {
+        let mock = Arc::new(RefCell::new(MockPublisher::default()));
+        mock.borrow_mut().error_trigger =
+            Some(("fail".to_owned(), Box::new(|| PublishingError::from(MockError))));
+        mock.borrow_mut().flush_error = Some(Box::new(|| PublishingError::from(MockError)));
+        mock
+    }
+
+    #[test]
+    fn forward_to_all() {
+        common::init_log();
+        // #### GIVEN ####
+        let first = Arc::new(RefCell::new(MockPublisher::default()));
+        let second = Arc::new(RefCell::new(MockPublisher::default()));
+        let mut composite = CompositePublisher::new(vec![("first".to_owned(),
+                                                          Box::new(first.clone())),
+                                                         ("second".to_owned(),
+                                                          Box::new(second.clone()))]);
+
+        // #### WHEN  ####
+        composite.begin_batch();
+        composite.publish(&publication("example.org")).expect("Publish");
+        composite.withdraw(&publication("old.example.org")).expect("Withdraw");
+        composite.flush().expect("Flush");
+        composite.reconfigure(Arc::new(Config::default())).expect("Reconfigure");
+
+        // #### THEN  ####
+        for mock in &[first, second] {
+            let mock = mock.borrow();
+            assert_eq!(mock.publications.len(), 1);
+            assert_eq!(mock.withdrawals.len(), 1);
+            assert_eq!(mock.flushes, 1);
+            assert_eq!(mock.reconfigurations, 1);
+        }
+    }
+
+    #[test]
+    fn failures_isolated() {
+        common::init_log();
+        // #### GIVEN ####
+        let failing = failing_mock();
+        let healthy = Arc::new(RefCell::new(MockPublisher::default()));
+        let mut composite = CompositePublisher::new(vec![("failing".to_owned(),
+                                                          Box::new(failing.clone())),
+                                                         ("healthy".to_owned(),
+                                                          Box::new(healthy.clone()))]);
+
+        // #### WHEN  ####
+        let publish_result = composite.publish(&publication("fail.example.org"));
+        let flush_result = composite.flush();
+
+        // #### THEN  ####
+        let healthy = healthy.borrow();
+        assert_eq!(healthy.publications.len(), 1, "Healthy publisher must still publish.");
+        assert_eq!(healthy.flushes, 1, "Healthy publisher must still be flushed.");
+        assert!(publish_result.is_ok(),
+                "Publishing must succeed if any publisher accepted it: {:?}",
+                publish_result);
+        match flush_result {
+            Err(e) => {
+                let message = format!("{}", e);
+                assert!(message.contains("1 of 2 publishers failed. failing: "),
+                        "Message: {}",
+                        message);
+            }
+            Ok(()) => assert!(false, "Flush must fail"),
+        }
+    }
+
+    #[test]
+    fn all_failed() {
+        common::init_log();
+        // #### GIVEN ####
+        let first = failing_mock();
+        let second = failing_mock();
+        let mut composite = CompositePublisher::new(vec![("first".to_owned(),
+                                                          Box::new(first.clone())),
+                                                         ("second".to_owned(),
+                                                          Box::new(second.clone()))]);
+
+        // #### WHEN  ####
+        let publish_result = composite.publish(&publication("fail.example.org"));
+        first.borrow_mut().error_trigger = None;
+        let _ = composite.flush();
+
+        // #### THEN  ####
+        match publish_result {
+            Err(e) => {
+                let message = format!("{}", e);
+                assert!(message.contains("2 of 2 publishers failed."), "Message: {}", message);
+            }
+            Ok(()) => assert!(false, "Publish must fail"),
+        }
+        assert_eq!(first.borrow().publications.len(),
+                   0,
+                   "The caller retries failed publications, not the composite publisher.");
+    }
+
+    #[test]
+    fn retry_failed_publisher() {
+        common::init_log();
+        // #### GIVEN ####
+        let failing = failing_mock();
+        let healthy = Arc::new(RefCell::new(MockPublisher::default()));
+        let mut composite = CompositePublisher::new(vec![("failing".to_owned(),
+                                                          Box::new(failing.clone())),
+                                                         ("healthy".to_owned(),
+                                                          Box::new(healthy.clone()))]);
+        composite.publish(&publication("fail.example.org")).expect("Publish");
+
+        // #### WHEN  ####
+        failing.borrow_mut().error_trigger = None;
+        failing.borrow_mut().flush_error = None;
+        composite.begin_batch();
+        composite.flush().expect("Flush");
+
+        // #### THEN  ####
+        assert_eq!(failing.borrow().publications.len(),
+                   1,
+                   "Failed publisher must receive the publication on the next flush.");
+        assert_eq!(healthy.borrow().publications.len(),
+                   1,
+                   "Healthy publisher must not receive the publication again.");
+    }
+
+    #[test]
+    fn withdraw_from_accepting_publishers() {
+        common::init_log();
+        // #### GIVEN ####
+        let failing = failing_mock();
+        let healthy = Arc::new(RefCell::new(MockPublisher::default()));
+        let mut composite = CompositePublisher::new(vec![("failing".to_owned(),
+                                                          Box::new(failing.clone())),
+                                                         ("healthy".to_owned(),
+                                                          Box::new(healthy.clone()))]);
+        composite.publish(&publication("fail.example.org")).expect("Publish");
+
+        // #### WHEN  ####
+        // the container disappears before the failed publisher recovers
+        composite.withdraw(&publication("fail.example.org")).expect("Withdraw");
+        failing.borrow_mut().error_trigger = None;
+        failing.borrow_mut().flush_error = None;
+        composite.flush().expect("Flush");
+
+        // #### THEN  ####
+        assert_eq!(healthy.borrow().withdrawals.len(),
+                   1,
+                   "Healthy publisher must withdraw the publication.");
+        let failing = failing.borrow();
+        assert!(failing.withdrawals.is_empty(),
+                "Failed publisher never had the publication: {:?}",
+                failing.withdrawals);
+        assert!(failing.publications.is_empty(),
+                "Withdrawn publication must not be retried: {:?}",
+                failing.publications);
+    }
+}
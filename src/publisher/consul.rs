@@ -0,0 +1,489 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Registers every domain spec as a service with the local consul agent. Instead of expiring keys,
+//! each service has a TTL check that is passed whenever the publication is refreshed. Consul
+//! considers services critical when the check isn't passed in time and eventually deregisters
+//! them.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::json::{self, Json, ToJson};
+
+use common::Config;
+use domain_spec::{DomainOption, DomainSpec};
+use http::{HttpClient, HttpError};
+use super::*;
+
+/// How long to wait for the consul agent to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// Consul doesn't deregister critical services any sooner than this.
+const MIN_DEREGISTER_SECONDS: u32 = 60;
+
+pub struct ConsulPublisher {
+    config: Arc<Config>,
+    client_opt: Option<HttpClient>,
+    /// The services registered for each publication (indexed by publication key), together with
+    /// the registration that was sent to the agent. `Json::Null` stands for a registration that
+    /// might not have made it.
+    registered: HashMap<String, HashMap<String, Json>>,
+}
+
+impl ConsulPublisher {
+    pub fn new(config: Arc<Config>) -> ConsulPublisher {
+        ConsulPublisher {
+            config: config,
+            client_opt: None,
+            registered: HashMap::new(),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        self.config.consul_url.serialize()
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, ConsulError> {
+        if self.client_opt.is_none() {
+            let mut client = try_!(HttpClient::new(&self.config.consul_url), self.endpoint());
+            client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    fn register(&mut self, registration: &Json) -> Result<(), ConsulError> {
+        let endpoint = self.endpoint();
+        let client = try!(self.client());
+        try_!(client.json("PUT", "/v1/agent/service/register", registration),
+              endpoint);
+        Ok(())
+    }
+
+    fn pass_check(&mut self, service_id: &str) -> Result<(), ConsulError> {
+        let endpoint = self.endpoint();
+        let client = try!(self.client());
+        let path = format!("/v1/agent/check/pass/service:{}", service_id);
+        try_!(client.json("PUT", &path, &Json::Null), endpoint);
+        Ok(())
+    }
+
+    fn deregister(&mut self, service_id: &str) -> Result<(), ConsulError> {
+        let endpoint = self.endpoint();
+        let client = try!(self.client());
+        let path = format!("/v1/agent/service/deregister/{}", service_id);
+        match client.json("PUT", &path, &Json::Null) {
+            // Not registered (anymore), e.g., because the agent has been restarted.
+            Err(HttpError::Status(404, _, _)) => Ok(()),
+            result => {
+                try_!(result, endpoint);
+                Ok(())
+            }
+        }
+    }
+
+    /// Register the service unless the agent already knows it in this form. In that case, only
+    /// pass its check.
+    fn refresh_service(&mut self,
+                       service_id: &str,
+                       registration: &Json,
+                       previous: Option<&Json>)
+                       -> Result<(), ConsulError> {
+        if previous != Some(registration) {
+            return self.register(registration);
+        }
+        if self.config.expire_seconds.is_none() {
+            return Ok(());
+        }
+        match self.pass_check(service_id) {
+            // The agent has lost the check (e.g., it has been restarted).
+            Err(ConsulError::Http(_, HttpError::Status(..))) => self.register(registration),
+            result => result,
+        }
+    }
+}
+
+impl Publish for ConsulPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let config = self.config.clone();
+        let previous = self.registered.remove(&publication.key).unwrap_or_else(HashMap::new);
+
+        let mut current = HashMap::new();
+        let mut result = Ok(());
+        for spec in &publication.specs {
+            let service_id = service_id(&config, &publication.key, spec);
+            let registration = service_registration(&config, &service_id, &publication.host, spec);
+            if result.is_ok() {
+                result = self.refresh_service(&service_id,
+                                              &registration,
+                                              previous.get(&service_id));
+            }
+            current.insert(service_id, if result.is_ok() { registration } else { Json::Null });
+        }
+        // Domains that are no longer part of the publication
+        for service_id in previous.keys() {
+            if current.contains_key(service_id) {
+                continue;
+            }
+            if result.is_ok() {
+                result = self.deregister(service_id);
+            }
+            if result.is_err() {
+                // Try again next time
+                current.insert(service_id.clone(), Json::Null);
+            }
+        }
+
+        if !current.is_empty() {
+            self.registered.insert(publication.key.clone(), current);
+        }
+        Ok(try!(result))
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let mut service_ids: Vec<String> = publication.specs
+            .iter()
+            .map(|spec| service_id(&self.config, &publication.key, spec))
+            .collect();
+        if let Some(registered) = self.registered.remove(&publication.key) {
+            for service_id in registered.into_iter().map(|(id, _)| id) {
+                if !service_ids.contains(&service_id) {
+                    service_ids.push(service_id);
+                }
+            }
+        }
+
+        for (i, service_id) in service_ids.iter().enumerate() {
+            if let Err(e) = self.deregister(service_id) {
+                // Remember the rest so that they get withdrawn with the next attempt.
+                let remaining = service_ids[i..].iter().map(|id| (id.clone(), Json::Null));
+                self.registered.insert(publication.key.clone(), remaining.collect());
+                return Err(From::from(e));
+            }
+        }
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.consul_url != self.config.consul_url {
+            info!("Switching to consul agent {}.", config.consul_url);
+            self.client_opt = None;
+            // The new agent doesn't know about our services.
+            self.registered.clear();
+        } else if config.consul_service != self.config.consul_service ||
+           config.expire_seconds != self.config.expire_seconds {
+            // Registrations will differ, forces re-registration.
+            self.registered.clear();
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Service IDs are used in URL paths, so only a conservative set of characters is allowed.
+fn service_id(config: &Config, publication_key: &str, spec: &DomainSpec) -> String {
+    let raw = format!("{}-{}-{}", config.consul_service, publication_key, spec.spec_id());
+    raw.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '-' })
+        .collect()
+}
+
+fn service_registration(config: &Config,
+                        service_id: &str,
+                        host: &str,
+                        spec: &DomainSpec)
+                        -> Json {
+    let mut tags = vec![format!("domain={}", spec.domain_name)];
+    if let Some(ref path_prefix) = spec.path_prefix {
+        tags.push(format!("path={}", path_prefix));
+    }
+    if let Some(http_port) = spec.http_port {
+        tags.push(format!("http={}", http_port));
+    }
+    if let Some(https_port) = spec.https_port {
+        tags.push(format!("https={}", https_port));
+    }
+    // Options are written like in domain specs: flags by name, values as `name=value`.
+    for option in spec.options.values() {
+        match *option {
+            DomainOption::RedirectHttps | DomainOption::Websocket => {
+                tags.push(option.name().to_owned())
+            }
+            DomainOption::MaxBody(size) => tags.push(format!("{}={}", option.name(), size)),
+        }
+    }
+
+    let mut registration = json::Object::new();
+    registration.insert("ID".to_owned(), service_id.to_json());
+    registration.insert("Name".to_owned(), config.consul_service.to_json());
+    registration.insert("Address".to_owned(), host.to_json());
+    if let Some(port) = spec.http_port.or(spec.https_port) {
+        registration.insert("Port".to_owned(), port.to_json());
+    }
+    registration.insert("Tags".to_owned(), tags.to_json());
+    if let Some(expire_seconds) = config.expire_seconds {
+        let mut check = json::Object::new();
+        check.insert("TTL".to_owned(), format!("{}s", expire_seconds).to_json());
+        check.insert("Status".to_owned(), "passing".to_json());
+        check.insert("DeregisterCriticalServiceAfter".to_owned(),
+                     format!("{}s", max(expire_seconds, MIN_DEREGISTER_SECONDS)).to_json());
+        registration.insert("Check".to_owned(), Json::Object(check));
+    }
+    Json::Object(registration)
+}
+
+// ############### CONSUL ERROR ###########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConsulError {
+        Http(endpoint: String, err: HttpError) {
+            description("Failed to communicate with the consul agent.")
+            cause(err)
+            display(me) -> ("{} Agent: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+    }
+}
+
+impl PublishingInnerError for ConsulError {}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use common::{self, Config};
+    use domain_spec::DomainOption;
+    use mock_http_server::{MockHttpServer, RecordedRequest};
+    use publisher::{Publication, Publish};
+
+    fn consul_config(server: &MockHttpServer) -> Arc<Config> {
+        let mut config = Config::default();
+        config.consul_url = server.url();
+        config.consul_service = Rc::new("beachhead".to_owned());
+        Arc::new(config)
+    }
+
+    fn example_publication() -> Publication {
+        common::test_publication("app",
+                                 "172.17.0.2",
+                                 vec![common::test_spec("www.example.com", Some(8080), Some(8043)),
+                                      common::test_spec("admin.example.com", None, Some(443))])
+    }
+
+    fn paths(requests: &[RecordedRequest]) -> Vec<String> {
+        let mut paths: Vec<String> =
+            requests.iter().map(|r| format!("{} {}", r.method, r.path)).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn register() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(paths(&requests),
+                   vec!["PUT /v1/agent/service/register", "PUT /v1/agent/service/register"]);
+        let registration = requests.iter()
+            .map(|r| Json::from_str(&r.body).unwrap())
+            .find(|r| r.find("ID").and_then(|id| id.as_string()) ==
+                      Some("beachhead-app-www_example_com"))
+            .expect("Registration of www.example.com");
+        assert_eq!(registration.find("Name").and_then(|n| n.as_string()), Some("beachhead"));
+        assert_eq!(registration.find("Address").and_then(|a| a.as_string()),
+                   Some("172.17.0.2"));
+        assert_eq!(registration.find("Port").and_then(|p| p.as_u64()), Some(8080));
+        let tags: Vec<&str> = registration.find("Tags")
+            .and_then(|t| t.as_array())
+            .unwrap()
+            .iter()
+            .map(|t| t.as_string().unwrap())
+            .collect();
+        assert_eq!(tags, vec!["domain=www.example.com", "http=8080", "https=8043"]);
+        assert_eq!(registration.find_path(&["Check", "TTL"]).and_then(|t| t.as_string()),
+                   Some("60s"));
+    }
+
+    #[test]
+    fn register_path_and_options() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+        let mut publication = example_publication();
+        publication.specs.truncate(1);
+        publication.specs[0].path_prefix = Some("/ws".to_owned());
+        publication.specs[0].options.insert("websocket", DomainOption::Websocket);
+        publication.specs[0].options.insert("max-body", DomainOption::MaxBody(10485760));
+
+        // #### WHEN  ####
+        publisher.publish(&publication).expect("Publish");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let registration = Json::from_str(&requests[0].body).unwrap();
+        assert_eq!(registration.find("ID").and_then(|id| id.as_string()),
+                   Some("beachhead-app-www_example_com_ws"));
+        let tags: Vec<&str> = registration.find("Tags")
+            .and_then(|t| t.as_array())
+            .unwrap()
+            .iter()
+            .map(|t| t.as_string().unwrap())
+            .collect();
+        assert_eq!(tags,
+                   vec!["domain=www.example.com",
+                        "path=/ws",
+                        "http=8080",
+                        "https=8043",
+                        "max-body=10485760",
+                        "websocket"]);
+    }
+
+    #[test]
+    fn refresh_passes_check() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+        publisher.publish(&example_publication()).expect("Publish");
+        server.clear();
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish again");
+
+        // #### THEN  ####
+        assert_eq!(paths(&server.requests()),
+                   vec!["PUT /v1/agent/check/pass/service:beachhead-app-admin_example_com",
+                        "PUT /v1/agent/check/pass/service:beachhead-app-www_example_com"]);
+    }
+
+    #[test]
+    fn refresh_unknown_check() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::with_handler(Box::new(|request: &RecordedRequest| {
+            if request.path.contains("/check/pass/") {
+                (500, "CheckID \"service:beachhead-app\" does not have associated TTL".to_owned())
+            } else {
+                (200, String::new())
+            }
+        }));
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+        publisher.publish(&example_publication()).expect("Publish");
+        server.clear();
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish again");
+
+        // #### THEN  ####
+        let registrations = server.requests()
+            .iter()
+            .filter(|r| r.path == "/v1/agent/service/register")
+            .count();
+        assert_eq!(registrations, 2);
+    }
+
+    #[test]
+    fn domain_removed() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+        let mut publication = example_publication();
+        publisher.publish(&publication).expect("Publish");
+        server.clear();
+
+        // #### WHEN  ####
+        publication.specs.truncate(1);
+        publisher.publish(&publication).expect("Publish again");
+
+        // #### THEN  ####
+        assert_eq!(paths(&server.requests()),
+                   vec!["PUT /v1/agent/check/pass/service:beachhead-app-www_example_com",
+                        "PUT /v1/agent/service/deregister/beachhead-app-admin_example_com"]);
+    }
+
+    #[test]
+    fn no_expire() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut config = (*consul_config(&server)).clone();
+        config.expire_seconds = None;
+        let mut publisher = ConsulPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+        publisher.publish(&example_publication()).expect("Publish again");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2, "Requests: {:?}", requests);
+        for request in requests {
+            let registration = Json::from_str(&request.body).unwrap();
+            assert!(registration.find("Check").is_none());
+        }
+    }
+
+    #[test]
+    fn withdraw() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::with_handler(Box::new(|request: &RecordedRequest| {
+            if request.path.contains("admin") {
+                (404, "Unknown service ID".to_owned())
+            } else {
+                (200, String::new())
+            }
+        }));
+        let mut publisher = ConsulPublisher::new(consul_config(&server));
+        publisher.publish(&example_publication()).expect("Publish");
+        server.clear();
+
+        // #### WHEN  ####
+        let result = publisher.withdraw(&example_publication());
+
+        // #### THEN  ####
+        assert!(result.is_ok(), "Withdrawal should succeed. Got {:?}", result);
+        assert_eq!(paths(&server.requests()),
+                   vec!["PUT /v1/agent/service/deregister/beachhead-app-admin_example_com",
+                        "PUT /v1/agent/service/deregister/beachhead-app-www_example_com"]);
+    }
+}
@@ -0,0 +1,252 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Publishes to etcd (v3 API) via its JSON gateway. Expiration is implemented with a single lease
+//! that all keys are attached to. The lease is kept alive at the start of every refresh, so the
+//! keys only disappear when beachhead-companion stops refreshing them (or withdraws them).
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json, ToJson};
+
+use common::Config;
+use http::{HttpClient, HttpError};
+use super::*;
+use super::json_serializer;
+
+/// Path of the JSON gateway if the etcd URL doesn't specify one.
+const DEFAULT_API_PATH: &'static str = "/v3";
+/// How long to wait for etcd to respond.
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+
+pub struct EtcdPublisher {
+    config: Arc<Config>,
+    client_opt: Option<HttpClient>,
+    /// ID of the lease that published keys are attached to. None if expiration is disabled or
+    /// no lease has been granted yet (or it has expired).
+    lease_opt: Option<i64>,
+}
+
+impl EtcdPublisher {
+    pub fn new(config: Arc<Config>) -> EtcdPublisher {
+        EtcdPublisher {
+            config: config,
+            client_opt: None,
+            lease_opt: None,
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        self.config.etcd_url.serialize()
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, EtcdError> {
+        if self.client_opt.is_none() {
+            let mut client = try_!(HttpClient::new(&self.config.etcd_url), self.endpoint());
+            client.set_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECONDS)));
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    /// POST a request to the JSON gateway. `method` is relative to the API path (e.g.,
+    /// `/kv/put`).
+    fn call(&mut self, method: &str, request: json::Object) -> Result<Json, EtcdError> {
+        let endpoint = self.endpoint();
+        let path = if self.config.etcd_url.serialize_path().map(|p| p == "/").unwrap_or(true) {
+            format!("{}{}", DEFAULT_API_PATH, method)
+        } else {
+            method.to_owned()
+        };
+        let client = try!(self.client());
+        Ok(try_!(client.json("POST", &path, &Json::Object(request)), endpoint))
+    }
+
+    /// The lease to attach keys to (granted on demand). None if expiration is disabled.
+    fn lease(&mut self) -> Result<Option<i64>, EtcdError> {
+        let expire_seconds = match self.config.expire_seconds {
+            Some(expire_seconds) => expire_seconds,
+            None => return Ok(None),
+        };
+        if let Some(lease) = self.lease_opt {
+            return Ok(Some(lease));
+        }
+
+        let mut request = json::Object::new();
+        request.insert("TTL".to_owned(), expire_seconds.to_json());
+        let response = try!(self.call("/lease/grant", request));
+        let lease = try!(int64_field(&response, "ID").ok_or_else(|| {
+            EtcdError::InvalidResponse(self.endpoint(), format!("No lease ID in {}", response))
+        }));
+        debug!("Granted etcd lease {:x} with a TTL of {} seconds.", lease, expire_seconds);
+        self.lease_opt = Some(lease);
+        Ok(Some(lease))
+    }
+
+    /// Refresh the TTL of the current lease. Forgets about the lease if it has expired in the
+    /// meantime (a new one is granted with the next publication).
+    fn keep_alive(&mut self) -> Result<(), EtcdError> {
+        let lease = match self.lease_opt {
+            Some(lease) => lease,
+            None => return Ok(()),
+        };
+        let mut request = json::Object::new();
+        request.insert("ID".to_owned(), lease.to_string().to_json());
+        let response = try!(self.call("/lease/keepalive", request));
+        // The gateway wraps the (streamed) response in a "result" object.
+        let result = response.find("result").unwrap_or(&response);
+        if int64_field(result, "TTL").unwrap_or(0) <= 0 {
+            info!("etcd lease {:x} has expired. Granting a new one.", lease);
+            self.lease_opt = None;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> Result<(), EtcdError> {
+        let lease_opt = try!(self.lease());
+        let mut request = json::Object::new();
+        request.insert("key".to_owned(), key.as_bytes().to_base64(STANDARD).to_json());
+        request.insert("value".to_owned(), value.as_bytes().to_base64(STANDARD).to_json());
+        if let Some(lease) = lease_opt {
+            request.insert("lease".to_owned(), lease.to_string().to_json());
+        }
+        try!(self.call("/kv/put", request));
+        Ok(())
+    }
+}
+
+impl Publish for EtcdPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let key = format!("{}{}", self.config.key_prefix, publication.key);
+        let published_config = json_serializer::domain_configs(&publication.host,
+                                                               &publication.specs);
+        let value = try!(json::encode(&published_config));
+
+        match self.put(&key, &value) {
+            Err(ref e) if e.is_lease_not_found() => {
+                // The lease expired between keep-alives (e.g., etcd was unreachable for a while).
+                info!("etcd lease has expired. Granting a new one.");
+                self.lease_opt = None;
+                Ok(try!(self.put(&key, &value)))
+            }
+            result => Ok(try!(result)),
+        }
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let key = format!("{}{}", self.config.key_prefix, publication.key);
+        let mut request = json::Object::new();
+        request.insert("key".to_owned(), key.as_bytes().to_base64(STANDARD).to_json());
+        try!(self.call("/kv/deleterange", request));
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) {
+        if let Err(e) = self.keep_alive() {
+            // Publishing will most likely fail as well and report the problem.
+            debug!("Failed to keep etcd lease alive. {}", e);
+        }
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.etcd_url != self.config.etcd_url {
+            info!("Switching to etcd server {}.", config.etcd_url);
+            self.client_opt = None;
+            // The old lease belongs to the old cluster.
+            self.lease_opt = None;
+        } else if config.expire_seconds != self.config.expire_seconds {
+            // Leases have a fixed TTL. Keys get attached to a new lease on the next publication.
+            self.lease_opt = None;
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// etcd encodes 64 bit integers as strings in JSON.
+fn int64_field(obj: &Json, field: &str) -> Option<i64> {
+    obj.find(field).and_then(|value| match *value {
+        Json::String(ref raw) => raw.parse().ok(),
+        ref other => other.as_i64(),
+    })
+}
+
+// ############### ETCD ERROR #############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum EtcdError {
+        Http(endpoint: String, err: HttpError) {
+            description("Failed to communicate with etcd.")
+            cause(err)
+            display(me) -> ("{} Server: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+        InvalidResponse(endpoint: String, reason: String) {
+            description("Unexpected response from etcd.")
+            display(me) -> ("{} Server: {}, {}", me.description(), endpoint, reason)
+        }
+    }
+}
+
+impl EtcdError {
+    fn is_lease_not_found(&self) -> bool {
+        match *self {
+            EtcdError::Http(_, HttpError::Status(_, _, ref body)) => {
+                body.contains("lease not found")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PublishingInnerError for EtcdError {}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use common;
+
+    #[test]
+    fn int64_fields() {
+        common::init_log();
+        // #### GIVEN ####
+        let response = Json::from_str(r#"{"ID": "7587848863232461845", "TTL": 60}"#).unwrap();
+
+        // #### WHEN  ####
+        let id = int64_field(&response, "ID");
+        let ttl = int64_field(&response, "TTL");
+        let missing = int64_field(&response, "lease");
+
+        // #### THEN  ####
+        assert_eq!(id, Some(7587848863232461845));
+        assert_eq!(ttl, Some(60));
+        assert_eq!(missing, None);
+    }
+}
@@ -0,0 +1,367 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Writes one JSON file per publication into a directory (e.g., for confd's file backend or
+//! tools that watch the directory with inotify). Files are replaced atomically and only when their
+//! contents change. Expiration is implemented by removing files that haven't been published for
+//! longer than the expiration time.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustc_serialize::json;
+
+use common::Config;
+use super::*;
+use super::json_serializer;
+
+const FILE_EXTENSION: &'static str = "json";
+
+pub struct FilePublisher {
+    config: Arc<Config>,
+    /// When each file in the directory was last published. Files that beachhead-companion hasn't
+    /// published itself (e.g., left over from before a restart) count as published at their
+    /// modification time.
+    last_seen: HashMap<PathBuf, SystemTime>,
+}
+
+impl FilePublisher {
+    pub fn new(config: Arc<Config>) -> FilePublisher {
+        FilePublisher {
+            config: config,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    fn file_path(&self, publication_key: &str) -> PathBuf {
+        let key = publication_key.trim_left_matches('/');
+        let mut file_name: String = key.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+        if file_name != key {
+            // Keys that only differ in the replaced characters (`a/b`, `a_b`) get files of their
+            // own.
+            file_name = format!("{}-{:08x}", file_name, fnv1a(key.as_bytes()));
+        }
+        Path::new(&*self.config.file_directory).join(format!("{}.{}", file_name, FILE_EXTENSION))
+    }
+
+    /// Remove files that haven't been published for longer than the expiration time.
+    fn expire(&mut self) -> Result<(), FileError> {
+        let expire_seconds = match self.config.expire_seconds {
+            Some(expire_seconds) => expire_seconds,
+            None => return Ok(()),
+        };
+        let directory = PathBuf::from(&*self.config.file_directory);
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            // Nothing published yet
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(From::from((e, directory))),
+        };
+
+        let now = SystemTime::now();
+        let max_age = Duration::from_secs(expire_seconds as u64);
+        for entry in entries {
+            let path = try_!(entry, directory.clone()).path();
+            if path.extension().map(|ext| ext != FILE_EXTENSION).unwrap_or(true) {
+                continue;
+            }
+            let last_seen = match self.last_seen.get(&path) {
+                Some(last_seen) => *last_seen,
+                None => try_!(fs::metadata(&path).and_then(|m| m.modified()), path.clone()),
+            };
+            self.last_seen.insert(path.clone(), last_seen);
+            // Modification times in the future count as 'just now'.
+            let age = now.duration_since(last_seen).unwrap_or(Duration::from_secs(0));
+            if age > max_age {
+                info!("Removing expired publication {}.", path.display());
+                try!(remove_file(&path));
+                self.last_seen.remove(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Publish for FilePublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let path = self.file_path(&publication.key);
+        let published_config = json_serializer::domain_configs(&publication.host,
+                                                               &publication.specs);
+        let contents = try!(json::encode(&published_config));
+
+        try!(write_if_changed(&path, &contents));
+        self.last_seen.insert(path, SystemTime::now());
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let path = self.file_path(&publication.key);
+        self.last_seen.remove(&path);
+        Ok(try!(remove_file(&path)))
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        Ok(try!(self.expire()))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.file_directory != self.config.file_directory {
+            info!("Writing publications to {}.", config.file_directory);
+            self.last_seen.clear();
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// 32-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+/// Replace the file at `path` with `contents` (atomically, via a temporary file in the same
+/// directory) unless it already has these contents. Returns whether the file has been replaced.
+pub fn write_if_changed(path: &Path, contents: &str) -> Result<bool, FileError> {
+    if read_if_exists(path).map(|current| current == contents).unwrap_or(false) {
+        return Ok(false);
+    }
+
+    let directory = path.parent().unwrap_or(Path::new("."));
+    try_!(fs::create_dir_all(directory), directory.to_owned());
+    // Hidden and without the .json extension, so consumers of the directory ignore it.
+    let temp_path = directory.join(format!(".{}.tmp",
+                                           path.file_name().unwrap().to_string_lossy()));
+    try_!(File::create(&temp_path).and_then(|mut file| {
+              try!(file.write_all(contents.as_bytes()));
+              file.sync_all()
+          }),
+          temp_path.clone());
+    try_!(fs::rename(&temp_path, path), path.to_owned());
+    debug!("Wrote {}.", path.display());
+    Ok(true)
+}
+
+/// Contents of the file at `path`. None if it doesn't exist or can't be read.
+pub fn read_if_exists(path: &Path) -> Option<String> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => Some(contents),
+        Err(_) => None,
+    }
+}
+
+/// Remove a file. A file that doesn't exist (anymore) is not an error.
+pub fn remove_file(path: &Path) -> Result<(), FileError> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(try_!(result, path.to_owned())),
+    }
+}
+
+// ############### FILE ERROR #############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FileError {
+        Io(path: PathBuf, err: io::Error) {
+            description("Failed to update file.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path.display(), err)
+            from(e: (io::Error, PathBuf)) -> (e.1, e.0)
+        }
+    }
+}
+
+impl PublishingInnerError for FileError {}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use common::{self, Config};
+    use publisher::{Publication, Publish};
+
+    /// A fresh directory for each test.
+    fn test_config(test_name: &str) -> (Arc<Config>, PathBuf) {
+        let directory = common::test_directory(&format!("file-publisher-{}", test_name));
+        let mut config = Config::default();
+        config.file_directory = Rc::new(directory.to_string_lossy().into_owned());
+        (Arc::new(config), directory)
+    }
+
+    fn publication(key: &str) -> Publication {
+        common::test_publication(key,
+                                 "172.17.0.2",
+                                 vec![common::test_spec("www.example.com", Some(8080), None)])
+    }
+
+    fn read(path: &PathBuf) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn publish() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("publish");
+        let mut publisher = FilePublisher::new(config);
+
+        // #### WHEN  ####
+        publisher.publish(&publication("172.17.0.2_web")).expect("Publish");
+
+        // #### THEN  ####
+        let published = Json::from_str(&read(&directory.join("172.17.0.2_web.json"))).unwrap();
+        let specs = published.as_array().expect("top-level value must be an array");
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].find("domain").and_then(|d| d.as_string()),
+                   Some("www.example.com"));
+        let files: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+        assert_eq!(files.len(), 1, "No temporary files should be left behind.");
+    }
+
+    #[test]
+    fn sanitized_keys_distinct() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("sanitized_keys_distinct");
+        let mut publisher = FilePublisher::new(config);
+        let mut other = publication("10.42.0.12_shop_api");
+        other.host = "10.42.0.13".to_owned();
+
+        // #### WHEN  ####
+        publisher.publish(&publication("10.42.0.12_shop/api")).expect("Publish pod");
+        publisher.publish(&other).expect("Publish container");
+
+        // #### THEN  ####
+        let mut files: Vec<String> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 2, "Neither publication should overwrite the other.");
+        assert_eq!(files[1], "10.42.0.12_shop_api.json", "Clean keys keep their file name.");
+        assert_eq!(files[0], "10.42.0.12_shop_api-5279a683.json");
+    }
+
+    #[test]
+    fn changed() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("changed");
+        let mut publisher = FilePublisher::new(config);
+        let path = directory.join("app.json");
+        publisher.publish(&publication("app")).expect("Publish");
+        let inode = fs::metadata(&path).unwrap().ino();
+
+        // #### WHEN  ####
+        let mut changed = publication("app");
+        changed.specs[0].http_port = Some(8081);
+        publisher.publish(&changed).expect("Publish changed");
+
+        // #### THEN  ####
+        assert!(read(&path).contains("8081"));
+        assert!(fs::metadata(&path).unwrap().ino() != inode,
+                "Changed publication should replace the file.");
+    }
+
+    #[test]
+    fn unchanged_not_rewritten() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("unchanged_not_rewritten");
+        let mut publisher = FilePublisher::new(config);
+        let path = directory.join("app.json");
+        publisher.publish(&publication("app")).expect("Publish");
+        let inode = fs::metadata(&path).unwrap().ino();
+
+        // #### WHEN  ####
+        publisher.publish(&publication("app")).expect("Publish again");
+
+        // #### THEN  ####
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+    }
+
+    #[test]
+    fn withdraw() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("withdraw");
+        let mut publisher = FilePublisher::new(config);
+        publisher.publish(&publication("app")).expect("Publish");
+        publisher.publish(&publication("other")).expect("Publish");
+
+        // #### WHEN  ####
+        publisher.withdraw(&publication("app")).expect("Withdraw");
+        // Withdrawing something that isn't published is not an error.
+        publisher.withdraw(&publication("app")).expect("Withdraw again");
+
+        // #### THEN  ####
+        assert!(!directory.join("app.json").exists());
+        assert!(directory.join("other.json").exists());
+    }
+
+    #[test]
+    fn expire() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("expire");
+        let mut config = (*config).clone();
+        config.expire_seconds = Some(1);
+        let mut publisher = FilePublisher::new(Arc::new(config));
+        fs::create_dir_all(&directory).unwrap();
+        // Left over from a previous run
+        File::create(directory.join("old.json")).unwrap().write_all(b"[]").unwrap();
+        File::create(directory.join("notes.txt")).unwrap().write_all(b"keep").unwrap();
+        sleep(Duration::from_millis(1500));
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication("app")).expect("Publish");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        assert!(!directory.join("old.json").exists(), "Expired file should be removed.");
+        assert!(directory.join("app.json").exists());
+        assert!(directory.join("notes.txt").exists(), "Only .json files are managed.");
+    }
+}
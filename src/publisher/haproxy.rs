@@ -0,0 +1,675 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Writes HAProxy map files (domain -> backend) and backend sections for all current
+//! publications. HAProxy is reloaded when backends appear (or need more servers). Other changes
+//! (new container addresses, containers or domains that went away) are pushed via the runtime API
+//! socket instead, if one is configured.
+//!
+//! The HAProxy configuration is expected to include the backends and to look up backends in the
+//! maps, e.g.:
+//!
+//! ```text
+//! haproxy -f /etc/haproxy/haproxy.cfg -f /etc/haproxy/beachhead/backends.cfg
+//! use_backend %[req.hdr(host),lower,map(/etc/haproxy/beachhead/http.map)]
+//! ```
+//!
+//! Domain specs with a path prefix go into maps of their own (`http_paths.map`), keyed by domain
+//! and path (`example.org/api`). They are meant to be matched against `base` with `map_beg`
+//! ahead of the domain maps. Domain spec options are not applied.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::io::{self, Read, Write as IoWrite};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::Config;
+use domain_spec::DomainSpec;
+use super::*;
+use super::aggregate::{self, Aggregate, CommandError};
+use super::file::{self, FileError};
+
+pub const BACKENDS_FILE: &'static str = "backends.cfg";
+const SCHEMES: [&'static str; 2] = ["http", "https"];
+/// Names of the maps for domain specs with a path prefix, by scheme.
+const PATH_MAPS: [&'static str; 2] = ["http_paths", "https_paths"];
+/// How long to wait for HAProxy to answer on the runtime API socket.
+const SOCKET_TIMEOUT_SECONDS: u64 = 5;
+
+/// Domain -> backend maps (by map name) and the servers of each backend.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct HaproxyConfig {
+    maps: BTreeMap<&'static str, BTreeMap<String, String>>,
+    backends: BTreeMap<String, Vec<(String, u16)>>,
+}
+
+/// What the running HAProxy knows about: maps and the server slots of each backend (None for
+/// slots that are in maintenance).
+#[derive(Debug, Clone, PartialEq, Default)]
+struct RunningConfig {
+    maps: BTreeMap<&'static str, BTreeMap<String, String>>,
+    slots: BTreeMap<String, Vec<Option<(String, u16)>>>,
+}
+
+pub struct HaproxyPublisher {
+    config: Arc<Config>,
+    aggregate: Aggregate,
+    /// None until the state of HAProxy is known (after the first reload or when the files
+    /// already had the right contents).
+    running_opt: Option<RunningConfig>,
+    /// The last reload failed (or there is no reload command), so HAProxy may still run older
+    /// files. Retried with the next rendering, whether the files change or not.
+    reload_pending: bool,
+}
+
+impl HaproxyPublisher {
+    pub fn new(config: Arc<Config>) -> HaproxyPublisher {
+        HaproxyPublisher {
+            config: config,
+            aggregate: Aggregate::new(),
+            running_opt: None,
+            reload_pending: false,
+        }
+    }
+
+    fn map_path(&self, map_name: &str) -> PathBuf {
+        Path::new(&*self.config.haproxy_directory).join(format!("{}.map", map_name))
+    }
+
+    fn render(&mut self) -> Result<(), HaproxyError> {
+        self.aggregate.expire(self.config.expire_seconds);
+        let desired = HaproxyConfig::build(self.aggregate.publications());
+
+        let mut files_changed = false;
+        for map_name in SCHEMES.iter().chain(PATH_MAPS.iter()) {
+            let map = desired.maps.get(map_name).map(render_map).unwrap_or_else(String::new);
+            files_changed |= try!(file::write_if_changed(&self.map_path(map_name), &map));
+        }
+        let backends_path = Path::new(&*self.config.haproxy_directory).join(BACKENDS_FILE);
+        files_changed |= try!(file::write_if_changed(&backends_path, &desired.render_backends()));
+
+        if self.reload_pending {
+            return self.reload(&desired);
+        }
+        let running = match self.running_opt.take() {
+            Some(running) => running,
+            None if !files_changed => {
+                // HAProxy presumably runs with these files already.
+                self.running_opt = Some(RunningConfig::from(&desired));
+                return Ok(());
+            }
+            None => return self.reload(&desired),
+        };
+        if running == RunningConfig::from(&desired) {
+            self.running_opt = Some(running);
+            return Ok(());
+        }
+
+        let socket_opt = self.config.haproxy_socket.clone();
+        match socket_opt {
+            Some(ref socket) if running.can_update_to(&desired) => {
+                let mut running = running;
+                let result = self.update_runtime(socket, &mut running, &desired);
+                self.running_opt = Some(running);
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        if self.config.haproxy_reload_command.is_some() {
+                            warn!("Failed to update HAProxy via its runtime API, reloading \
+                                   instead. {}",
+                                  e);
+                            self.reload(&desired)
+                        } else {
+                            Err(e)
+                        }
+                    }
+                }
+            }
+            _ => self.reload(&desired),
+        }
+    }
+
+    fn reload(&mut self, desired: &HaproxyConfig) -> Result<(), HaproxyError> {
+        self.running_opt = None;
+        match self.config.haproxy_reload_command {
+            Some(ref reload_command) => {
+                self.reload_pending = true;
+                try!(aggregate::run(reload_command));
+                self.reload_pending = false;
+                self.running_opt = Some(RunningConfig::from(desired));
+            }
+            None => {
+                // Nobody tells us when HAProxy loads the files, so its state stays unknown.
+                if !self.reload_pending {
+                    info!("HAProxy needs to be reloaded to pick up the new backends.");
+                }
+                self.reload_pending = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the running HAProxy up to date via the runtime API. `running` reflects the
+    /// changes that have been made, even if a command fails.
+    fn update_runtime(&self,
+                      socket: &str,
+                      running: &mut RunningConfig,
+                      desired: &HaproxyConfig)
+                      -> Result<(), HaproxyError> {
+        // Servers first, so that new map entries point to backends that are ready.
+        for (backend, slots) in running.slots.iter_mut() {
+            let no_servers = Vec::new();
+            let servers = desired.backends.get(backend).unwrap_or(&no_servers);
+            for (i, slot) in slots.iter_mut().enumerate() {
+                let server = format!("{}/s{}", backend, i + 1);
+                match servers.get(i) {
+                    Some(addr) if slot.as_ref() == Some(addr) => (),
+                    Some(addr) => {
+                        try!(runtime_command(socket,
+                                             &format!("set server {} addr {} port {}",
+                                                      server,
+                                                      addr.0,
+                                                      addr.1)));
+                        if slot.is_none() {
+                            try!(runtime_command(socket,
+                                                 &format!("set server {} state ready", server)));
+                        }
+                        *slot = Some(addr.clone());
+                    }
+                    None if slot.is_some() => {
+                        try!(runtime_command(socket,
+                                             &format!("set server {} state maint", server)));
+                        *slot = None;
+                    }
+                    None => (),
+                }
+            }
+        }
+
+        for map_name in SCHEMES.iter().chain(PATH_MAPS.iter()) {
+            let map_path = self.map_path(map_name);
+            let map_path = map_path.display();
+            let no_entries = BTreeMap::new();
+            let desired_map = desired.maps.get(map_name).unwrap_or(&no_entries);
+            let running_map = running.maps.entry(map_name).or_insert_with(BTreeMap::new);
+            let removed: Vec<String> = running_map.keys()
+                .filter(|domain| !desired_map.contains_key(*domain))
+                .cloned()
+                .collect();
+            for domain in removed {
+                try!(runtime_command(socket, &format!("del map {} {}", map_path, domain)));
+                running_map.remove(&domain);
+            }
+            for (domain, backend) in desired_map {
+                let verb = match running_map.get(domain) {
+                    Some(current) if current == backend => continue,
+                    Some(_) => "set",
+                    None => "add",
+                };
+                try!(runtime_command(socket,
+                                     &format!("{} map {} {} {}", verb, map_path, domain, backend)));
+                running_map.insert(domain.clone(), backend.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HaproxyConfig {
+    fn build<'a, I: IntoIterator<Item = &'a Publication>>(publications: I) -> HaproxyConfig {
+        let mut config = HaproxyConfig::default();
+        for publication in publications {
+            for spec in &publication.specs {
+                let (map_names, map_key) = match spec.path_prefix {
+                    Some(ref path_prefix) => {
+                        (&PATH_MAPS, format!("{}{}", spec.domain_name.to_lowercase(), path_prefix))
+                    }
+                    None => (&SCHEMES, spec.domain_name.to_lowercase()),
+                };
+                let ports = [spec.http_port, spec.https_port];
+                for ((scheme, map_name), port_opt) in SCHEMES.iter().zip(map_names).zip(&ports) {
+                    if let Some(port) = *port_opt {
+                        let backend = backend_name(spec, scheme);
+                        config.maps
+                            .entry(map_name)
+                            .or_insert_with(BTreeMap::new)
+                            .insert(map_key.clone(), backend.clone());
+                        config.backends
+                            .entry(backend)
+                            .or_insert_with(Vec::new)
+                            .push((publication.host.clone(), port));
+                    }
+                }
+            }
+        }
+        config
+    }
+
+    fn render_backends(&self) -> String {
+        let mut output = String::new();
+        output.push_str("# Generated by beachhead-companion. Changes will be overwritten.\n");
+        for (backend, servers) in &self.backends {
+            let _ = write!(output, "\nbackend {}\n", backend);
+            for (i, &(ref host, port)) in servers.iter().enumerate() {
+                let _ = write!(output, "    server s{} {}:{}\n", i + 1, host, port);
+            }
+        }
+        output
+    }
+}
+
+impl<'a> From<&'a HaproxyConfig> for RunningConfig {
+    fn from(config: &'a HaproxyConfig) -> RunningConfig {
+        RunningConfig {
+            maps: config.maps.clone(),
+            slots: config.backends
+                .iter()
+                .map(|(backend, servers)| {
+                    (backend.clone(), servers.iter().map(|server| Some(server.clone())).collect())
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RunningConfig {
+    /// Whether the desired configuration can be reached without a reload (all backends exist
+    /// and have enough server slots).
+    fn can_update_to(&self, desired: &HaproxyConfig) -> bool {
+        desired.backends.iter().all(|(backend, servers)| {
+            self.slots.get(backend).map(|slots| slots.len() >= servers.len()).unwrap_or(false)
+        })
+    }
+}
+
+fn backend_name(spec: &DomainSpec, scheme: &str) -> String {
+    format!("beachhead_{}_{}", spec.spec_id().to_lowercase(), scheme)
+}
+
+fn render_map(map: &BTreeMap<String, String>) -> String {
+    let mut output = String::new();
+    for (domain, backend) in map {
+        let _ = write!(output, "{} {}\n", domain, backend);
+    }
+    output
+}
+
+/// Send a single command to the runtime API socket and check the response.
+fn runtime_command(socket: &str, command: &str) -> Result<(), HaproxyError> {
+    debug!("HAProxy runtime API: {}", command);
+    let mut response = String::new();
+    try_!(UnixStream::connect(socket).and_then(|mut stream| {
+              let timeout = Some(Duration::from_secs(SOCKET_TIMEOUT_SECONDS));
+              try!(stream.set_read_timeout(timeout));
+              try!(stream.set_write_timeout(timeout));
+              try!(stream.write_all(command.as_bytes()));
+              try!(stream.write_all(b"\n"));
+              stream.read_to_string(&mut response)
+          }),
+          socket.to_owned());
+    let response = response.trim();
+    // Successful commands answer with nothing, except for address changes.
+    if response.is_empty() || response.contains("changed from") ||
+       response.contains("no need to change") {
+        Ok(())
+    } else {
+        Err(HaproxyError::Rejected(command.to_owned(), response.to_owned()))
+    }
+}
+
+// ############### HAPROXY ERROR ##########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum HaproxyError {
+        File(err: FileError) {
+            description("Failed to write HAProxy configuration.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+            from()
+        }
+        Command(err: CommandError) {
+            description("Failed to reload HAProxy.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+            from()
+        }
+        Socket(socket: String, err: io::Error) {
+            description("Failed to talk to the HAProxy runtime API.")
+            cause(err)
+            display(me) -> ("{} Socket: {}, error: {}", me.description(), socket, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+        Rejected(command: String, response: String) {
+            description("HAProxy rejected runtime API command.")
+            display(me) -> ("{} Command: {}, response: {}", me.description(), command, response)
+        }
+    }
+}
+
+impl PublishingInnerError for HaproxyError {}
+
+impl Publish for HaproxyPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.publish(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.withdraw(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) {
+        self.aggregate.begin_batch();
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.aggregate.end_batch();
+        Ok(try!(self.render()))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.haproxy_directory != self.config.haproxy_directory {
+            // The new files haven't been loaded by HAProxy yet.
+            self.running_opt = None;
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+    use common::{self, Config};
+    use publisher::file::read_if_exists;
+    use publisher::{Publication, Publish};
+
+    /// Stand-in for the HAProxy runtime API. Records commands and accepts all of them.
+    struct RuntimeApi {
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RuntimeApi {
+        fn start(path: &PathBuf) -> RuntimeApi {
+            let listener = UnixListener::bind(path).unwrap();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let thread_commands = commands.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    };
+                    let mut command = String::new();
+                    BufReader::new(&stream).read_line(&mut command).unwrap();
+                    let command = command.trim().to_owned();
+                    let response = if command.contains(" addr ") {
+                        "IP changed from '172.17.0.2' to '172.17.0.9' by 'stats socket command'\n"
+                    } else {
+                        "\n"
+                    };
+                    let mut writer = &stream;
+                    let _ = writer.write_all(response.as_bytes());
+                    thread_commands.lock().unwrap().push(command);
+                }
+            });
+            RuntimeApi { commands: commands }
+        }
+
+        fn take_commands(&self) -> Vec<String> {
+            let mut commands = self.commands.lock().unwrap();
+            commands.drain(..).collect()
+        }
+    }
+
+    /// A fresh directory (with runtime API stand-in) for each test.
+    fn test_setup(test_name: &str) -> (Config, PathBuf, RuntimeApi) {
+        let directory = common::test_directory(&format!("haproxy-publisher-{}", test_name));
+        let socket = directory.join("haproxy.sock");
+        let api = RuntimeApi::start(&socket);
+
+        let mut config = Config::default();
+        config.haproxy_directory = Rc::new(directory.to_string_lossy().into_owned());
+        config.haproxy_socket = Some(Rc::new(socket.to_string_lossy().into_owned()));
+        config.haproxy_reload_command = Some(Rc::new(format!("echo reload >> {}",
+                                                             directory.join("reloads")
+                                                                 .display())));
+        (config, directory, api)
+    }
+
+    fn publication(key: &str, host: &str, domain_name: &str) -> Publication {
+        common::test_publication(key, host, vec![common::test_spec(domain_name, Some(8080), None)])
+    }
+
+    fn reloads(directory: &PathBuf) -> usize {
+        read_if_exists(&directory.join("reloads")).map(|r| r.lines().count()).unwrap_or(0)
+    }
+
+    fn refresh(publisher: &mut HaproxyPublisher, publications: &[Publication]) {
+        publisher.begin_batch();
+        for publication in publications {
+            publisher.publish(publication).expect("Publish");
+        }
+        publisher.flush().expect("Flush");
+    }
+
+    #[test]
+    fn files() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, _api) = test_setup("files");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org"),
+                  publication("web2", "172.17.0.3", "www.example.org")]);
+
+        // #### THEN  ####
+        assert_eq!(read_if_exists(&directory.join("http.map")),
+                   Some("www.example.org beachhead_www_example_org_http\n".to_owned()));
+        assert_eq!(read_if_exists(&directory.join("https.map")), Some("".to_owned()));
+        let backends = read_if_exists(&directory.join(BACKENDS_FILE)).expect("Backends");
+        assert!(backends.contains("\nbackend beachhead_www_example_org_http\n    server s1 \
+                                   172.17.0.2:8080\n    server s2 172.17.0.3:8080\n"),
+                "Backends: {}",
+                backends);
+        assert_eq!(reloads(&directory), 1);
+    }
+
+    #[test]
+    fn path_prefix_map() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, _api) = test_setup("path_prefix_map");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        let mut api = publication("api1", "172.17.0.4", "www.example.org");
+        api.specs[0].path_prefix = Some("/api".to_owned());
+
+        // #### WHEN  ####
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org"), api]);
+
+        // #### THEN  ####
+        assert_eq!(read_if_exists(&directory.join("http.map")),
+                   Some("www.example.org beachhead_www_example_org_http\n".to_owned()));
+        assert_eq!(read_if_exists(&directory.join("http_paths.map")),
+                   Some("www.example.org/api beachhead_www_example_org_api_http\n".to_owned()));
+        let backends = read_if_exists(&directory.join(BACKENDS_FILE)).expect("Backends");
+        assert!(backends.contains("\nbackend beachhead_www_example_org_api_http\n    server s1 \
+                                   172.17.0.4:8080\n"),
+                "Backends: {}",
+                backends);
+    }
+
+    #[test]
+    fn reload_retried() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, directory, api) = test_setup("reload_retried");
+        let reload_marker = directory.join("haproxy-down");
+        config.haproxy_reload_command = Some(Rc::new(format!("test ! -e {} && echo reload >> {}",
+                                                             reload_marker.display(),
+                                                             directory.join("reloads")
+                                                                 .display())));
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        let web1 = publication("web1", "172.17.0.2", "www.example.org");
+        File::create(&reload_marker).unwrap();
+        let result = publisher.publish(&web1);
+        fs::remove_file(&reload_marker).unwrap();
+
+        // #### WHEN  ####
+        refresh(&mut publisher, &[web1]);
+
+        // #### THEN  ####
+        assert!(result.is_err(), "The failed reload should be reported");
+        assert_eq!(reloads(&directory), 1, "The reload should be retried");
+        assert!(api.take_commands().is_empty());
+    }
+
+    #[test]
+    fn no_reload_command() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, directory, api) = test_setup("no_reload_command");
+        config.haproxy_reload_command = None;
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org")]);
+
+        // #### WHEN  ####
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.9", "www.example.org")]);
+
+        // #### THEN  ####
+        assert!(api.take_commands().is_empty(),
+                "HAProxy may not have loaded the backends the runtime API would update");
+        assert!(publisher.running_opt.is_none());
+        assert!(publisher.reload_pending);
+        let backends = read_if_exists(&directory.join(BACKENDS_FILE)).expect("Backends");
+        assert!(backends.contains("server s1 172.17.0.9:8080"), "Backends: {}", backends);
+    }
+
+    #[test]
+    fn address_changed() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, api) = test_setup("address_changed");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org")]);
+
+        // #### WHEN  ####
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.9", "www.example.org")]);
+
+        // #### THEN  ####
+        assert_eq!(reloads(&directory), 1);
+        assert_eq!(api.take_commands(),
+                   vec!["set server beachhead_www_example_org_http/s1 addr 172.17.0.9 port 8080"]);
+    }
+
+    #[test]
+    fn server_and_domain_removed() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, api) = test_setup("server_and_domain_removed");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org"),
+                  publication("web2", "172.17.0.3", "www.example.org"),
+                  publication("admin", "172.17.0.4", "admin.example.org")]);
+
+        // #### WHEN  ####
+        publisher.withdraw(&publication("web2", "172.17.0.3", "www.example.org")).unwrap();
+        publisher.withdraw(&publication("admin", "172.17.0.4", "admin.example.org")).unwrap();
+
+        // #### THEN  ####
+        assert_eq!(reloads(&directory), 1);
+        let map = directory.join("http.map");
+        assert_eq!(api.take_commands(),
+                   vec!["set server beachhead_www_example_org_http/s2 state maint".to_owned(),
+                        "set server beachhead_admin_example_org_http/s1 state maint".to_owned(),
+                        format!("del map {} admin.example.org", map.display())]);
+    }
+
+    #[test]
+    fn server_returns() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, api) = test_setup("server_returns");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        let web1 = publication("web1", "172.17.0.2", "www.example.org");
+        let web2 = publication("web2", "172.17.0.3", "www.example.org");
+        refresh(&mut publisher, &[web1.clone(), web2.clone()]);
+        publisher.withdraw(&web2).unwrap();
+        api.take_commands();
+
+        // #### WHEN  ####
+        publisher.publish(&web2).unwrap();
+
+        // #### THEN  ####
+        assert_eq!(reloads(&directory), 1);
+        assert_eq!(api.take_commands(),
+                   vec!["set server beachhead_www_example_org_http/s2 addr 172.17.0.3 port 8080",
+                        "set server beachhead_www_example_org_http/s2 state ready"]);
+    }
+
+    #[test]
+    fn new_backend_reloads() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory, api) = test_setup("new_backend_reloads");
+        let mut publisher = HaproxyPublisher::new(Arc::new(config));
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org")]);
+
+        // #### WHEN  ####
+        refresh(&mut publisher,
+                &[publication("web1", "172.17.0.2", "www.example.org"),
+                  publication("web2", "172.17.0.3", "www.example.org")]);
+
+        // #### THEN  ####
+        assert_eq!(reloads(&directory), 2);
+        assert!(api.take_commands().is_empty());
+    }
+}
@@ -20,17 +20,23 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use rustc_serialize::json::{self, ToJson};
+use std::collections::BTreeMap;
 
-use domain_spec::DomainSpec;
+use rustc_serialize::json::{self, Json, ToJson};
+
+use domain_spec::{DomainOption, DomainSpec};
 use super::*;
 
 pub const JSON_HOST: &'static str = "host";
 pub const JSON_PORT: &'static str = "port";
 pub const JSON_ID: &'static str = "id";
 pub const JSON_DOMAIN: &'static str = "domain";
+pub const JSON_PATH: &'static str = "path";
+pub const JSON_OPTIONS: &'static str = "options";
 pub const JSON_HTTP: &'static str = "http";
 pub const JSON_HTTPS: &'static str = "https";
+pub const JSON_SERVERS: &'static str = "servers";
+pub const JSON_KEY: &'static str = "key";
 
 pub fn svc_config<T: ToJson>(domain_config: &mut json::Object, field: &str, value_opt: Option<T>) {
     if let Some(value) = value_opt {
@@ -45,10 +51,32 @@ pub fn backend_setup(host: &str, port: u16) -> Option<json::Object> {
     Some(setup)
 }
 
+/// Routing options by name: `{"redirect-https": true, "max-body": 10485760}`. None if there are
+/// no options.
+fn options_config<'a, I>(options: I) -> Option<json::Object>
+    where I: IntoIterator<Item = &'a DomainOption>
+{
+    let mut config = json::Object::new();
+    for option in options {
+        let value = match *option {
+            DomainOption::RedirectHttps | DomainOption::Websocket => Json::Boolean(true),
+            DomainOption::MaxBody(size) => size.to_json(),
+        };
+        config.insert(option.name().to_owned(), value);
+    }
+    if config.is_empty() {
+        None
+    } else {
+        Some(config)
+    }
+}
+
 pub fn domain_config(container_host: &str, spec: &DomainSpec) -> json::Object {
     let mut domain_config = json::Object::new();
     svc_config(&mut domain_config, JSON_ID, Some(spec.spec_id()));
     svc_config(&mut domain_config, JSON_DOMAIN, Some(spec.domain_name.clone()));
+    svc_config(&mut domain_config, JSON_PATH, spec.path_prefix.clone());
+    svc_config(&mut domain_config, JSON_OPTIONS, options_config(spec.options.values()));
     svc_config(&mut domain_config,
                JSON_HTTP,
                spec.http_port.map(|http_port| backend_setup(&container_host, http_port)));
@@ -67,12 +95,69 @@ pub fn domain_configs(container_host: &str, specs: &[DomainSpec]) -> json::Array
     configs
 }
 
+/// The domains (and path prefixes) of all publications, sorted by name. Publications that serve
+/// the same domain and path end up as servers of the same backend:
+/// `{"id": ..., "domain": ..., "path": ..., "options": {...},
+///   "http": {"servers": [{"host": ..., "port": ..., "key": ...}]}}`
+/// A path prefix sorts after the whole domain. The options of all servers are combined.
+/// Used by publishers that render the complete configuration of a reverse proxy.
+pub fn domain_backends<'a, I>(publications: I) -> json::Array
+    where I: IntoIterator<Item = &'a Publication>
+{
+    // (domain name, path prefix) -> (id, options, http servers, https servers)
+    let mut domains: BTreeMap<(String, Option<String>),
+                              (String, BTreeMap<&str, DomainOption>, Vec<Json>, Vec<Json>)> =
+        BTreeMap::new();
+    for publication in publications {
+        for spec in &publication.specs {
+            let entry = domains.entry((spec.domain_name.clone(), spec.path_prefix.clone()))
+                .or_insert_with(|| (spec.spec_id(), BTreeMap::new(), Vec::new(), Vec::new()));
+            entry.1.extend(spec.options.iter().map(|(name, option)| (*name, option.clone())));
+            let server = |port: u16| {
+                let mut server = backend_setup(&publication.host, port).unwrap();
+                server.insert(JSON_KEY.to_owned(), publication.key.to_json());
+                Json::Object(server)
+            };
+            if let Some(http_port) = spec.http_port {
+                entry.2.push(server(http_port));
+            }
+            if let Some(https_port) = spec.https_port {
+                entry.3.push(server(https_port));
+            }
+        }
+    }
+
+    let backend = |servers: Vec<Json>| {
+        if servers.is_empty() {
+            None
+        } else {
+            let mut backend = json::Object::new();
+            backend.insert(JSON_SERVERS.to_owned(), Json::Array(servers));
+            Some(backend)
+        }
+    };
+    let mut backends = json::Array::new();
+    for ((domain_name, path_prefix), (id, options, http_servers, https_servers)) in domains {
+        let mut domain_backend = json::Object::new();
+        svc_config(&mut domain_backend, JSON_ID, Some(id));
+        svc_config(&mut domain_backend, JSON_DOMAIN, Some(domain_name));
+        svc_config(&mut domain_backend, JSON_PATH, path_prefix);
+        svc_config(&mut domain_backend, JSON_OPTIONS, options_config(options.values()));
+        svc_config(&mut domain_backend, JSON_HTTP, backend(http_servers));
+        svc_config(&mut domain_backend, JSON_HTTPS, backend(https_servers));
+        backends.push(Json::Object(domain_backend));
+    }
+    backends
+}
+
 // ############### PUBLISHING ERROR #######################
 impl PublishingInnerError for json::EncoderError {}
 
 // ############### TESTING ################################
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use super::*;
     use common;
     use domain_spec::DomainSpec;
@@ -86,8 +171,10 @@ mod tests {
         let host = "app-server";
         let spec = DomainSpec {
             domain_name: "example.org".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: Some(8043),
+            options: BTreeMap::new(),
         };
 
         // #### WHEN  ####
@@ -104,8 +191,10 @@ mod tests {
         let host = "app-server";
         let spec = DomainSpec {
             domain_name: "example.org".to_owned(),
+            path_prefix: None,
             http_port: None,
             https_port: Some(8043),
+            options: BTreeMap::new(),
         };
 
         // #### WHEN  ####
@@ -122,8 +211,10 @@ mod tests {
         let host = "app-server";
         let spec = DomainSpec {
             domain_name: "example.org".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: None,
+            options: BTreeMap::new(),
         };
 
         // #### WHEN  ####
@@ -141,8 +232,10 @@ mod tests {
         let host = "app-server";
         let spec = DomainSpec {
             domain_name: "example.org".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
 
         // #### WHEN  ####
@@ -159,13 +252,17 @@ mod tests {
         let host = "app-server";
         let spec1 = DomainSpec {
             domain_name: "example.org".to_owned(),
+            path_prefix: None,
             http_port: Some(80),
             https_port: Some(443),
+            options: BTreeMap::new(),
         };
         let spec2 = DomainSpec {
             domain_name: "www.example.org".to_owned(),
+            path_prefix: None,
             http_port: Some(8080),
             https_port: Some(8043),
+            options: BTreeMap::new(),
         };
 
         // #### WHEN  ####
@@ -185,6 +282,91 @@ mod tests {
         }
     }
 
+    #[test]
+    fn domain_backends_grouped() {
+        common::init_log();
+        // #### GIVEN ####
+        let specs = vec![common::test_spec("www.example.org", Some(8080), None),
+                         common::test_spec("admin.example.org", None, Some(8043))];
+        let web1 = common::test_publication("web1", "172.17.0.2", specs);
+        let specs = vec![common::test_spec("www.example.org", Some(8080), Some(8043))];
+        let web2 = common::test_publication("web2", "172.17.0.3", specs);
+
+        // #### WHEN  ####
+        let backends = domain_backends(vec![&web1, &web2]).to_json();
+
+        // #### THEN  ####
+        let backends = backends.as_array().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0].find(JSON_DOMAIN).and_then(|d| d.as_string()),
+                   Some("admin.example.org"));
+        assert!(backends[0].find(JSON_HTTP).is_none());
+        let www = &backends[1];
+        assert_eq!(www.find(JSON_ID).and_then(|d| d.as_string()), Some("www_example_org"));
+        let http_servers = www.find_path(&[JSON_HTTP, JSON_SERVERS]).unwrap().as_array().unwrap();
+        assert_eq!(http_servers.len(), 2);
+        assert_eq!(http_servers[1].find(JSON_HOST).and_then(|h| h.as_string()),
+                   Some("172.17.0.3"));
+        assert_eq!(http_servers[1].find(JSON_KEY).and_then(|k| k.as_string()), Some("web2"));
+        let https_servers =
+            www.find_path(&[JSON_HTTPS, JSON_SERVERS]).unwrap().as_array().unwrap();
+        assert_eq!(https_servers.len(), 1);
+    }
+
+    #[test]
+    fn path_and_options() {
+        common::init_log();
+        // #### GIVEN ####
+        let host = "app-server";
+        let mut specs = Vec::new();
+        DomainSpec::parse_all("example.org/api:http=8080:websocket:max-body=10m", &mut specs)
+            .expect("Parse domain spec with path and options");
+
+        // #### WHEN  ####
+        let cfg = domain_config(host, &specs[0]).to_json();
+
+        // #### THEN  ####
+        assert_eq_domain_spec(&cfg, &host, &specs[0]);
+        assert_eq!(cfg.find(JSON_ID).and_then(|id| id.as_string()), Some("example_org_api"));
+        assert_eq!(cfg.find(JSON_PATH).and_then(|path| path.as_string()), Some("/api"));
+        let options = cfg.find(JSON_OPTIONS).and_then(|options| options.as_object()).unwrap();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options.get("websocket"), Some(&Json::Boolean(true)));
+        assert_eq!(options.get("max-body"), Some(&Json::U64(10 * 1024 * 1024)));
+    }
+
+    #[test]
+    fn domain_backends_by_path() {
+        common::init_log();
+        // #### GIVEN ####
+        let publication = |key: &str, host: &str, raw_specs: &str| {
+            let mut specs = Vec::new();
+            DomainSpec::parse_all(raw_specs, &mut specs).expect("Parse domain specs");
+            common::test_publication(key, host, specs)
+        };
+        let web = publication("web", "172.17.0.2", "example.org:http=8080:redirect-https");
+        let api1 = publication("api1", "172.17.0.3", "example.org/api:http=3000:websocket");
+        let api2 = publication("api2", "172.17.0.4", "example.org/api/:http=3000");
+
+        // #### WHEN  ####
+        let backends = domain_backends(vec![&web, &api1, &api2]).to_json();
+
+        // #### THEN  ####
+        let backends = backends.as_array().unwrap();
+        assert_eq!(backends.len(), 2, "Each path prefix should get its own backend.");
+        let root = &backends[0];
+        assert!(root.find(JSON_PATH).is_none());
+        assert!(root.find_path(&[JSON_OPTIONS, "redirect-https"]).is_some());
+        let api = &backends[1];
+        assert_eq!(api.find(JSON_DOMAIN).and_then(|d| d.as_string()), Some("example.org"));
+        assert_eq!(api.find(JSON_PATH).and_then(|p| p.as_string()), Some("/api"));
+        assert_eq!(api.find(JSON_ID).and_then(|d| d.as_string()), Some("example_org_api"));
+        assert!(api.find_path(&[JSON_OPTIONS, "websocket"]).is_some(),
+                "Options of all servers should be combined.");
+        let http_servers = api.find_path(&[JSON_HTTP, JSON_SERVERS]).unwrap().as_array().unwrap();
+        assert_eq!(http_servers.len(), 2);
+    }
+
     fn assert_eq_domain_spec(val: &Json, host: &str, domain_spec: &DomainSpec) {
         fn assert_backend_spec(obj: &json::Object,
                                field: &str,
@@ -215,6 +397,17 @@ mod tests {
             &Json::Object(ref obj) => {
                 assert_json_obj_field_eq(obj, JSON_ID, spec_id.as_str());
                 assert_json_obj_field_eq(obj, JSON_DOMAIN, domain_spec.domain_name.as_str());
+                match domain_spec.path_prefix {
+                    Some(ref path_prefix) => {
+                        assert_json_obj_field_eq(obj, JSON_PATH, path_prefix.as_str())
+                    }
+                    None => assert_json_no_obj_field(obj, JSON_PATH),
+                }
+                if domain_spec.options.is_empty() {
+                    assert_json_no_obj_field(obj, JSON_OPTIONS);
+                } else {
+                    assert_json_obj_field_present(obj, JSON_OPTIONS);
+                }
                 assert_backend_spec(obj, JSON_HTTP, host, domain_spec.http_port);
                 assert_backend_spec(obj, JSON_HTTPS, host, domain_spec.https_port);
             }
@@ -26,24 +26,43 @@ use std::cell::RefCell;
 use std::sync::Arc;
 use std::error::Error;
 
+use common::Config;
 use super::{Publication, PublishingError, PublishingInnerError, Publish};
 
 pub struct MockPublisher {
     pub publications: Vec<Publication>,
+    pub withdrawals: Vec<Publication>,
     pub error_trigger: Option<(String, Box<Fn() -> PublishingError>)>,
+    /// How often the publisher has been reconfigured.
+    pub reconfigurations: usize,
+    /// How often the publisher has been flushed.
+    pub flushes: usize,
+    /// Makes `flush` fail.
+    pub flush_error: Option<Box<Fn() -> PublishingError>>,
 }
 
 impl Default for MockPublisher {
     fn default() -> MockPublisher {
-        MockPublisher { error_trigger: None, publications: Vec::new() }
+        MockPublisher {
+            error_trigger: None,
+            publications: Vec::new(),
+            withdrawals: Vec::new(),
+            reconfigurations: 0,
+            flushes: 0,
+            flush_error: None,
+        }
     }
 }
 
 impl Debug for MockPublisher {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-               "MockPublisher {{ publications: {:?}, error_trigger: {:?} }}",
+               "MockPublisher {{ publications: {:?}, withdrawals: {:?}, reconfigurations: {}, \
+                flushes: {}, error_trigger: {:?} }}",
                self.publications,
+               self.withdrawals,
+               self.reconfigurations,
+               self.flushes,
                self.error_trigger.as_ref().map(|p| {
                    let &(ref k, _) = p;
                    (k, "*")
@@ -51,17 +70,43 @@ impl Debug for MockPublisher {
     }
 }
 
-impl Publish for MockPublisher {
-    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+impl MockPublisher {
+    fn check_error_trigger(&self, publication: &Publication) -> Result<(), PublishingError> {
         if let Some((ref trigger, ref error)) = self.error_trigger {
             let specs = &publication.specs;
             if specs.into_iter().any(|spec| spec.domain_name.contains(trigger)) {
                 return Err(error());
             }
         }
+        Ok(())
+    }
+}
+
+impl Publish for MockPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        try!(self.check_error_trigger(publication));
         self.publications.push(publication.clone());
         Ok(())
     }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        try!(self.check_error_trigger(publication));
+        self.withdrawals.push(publication.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.flushes += 1;
+        match self.flush_error {
+            Some(ref error) => Err(error()),
+            None => Ok(()),
+        }
+    }
+
+    fn reconfigure(&mut self, _config: Arc<Config>) -> Result<(), PublishingError> {
+        self.reconfigurations += 1;
+        Ok(())
+    }
 }
 
 /// Runtime checked reference to allow a mock publisher to be inspected even after it has been
@@ -70,6 +115,22 @@ impl Publish for Arc<RefCell<MockPublisher>> {
     fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
         (*self).borrow_mut().publish(publication)
     }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        (*self).borrow_mut().withdraw(publication)
+    }
+
+    fn begin_batch(&mut self) {
+        (*self).borrow_mut().begin_batch()
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        (*self).borrow_mut().flush()
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        (*self).borrow_mut().reconfigure(config)
+    }
 }
 
 #[derive(Debug,Clone,Eq,PartialEq)]
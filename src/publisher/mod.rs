@@ -22,27 +22,61 @@
 
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::sync::Arc;
 
+use common::Config;
 use domain_spec::DomainSpec;
 
 /// Abstract interface for the component that publishes the current configuration state to whatever
 /// system needs to be informed.
 pub trait Publish {
     fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError>;
+
+    /// Remove a previous publication (e.g., because its container has disappeared). Receives the
+    /// publication as it was last published.
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError>;
+
+    /// Marks the start of a refresh iteration. Publishers may hold back the publications and
+    /// withdrawals that follow until `flush` is called (e.g., to send them in one round trip).
+    fn begin_batch(&mut self) {}
+
+    /// Sends everything held back since `begin_batch`. An error concerns the batch as a whole.
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        Ok(())
+    }
+
+    /// Switch to a new configuration (e.g., after it has been reloaded). Connections are only
+    /// re-established if their settings changed. Publications made under the old configuration
+    /// are left alone (they expire unless published again).
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError>;
 }
 
 #[derive(Debug, Clone)]
 pub struct Publication {
+    /// Identifies the publication (e.g., as part of the redis key). Usually the same as `host`.
+    pub key: String,
     pub host: String,
     pub specs: Vec<DomainSpec>,
 }
 
 pub mod redis;
+pub mod etcd;
+pub mod consul;
+pub mod file;
+pub mod nginx;
+pub mod aggregate;
+pub mod haproxy;
+pub mod traefik;
+pub mod caddy;
+pub mod webhook;
+pub mod composite;
 #[cfg(test)]
 pub mod mock_publisher;
 mod json_serializer;
 #[cfg(test)]
 mod test_redis_publisher;
+#[cfg(test)]
+mod test_etcd_publisher;
 
 // ############### PUBLISHING ERROR #######################
 
@@ -0,0 +1,451 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Renders all current publications into an nginx configuration file using a user-supplied
+//! template (see `template`). The configuration is only replaced (and nginx only reloaded) when
+//! the rendered output changes.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustc_serialize::json::{self, Json, ToJson};
+
+use common::Config;
+use template::{Template, TemplateError};
+use super::*;
+use super::aggregate::{self, Aggregate, CommandError};
+use super::file::{self, FileError};
+use super::json_serializer::{self, JSON_DOMAIN, JSON_HTTP, JSON_HTTPS, JSON_ID, JSON_OPTIONS,
+                             JSON_PATH, JSON_SERVERS};
+
+pub const JSON_DOMAINS: &'static str = "domains";
+pub const JSON_PUBLICATIONS: &'static str = "publications";
+pub const JSON_SITES: &'static str = "sites";
+pub const JSON_LOCATIONS: &'static str = "locations";
+
+pub struct NginxPublisher {
+    config: Arc<Config>,
+    aggregate: Aggregate,
+    /// The configuration file has changed, but nginx hasn't been reloaded successfully since.
+    reload_pending: bool,
+}
+
+impl NginxPublisher {
+    pub fn new(config: Arc<Config>) -> NginxPublisher {
+        NginxPublisher {
+            config: config,
+            aggregate: Aggregate::new(),
+            reload_pending: false,
+        }
+    }
+
+    /// Data that templates are rendered with:
+    /// `{"domains": json_serializer::domain_backends(...), "sites": sites(...), "publications":
+    /// [{"key": ..., "host": ..., "domains": json_serializer::domain_configs(...)}]}`
+    pub fn render_data(&self) -> Json {
+        let mut publications = json::Array::new();
+        for publication in self.aggregate.publications() {
+            let mut entry = json::Object::new();
+            entry.insert(json_serializer::JSON_KEY.to_owned(), publication.key.to_json());
+            entry.insert(json_serializer::JSON_HOST.to_owned(), publication.host.to_json());
+            entry.insert(JSON_DOMAINS.to_owned(),
+                         Json::Array(json_serializer::domain_configs(&publication.host,
+                                                                     &publication.specs)));
+            publications.push(Json::Object(entry));
+        }
+        let domains = json_serializer::domain_backends(self.aggregate.publications());
+
+        let mut data = json::Object::new();
+        data.insert(JSON_SITES.to_owned(), Json::Array(sites(&domains)));
+        data.insert(JSON_DOMAINS.to_owned(), Json::Array(domains));
+        data.insert(JSON_PUBLICATIONS.to_owned(), Json::Array(publications));
+        Json::Object(data)
+    }
+
+    /// Render the configuration. If it has changed, write it, check it and reload nginx. A failed
+    /// reload is retried with the next rendering, even if the configuration stays the same.
+    fn render(&mut self) -> Result<(), NginxError> {
+        self.aggregate.expire(self.config.expire_seconds);
+
+        let template_path = match self.config.nginx_template {
+            Some(ref path) => path.clone(),
+            None => return Err(NginxError::NoTemplate),
+        };
+        let template_text = try!(file::read_if_exists(Path::new(&*template_path))
+            .ok_or_else(|| NginxError::TemplateMissing((*template_path).clone())));
+        let template = try_!(Template::parse(&template_text), (*template_path).clone());
+        let output = template.render(&self.render_data());
+
+        let output_path = Path::new(&*self.config.nginx_output);
+        let previous = file::read_if_exists(output_path);
+        if try!(file::write_if_changed(output_path, &output)) {
+            info!("nginx configuration {} has changed.", output_path.display());
+            if let Some(ref check_command) = self.config.nginx_check_command {
+                if let Err(e) = aggregate::run(check_command) {
+                    // Don't leave a broken configuration behind for the next nginx (re)start.
+                    match previous {
+                        Some(ref previous) => try!(file::write_if_changed(output_path, previous)),
+                        None => try!(file::remove_file(output_path).map(|_| true)),
+                    };
+                    return Err(From::from(e));
+                }
+            }
+            self.reload_pending = true;
+        } else if !self.reload_pending {
+            return Ok(());
+        }
+
+        if let Some(ref reload_command) = self.config.nginx_reload_command {
+            try!(aggregate::run(reload_command));
+        }
+        self.reload_pending = false;
+        Ok(())
+    }
+}
+
+/// The backends of `json_serializer::domain_backends` grouped by domain, so that templates can
+/// emit a single `server` block per domain and scheme with a `location` for each path prefix:
+/// `[{"domain": ..., "http": {"locations": [{"id": ..., "path": ..., "options": {...},
+/// "servers": [...]}]}, "https": {...}}]`. The path of the whole domain is "/".
+pub fn sites(backends: &json::Array) -> json::Array {
+    // domain name -> (http locations, https locations)
+    let mut sites: BTreeMap<String, (json::Array, json::Array)> = BTreeMap::new();
+    for backend in backends {
+        let domain_name = backend.find(JSON_DOMAIN).and_then(|d| d.as_string()).unwrap_or("");
+        let site = sites.entry(domain_name.to_owned()).or_insert_with(|| (Vec::new(), Vec::new()));
+        if let Some(location) = location(backend, JSON_HTTP) {
+            site.0.push(location);
+        }
+        if let Some(location) = location(backend, JSON_HTTPS) {
+            site.1.push(location);
+        }
+    }
+
+    let locations = |locations: json::Array| {
+        if locations.is_empty() {
+            None
+        } else {
+            let mut scheme = json::Object::new();
+            scheme.insert(JSON_LOCATIONS.to_owned(), Json::Array(locations));
+            Some(scheme)
+        }
+    };
+    let mut result = json::Array::new();
+    for (domain_name, (http_locations, https_locations)) in sites {
+        let mut site = json::Object::new();
+        json_serializer::svc_config(&mut site, JSON_DOMAIN, Some(domain_name));
+        json_serializer::svc_config(&mut site, JSON_HTTP, locations(http_locations));
+        json_serializer::svc_config(&mut site, JSON_HTTPS, locations(https_locations));
+        result.push(Json::Object(site));
+    }
+    result
+}
+
+/// The location for the `scheme` ("http" or "https") servers of `backend`, if it has any.
+fn location(backend: &Json, scheme: &str) -> Option<Json> {
+    backend.find(scheme).and_then(|servers| servers.find(JSON_SERVERS)).map(|servers| {
+        let mut location = json::Object::new();
+        location.insert(JSON_ID.to_owned(), backend.find(JSON_ID).cloned().unwrap_or(Json::Null));
+        location.insert(JSON_PATH.to_owned(),
+                        backend.find(JSON_PATH).cloned().unwrap_or_else(|| "/".to_json()));
+        let options = backend.find(JSON_OPTIONS).cloned();
+        json_serializer::svc_config(&mut location, JSON_OPTIONS, options);
+        location.insert(JSON_SERVERS.to_owned(), servers.clone());
+        Json::Object(location)
+    })
+}
+
+impl Publish for NginxPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.publish(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.withdraw(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) {
+        self.aggregate.begin_batch();
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.aggregate.end_batch();
+        Ok(try!(self.render()))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        // Template and output are looked at anew with every rendering.
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### NGINX ERROR ############################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum NginxError {
+        NoTemplate {
+            description("No nginx configuration template configured (--nginx-template).")
+        }
+        TemplateMissing(path: String) {
+            description("Failed to read nginx configuration template.")
+            display(me) -> ("{} File: {}", me.description(), path)
+        }
+        Template(path: String, err: TemplateError) {
+            description("Invalid nginx configuration template.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path, err)
+            from(e: (TemplateError, String)) -> (e.1, e.0)
+        }
+        File(err: FileError) {
+            description("Failed to write nginx configuration.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+            from()
+        }
+        Command(err: CommandError) {
+            description("Failed to update nginx configuration.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+            from()
+        }
+    }
+}
+
+impl PublishingInnerError for NginxError {}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::*;
+    use common::{self, Config};
+    use publisher::file::read_if_exists;
+    use publisher::{Publication, Publish};
+
+    const TEMPLATE: &'static str = "\
+{{#domains}}
+{{#http}}
+upstream {{id}}_http {
+{{#servers}}
+    server {{host}}:{{port}};
+{{/servers}}
+}
+server {
+    listen 80;
+    server_name {{domain}};
+    location / { proxy_pass http://{{id}}_http; }
+}
+{{/http}}
+{{/domains}}
+";
+
+    /// A fresh directory with the template for each test.
+    fn test_config(test_name: &str) -> (Config, PathBuf) {
+        let directory = common::test_directory(&format!("nginx-publisher-{}", test_name));
+        let template_path = directory.join("nginx.conf.mustache");
+        File::create(&template_path).unwrap().write_all(TEMPLATE.as_bytes()).unwrap();
+
+        let mut config = Config::default();
+        config.nginx_template = Some(Rc::new(template_path.to_string_lossy().into_owned()));
+        config.nginx_output = Rc::new(directory.join("beachhead.conf")
+            .to_string_lossy()
+            .into_owned());
+        config.nginx_reload_command = Some(Rc::new(format!("echo reload >> {}",
+                                                           directory.join("reloads")
+                                                               .display())));
+        (config, directory)
+    }
+
+    fn publication(key: &str, host: &str) -> Publication {
+        common::test_publication(key,
+                                 host,
+                                 vec![common::test_spec("www.example.com", Some(8080), None)])
+    }
+
+    fn reloads(directory: &PathBuf) -> usize {
+        read_if_exists(&directory.join("reloads")).map(|r| r.lines().count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn render() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("render");
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish");
+        publisher.publish(&publication("web2", "172.17.0.3")).expect("Publish");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let output = read_if_exists(&directory.join("beachhead.conf")).expect("Output");
+        assert_eq!(output,
+                   "upstream www_example_com_http {\n    server 172.17.0.2:8080;\n    server \
+                    172.17.0.3:8080;\n}\nserver {\n    listen 80;\n    server_name \
+                    www.example.com;\n    location / { proxy_pass http://www_example_com_http; \
+                    }\n}\n");
+        assert_eq!(reloads(&directory), 1);
+    }
+
+    #[test]
+    fn render_paths() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("render_paths");
+        File::create(directory.join("nginx.conf.mustache"))
+            .unwrap()
+            .write_all(include_str!("../../doc/nginx.conf.mustache").as_bytes())
+            .unwrap();
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+        let mut api = publication("api1", "172.17.0.4");
+        api.specs[0].path_prefix = Some("/api".to_owned());
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish");
+        publisher.publish(&api).expect("Publish");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let output = read_if_exists(&directory.join("beachhead.conf")).expect("Output");
+        assert_eq!(output.matches("server_name www.example.com;").count(),
+                   1,
+                   "One server block per domain: {}",
+                   output);
+        assert!(output.contains("    location / {\n        proxy_pass \
+                                 http://www_example_com_http;"),
+                "{}",
+                output);
+        assert!(output.contains("    location /api {\n        proxy_pass \
+                                 http://www_example_com_api_http;"),
+                "{}",
+                output);
+        assert!(output.contains("upstream www_example_com_api_http {\n    server \
+                                 172.17.0.4:8080; # api1\n}"),
+                "{}",
+                output);
+    }
+
+    #[test]
+    fn reload_only_when_changed() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, directory) = test_config("reload_only_when_changed");
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+        publisher.begin_batch();
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish");
+        publisher.flush().expect("Flush");
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish again");
+        publisher.flush().expect("Flush again");
+        publisher.withdraw(&publication("web1", "172.17.0.2")).expect("Withdraw");
+
+        // #### THEN  ####
+        assert_eq!(reloads(&directory), 2);
+        let output = read_if_exists(&directory.join("beachhead.conf")).expect("Output");
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn check_failed() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, directory) = test_config("check_failed");
+        let check_marker = directory.join("broken");
+        config.nginx_check_command = Some(Rc::new(format!("test ! -e {}",
+                                                          check_marker.display())));
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish");
+        let good_output = read_if_exists(&directory.join("beachhead.conf")).expect("Output");
+
+        // #### WHEN  ####
+        File::create(&check_marker).unwrap();
+        let result = publisher.publish(&publication("web2", "172.17.0.3"));
+
+        // #### THEN  ####
+        assert!(result.is_err(), "Publication should fail");
+        assert_eq!(read_if_exists(&directory.join("beachhead.conf")),
+                   Some(good_output),
+                   "The previous configuration should be restored");
+        assert_eq!(reloads(&directory), 1);
+    }
+
+    #[test]
+    fn reload_retried() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, directory) = test_config("reload_retried");
+        let reload_marker = directory.join("nginx-down");
+        config.nginx_reload_command = Some(Rc::new(format!("test ! -e {} && echo reload >> {}",
+                                                           reload_marker.display(),
+                                                           directory.join("reloads")
+                                                               .display())));
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+        File::create(&reload_marker).unwrap();
+        let result = publisher.publish(&publication("web1", "172.17.0.2"));
+        fs::remove_file(&reload_marker).unwrap();
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        publisher.publish(&publication("web1", "172.17.0.2")).expect("Publish again");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        assert!(result.is_err(), "The failed reload should be reported");
+        assert_eq!(reloads(&directory), 1, "The reload should be retried");
+    }
+
+    #[test]
+    fn no_template() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, _) = test_config("no_template");
+        config.nginx_template = None;
+        let mut publisher = NginxPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let result = publisher.publish(&publication("web1", "172.17.0.2"));
+
+        // #### THEN  ####
+        assert!(result.is_err(), "Publication should fail");
+    }
+}
@@ -20,80 +20,409 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::cmp::min;
+use std::time::{Duration, Instant};
 
-use rustc_serialize::json;
+use rustc_serialize::{json, Decodable, Decoder};
 use redis as libredis;
-use redis::{RedisResult, Commands};
+use url::percent_encoding::percent_decode;
 
 use common::Config;
 use super::*;
 use super::json_serializer;
 
+/// Waiting time before the first reconnection attempt. Doubles with every failed attempt.
+const RETRY_MIN_SECONDS: u64 = 1;
+/// Upper bound for the waiting time between reconnection attempts.
+const RETRY_MAX_SECONDS: u64 = 60;
+
 pub struct RedisPublisher {
     config: Arc<Config>,
     redis_client_opt: Option<libredis::Client>,
+    /// Re-used across refresh iterations. Dropped when a command fails.
+    connection_opt: Option<libredis::Connection>,
+    /// Commands held back since `begin_batch` (sent as one MULTI/EXEC transaction on `flush`).
+    /// None outside of batches.
+    batch_opt: Option<libredis::Pipeline>,
+    batch_len: usize,
+    /// Waiting time before the next reconnection attempt (after the current one has passed).
+    retry_seconds: u64,
+    /// While Redis is unavailable: no connection attempts before this point in time.
+    retry_at: Option<Instant>,
 }
 
 impl RedisPublisher {
     pub fn new(config: Arc<Config>) -> RedisPublisher {
-        RedisPublisher { config: config, redis_client_opt: None }
+        RedisPublisher {
+            config: config,
+            redis_client_opt: None,
+            connection_opt: None,
+            batch_opt: None,
+            batch_len: 0,
+            retry_seconds: RETRY_MIN_SECONDS,
+            retry_at: None,
+        }
     }
 
-    fn create_redis_client(&mut self) -> RedisResult<&mut libredis::Client> {
+    fn create_redis_client(&mut self) -> Result<&mut libredis::Client, PublishingError> {
         if let Some(ref mut client) = self.redis_client_opt {
             Ok(client)
         } else {
-            let addr = libredis::ConnectionAddr::Tcp((*self.config.redis_host).clone(),
-                                                     self.config.redis_port);
-            let info = libredis::ConnectionInfo { addr: Box::new(addr), db: 0, passwd: None };
+            let url = &self.config.redis_url;
+            let passwd = match self.config.redis_password_file {
+                Some(ref path) => Some(try!(read_password_file(path))),
+                None => url.password.clone(),
+            };
+            let addr = match url.addr {
+                RedisAddr::Tcp(ref host, port) => libredis::ConnectionAddr::Tcp(host.clone(), port),
+                RedisAddr::Unix(ref path) => libredis::ConnectionAddr::Unix(path.clone()),
+            };
+            let info = libredis::ConnectionInfo {
+                addr: Box::new(addr),
+                db: i64::from(url.db),
+                passwd: passwd,
+            };
             let client = try!(libredis::Client::open(info));
             self.redis_client_opt = Some(client);
             Ok(self.redis_client_opt.as_mut().unwrap())
         }
     }
+
+    /// Add commands to the current batch or, outside of batches, send them right away.
+    fn submit<F: FnOnce(&mut libredis::Pipeline)>(&mut self,
+                                                   add_commands: F)
+                                                   -> Result<(), PublishingError> {
+        if let Some(ref mut batch) = self.batch_opt {
+            add_commands(batch);
+            self.batch_len += 1;
+            return Ok(());
+        }
+        let mut pipe = libredis::pipe();
+        add_commands(&mut pipe);
+        self.execute(&pipe)
+    }
+
+    /// Send the commands in `pipe` over the persistent connection (connecting first if
+    /// necessary). While Redis is unavailable, fails right away until the next reconnection
+    /// attempt is due.
+    fn execute(&mut self, pipe: &libredis::Pipeline) -> Result<(), PublishingError> {
+        if let Some(retry_at) = self.retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                let server = self.config.redis_url.to_string();
+                let remaining_seconds = (retry_at - now).as_secs() + 1;
+                return Err(From::from(RedisUnavailableError::Backoff(server, remaining_seconds)));
+            }
+        }
+
+        if self.connection_opt.is_none() {
+            let connection_result = {
+                let client = try!(self.create_redis_client());
+                client.get_connection()
+            };
+            match connection_result {
+                Ok(connection) => {
+                    debug!("Connected to Redis server {}.", self.config.redis_url);
+                    self.connection_opt = Some(connection);
+                }
+                Err(e) => return Err(self.unavailable(e)),
+            }
+        }
+
+        let result: libredis::RedisResult<()> = pipe.query(self.connection_opt
+            .as_ref()
+            .unwrap());
+        match result {
+            Ok(()) => {
+                if self.retry_at.is_some() {
+                    info!("Redis server {} is available again.", self.config.redis_url);
+                }
+                self.retry_seconds = RETRY_MIN_SECONDS;
+                self.retry_at = None;
+                Ok(())
+            }
+            Err(e) => Err(self.unavailable(e)),
+        }
+    }
+
+    /// Drop the (possibly broken) connection and schedule the next reconnection attempt.
+    fn unavailable(&mut self, err: libredis::RedisError) -> PublishingError {
+        self.connection_opt = None;
+        let retry_seconds = self.retry_seconds;
+        self.retry_at = Some(Instant::now() + Duration::from_secs(retry_seconds));
+        self.retry_seconds = min(retry_seconds * 2, RETRY_MAX_SECONDS);
+        From::from(RedisUnavailableError::Failed(self.config.redis_url.to_string(),
+                                                 retry_seconds,
+                                                 err))
+    }
 }
 
 impl Publish for RedisPublisher {
     fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
         let config = self.config.clone();
-        let r_client = try!(self.create_redis_client());
 
         let mut key = String::new();
-        service_key(&config, &publication.host, &mut key);
+        service_key(&config, &publication.key, &mut key);
         let key = key;
 
         let published_config = json_serializer::domain_configs(&publication.host,
                                                                &publication.specs);
         let redis_value = try!(json::encode(&published_config));
 
+        self.submit(|pipe| {
+            if let Some(expire_seconds) = config.expire_seconds {
+                pipe.cmd("SETEX").arg(key).arg(expire_seconds as usize).arg(redis_value).ignore();
+            } else {
+                pipe.cmd("SET").arg(key).arg(redis_value).ignore();
+            }
+        })
+    }
 
-        if let Some(expire_seconds) = config.expire_seconds {
-            try!(r_client.set_ex(key, redis_value, expire_seconds as usize));
-        } else {
-            try!(r_client.set(key, published_config));
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let config = self.config.clone();
+
+        let mut key = String::new();
+        service_key(&config, &publication.key, &mut key);
+
+        self.submit(|pipe| {
+            pipe.cmd("DEL").arg(key).ignore();
+        })
+    }
+
+    fn begin_batch(&mut self) {
+        let mut batch = libredis::pipe();
+        batch.atomic();
+        self.batch_opt = Some(batch);
+        self.batch_len = 0;
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        let batch_len = self.batch_len;
+        self.batch_len = 0;
+        match self.batch_opt.take() {
+            Some(ref batch) if batch_len > 0 => {
+                debug!("Sending {} Redis updates.", batch_len);
+                self.execute(batch)
+            }
+            _ => Ok(()),
         }
+    }
 
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        // The password file is read again in any case, the password might have been rotated.
+        if config.redis_url != self.config.redis_url ||
+           config.redis_password_file != self.config.redis_password_file ||
+           config.redis_password_file.is_some() {
+            info!("Reconnecting to Redis server {}.", config.redis_url);
+            self.redis_client_opt = None;
+            self.connection_opt = None;
+            self.retry_seconds = RETRY_MIN_SECONDS;
+            self.retry_at = None;
+        }
+        self.config = config;
         Ok(())
     }
 }
 
-fn service_key(config: &Config, container_name: &str, key: &mut String) {
+fn service_key(config: &Config, publication_key: &str, key: &mut String) {
     key.push_str(&config.key_prefix);
-    key.push_str(container_name);
+    key.push_str(publication_key);
+}
+
+/// Read a password from a file. Surrounding whitespace (usually a trailing newline) is ignored.
+fn read_password_file(path: &str) -> Result<String, RedisConfigError> {
+    let mut password = String::new();
+    try_!(File::open(path).and_then(|mut file| file.read_to_string(&mut password)),
+          path.to_owned());
+    Ok(password.trim().to_owned())
+}
+
+// ############### REDIS URL ##############################
+
+pub const REDIS_DEFAULT_PORT: u16 = 6379;
+
+/// Location of the Redis server.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RedisAddr {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+/// Parsed Redis URL. Either `redis://[:PASSWORD@]HOST[:PORT][/DB]` or
+/// `redis+unix://[:PASSWORD@]/PATH/TO/SOCKET[?db=DB]`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RedisUrl {
+    pub addr: RedisAddr,
+    /// Index of the database (`SELECT`), never negative.
+    pub db: u32,
+    pub password: Option<String>,
+}
+
+impl RedisUrl {
+    /// Address of a Redis server reachable via TCP (database 0, no password).
+    pub fn tcp(host: String, port: u16) -> RedisUrl {
+        RedisUrl { addr: RedisAddr::Tcp(host, port), db: 0, password: None }
+    }
+
+    pub fn parse(raw: &str) -> Result<RedisUrl, RedisConfigError> {
+        let invalid = |reason: &'static str| RedisConfigError::InvalidUrl(raw.to_owned(), reason);
+        let (unix, rest) = if raw.starts_with("redis://") {
+            (false, &raw["redis://".len()..])
+        } else if raw.starts_with("redis+unix://") {
+            (true, &raw["redis+unix://".len()..])
+        } else {
+            return Err(invalid("Expected scheme redis:// or redis+unix://."));
+        };
+
+        // Split off the query (only used for unix sockets)
+        let (rest, query) = match rest.find('?') {
+            Some(idx) => (&rest[..idx], Some(&rest[idx + 1..])),
+            None => (rest, None),
+        };
+
+        // User info. The user name is ignored, Redis only knows about passwords.
+        let (password, rest) = match rest.rfind('@') {
+            Some(idx) => {
+                let user_info = &rest[..idx];
+                let raw_password = user_info.splitn(2, ':').nth(1).unwrap_or("");
+                let password = String::from_utf8_lossy(&percent_decode(raw_password.as_bytes()))
+                    .into_owned();
+                (if password.is_empty() { None } else { Some(password) }, &rest[idx + 1..])
+            }
+            None => (None, rest),
+        };
+
+        let mut db_opt = None;
+        let addr = if unix {
+            if !rest.starts_with('/') {
+                return Err(invalid("Expected absolute path to the Redis socket."));
+            }
+            for pair in query.unwrap_or("").split('&') {
+                let mut parts = pair.splitn(2, '=');
+                if parts.next() == Some("db") {
+                    db_opt = parts.next();
+                }
+            }
+            RedisAddr::Unix(PathBuf::from(rest))
+        } else {
+            let (host_port, path) = match rest.find('/') {
+                Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+                None => (rest, ""),
+            };
+            if !path.is_empty() {
+                db_opt = Some(path);
+            }
+            // IPv6 addresses are enclosed in brackets: [::1]:6379
+            let port_sep = if host_port.starts_with('[') {
+                host_port.find(']').and_then(|end| host_port[end..].find(':').map(|i| end + i))
+            } else {
+                host_port.rfind(':')
+            };
+            let (host, port) = match port_sep {
+                Some(idx) => {
+                    let port = try!(u16::from_str_radix(&host_port[idx + 1..], 10)
+                        .map_err(|_| invalid("Invalid port.")));
+                    (&host_port[..idx], port)
+                }
+                None => (host_port, REDIS_DEFAULT_PORT),
+            };
+            let host = host.trim_left_matches('[').trim_right_matches(']');
+            if host.is_empty() {
+                return Err(invalid("Missing host name."));
+            }
+            RedisAddr::Tcp(host.to_owned(), port)
+        };
+
+        let db = match db_opt {
+            Some(db) => {
+                try!(u32::from_str_radix(db, 10)
+                    .map_err(|_| invalid("Database must be a non-negative number.")))
+            }
+            None => 0,
+        };
+        Ok(RedisUrl { addr: addr, db: db, password: password })
+    }
+}
+
+/// Displays the URL without the password.
+impl Display for RedisUrl {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            RedisAddr::Tcp(ref host, port) if host.contains(':') => {
+                write!(fmt, "redis://[{}]:{}/{}", host, port, self.db)
+            }
+            RedisAddr::Tcp(ref host, port) => write!(fmt, "redis://{}:{}/{}", host, port, self.db),
+            RedisAddr::Unix(ref path) => {
+                write!(fmt, "redis+unix://{}?db={}", path.display(), self.db)
+            }
+        }
+    }
+}
+
+impl Decodable for RedisUrl {
+    fn decode<D: Decoder>(d: &mut D) -> Result<RedisUrl, D::Error> {
+        let raw = try!(d.read_str());
+        RedisUrl::parse(&raw).map_err(|e| d.error(&e.to_string()))
+    }
 }
 
 // ############### PUBLISHING ERROR #######################
 impl PublishingInnerError for libredis::RedisError {}
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum RedisConfigError {
+        InvalidUrl(url: String, reason: &'static str) {
+            description("Invalid Redis URL.")
+            display(me) -> ("{} {} URL: {}", me.description(), reason, url)
+        }
+        PasswordFile(path: String, err: io::Error) {
+            description("Failed to read Redis password file.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+    }
+}
+
+impl PublishingInnerError for RedisConfigError {}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RedisUnavailableError {
+        Failed(server: String, retry_seconds: u64, err: libredis::RedisError) {
+            description("Redis unavailable.")
+            cause(err)
+            display(me) -> ("{} Server: {}, retrying in {}s. Error: {}", me.description(),
+                server, retry_seconds, err)
+        }
+        Backoff(server: String, retry_seconds: u64) {
+            description("Redis unavailable.")
+            display(me) -> ("{} Server: {}, retrying in {}s.", me.description(), server,
+                retry_seconds)
+        }
+    }
+}
+
+impl PublishingInnerError for RedisUnavailableError {}
+
 // ############### TESTING ################################
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
     use std::default::Default;
+    use std::path::PathBuf;
 
     use super::*;
+    use super::RETRY_MIN_SECONDS;
     use common::{self, Config};
+    use publisher::Publish;
 
     #[test]
     fn new_redis() {
@@ -107,4 +436,93 @@ mod tests {
         // #### THEN  ####
         // doesn't panic
     }
+
+    #[test]
+    fn unavailable_backoff() {
+        common::init_log();
+        // #### GIVEN ####
+        let mut cfg = Config::default();
+        // Nothing listens on port 1
+        cfg.redis_url = RedisUrl::tcp("127.0.0.1".to_owned(), 1);
+        let mut redis_publisher = RedisPublisher::new(Arc::new(cfg));
+        let publication = common::test_publication("example.org", "example.org", Vec::new());
+
+        // #### WHEN  ####
+        let first = redis_publisher.publish(&publication);
+        let second = redis_publisher.publish(&publication);
+
+        // #### THEN  ####
+        assert!(first.is_err(), "Publishing to an unavailable server must fail.");
+        assert!(second.is_err(), "Publishing to an unavailable server must fail.");
+        assert!(redis_publisher.retry_at.is_some(), "Next connection attempt must be delayed.");
+        assert_eq!(redis_publisher.retry_seconds, 2 * RETRY_MIN_SECONDS);
+    }
+
+    #[test]
+    fn url_tcp() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "redis://:s3cr%40t@redis.example.org:6380/2";
+
+        // #### WHEN  ####
+        let url = RedisUrl::parse(raw).expect("Parse redis URL");
+
+        // #### THEN  ####
+        assert_eq!(url.addr, RedisAddr::Tcp("redis.example.org".to_owned(), 6380));
+        assert_eq!(url.db, 2);
+        assert_eq!(url.password, Some("s3cr@t".to_owned()));
+        assert_eq!(url.to_string(), "redis://redis.example.org:6380/2");
+    }
+
+    #[test]
+    fn url_tcp_defaults() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "redis://localhost";
+
+        // #### WHEN  ####
+        let url = RedisUrl::parse(raw).expect("Parse redis URL");
+
+        // #### THEN  ####
+        assert_eq!(url, RedisUrl::tcp("localhost".to_owned(), REDIS_DEFAULT_PORT));
+    }
+
+    #[test]
+    fn url_ipv6() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "redis://[::1]:6380";
+
+        // #### WHEN  ####
+        let url = RedisUrl::parse(raw).expect("Parse redis URL");
+
+        // #### THEN  ####
+        assert_eq!(url.addr, RedisAddr::Tcp("::1".to_owned(), 6380));
+    }
+
+    #[test]
+    fn url_unix() {
+        common::init_log();
+        // #### GIVEN ####
+        let raw = "redis+unix:///run/redis/redis.sock?db=3";
+
+        // #### WHEN  ####
+        let url = RedisUrl::parse(raw).expect("Parse redis URL");
+
+        // #### THEN  ####
+        assert_eq!(url.addr, RedisAddr::Unix(PathBuf::from("/run/redis/redis.sock")));
+        assert_eq!(url.db, 3);
+        assert_eq!(url.password, None);
+    }
+
+    #[test]
+    fn url_invalid() {
+        common::init_log();
+        assert!(RedisUrl::parse("http://localhost").is_err());
+        assert!(RedisUrl::parse("redis://localhost:http").is_err());
+        assert!(RedisUrl::parse("redis://localhost/zero").is_err());
+        assert!(RedisUrl::parse("redis://localhost/-1").is_err());
+        assert!(RedisUrl::parse("redis+unix:///run/redis/redis.sock?db=-1").is_err());
+        assert!(RedisUrl::parse("redis+unix://relative.sock").is_err());
+    }
 }
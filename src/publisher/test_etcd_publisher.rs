@@ -0,0 +1,374 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Tests against an ad-hoc etcd server, modelled after the redis-server tests in
+// test_redis_publisher.rs. Requires the `etcd` binary on the PATH.
+
+extern crate net2;
+extern crate wait_timeout;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+use self::wait_timeout::ChildExt;
+
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json, ToJson};
+use url::Url;
+
+use common::{self, Config, PublisherKind, PublisherKinds};
+use http::HttpClient;
+use publisher::{Publication, Publish};
+use publisher::etcd::EtcdPublisher;
+
+fn free_port() -> u16 {
+    // this is technically a race but etcd doesn't let us pick port 0 and report back either
+    let listener = net2::TcpBuilder::new_v4()
+        .unwrap()
+        .reuse_address(true)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap()
+        .listen(1)
+        .unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+pub struct EtcdServer {
+    pub process: process::Child,
+    data_dir: PathBuf,
+    url: Url,
+}
+
+impl EtcdServer {
+    pub fn new() -> EtcdServer {
+        let mut retries_left = 3;
+
+        loop {
+            let client_port = free_port();
+            let peer_port = free_port();
+            let client_url = format!("http://127.0.0.1:{}", client_port);
+            let peer_url = format!("http://127.0.0.1:{}", peer_port);
+            let data_dir = env::temp_dir().join(format!("beachhead-etcd-{}", client_port));
+
+            let mut cmd = process::Command::new("etcd");
+            // switch these to ::inherit() if you need to see etcd output
+            cmd.stdout(process::Stdio::null())
+                .stderr(process::Stdio::null());
+            cmd.arg("--name")
+                .arg("beachhead-test")
+                .arg("--data-dir")
+                .arg(&data_dir)
+                .arg("--listen-client-urls")
+                .arg(&client_url)
+                .arg("--advertise-client-urls")
+                .arg(&client_url)
+                .arg("--listen-peer-urls")
+                .arg(&peer_url)
+                .arg("--initial-advertise-peer-urls")
+                .arg(&peer_url)
+                .arg("--initial-cluster")
+                .arg(format!("beachhead-test={}", peer_url));
+
+            let mut process = cmd.spawn().unwrap();
+            match process.wait_timeout(Duration::from_millis(500)).unwrap() {
+                Some(err_status) => {
+                    warn!("etcd child process exited unexpectedly early with exit status {}",
+                          err_status);
+                    let _ = fs::remove_dir_all(&data_dir);
+                    if retries_left > 0 {
+                        retries_left -= 1;
+                        continue;
+                    } else {
+                        panic!("Failed to launch an etcd sub-process that wouldn't exit \
+                                immediately.");
+                    }
+                }
+                None => {
+                    // it's probably running fine
+                }
+            }
+            let server = EtcdServer {
+                process: process,
+                data_dir: data_dir,
+                url: Url::parse(&client_url).unwrap(),
+            };
+            server.wait_until_ready();
+            return server;
+        }
+    }
+
+    fn wait_until_ready(&self) {
+        const MAX_WAIT_MS: u64 = 5000;
+        const WAIT_INTERVAL_MS: u64 = 10;
+        let mut waited_ms = 0;
+        loop {
+            match self.range("") {
+                Ok(_) => return,
+                Err(e) => {
+                    if waited_ms < MAX_WAIT_MS {
+                        waited_ms += WAIT_INTERVAL_MS;
+                        sleep(Duration::from_millis(WAIT_INTERVAL_MS));
+                    } else {
+                        panic!("Could not connect to ad-hoc etcd instance after {}ms. Server \
+                                address: {}, error: {}",
+                               MAX_WAIT_MS,
+                               self.url,
+                               e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn configure(&self, config: &mut Config) {
+        config.publishers = PublisherKinds(vec![PublisherKind::Etcd]);
+        config.etcd_url = self.url.clone();
+    }
+
+    fn call(&self, method: &str, request: json::Object) -> Result<Json, ::http::HttpError> {
+        let client = HttpClient::new(&self.url).unwrap();
+        client.json("POST", &format!("/v3{}", method), &Json::Object(request))
+    }
+
+    /// All key-value pairs whose key starts with `prefix`: (key, value, lease).
+    pub fn range(&self, prefix: &str) -> Result<Vec<(String, String, i64)>, ::http::HttpError> {
+        let mut range_end = prefix.as_bytes().to_owned();
+        match range_end.pop() {
+            Some(last) => range_end.push(last + 1),
+            // "\0" means all keys
+            None => range_end.push(0),
+        }
+        let mut request = json::Object::new();
+        let key = if prefix.is_empty() { "\0" } else { prefix };
+        request.insert("key".to_owned(), key.as_bytes().to_base64(STANDARD).to_json());
+        request.insert("range_end".to_owned(), range_end.to_base64(STANDARD).to_json());
+        let response = try!(self.call("/kv/range", request));
+
+        let mut kvs = Vec::new();
+        if let Some(raw_kvs) = response.find("kvs").and_then(|kvs| kvs.as_array()) {
+            for raw_kv in raw_kvs {
+                let decode = |field: &str| {
+                    let raw = raw_kv.find(field).and_then(|v| v.as_string()).unwrap_or("");
+                    String::from_utf8(raw.from_base64().unwrap()).unwrap()
+                };
+                let lease = raw_kv.find("lease")
+                    .and_then(|l| l.as_string())
+                    .map(|l| l.parse().unwrap())
+                    .unwrap_or(0);
+                kvs.push((decode("key"), decode("value"), lease));
+            }
+        }
+        Ok(kvs)
+    }
+
+    /// Remaining TTL of a lease in seconds (-1 if it doesn't exist).
+    pub fn lease_ttl(&self, lease: i64) -> i64 {
+        let mut request = json::Object::new();
+        request.insert("ID".to_owned(), lease.to_string().to_json());
+        let response = self.call("/lease/timetolive", request).unwrap();
+        match response.find("TTL") {
+            Some(&Json::String(ref ttl)) => ttl.parse().unwrap(),
+            Some(other) => other.as_i64().unwrap(),
+            None => 0,
+        }
+    }
+
+    pub fn revoke_lease(&self, lease: i64) {
+        let mut request = json::Object::new();
+        request.insert("ID".to_owned(), lease.to_string().to_json());
+        self.call("/lease/revoke", request).unwrap();
+    }
+}
+
+impl Drop for EtcdServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn example_publication() -> Publication {
+    common::test_publication("example.com",
+                             "example.com",
+                             vec![common::test_spec("www.example.com", Some(81), Some(444))])
+}
+
+#[test]
+fn test_publish() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+    let publication = example_publication();
+
+    // #### WHEN  ####
+    etcd_publisher.publish(&publication).unwrap();
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected etcd to contain exactly 1 key. Actual: {:?}", kvs);
+    let (ref key, ref value, _) = kvs[0];
+    assert_eq!(key, &format!("{}example.com", config.key_prefix));
+
+    let rawpub = Json::from_str(value).unwrap();
+    let specs = rawpub.as_array().expect("top-level value stored in etcd must be an array");
+    assert_eq!(specs.len(), 1);
+    assert_eq!(specs[0].find("domain").and_then(|d| d.as_string()),
+               Some("www.example.com"));
+    assert_eq!(specs[0].find_path(&["http", "port"]).and_then(|p| p.as_u64()),
+               Some(81));
+    assert_eq!(specs[0].find_path(&["https", "host"]).and_then(|h| h.as_string()),
+               Some("example.com"));
+}
+
+#[test]
+fn test_lease_ttl() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    config.expire_seconds = Some(30);
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+
+    // #### WHEN  ####
+    etcd_publisher.publish(&example_publication()).unwrap();
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected etcd to contain exactly 1 key. Actual: {:?}", kvs);
+    let lease = kvs[0].2;
+    assert!(lease != 0, "The key should be attached to a lease.");
+    let ttl = server.lease_ttl(lease);
+    assert!(ttl > 25 && ttl <= 30,
+            "TTL of the lease is expected to be close to 30. Was: {}",
+            ttl);
+}
+
+#[test]
+fn test_no_expire() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    config.expire_seconds = None;
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+
+    // #### WHEN  ####
+    etcd_publisher.publish(&example_publication()).unwrap();
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected etcd to contain exactly 1 key. Actual: {:?}", kvs);
+    assert_eq!(kvs[0].2, 0);
+}
+
+#[test]
+fn test_lease_kept_alive() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    config.expire_seconds = Some(3);
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+    etcd_publisher.publish(&example_publication()).unwrap();
+
+    // #### WHEN  ####
+    // Refresh iterations without any publications
+    for _ in 0..4 {
+        sleep(Duration::from_millis(1000));
+        etcd_publisher.begin_batch();
+        etcd_publisher.flush().unwrap();
+    }
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected the key to survive. Actual: {:?}", kvs);
+}
+
+#[test]
+fn test_lease_expired() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+    etcd_publisher.publish(&example_publication()).unwrap();
+    let old_lease = server.range(&config.key_prefix).unwrap()[0].2;
+    // Revoking the lease deletes the key, like an expiration would
+    server.revoke_lease(old_lease);
+
+    // #### WHEN  ####
+    etcd_publisher.publish(&example_publication()).unwrap();
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected etcd to contain exactly 1 key. Actual: {:?}", kvs);
+    assert!(kvs[0].2 != 0 && kvs[0].2 != old_lease,
+            "Expected the key to be attached to a new lease. Actual: {:?}",
+            kvs);
+}
+
+#[test]
+fn test_withdraw() {
+    common::init_log();
+    // #### GIVEN ####
+    let server = EtcdServer::new();
+    let mut config = Config::default();
+    server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut etcd_publisher = EtcdPublisher::new(config.clone());
+    let publication = example_publication();
+    let mut other_publication = example_publication();
+    other_publication.key = "example.org".to_owned();
+    other_publication.host = "example.org".to_owned();
+    etcd_publisher.publish(&other_publication).unwrap();
+    etcd_publisher.publish(&publication).unwrap();
+
+    // #### WHEN  ####
+    etcd_publisher.withdraw(&publication).unwrap();
+    // Withdrawing something that isn't published is not an error.
+    etcd_publisher.withdraw(&publication).unwrap();
+
+    // #### THEN  ####
+    let kvs = server.range(&config.key_prefix).unwrap();
+    assert!(kvs.len() == 1, "Expected etcd to contain exactly 1 key. Actual: {:?}", kvs);
+    assert!(kvs[0].0.contains("example.org"),
+            "Expected the remaining key to belong to example.org. Actual: {:?}",
+            kvs);
+}
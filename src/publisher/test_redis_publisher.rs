@@ -37,10 +37,10 @@ use redis::{self, Commands};
 extern crate net2;
 extern crate wait_timeout;
 
+use std::collections::BTreeMap;
 use std::process;
 use std::thread::sleep;
 use std::time::Duration;
-use std::rc::Rc;
 use self::wait_timeout::ChildExt;
 
 pub struct RedisServer {
@@ -109,8 +109,7 @@ impl RedisServer {
     pub fn configure(&self, config: &mut Config) {
         let addr = self.get_client_addr();
         if let &redis::ConnectionAddr::Tcp(ref host, port) = addr {
-            config.redis_host = Rc::new(host.to_owned());
-            config.redis_port = port;
+            config.redis_url = RedisUrl::tcp(host.to_owned(), port);
         } else {
             panic!("Expected TCP address, got {:?}", addr);
         }
@@ -196,6 +195,7 @@ use domain_spec::DomainSpec;
 use rustc_serialize::json::{self, Json};
 use std::sync::Arc;
 use ::publisher::Publish;
+use ::publisher::redis::RedisUrl;
 
 #[test]
 fn test_hostonly() {
@@ -208,7 +208,11 @@ fn test_hostonly() {
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
 
     // #### WHEN  ####
-    redis_publisher.publish(&Publication { host: "example.com".to_owned(), specs: Vec::new() })
+    redis_publisher.publish(&Publication {
+            key: "example.com".to_owned(),
+            host: "example.com".to_owned(),
+            specs: Vec::new(),
+        })
         .unwrap();
 
     // #### THEN  ####
@@ -237,16 +241,21 @@ fn test_domains() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(81),
                         https_port: Some(444),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8443),
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -291,16 +300,21 @@ fn test_domains_update() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let mut publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(81),
                         https_port: Some(444),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8443),
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -351,29 +365,39 @@ fn test_domains_multi_host() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let other_publication = Publication {
+        key: "example.org".to_owned(),
         host: "example.org".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.org".to_owned(),
+                        path_prefix: None,
                         http_port: Some(83),
                         https_port: Some(446),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.org".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8448),
+                        options: BTreeMap::new(),
                     }],
     };
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(81),
                         https_port: Some(444),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8443),
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -423,29 +447,39 @@ fn test_domains_multi_host2() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let other_publication = Publication {
+        key: "example.org".to_owned(),
         host: "example.org".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.org".to_owned(),
+                        path_prefix: None,
                         http_port: Some(83),
                         https_port: Some(446),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.org".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8448),
+                        options: BTreeMap::new(),
                     }],
     };
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "www.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(81),
                         https_port: Some(444),
+                        options: BTreeMap::new(),
                     },
                     DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: None,
                         https_port: Some(8443),
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -496,11 +530,14 @@ fn test_domain_id() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "admin-2.ex-ample.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(80),
                         https_port: None,
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -544,11 +581,14 @@ fn test_ttl_no_expire() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(80),
                         https_port: None,
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -585,11 +625,14 @@ fn test_ttl_expire() {
     let config = Arc::new(config);
     let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
     let publication = Publication {
+        key: "example.com".to_owned(),
         host: "example.com".to_owned(),
         specs: vec![DomainSpec {
                         domain_name: "admin.example.com".to_owned(),
+                        path_prefix: None,
                         http_port: Some(80),
                         https_port: None,
+                        options: BTreeMap::new(),
                     }],
     };
 
@@ -616,6 +659,79 @@ fn test_ttl_expire() {
             ttl);
 }
 
+#[test]
+fn test_withdraw() {
+    common::init_log();
+    // #### GIVEN ####
+    let tc = TestContext::new();
+    let mut config = Config::default();
+    tc.server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
+    let other_publication = Publication {
+        key: "example.org".to_owned(),
+        host: "example.org".to_owned(),
+        specs: vec![DomainSpec {
+                        domain_name: "www.example.org".to_owned(),
+                        path_prefix: None,
+                        http_port: Some(83),
+                        https_port: Some(446),
+                        options: BTreeMap::new(),
+                    }],
+    };
+    let publication = Publication {
+        key: "example.com".to_owned(),
+        host: "example.com".to_owned(),
+        specs: vec![DomainSpec {
+                        domain_name: "www.example.com".to_owned(),
+                        path_prefix: None,
+                        http_port: Some(81),
+                        https_port: Some(444),
+                        options: BTreeMap::new(),
+                    }],
+    };
+    redis_publisher.publish(&other_publication).unwrap();
+    redis_publisher.publish(&publication).unwrap();
+
+    // #### WHEN  ####
+    redis_publisher.withdraw(&publication).unwrap();
+
+    // #### THEN  ####
+    // Only the other publication should be left.
+    let mut key_query = (*config.key_prefix).to_owned();
+    key_query.push_str("*");
+    let keys: Vec<String> = tc.client.keys(key_query).unwrap();
+    assert!(keys.len() == 1, "Expected Redis to contain exactly 1 key. Actual: {:?}", keys);
+    assert!(keys[0].contains("example.org"),
+            "Expected the remaining key to belong to example.org. Actual: {:?}",
+            keys);
+}
+
+#[test]
+fn test_withdraw_unknown() {
+    // Withdrawing something that isn't (or no longer) published is not an error.
+
+    common::init_log();
+    // #### GIVEN ####
+    let tc = TestContext::new();
+    let mut config = Config::default();
+    tc.server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
+
+    let publication = Publication {
+        key: "example.com".to_owned(),
+        host: "example.com".to_owned(),
+        specs: Vec::new(),
+    };
+
+    // #### WHEN  ####
+    let result = redis_publisher.withdraw(&publication);
+
+    // #### THEN  ####
+    assert!(result.is_ok(), "Withdrawal should succeed. Got {:?}", result);
+}
+
 fn parse_domain_config(raw_domain_config: &Json, expected_host: &str) -> (String, DomainSpec) {
     use publisher::json_serializer::*;
 
@@ -703,3 +819,71 @@ fn parse_host_config(raw_domain_config: &json::Object,
         None
     }
 }
+
+#[test]
+fn test_database() {
+    common::init_log();
+    // #### GIVEN ####
+    let tc = TestContext::new();
+    let mut config = Config::default();
+    tc.server.configure(&mut config);
+    config.redis_url.db = 1;
+    let config = Arc::new(config);
+    let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
+
+    // #### WHEN  ####
+    redis_publisher.publish(&Publication {
+            key: "example.com".to_owned(),
+            host: "example.com".to_owned(),
+            specs: Vec::new(),
+        })
+        .unwrap();
+
+    // #### THEN  ####
+    let mut key_query = (*config.key_prefix).to_owned();
+    key_query.push_str("*");
+    let keys: Vec<String> = tc.client.keys(key_query.clone()).unwrap();
+    assert!(keys.is_empty(), "Expected database 0 to be empty. Actual: {:?}", keys);
+
+    let db1_client = redis::Client::open(redis::ConnectionInfo {
+            addr: Box::new(tc.server.get_client_addr().clone()),
+            db: 1,
+            passwd: None,
+        })
+        .unwrap();
+    let keys: Vec<String> = db1_client.keys(key_query).unwrap();
+    assert!(keys.len() == 1, "Expected database 1 to contain exactly 1 key. Actual: {:?}", keys);
+}
+
+#[test]
+fn test_batch() {
+    common::init_log();
+    // #### GIVEN ####
+    let tc = TestContext::new();
+    let mut config = Config::default();
+    tc.server.configure(&mut config);
+    let config = Arc::new(config);
+    let mut redis_publisher = ::publisher::redis::RedisPublisher::new(config.clone());
+    let mut key_query = (*config.key_prefix).to_owned();
+    key_query.push_str("*");
+
+    // #### WHEN  ####
+    redis_publisher.begin_batch();
+    for host in vec!["example.com", "example.org"] {
+        redis_publisher.publish(&Publication {
+                key: host.to_owned(),
+                host: host.to_owned(),
+                specs: Vec::new(),
+            })
+            .unwrap();
+    }
+    let keys_before_flush: Vec<String> = tc.client.keys(key_query.clone()).unwrap();
+    redis_publisher.flush().unwrap();
+
+    // #### THEN  ####
+    assert!(keys_before_flush.is_empty(),
+            "Expected updates to be held back until flush. Actual: {:?}",
+            keys_before_flush);
+    let keys: Vec<String> = tc.client.keys(key_query).unwrap();
+    assert!(keys.len() == 2, "Expected Redis to contain exactly 2 keys. Actual: {:?}", keys);
+}
@@ -0,0 +1,336 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Writes a dynamic configuration file for Traefik's file provider: one router and one service
+//! per domain and scheme. Routers for http ports are attached to the http entrypoint, routers for
+//! https ports to the https entrypoint (with TLS terminated by Traefik). Traefik picks up the
+//! file by itself (`--providers.file.directory=... --providers.file.watch=true`). Path prefixes
+//! become `PathPrefix` rules, domain spec options are not applied.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use toml;
+
+use common::Config;
+use domain_spec::DomainSpec;
+use super::*;
+use super::aggregate::Aggregate;
+use super::file::{self, FileError};
+
+const SCHEMES: [&'static str; 2] = ["http", "https"];
+
+pub struct TraefikPublisher {
+    config: Arc<Config>,
+    aggregate: Aggregate,
+}
+
+impl TraefikPublisher {
+    pub fn new(config: Arc<Config>) -> TraefikPublisher {
+        TraefikPublisher {
+            config: config,
+            aggregate: Aggregate::new(),
+        }
+    }
+
+    /// The dynamic configuration for all current publications.
+    pub fn dynamic_config(&self) -> toml::Table {
+        // router name -> (rule, scheme, server URLs)
+        let mut routes: BTreeMap<String, (String, &'static str, Vec<toml::Value>)> =
+            BTreeMap::new();
+        for publication in self.aggregate.publications() {
+            for spec in &publication.specs {
+                for (scheme, port_opt) in SCHEMES.iter().zip(&[spec.http_port, spec.https_port]) {
+                    if let Some(port) = *port_opt {
+                        let name = format!("beachhead_{}_{}",
+                                           spec.spec_id().to_lowercase(),
+                                           scheme);
+                        let mut server = toml::Table::new();
+                        server.insert("url".to_owned(),
+                                      toml::Value::String(server_url(scheme,
+                                                                     &publication.host,
+                                                                     port)));
+                        let rule = router_rule(spec);
+                        routes.entry(name)
+                            .or_insert_with(|| (rule, scheme, Vec::new()))
+                            .2
+                            .push(toml::Value::Table(server));
+                    }
+                }
+            }
+        }
+
+        let mut routers = toml::Table::new();
+        let mut services = toml::Table::new();
+        for (name, (rule, scheme, servers)) in routes {
+            let entrypoint = if scheme == "https" {
+                &self.config.traefik_https_entrypoint
+            } else {
+                &self.config.traefik_http_entrypoint
+            };
+            let mut router = toml::Table::new();
+            router.insert("rule".to_owned(), toml::Value::String(rule));
+            router.insert("entryPoints".to_owned(),
+                          toml::Value::Array(vec![toml::Value::String((**entrypoint).clone())]));
+            router.insert("service".to_owned(), toml::Value::String(name.clone()));
+            if scheme == "https" {
+                router.insert("tls".to_owned(), toml::Value::Table(toml::Table::new()));
+            }
+            routers.insert(name.clone(), toml::Value::Table(router));
+
+            let mut load_balancer = toml::Table::new();
+            load_balancer.insert("servers".to_owned(), toml::Value::Array(servers));
+            let mut service = toml::Table::new();
+            service.insert("loadBalancer".to_owned(), toml::Value::Table(load_balancer));
+            services.insert(name, toml::Value::Table(service));
+        }
+
+        let mut dynamic_config = toml::Table::new();
+        if !routers.is_empty() {
+            let mut http = toml::Table::new();
+            http.insert("routers".to_owned(), toml::Value::Table(routers));
+            http.insert("services".to_owned(), toml::Value::Table(services));
+            dynamic_config.insert("http".to_owned(), toml::Value::Table(http));
+        }
+        dynamic_config
+    }
+
+    fn render(&mut self) -> Result<(), TraefikError> {
+        self.aggregate.expire(self.config.expire_seconds);
+        let mut output = String::new();
+        output.push_str("# Generated by beachhead-companion. Changes will be overwritten.\n");
+        output.push_str(&toml::Value::Table(self.dynamic_config()).to_string());
+        let path = Path::new(&*self.config.traefik_file);
+        if try!(file::write_if_changed(path, &output)) {
+            info!("Updated Traefik configuration {}.", path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Matches the domain and, if the spec has one, the path prefix. Traefik prefers longer rules, so
+/// path prefixes take precedence over the whole domain.
+fn router_rule(spec: &DomainSpec) -> String {
+    let host = format!("Host(`{}`)", spec.domain_name.to_lowercase());
+    match spec.path_prefix {
+        Some(ref path_prefix) => format!("{} && PathPrefix(`{}`)", host, path_prefix),
+        None => host,
+    }
+}
+
+fn server_url(scheme: &str, host: &str, port: u16) -> String {
+    if host.contains(':') {
+        // IPv6 address
+        format!("{}://[{}]:{}", scheme, host, port)
+    } else {
+        format!("{}://{}:{}", scheme, host, port)
+    }
+}
+
+// ############### TRAEFIK ERROR ##########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TraefikError {
+        File(err: FileError) {
+            description("Failed to write Traefik configuration.")
+            cause(err)
+            display(me) -> ("{} {}", me.description(), err)
+            from()
+        }
+    }
+}
+
+impl PublishingInnerError for TraefikError {}
+
+impl Publish for TraefikPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.publish(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        if self.aggregate.withdraw(publication) {
+            try!(self.render());
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) {
+        self.aggregate.begin_batch();
+    }
+
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        self.aggregate.end_batch();
+        Ok(try!(self.render()))
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use toml;
+
+    use super::*;
+    use common::{self, Config};
+    use publisher::file::read_if_exists;
+    use publisher::Publish;
+
+    fn test_config(test_name: &str) -> (Config, PathBuf) {
+        let directory = common::test_directory(&format!("traefik-publisher-{}", test_name));
+        let path = directory.join("beachhead.toml");
+        let mut config = Config::default();
+        config.traefik_file = Rc::new(path.to_string_lossy().into_owned());
+        (config, path)
+    }
+
+    /// Parse the written file back.
+    fn read_config(path: &PathBuf) -> toml::Value {
+        let text = read_if_exists(path).expect("Traefik configuration");
+        toml::Value::Table(toml::Parser::new(&text).parse().expect("Valid TOML"))
+    }
+
+    fn lookup_str<'a>(value: &'a toml::Value, path: &str) -> Option<&'a str> {
+        value.lookup(path).and_then(|v| v.as_str())
+    }
+
+    #[test]
+    fn routers_and_services() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, path) = test_config("routers_and_services");
+        let mut publisher = TraefikPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        publisher.begin_batch();
+        let specs = vec![common::test_spec("App.example.org", Some(8080), Some(8043))];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+        let specs = vec![common::test_spec("app.example.org", Some(8080), None)];
+        publisher.publish(&common::test_publication("web2", "fd00::2", specs)).unwrap();
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let config = read_config(&path);
+        let http = "http.routers.beachhead_app_example_org_http";
+        assert_eq!(lookup_str(&config, &format!("{}.rule", http)),
+                   Some("Host(`app.example.org`)"));
+        assert_eq!(lookup_str(&config, &format!("{}.entryPoints.0", http)), Some("web"));
+        assert_eq!(lookup_str(&config, &format!("{}.service", http)),
+                   Some("beachhead_app_example_org_http"));
+        assert!(config.lookup(&format!("{}.tls", http)).is_none());
+        let https = "http.routers.beachhead_app_example_org_https";
+        assert_eq!(lookup_str(&config, &format!("{}.entryPoints.0", https)),
+                   Some("websecure"));
+        assert!(config.lookup(&format!("{}.tls", https)).is_some());
+
+        let servers = "loadBalancer.servers";
+        assert_eq!(lookup_str(&config,
+                              &format!("http.services.beachhead_app_example_org_http.{}.0.url",
+                                       servers)),
+                   Some("http://172.17.0.2:8080"));
+        assert_eq!(lookup_str(&config,
+                              &format!("http.services.beachhead_app_example_org_http.{}.1.url",
+                                       servers)),
+                   Some("http://[fd00::2]:8080"));
+        assert_eq!(lookup_str(&config,
+                              &format!("http.services.beachhead_app_example_org_https.{}.0.url",
+                                       servers)),
+                   Some("https://172.17.0.2:8043"));
+    }
+
+    #[test]
+    fn entrypoints() {
+        common::init_log();
+        // #### GIVEN ####
+        let (mut config, path) = test_config("entrypoints");
+        config.traefik_http_entrypoint = Rc::new("http-in".to_owned());
+        let mut publisher = TraefikPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        let specs = vec![common::test_spec("example.org", Some(80), None)];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+
+        // #### THEN  ####
+        let config = read_config(&path);
+        assert_eq!(lookup_str(&config,
+                              "http.routers.beachhead_example_org_http.entryPoints.0"),
+                   Some("http-in"));
+    }
+
+    #[test]
+    fn path_prefix_rule() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, path) = test_config("path_prefix_rule");
+        let mut publisher = TraefikPublisher::new(Arc::new(config));
+        let mut api = common::test_spec("example.org", Some(3000), None);
+        api.path_prefix = Some("/api".to_owned());
+
+        // #### WHEN  ####
+        let specs = vec![common::test_spec("example.org", Some(80), None)];
+        publisher.publish(&common::test_publication("web1", "172.17.0.2", specs)).unwrap();
+        publisher.publish(&common::test_publication("api1", "172.17.0.3", vec![api])).unwrap();
+
+        // #### THEN  ####
+        let config = read_config(&path);
+        assert_eq!(lookup_str(&config, "http.routers.beachhead_example_org_http.rule"),
+                   Some("Host(`example.org`)"));
+        assert_eq!(lookup_str(&config, "http.routers.beachhead_example_org_api_http.rule"),
+                   Some("Host(`example.org`) && PathPrefix(`/api`)"));
+        assert_eq!(lookup_str(&config,
+                              "http.services.beachhead_example_org_api_http.loadBalancer.\
+                               servers.0.url"),
+                   Some("http://172.17.0.3:3000"));
+    }
+
+    #[test]
+    fn withdraw() {
+        common::init_log();
+        // #### GIVEN ####
+        let (config, path) = test_config("withdraw");
+        let mut publisher = TraefikPublisher::new(Arc::new(config));
+        let specs = vec![common::test_spec("example.org", Some(80), None)];
+        let publication = common::test_publication("web1", "172.17.0.2", specs);
+        publisher.publish(&publication).unwrap();
+
+        // #### WHEN  ####
+        publisher.withdraw(&publication).unwrap();
+
+        // #### THEN  ####
+        let config = read_config(&path);
+        assert!(config.lookup("http").is_none());
+    }
+}
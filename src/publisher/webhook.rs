@@ -0,0 +1,748 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! POSTs every publication (and every withdrawal) as a JSON event to an HTTP endpoint:
+//!
+//! ```text
+//! {"event": "publish", "key": ..., "host": ..., "expire": 60, "domains": [...]}
+//! {"event": "withdraw", "key": ..., "host": ..., "domains": [...]}
+//! ```
+//!
+//! `domains` is the same as the Redis value. With a secret, requests carry an HMAC-SHA256 of the
+//! body in `X-Beachhead-Signature: sha256=<hex>`.
+//!
+//! Events are delivered by a thread of its own, so that a slow or broken endpoint doesn't stall
+//! the refresh loop. They wait in a bounded queue that holds at most one event per publication
+//! (a newer event replaces the queued one). Failed requests are retried a few times. If the
+//! endpoint remains unavailable, the queue is left alone until the next attempt is due (with
+//! exponential backoff). Delivery failures are reported by the next `flush`. Events count as done
+//! once queued, so that the companion doesn't withdraw the same publication over and over.
+//! Unchanged publications are only sent again to keep them from expiring.
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use openssl::crypto::hash::Type;
+use openssl::crypto::hmac::hmac;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json, ToJson};
+use url::Url;
+
+use common::Config;
+use http::{HttpClient, HttpError};
+use super::*;
+use super::json_serializer;
+
+pub const SIGNATURE_HEADER: &'static str = "X-Beachhead-Signature";
+/// Delay before the first retry of a failed request. Doubles with every retry.
+const RETRY_DELAY_MILLIS: u64 = 250;
+/// Waiting time before contacting an unavailable endpoint again. Doubles with every failure.
+const BACKOFF_MIN_SECONDS: u64 = 1;
+/// Upper bound for the waiting time before contacting an unavailable endpoint again.
+const BACKOFF_MAX_SECONDS: u64 = 60;
+/// Events for further publications are refused while this many are waiting for delivery.
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+pub struct WebhookPublisher {
+    config: Arc<Config>,
+    /// Shared with the delivery thread, which is started along with the first event.
+    queue_opt: Option<Arc<Queue>>,
+    /// The last publish event queued for each key (body and when it was queued).
+    sent: HashMap<String, (String, Instant)>,
+}
+
+impl WebhookPublisher {
+    pub fn new(config: Arc<Config>) -> WebhookPublisher {
+        WebhookPublisher {
+            config: config,
+            queue_opt: None,
+            sent: HashMap::new(),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        endpoint(&self.config.webhook_url)
+    }
+
+    fn queue(&mut self) -> Result<Arc<Queue>, WebhookError> {
+        if self.queue_opt.is_none() {
+            let queue = Arc::new(Queue::new());
+            let thread_queue = queue.clone();
+            let delivery = Delivery::new(WebhookSettings::from_config(&self.config));
+            try!(thread::Builder::new()
+                .name("webhook".to_owned())
+                .spawn(move || deliver_events(thread_queue, delivery)));
+            self.queue_opt = Some(queue);
+        }
+        Ok(self.queue_opt.as_ref().unwrap().clone())
+    }
+
+    /// Queue an event for delivery. Replaces an event for the same key that is still queued.
+    fn enqueue(&mut self, key: &str, event: Json) -> Result<(), WebhookError> {
+        let queue = try!(self.queue());
+        let mut state = queue.state.lock().unwrap();
+        match state.events.iter().position(|&(ref k, _)| k == key) {
+            Some(index) => {
+                state.events.remove(index);
+            }
+            None if state.events.len() >= MAX_QUEUED_EVENTS => {
+                return Err(WebhookError::QueueFull(self.endpoint(), state.events.len()));
+            }
+            None => (),
+        }
+        state.events.push_back((key.to_owned(), event));
+        queue.queued.notify_all();
+        Ok(())
+    }
+
+    /// Whether `body` has been queued for `key` before and doesn't have to be sent again (yet).
+    /// Publications with an expiry are sent again once a third of it has passed.
+    fn unchanged(&self, key: &str, body: &str) -> bool {
+        match self.sent.get(key) {
+            Some(&(ref sent_body, sent_at)) if sent_body == body => {
+                match self.config.expire_seconds {
+                    Some(expire_seconds) => {
+                        sent_at.elapsed() < Duration::from_secs(expire_seconds as u64 / 3)
+                    }
+                    None => true,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Wait until the queue is empty or a delivery failed, at most until `deadline`.
+    fn wait_until_idle(&self, deadline: Instant) {
+        if let Some(ref queue) = self.queue_opt {
+            let mut state = queue.state.lock().unwrap();
+            loop {
+                let now = Instant::now();
+                if (state.events.is_empty() && !state.busy) || state.failure_opt.is_some() ||
+                   now >= deadline {
+                    return;
+                }
+                state = queue.idle.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+    }
+}
+
+impl Drop for WebhookPublisher {
+    fn drop(&mut self) {
+        if let Some(ref queue) = self.queue_opt {
+            queue.state.lock().unwrap().stop = true;
+            queue.queued.notify_all();
+        }
+    }
+}
+
+fn endpoint(url_opt: &Option<Url>) -> String {
+    url_opt.as_ref().map(|url| url.serialize()).unwrap_or_else(String::new)
+}
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of `body`.
+pub fn signature(secret: &[u8], body: &str) -> String {
+    format!("sha256={}", hmac(Type::SHA256, secret, body.as_bytes()).to_hex())
+}
+
+fn event(config: &Config, kind: &str, publication: &Publication) -> Json {
+    let mut event = json::Object::new();
+    event.insert("event".to_owned(), kind.to_json());
+    event.insert(json_serializer::JSON_KEY.to_owned(), publication.key.to_json());
+    event.insert(json_serializer::JSON_HOST.to_owned(), publication.host.to_json());
+    event.insert("domains".to_owned(),
+                 Json::Array(json_serializer::domain_configs(&publication.host,
+                                                             &publication.specs)));
+    if kind == "publish" {
+        if let Some(expire_seconds) = config.expire_seconds {
+            event.insert("expire".to_owned(), expire_seconds.to_json());
+        }
+    }
+    Json::Object(event)
+}
+
+/// Read the secret from a file. Surrounding whitespace (usually a trailing newline) is ignored.
+fn read_secret_file(path: &str) -> Result<Vec<u8>, WebhookError> {
+    let mut secret = String::new();
+    try_!(File::open(path).and_then(|mut file| file.read_to_string(&mut secret)),
+          path.to_owned());
+    Ok(secret.trim().as_bytes().to_vec())
+}
+
+// ############### DELIVERY ###############################
+
+/// Events waiting for delivery, shared between the publisher and the delivery thread.
+struct Queue {
+    state: Mutex<QueueState>,
+    /// Signalled when an event is queued, the settings change or the publisher goes away.
+    queued: Condvar,
+    /// Signalled when the delivery thread is done with an event.
+    idle: Condvar,
+}
+
+struct QueueState {
+    /// Events with the key of their publication (oldest first).
+    events: VecDeque<(String, Json)>,
+    /// Settings for the delivery thread to switch to.
+    settings_opt: Option<WebhookSettings>,
+    /// Whether the delivery thread is sending an event right now.
+    busy: bool,
+    /// The last delivery failure that hasn't been reported yet.
+    failure_opt: Option<WebhookError>,
+    /// Set once the publisher is gone.
+    stop: bool,
+}
+
+impl Queue {
+    fn new() -> Queue {
+        Queue {
+            state: Mutex::new(QueueState {
+                events: VecDeque::new(),
+                settings_opt: None,
+                busy: false,
+                failure_opt: None,
+                stop: false,
+            }),
+            queued: Condvar::new(),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Wait for the next event that is due for delivery. `None` once the publisher is gone.
+    fn next_event(&self, delivery: &mut Delivery) -> Option<(String, Json)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stop {
+                return None;
+            }
+            if let Some(settings) = state.settings_opt.take() {
+                delivery.reconfigure(settings);
+            }
+            let now = Instant::now();
+            match delivery.retry_at {
+                Some(retry_at) if now < retry_at => {
+                    state = self.queued.wait_timeout(state, retry_at - now).unwrap().0;
+                }
+                _ => {
+                    if let Some(entry) = state.events.pop_front() {
+                        state.busy = true;
+                        return Some(entry);
+                    }
+                    state = self.queued.wait(state).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Runs on a dedicated thread. Delivers the queued events in order. Events that couldn't be
+/// delivered stay queued for the next attempt. Ends once the publisher is gone.
+fn deliver_events(queue: Arc<Queue>, mut delivery: Delivery) {
+    while let Some((key, event)) = queue.next_event(&mut delivery) {
+        let result = delivery.send(&event);
+        let mut state = queue.state.lock().unwrap();
+        state.busy = false;
+        match result {
+            Ok(()) => (),
+            Err(e @ WebhookError::Rejected(..)) => {
+                warn!("Dropping webhook event for {}. Error: {}", key, e);
+                state.failure_opt = Some(e);
+            }
+            Err(e) => {
+                // Unless a newer event for the same publication has been queued in the meantime.
+                if !state.events.iter().any(|&(ref k, _)| *k == key) {
+                    state.events.push_front((key, event));
+                }
+                warn!("{} webhook event(s) remain queued. Error: {}",
+                      state.events.len(),
+                      e);
+                state.failure_opt = Some(e);
+            }
+        }
+        queue.idle.notify_all();
+    }
+}
+
+/// Webhook settings (see `Config`). Plain values so that the delivery thread can take them along.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct WebhookSettings {
+    url: Option<Url>,
+    secret_file: Option<String>,
+    timeout_seconds: u32,
+    retries: u32,
+}
+
+impl WebhookSettings {
+    fn from_config(config: &Config) -> WebhookSettings {
+        WebhookSettings {
+            url: config.webhook_url.clone(),
+            secret_file: config.webhook_secret_file.as_ref().map(|path| (**path).clone()),
+            timeout_seconds: config.webhook_timeout_seconds,
+            retries: config.webhook_retries,
+        }
+    }
+}
+
+/// The connection to the endpoint, owned by the delivery thread.
+struct Delivery {
+    settings: WebhookSettings,
+    client_opt: Option<HttpClient>,
+    /// Read from `webhook_secret_file` along with creating the client.
+    secret_opt: Option<Vec<u8>>,
+    backoff_seconds: u64,
+    /// While the endpoint is unavailable: no requests before this point in time.
+    retry_at: Option<Instant>,
+}
+
+impl Delivery {
+    fn new(settings: WebhookSettings) -> Delivery {
+        Delivery {
+            settings: settings,
+            client_opt: None,
+            secret_opt: None,
+            backoff_seconds: BACKOFF_MIN_SECONDS,
+            retry_at: None,
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        endpoint(&self.settings.url)
+    }
+
+    fn client(&mut self) -> Result<&HttpClient, WebhookError> {
+        if self.client_opt.is_none() {
+            let url = try!(self.settings.url.clone().ok_or(WebhookError::MissingUrl));
+            let mut client = try_!(HttpClient::new(&url), self.endpoint());
+            let timeout = self.settings.timeout_seconds as u64;
+            client.set_timeout(Some(Duration::from_secs(timeout)));
+            self.secret_opt = match self.settings.secret_file {
+                Some(ref path) => Some(try!(read_secret_file(path))),
+                None => None,
+            };
+            self.client_opt = Some(client);
+        }
+        Ok(self.client_opt.as_ref().unwrap())
+    }
+
+    fn post(&mut self, body: &str) -> Result<(), WebhookError> {
+        let endpoint = self.endpoint();
+        let signature_opt = self.secret_opt.as_ref().map(|secret| signature(secret, body));
+        let client = try!(self.client());
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(ref signature) = signature_opt {
+            headers.push((SIGNATURE_HEADER, &signature[..]));
+        }
+        let response = try_!(client.request("POST", "", &headers, body.as_bytes()),
+                             endpoint.clone());
+        if response.is_success() {
+            Ok(())
+        } else {
+            let body = response.body_text();
+            Err(WebhookError::Http(endpoint,
+                                   HttpError::Status(response.status, response.reason, body)))
+        }
+    }
+
+    /// Deliver an event, retrying a few times.
+    fn send(&mut self, event: &Json) -> Result<(), WebhookError> {
+        // Make sure that the secret has been read before signing.
+        if let Err(e) = self.client() {
+            return Err(self.unavailable(e));
+        }
+
+        let body = event.to_string();
+        let mut delay_millis = RETRY_DELAY_MILLIS;
+        let mut retries = 0;
+        loop {
+            match self.post(&body) {
+                Ok(()) => {
+                    if self.retry_at.is_some() {
+                        info!("Webhook {} is available again.", self.endpoint());
+                    }
+                    self.backoff_seconds = BACKOFF_MIN_SECONDS;
+                    self.retry_at = None;
+                    return Ok(());
+                }
+                // The endpoint doesn't want this event, no point in trying again.
+                Err(WebhookError::Http(endpoint, HttpError::Status(status, reason, body))) => {
+                    if status < 500 {
+                        return Err(WebhookError::Rejected(endpoint, status, reason, body));
+                    } else if retries >= self.settings.retries {
+                        let err = HttpError::Status(status, reason, body);
+                        return Err(self.unavailable(WebhookError::Http(endpoint, err)));
+                    }
+                }
+                Err(e @ WebhookError::Http(..)) => {
+                    if retries >= self.settings.retries {
+                        return Err(self.unavailable(e));
+                    }
+                }
+                Err(e) => return Err(self.unavailable(e)),
+            }
+            retries += 1;
+            thread::sleep(Duration::from_millis(delay_millis));
+            delay_millis *= 2;
+        }
+    }
+
+    /// Schedule the next attempt to contact the endpoint.
+    fn unavailable(&mut self, err: WebhookError) -> WebhookError {
+        let backoff_seconds = self.backoff_seconds;
+        self.retry_at = Some(Instant::now() + Duration::from_secs(backoff_seconds));
+        self.backoff_seconds = min(backoff_seconds * 2, BACKOFF_MAX_SECONDS);
+        warn!("Webhook {} unavailable, trying again in {}s.",
+              self.endpoint(),
+              backoff_seconds);
+        err
+    }
+
+    fn reconfigure(&mut self, settings: WebhookSettings) {
+        // The secret file is read again in any case, the secret might have been rotated.
+        if settings.url != self.settings.url ||
+           settings.timeout_seconds != self.settings.timeout_seconds ||
+           settings.secret_file.is_some() || self.settings.secret_file.is_some() {
+            self.client_opt = None;
+            self.secret_opt = None;
+            self.backoff_seconds = BACKOFF_MIN_SECONDS;
+            self.retry_at = None;
+        }
+        self.settings = settings;
+    }
+}
+
+// ############### WEBHOOK ERROR ##########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum WebhookError {
+        MissingUrl {
+            description("No webhook URL configured.")
+        }
+        SecretFile(path: String, err: io::Error) {
+            description("Failed to read webhook secret file.")
+            cause(err)
+            display(me) -> ("{} File: {}, error: {}", me.description(), path, err)
+            from(e: (io::Error, String)) -> (e.1, e.0)
+        }
+        Http(endpoint: String, err: HttpError) {
+            description("Webhook unavailable.")
+            cause(err)
+            display(me) -> ("{} Endpoint: {}, error: {}", me.description(), endpoint, err)
+            from(e: (HttpError, String)) -> (e.1, e.0)
+        }
+        Rejected(endpoint: String, status: u16, reason: String, body: String) {
+            description("Webhook rejected event.")
+            display(me) -> ("{} Endpoint: {}, status: {} {}, response: {}", me.description(),
+                endpoint, status, reason, body)
+        }
+        QueueFull(endpoint: String, queued: usize) {
+            description("Too many webhook events waiting for delivery.")
+            display(me) -> ("{} Endpoint: {}, queued: {}", me.description(), endpoint, queued)
+        }
+        Thread(err: io::Error) {
+            description("Failed to start webhook delivery thread.")
+            cause(err)
+            display(me) -> ("{} Error: {}", me.description(), err)
+            from()
+        }
+    }
+}
+
+impl PublishingInnerError for WebhookError {}
+
+impl Publish for WebhookPublisher {
+    fn publish(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        let event = event(&self.config, "publish", publication);
+        let body = event.to_string();
+        if self.unchanged(&publication.key, &body) {
+            debug!("Webhook publication {} unchanged, not sending it again.",
+                   publication.key);
+            return Ok(());
+        }
+        try!(self.enqueue(&publication.key, event));
+        self.sent.insert(publication.key.clone(), (body, Instant::now()));
+        Ok(())
+    }
+
+    fn withdraw(&mut self, publication: &Publication) -> Result<(), PublishingError> {
+        self.sent.remove(&publication.key);
+        let event = event(&self.config, "withdraw", publication);
+        Ok(try!(self.enqueue(&publication.key, event)))
+    }
+
+    /// Reports the last delivery failure since the previous flush. When running just once,
+    /// waits for the queued events to be delivered first (at most as long as all attempts to
+    /// deliver one event may take).
+    fn flush(&mut self) -> Result<(), PublishingError> {
+        if self.config.refresh_seconds.is_none() {
+            let attempts = self.config.webhook_retries as u64 + 1;
+            let timeout_seconds = self.config.webhook_timeout_seconds as u64 * attempts;
+            self.wait_until_idle(Instant::now() + Duration::from_secs(timeout_seconds));
+        }
+        let failure_opt = match self.queue_opt {
+            Some(ref queue) => queue.state.lock().unwrap().failure_opt.take(),
+            None => None,
+        };
+        match failure_opt {
+            Some(e) => Err(From::from(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn reconfigure(&mut self, config: Arc<Config>) -> Result<(), PublishingError> {
+        if config.webhook_url != self.config.webhook_url {
+            // The new endpoint hasn't seen any of the publications.
+            self.sent.clear();
+        }
+        if let Some(ref queue) = self.queue_opt {
+            let settings = WebhookSettings::from_config(&config);
+            queue.state.lock().unwrap().settings_opt = Some(settings);
+            queue.queued.notify_all();
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use rustc_serialize::json::Json;
+    use url::Url;
+
+    use super::*;
+    use common::{self, Config};
+    use mock_http_server::{MockHttpServer, RecordedRequest};
+    use publisher::{Publication, Publish};
+
+    fn webhook_config(server: &MockHttpServer) -> Config {
+        let mut config = Config::default();
+        let url = format!("{}/hooks/beachhead", server.url().serialize().trim_right_matches('/'));
+        config.webhook_url = Some(Url::parse(&url).unwrap());
+        config.webhook_retries = 1;
+        // Running once, so that `flush` waits for the events to be delivered.
+        config.refresh_seconds = None;
+        config
+    }
+
+    fn example_publication() -> Publication {
+        common::test_publication("web",
+                                 "172.17.0.2",
+                                 vec![common::test_spec("example.org", Some(8080), None)])
+    }
+
+    /// A server that fails the first `failures` requests with `status`.
+    fn failing_server(failures: usize, status: u16) -> MockHttpServer {
+        let count = AtomicUsize::new(0);
+        MockHttpServer::with_handler(Box::new(move |_: &RecordedRequest| {
+            if count.fetch_add(1, Ordering::SeqCst) < failures {
+                (status, "failure".to_owned())
+            } else {
+                (200, String::new())
+            }
+        }))
+    }
+
+    #[test]
+    fn signature_rfc4231() {
+        common::init_log();
+        // #### GIVEN ####
+        let secret = b"Jefe";
+
+        // #### WHEN  ####
+        let signature = signature(secret, "what do ya want for nothing?");
+
+        // #### THEN  ####
+        assert_eq!(signature,
+                   "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn publish_signed() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let secret_path = env::temp_dir().join("beachhead-webhook-publisher-secret");
+        File::create(&secret_path).unwrap().write_all(b"s3cret\n").unwrap();
+        let mut config = webhook_config(&server);
+        config.webhook_secret_file = Some(Rc::new(secret_path.to_string_lossy().into_owned()));
+        let mut publisher = WebhookPublisher::new(Arc::new(config));
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/hooks/beachhead");
+        assert_eq!(requests[0].header("x-beachhead-signature"),
+                   Some(&signature(b"s3cret", &requests[0].body)[..]));
+        let event = Json::from_str(&requests[0].body).unwrap();
+        assert_eq!(event.find("event").and_then(|e| e.as_string()), Some("publish"));
+        assert_eq!(event.find("key").and_then(|k| k.as_string()), Some("web"));
+        assert_eq!(event.find("expire").and_then(|e| e.as_u64()), Some(60));
+        assert_eq!(event.find("domains").and_then(|d| d.as_array()).map(|d| d.len()),
+                   Some(1));
+    }
+
+    #[test]
+    fn retry() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = failing_server(1, 503);
+        let mut publisher = WebhookPublisher::new(Arc::new(webhook_config(&server)));
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+        let result = publisher.flush();
+
+        // #### THEN  ####
+        assert!(result.is_ok(), "Publish after retry: {:?}", result);
+        assert_eq!(server.requests().len(), 2);
+        assert!(server.requests()[0].header("x-beachhead-signature").is_none());
+    }
+
+    #[test]
+    fn rejected_not_retried() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = failing_server(1, 400);
+        let mut publisher = WebhookPublisher::new(Arc::new(webhook_config(&server)));
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+        let result = publisher.flush();
+        let mut withdrawal = example_publication();
+        withdrawal.key = "other".to_owned();
+        publisher.withdraw(&withdrawal).expect("Withdraw");
+        let next_result = publisher.flush();
+
+        // #### THEN  ####
+        assert!(result.is_err(), "Rejection must be reported.");
+        assert!(next_result.is_ok(), "Endpoint is available: {:?}", next_result);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let event = Json::from_str(&requests[1].body).unwrap();
+        assert_eq!(event.find("event").and_then(|e| e.as_string()), Some("withdraw"));
+    }
+
+    #[test]
+    fn unavailable_withdrawal_kept() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = failing_server(2, 500);
+        let mut publisher = WebhookPublisher::new(Arc::new(webhook_config(&server)));
+
+        // #### WHEN  ####
+        let first = publisher.withdraw(&example_publication());
+        let flush_unavailable = publisher.flush();
+        let requests_unavailable = server.requests().len();
+        let flush_available = publisher.flush();
+
+        // #### THEN  ####
+        assert!(first.is_ok(), "Queued withdrawals count as done: {:?}", first);
+        assert!(flush_unavailable.is_err(), "Unavailable endpoint must be reported.");
+        assert_eq!(requests_unavailable, 2);
+        assert!(flush_available.is_ok(), "Pending withdrawal delivered: {:?}", flush_available);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        let event = Json::from_str(&requests[2].body).unwrap();
+        assert_eq!(event.find("event").and_then(|e| e.as_string()), Some("withdraw"));
+    }
+
+    #[test]
+    fn unchanged_not_sent_again() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::new();
+        let mut publisher = WebhookPublisher::new(Arc::new(webhook_config(&server)));
+        let mut changed = example_publication();
+        changed.host = "172.17.0.3".to_owned();
+
+        // #### WHEN  ####
+        publisher.publish(&example_publication()).expect("Publish");
+        publisher.publish(&example_publication()).expect("Publish unchanged");
+        publisher.flush().expect("Flush");
+        publisher.publish(&changed).expect("Publish changed");
+        publisher.flush().expect("Flush");
+
+        // #### THEN  ####
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2, "Requests: {:#?}", requests);
+        let event = Json::from_str(&requests[1].body).unwrap();
+        assert_eq!(event.find("host").and_then(|h| h.as_string()), Some("172.17.0.3"));
+    }
+
+    #[test]
+    fn slow_endpoint_not_waited_for() {
+        common::init_log();
+        // #### GIVEN ####
+        let server = MockHttpServer::with_handler(Box::new(|_: &RecordedRequest| {
+            thread::sleep(Duration::from_millis(1500));
+            (200, String::new())
+        }));
+        let mut config = webhook_config(&server);
+        config.refresh_seconds = Some(27);
+        let mut publisher = WebhookPublisher::new(Arc::new(config));
+        let mut other = example_publication();
+        other.key = "other".to_owned();
+
+        // #### WHEN  ####
+        let start = Instant::now();
+        publisher.publish(&example_publication()).expect("Publish");
+        publisher.publish(&other).expect("Publish other");
+        publisher.withdraw(&other).expect("Withdraw other");
+        publisher.flush().expect("Flush");
+        let elapsed = start.elapsed();
+
+        // #### THEN  ####
+        assert!(elapsed < Duration::from_millis(500),
+                "Publishing must not wait for the endpoint. Took {:?}",
+                elapsed);
+        let queue = publisher.queue_opt.as_ref().unwrap();
+        let state = queue.state.lock().unwrap();
+        let queued = state.events
+            .iter()
+            .filter(|e| e.0 == "other")
+            .map(|e| e.1.find("event").and_then(|e| e.as_string()).unwrap_or(""))
+            .collect::<Vec<_>>();
+        assert_eq!(queued, vec!["withdraw"], "Withdrawal must replace the queued publication.");
+    }
+}
@@ -0,0 +1,349 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Christian Klauser
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A small subset of [mustache](https://mustache.github.io/mustache.5.html) templates, rendered
+//! against JSON data. Used to render configuration files (e.g., for nginx).
+//!
+//! Supported tags: `{{name}}` (no HTML escaping), `{{#name}}...{{/name}}` (sections: repeated for
+//! arrays, skipped for missing/false/null values and empty arrays), `{{^name}}...{{/name}}`
+//! (inverted sections) and `{{! comments }}`. Names can be dotted (`http.port`) and `.` stands for
+//! the current value. Lines that only hold a section tag or a comment don't show up in the output.
+
+use std::error::Error;
+
+use rustc_serialize::json::Json;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Variable(String),
+    /// Name, inverted, contents
+    Section(String, bool, Vec<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    /// Sigil ('#', '^', '/', '!' or ' ' for variables) and name. Line number for error messages.
+    Tag(char, String, usize),
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template, TemplateError> {
+        let mut tokens = try!(tokenize(text));
+        trim_standalone_tags(&mut tokens);
+        let mut tokens = tokens.into_iter();
+        let nodes = try!(build(&mut tokens, None));
+        Ok(Template { nodes: nodes })
+    }
+
+    pub fn render(&self, data: &Json) -> String {
+        let mut output = String::new();
+        let mut stack = vec![data];
+        render_nodes(&self.nodes, &mut stack, &mut output);
+        output
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    let mut line = 1;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token::Text(rest[..start].to_owned()));
+        }
+        line += rest[..start].matches('\n').count();
+        let after_open = &rest[start + 2..];
+        let end = try!(after_open.find("}}")
+            .ok_or_else(|| TemplateError::Syntax(line, "Unclosed tag.".to_owned())));
+        let tag = after_open[..end].trim();
+        let (sigil, name) = match tag.chars().next() {
+            Some(c) if c == '#' || c == '^' || c == '/' || c == '!' => (c, tag[1..].trim()),
+            _ => (' ', tag),
+        };
+        if name.is_empty() && sigil != '!' {
+            return Err(TemplateError::Syntax(line, "Empty tag.".to_owned()));
+        }
+        tokens.push(Token::Tag(sigil, name.to_owned(), line));
+        line += after_open[..end].matches('\n').count();
+        rest = &after_open[end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest.to_owned()));
+    }
+    Ok(tokens)
+}
+
+/// Remove the indentation and line break around tags that are alone on their line (except for
+/// variables).
+fn trim_standalone_tags(tokens: &mut Vec<Token>) {
+    // Whether a text token starts at the beginning of a line.
+    let mut starts_line = vec![false; tokens.len()];
+    if !starts_line.is_empty() {
+        starts_line[0] = true;
+    }
+    for i in 0..tokens.len() {
+        let standalone_kind = match tokens[i] {
+            Token::Tag(sigil, _, _) => sigil != ' ',
+            _ => false,
+        };
+        if !standalone_kind {
+            continue;
+        }
+        let before_ok = {
+            let previous = if i > 0 { tokens.get(i - 1) } else { None };
+            match previous {
+                Some(&Token::Text(ref text)) => {
+                    let line_start = text.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+                    text[line_start..].chars().all(|c| c == ' ' || c == '\t') &&
+                    (line_start > 0 || starts_line[i - 1])
+                }
+                None => true,
+                _ => false,
+            }
+        };
+        let after_ok = match tokens.get(i + 1) {
+            Some(&Token::Text(ref text)) => {
+                let line_end = text.find('\n').unwrap_or(text.len());
+                text[..line_end].trim_right_matches('\r').chars().all(|c| c == ' ' || c == '\t')
+            }
+            None => true,
+            _ => false,
+        };
+        if !before_ok || !after_ok {
+            continue;
+        }
+        if i > 0 {
+            if let Token::Text(ref mut text) = tokens[i - 1] {
+                let line_start = text.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+                text.truncate(line_start);
+            }
+        }
+        if let Some(&mut Token::Text(ref mut text)) = tokens.get_mut(i + 1) {
+            let remove = text.find('\n').map(|idx| idx + 1).unwrap_or(text.len());
+            text.drain(..remove);
+            starts_line[i + 1] = true;
+        }
+    }
+}
+
+fn build<I: Iterator<Item = Token>>(tokens: &mut I,
+                                    section: Option<(&str, usize)>)
+                                    -> Result<Vec<Node>, TemplateError> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Text(text) => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text))
+                }
+            }
+            Token::Tag('!', _, _) => (),
+            Token::Tag('/', name, line) => {
+                return match section {
+                    Some((open, _)) if open == name => Ok(nodes),
+                    Some((open, _)) => {
+                        Err(TemplateError::Syntax(line,
+                                                  format!("Expected {{{{/{}}}}}, found \
+                                                           {{{{/{}}}}}.",
+                                                          open,
+                                                          name)))
+                    }
+                    None => {
+                        Err(TemplateError::Syntax(line,
+                                                  format!("Unexpected {{{{/{}}}}}.", name)))
+                    }
+                };
+            }
+            Token::Tag(sigil, name, line) => {
+                if sigil == '#' || sigil == '^' {
+                    let contents = try!(build(tokens, Some((&name, line))));
+                    nodes.push(Node::Section(name, sigil == '^', contents));
+                } else {
+                    nodes.push(Node::Variable(name));
+                }
+            }
+        }
+    }
+    match section {
+        Some((name, line)) => {
+            Err(TemplateError::Syntax(line, format!("Section {} is never closed.", name)))
+        }
+        None => Ok(nodes),
+    }
+}
+
+/// Look up a (dotted) name. The first part is searched for from the innermost context outwards.
+fn lookup<'a>(stack: &[&'a Json], name: &str) -> Option<&'a Json> {
+    if name == "." {
+        return stack.last().map(|top| *top);
+    }
+    let mut parts = name.split('.');
+    let first = parts.next().unwrap_or("");
+    let mut value = match stack.iter().rev().filter_map(|context| context.find(first)).next() {
+        Some(value) => value,
+        None => return None,
+    };
+    for part in parts {
+        value = match value.find(part) {
+            Some(nested) => nested,
+            None => return None,
+        };
+    }
+    Some(value)
+}
+
+fn render_nodes<'a>(nodes: &[Node], stack: &mut Vec<&'a Json>, output: &mut String) {
+    for node in nodes {
+        match *node {
+            Node::Text(ref text) => output.push_str(text),
+            Node::Variable(ref name) => {
+                match lookup(stack, name) {
+                    Some(&Json::String(ref s)) => output.push_str(s),
+                    Some(&Json::Null) | None => (),
+                    Some(other) => output.push_str(&other.to_string()),
+                }
+            }
+            Node::Section(ref name, inverted, ref contents) => {
+                let value = lookup(stack, name);
+                let truthy = match value {
+                    None | Some(&Json::Null) | Some(&Json::Boolean(false)) => false,
+                    Some(&Json::Array(ref items)) => !items.is_empty(),
+                    _ => true,
+                };
+                if inverted {
+                    if !truthy {
+                        render_nodes(contents, stack, output);
+                    }
+                } else if truthy {
+                    let items = match value {
+                        Some(&Json::Array(ref items)) => items.iter().collect(),
+                        Some(value) => vec![value],
+                        None => Vec::new(),
+                    };
+                    for item in items {
+                        stack.push(item);
+                        render_nodes(contents, stack, output);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ############### TEMPLATE ERROR #########################
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TemplateError {
+        Syntax(line: usize, reason: String) {
+            description("Invalid template.")
+            display(me) -> ("{} Line {}: {}", me.description(), line, reason)
+        }
+    }
+}
+
+// ############### TESTING ################################
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use common;
+
+    fn render(template: &str, data: &str) -> String {
+        Template::parse(template)
+            .expect("Parse template")
+            .render(&Json::from_str(data).expect("Parse test data"))
+    }
+
+    #[test]
+    fn variables() {
+        common::init_log();
+        // #### GIVEN ####
+        let template = "server {{host}}:{{http.port}}; # {{missing}}{{flag}}";
+
+        // #### WHEN  ####
+        let output = render(template, r#"{"host": "a&b", "http": {"port": 80}, "flag": true}"#);
+
+        // #### THEN  ####
+        assert_eq!(output, "server a&b:80; # true");
+    }
+
+    #[test]
+    fn sections() {
+        common::init_log();
+        // #### GIVEN ####
+        let template = "upstream {{id}} {\n\
+                        {{#servers}}\n\
+                        \x20   server {{host}}:{{port}}; # {{id}}\n\
+                        {{/servers}}\n\
+                        {{^servers}}\n\
+                        \x20   server 127.0.0.1:9 down;\n\
+                        {{/servers}}\n\
+                        }\n";
+
+        // #### WHEN  ####
+        let full = render(template,
+                          r#"{"id": "app", "servers": [{"host": "a", "port": 1},
+                                                         {"host": "b", "port": 2}]}"#);
+        let empty = render(template, r#"{"id": "app", "servers": []}"#);
+
+        // #### THEN  ####
+        assert_eq!(full,
+                   "upstream app {\n    server a:1; # app\n    server b:2; # app\n}\n");
+        assert_eq!(empty, "upstream app {\n    server 127.0.0.1:9 down;\n}\n");
+    }
+
+    #[test]
+    fn object_section_and_comment() {
+        common::init_log();
+        // #### GIVEN ####
+        let template = "{{! only with https }}\n{{#https}}listen {{port}} ssl;{{/https}}";
+
+        // #### WHEN  ####
+        let with = render(template, r#"{"https": {"port": 443}}"#);
+        let without = render(template, r#"{"http": {"port": 80}}"#);
+
+        // #### THEN  ####
+        assert_eq!(with, "listen 443 ssl;");
+        assert_eq!(without, "");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        common::init_log();
+        assert!(Template::parse("{{#a}}\n{{/b}}").is_err());
+        assert!(Template::parse("{{#a}}").is_err());
+        assert!(Template::parse("{{/a}}").is_err());
+        assert!(Template::parse("{{a").is_err());
+        assert!(Template::parse("{{}}").is_err());
+    }
+}